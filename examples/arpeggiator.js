@@ -0,0 +1,17 @@
+// Sends an arpeggio to the host on every sixteenth note while it's playing
+const notes = [60, 64, 67, 72];
+let step = -1;
+
+function block(t, { playing, beats, duration }) {
+  if (!playing || beats === null) {
+    return;
+  }
+
+  const next = Math.floor(beats * 4);
+  if (next !== step) {
+    step = next;
+    const note = notes[step % notes.length];
+    midi.noteOn(note, 0.8);
+    midi.noteOff(note, { at: t + duration / 2 });
+  }
+}
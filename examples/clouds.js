@@ -0,0 +1,11 @@
+// Granulates a sample loaded as "pad"
+const clouds = dsp.granular({
+  buffer: "pad",
+  size: 0.2,
+  density: 40,
+  onGrain(grain, t) {
+    grain.position = (t * 0.05) % 1;
+    grain.pitch = [0, 7, 12][Math.floor(Math.random() * 3)];
+  },
+});
+dsp.out(clouds, 0.2);
@@ -0,0 +1,7 @@
+function gain(t) {
+  const g = Math.sin(t * 3);
+  if (g < 0) {
+    console.warn("negative gain", g);
+  }
+  return g;
+}
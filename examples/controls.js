@@ -0,0 +1,30 @@
+// A small instrument with its own controls. The pad's x sets the pitch and y the level.
+const controls = {
+  cutoff: { type: "knob", min: 50, max: 8000, default: 800, log: true, unit: " Hz" },
+  resonance: { type: "knob", min: 0.5, max: 10, default: 2 },
+  level: { type: "slider", default: 0.3 },
+  square: { type: "toggle", label: "Square wave" },
+  pad: { type: "xy", x: { min: 55, max: 440, log: true, unit: " Hz" }, y: { default: 0 } },
+  pluck: { type: "button", label: "Pluck", onPress: () => (pluck = 1) },
+};
+
+const oscs = [dsp.saw({ freq: 110 }), dsp.square({ freq: 110 })];
+const [saw, square] = oscs.map((osc) => dsp.svf({ mode: "lowpass", input: osc }));
+
+let pluck = 0;
+
+function block(t, { duration }) {
+  const cutoff = Math.min(ui.cutoff * (1 + 4 * pluck), 18000);
+  for (const osc of oscs) {
+    osc.set({ freq: ui.pad.x });
+  }
+  for (const filter of [saw, square]) {
+    filter.set({ cutoff, q: ui.resonance });
+  }
+
+  const level = ui.level * Math.max(ui.pad.y, pluck);
+  dsp.out(saw, ui.square ? 0 : level);
+  dsp.out(square, ui.square ? level : 0);
+
+  pluck *= Math.exp(-duration * 8);
+}
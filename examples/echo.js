@@ -0,0 +1,9 @@
+let sampleRate = 44100;
+
+function block(t, info) {
+  sampleRate = info.sampleRate;
+}
+
+function process(t, inputs) {
+  return inputs.map((x, ch) => x + 0.5 * output(ch, 0.3 * sampleRate));
+}
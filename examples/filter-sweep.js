@@ -0,0 +1,7 @@
+const osc = dsp.saw({ freq: 55 });
+const filter = dsp.svf({ mode: "lowpass", cutoff: 400, q: 4, input: osc });
+dsp.out(filter, 0.3);
+
+function block(t) {
+  filter.set({ cutoff: 400 + 300 * Math.sin(t * 2) });
+}
@@ -0,0 +1,12 @@
+// Plays a sample loaded as "kick"
+const kick = dsp.player({ buffer: "kick" });
+dsp.out(kick);
+
+// Retrigger the kick on every beat
+let lastBeat = -1;
+function block(t, { beats }) {
+  if (beats !== null && Math.floor(beats) !== lastBeat) {
+    lastBeat = Math.floor(beats);
+    kick.trigger();
+  }
+}
@@ -0,0 +1,13 @@
+const latency = 64;
+let peak = 0;
+
+// A crude lookahead limiter
+function process(t, inputs) {
+  let ahead = 0;
+  for (let n = 0; n <= latency; n++) {
+    ahead = Math.max(ahead, Math.abs(lookahead(0, n)));
+  }
+  peak = Math.max(ahead, peak * 0.999);
+
+  return inputs.map((x) => (peak > 1 ? x / peak : x));
+}
@@ -0,0 +1,11 @@
+const clouds = dsp.granular({ buffer: "loop", size: 0.3, density: 30, jitter: 0.2 });
+dsp.out(clouds, 0.3);
+
+function block(t, { beats }) {
+  // Record the first four bars, then granulate them. Stopping waits for the bar line at beat 16.
+  if (beats !== null && beats >= 12 && looper.status.state === "recording") {
+    looper.stop();
+  }
+}
+
+looper.record();
@@ -0,0 +1,19 @@
+const analysisOptions = {
+  attack: 0.01, // seconds
+  release: 0.1,
+  pitch: true,
+  minFreq: 50, // the range of pitches to look for, in Hz
+  maxFreq: 2000,
+  threshold: 0.15, // lower is stricter about what counts as pitched
+  onset: true,
+  onsetThreshold: 2, // how much louder than the recent average an onset is
+};
+
+const osc = dsp.sine({ freq: 440 });
+
+function block(t, info) {
+  if (analysis.pitch !== null) {
+    osc.set({ freq: analysis.pitch * 1.5 });
+  }
+  dsp.out(osc, analysis.envelope);
+}
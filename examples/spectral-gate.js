@@ -0,0 +1,12 @@
+const spectralOptions = { size: 2048, hop: 512, window: "hann" };
+
+// A spectral gate
+function spectral(t, frames) {
+  for (const { magnitude } of frames) {
+    for (let i = 0; i < magnitude.length; i++) {
+      if (magnitude[i] < 0.5) {
+        magnitude[i] = 0;
+      }
+    }
+  }
+}
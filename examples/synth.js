@@ -0,0 +1,10 @@
+// A plucked sine synth
+function voice(t, note, velocity, state) {
+  const freq = 440 * Math.pow(2, (note - 69) / 12);
+  const env = Math.exp(-t * 4) * (state.gate ? 1 : Math.exp(-state.released * 20));
+  if (env < 0.0001) {
+    state.done = true;
+  }
+
+  return velocity * env * Math.sin(2 * Math.PI * freq * t);
+}
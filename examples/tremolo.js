@@ -0,0 +1 @@
+Math.sin(t * 2 * Math.PI)
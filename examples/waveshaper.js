@@ -0,0 +1,4 @@
+// Run this with oversampling to reduce the aliasing
+function process(t, inputs) {
+  return inputs.map((x) => Math.tanh(8 * x));
+}
@@ -0,0 +1,462 @@
+//! Features of the input that are computed natively once per block, for scripts that react to their
+//! input. Scripts opt in by defining an `analysisOptions` object, and read the features from the
+//! `analysis` object or from `info.analysis` in `block(t, info)`.
+
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// The number of samples YIN compares with their delayed copies. The lowest pitch it can detect is
+/// the sample rate divided by this.
+const YIN_WINDOW: usize = 1024;
+/// The pitch estimate is updated after this many samples, since YIN is relatively expensive.
+const YIN_INTERVAL: usize = 512;
+/// Inputs quieter than this don't have a pitch.
+const YIN_MIN_RMS: f32 = 1e-4;
+
+/// The time constants of the onset detector's fast and slow envelopes, and the minimum time
+/// between two onsets, in seconds.
+const ONSET_FAST_ATTACK: f32 = 0.001;
+const ONSET_FAST_RELEASE: f32 = 0.03;
+const ONSET_SLOW_RELEASE: f32 = 0.25;
+const ONSET_MIN_INTERVAL: f32 = 0.05;
+/// Onsets are only detected above roughly -50 dB.
+const ONSET_FLOOR: f32 = 0.003;
+
+/// The options from the script's `analysisOptions` object. Anything the script leaves out uses
+/// the defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct AnalysisOptions {
+    /// The envelope follower's attack and release times in seconds.
+    pub attack: f32,
+    pub release: f32,
+    /// Whether to estimate the input's pitch.
+    pub pitch: bool,
+    pub min_freq: f32,
+    pub max_freq: f32,
+    /// YIN's threshold for the normalized difference function. Lower values are stricter.
+    pub threshold: f32,
+    /// Whether to detect onsets.
+    pub onset: bool,
+    /// How much louder than the recent average the input needs to get to count as an onset.
+    pub onset_threshold: f32,
+}
+
+impl Default for AnalysisOptions {
+    fn default() -> Self {
+        Self {
+            attack: 0.01,
+            release: 0.1,
+            pitch: false,
+            min_freq: 50.0,
+            max_freq: 2000.0,
+            threshold: 0.15,
+            onset: false,
+            onset_threshold: 2.0,
+        }
+    }
+}
+
+/// The features of a single block of input, as passed to the script.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Features {
+    pub peak: f32,
+    pub rms: f32,
+    /// The envelope follower's value at the end of the block.
+    pub envelope: f32,
+    /// Zero crossings per second.
+    pub zero_crossing_rate: f32,
+    /// The estimated pitch in Hertz, if pitch detection is enabled and the input is pitched.
+    pub pitch: Option<f32>,
+    /// How periodic the input is, from 0 to 1.
+    pub pitch_confidence: f32,
+    /// Whether an onset was detected during the block.
+    pub onset: bool,
+}
+
+pub struct Analyzer {
+    options: AnalysisOptions,
+    sample_rate: f32,
+
+    envelope: f32,
+    attack: f32,
+    release: f32,
+    /// The last sample of the previous block, for counting zero crossings across blocks.
+    last_sample: f32,
+
+    /// The most recent `2 * YIN_WINDOW` samples, and YIN's difference function.
+    pitch_buffer: Vec<f32>,
+    pitch_pos: usize,
+    since_pitch: usize,
+    pitch_frame: Vec<f32>,
+    difference: Vec<f32>,
+    /// Only set up when pitch detection is enabled.
+    correlation: Option<Correlation>,
+    pitch: Option<f32>,
+    pitch_confidence: f32,
+
+    onset_fast: f32,
+    onset_slow: f32,
+    onset_fast_attack: f32,
+    onset_fast_release: f32,
+    onset_slow_release: f32,
+    since_onset: usize,
+    /// Cleared after an onset until the input settles down again, so a single attack is only
+    /// reported once.
+    onset_armed: bool,
+}
+
+impl Analyzer {
+    pub fn new(options: AnalysisOptions, sample_rate: f32) -> Self {
+        let pitch_len = if options.pitch { YIN_WINDOW * 2 } else { 0 };
+
+        Self {
+            options,
+            sample_rate,
+
+            envelope: 0.0,
+            attack: coefficient(options.attack, sample_rate),
+            release: coefficient(options.release, sample_rate),
+            last_sample: 0.0,
+
+            pitch_buffer: vec![0.0; pitch_len],
+            pitch_pos: 0,
+            since_pitch: 0,
+            pitch_frame: vec![0.0; pitch_len],
+            difference: vec![0.0; pitch_len / 2],
+            correlation: options.pitch.then(Correlation::new),
+            pitch: None,
+            pitch_confidence: 0.0,
+
+            onset_fast: 0.0,
+            onset_slow: 0.0,
+            onset_fast_attack: coefficient(ONSET_FAST_ATTACK, sample_rate),
+            onset_fast_release: coefficient(ONSET_FAST_RELEASE, sample_rate),
+            onset_slow_release: coefficient(ONSET_SLOW_RELEASE, sample_rate),
+            since_onset: usize::MAX,
+            onset_armed: true,
+        }
+    }
+
+    pub fn options(&self) -> AnalysisOptions {
+        self.options
+    }
+
+    /// Analyzes a block of (mono) input.
+    pub fn process(&mut self, samples: impl IntoIterator<Item = f32>) -> Features {
+        let min_onset_interval = (ONSET_MIN_INTERVAL * self.sample_rate) as usize;
+
+        let mut num_samples = 0;
+        let mut peak: f32 = 0.0;
+        let mut sum_squares = 0.0;
+        let mut zero_crossings = 0;
+        let mut onset = false;
+        for x in samples {
+            num_samples += 1;
+
+            let level = x.abs();
+            peak = peak.max(level);
+            sum_squares += x * x;
+            if (x >= 0.0) != (self.last_sample >= 0.0) {
+                zero_crossings += 1;
+            }
+            self.last_sample = x;
+
+            let coefficient = if level > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope = level + (self.envelope - level) * coefficient;
+
+            if self.options.onset {
+                // The fast envelope follows attacks within a millisecond, the slow envelope is the
+                // recent average
+                let coefficient = if level > self.onset_fast {
+                    self.onset_fast_attack
+                } else {
+                    self.onset_fast_release
+                };
+                self.onset_fast = level + (self.onset_fast - level) * coefficient;
+                self.since_onset = self.since_onset.saturating_add(1);
+                let rising = self.onset_fast > self.onset_slow * self.options.onset_threshold;
+                if self.onset_armed
+                    && rising
+                    && self.onset_fast > ONSET_FLOOR
+                    && self.since_onset > min_onset_interval
+                {
+                    onset = true;
+                    self.onset_armed = false;
+                    self.since_onset = 0;
+                } else if !rising {
+                    self.onset_armed = true;
+                }
+                self.onset_slow = level + (self.onset_slow - level) * self.onset_slow_release;
+            }
+
+            if self.options.pitch {
+                self.pitch_buffer[self.pitch_pos] = x;
+                self.pitch_pos = (self.pitch_pos + 1) % self.pitch_buffer.len();
+                self.since_pitch += 1;
+                if self.since_pitch >= YIN_INTERVAL {
+                    self.since_pitch = 0;
+                    self.estimate_pitch();
+                }
+            }
+        }
+
+        let duration = num_samples as f32 / self.sample_rate;
+        Features {
+            peak,
+            rms: if num_samples > 0 {
+                (sum_squares / num_samples as f32).sqrt()
+            } else {
+                0.0
+            },
+            envelope: self.envelope,
+            zero_crossing_rate: if num_samples > 0 {
+                zero_crossings as f32 / duration
+            } else {
+                0.0
+            },
+            pitch: self.pitch,
+            pitch_confidence: self.pitch_confidence,
+            onset,
+        }
+    }
+
+    /// Estimates the pitch of the most recent samples using YIN.
+    fn estimate_pitch(&mut self) {
+        let len = self.pitch_buffer.len();
+        for (i, x) in self.pitch_frame.iter_mut().enumerate() {
+            *x = self.pitch_buffer[(self.pitch_pos + i) % len];
+        }
+
+        let frame = &self.pitch_frame;
+        let rms = (frame.iter().map(|x| x * x).sum::<f32>() / len as f32).sqrt();
+        if rms < YIN_MIN_RMS {
+            self.pitch = None;
+            self.pitch_confidence = 0.0;
+            return;
+        }
+
+        let min_lag = ((self.sample_rate / self.options.max_freq) as usize).max(2);
+        let max_lag = ((self.sample_rate / self.options.min_freq) as usize).min(YIN_WINDOW - 1);
+        if min_lag >= max_lag {
+            self.pitch = None;
+            self.pitch_confidence = 0.0;
+            return;
+        }
+
+        let correlation = match &mut self.correlation {
+            Some(correlation) => correlation,
+            None => return,
+        };
+        correlation.process(frame);
+
+        // The difference function expands to the energy of the window, plus the energy of the
+        // delayed window, minus twice their correlation. The delayed window's energy is kept as a
+        // running sum. With the correlation computed using FFTs this takes `O(n log n)` instead of
+        // `O(n^2)`.
+        let energy: f32 = frame[..YIN_WINDOW].iter().map(|x| x * x).sum();
+        let mut delayed_energy = energy;
+        let difference = &mut self.difference;
+        difference[0] = 1.0;
+        let mut running_sum = 0.0;
+        for lag in 1..=max_lag {
+            delayed_energy += frame[lag + YIN_WINDOW - 1].powi(2) - frame[lag - 1].powi(2);
+            let d = (energy + delayed_energy - 2.0 * correlation.output[lag]).max(0.0);
+            running_sum += d;
+            difference[lag] = if running_sum > 0.0 {
+                d * lag as f32 / running_sum
+            } else {
+                1.0
+            };
+        }
+
+        // The first dip below the threshold, or the global minimum if there is none
+        let mut best = (min_lag..=max_lag)
+            .find(|&lag| difference[lag] < self.options.threshold)
+            .unwrap_or_else(|| {
+                (min_lag..=max_lag)
+                    .min_by(|&a, &b| difference[a].total_cmp(&difference[b]))
+                    .unwrap_or(min_lag)
+            });
+        while best < max_lag && difference[best + 1] < difference[best] {
+            best += 1;
+        }
+
+        self.pitch_confidence = (1.0 - difference[best]).clamp(0.0, 1.0);
+        if difference[best] >= self.options.threshold {
+            self.pitch = None;
+            return;
+        }
+
+        // Parabolic interpolation between the neighbouring lags
+        let lag = if best > 1 && best < max_lag {
+            let (a, b, c) = (difference[best - 1], difference[best], difference[best + 1]);
+            let denominator = a - 2.0 * b + c;
+            if denominator.abs() > f32::EPSILON {
+                best as f32 + 0.5 * (a - c) / denominator
+            } else {
+                best as f32
+            }
+        } else {
+            best as f32
+        };
+
+        self.pitch = Some(self.sample_rate / lag);
+    }
+
+    pub fn reset(&mut self) {
+        self.envelope = 0.0;
+        self.last_sample = 0.0;
+        self.pitch_buffer.fill(0.0);
+        self.since_pitch = 0;
+        self.pitch = None;
+        self.pitch_confidence = 0.0;
+        self.onset_fast = 0.0;
+        self.onset_slow = 0.0;
+        self.since_onset = usize::MAX;
+        self.onset_armed = true;
+    }
+}
+
+/// Cross-correlates the first `YIN_WINDOW` samples of a frame with the whole frame, using FFTs.
+struct Correlation {
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    time: Vec<f32>,
+    window_spectrum: Vec<Complex<f32>>,
+    frame_spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// The correlation for every lag. Only the first `YIN_WINDOW` are free of circular aliasing.
+    output: Vec<f32>,
+}
+
+impl Correlation {
+    fn new() -> Self {
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(YIN_WINDOW * 2);
+        let inverse = planner.plan_fft_inverse(YIN_WINDOW * 2);
+        let scratch_len = forward.get_scratch_len().max(inverse.get_scratch_len());
+
+        Self {
+            time: forward.make_input_vec(),
+            window_spectrum: forward.make_output_vec(),
+            frame_spectrum: forward.make_output_vec(),
+            scratch: vec![Complex::default(); scratch_len],
+            output: inverse.make_output_vec(),
+            forward,
+            inverse,
+        }
+    }
+
+    /// Computes `sum(frame[j] * frame[j + lag])` over the first `YIN_WINDOW` values of `j`. The
+    /// window is zero padded, so lags below `YIN_WINDOW` don't wrap around.
+    fn process(&mut self, frame: &[f32]) {
+        self.time.fill(0.0);
+        self.time[..YIN_WINDOW].copy_from_slice(&frame[..YIN_WINDOW]);
+        let _ = self.forward.process_with_scratch(
+            &mut self.time,
+            &mut self.window_spectrum,
+            &mut self.scratch,
+        );
+
+        self.time.copy_from_slice(frame);
+        let _ = self.forward.process_with_scratch(
+            &mut self.time,
+            &mut self.frame_spectrum,
+            &mut self.scratch,
+        );
+
+        // The inverse FFT isn't normalized
+        let scale = 1.0 / (YIN_WINDOW * 2) as f32;
+        for (frame, window) in self.frame_spectrum.iter_mut().zip(&self.window_spectrum) {
+            *frame *= window.conj() * scale;
+        }
+        // These are real in theory, but rounding errors would make the inverse FFT complain
+        if let Some(first) = self.frame_spectrum.first_mut() {
+            first.im = 0.0;
+        }
+        if let Some(last) = self.frame_spectrum.last_mut() {
+            last.im = 0.0;
+        }
+
+        let _ = self.inverse.process_with_scratch(
+            &mut self.frame_spectrum,
+            &mut self.output,
+            &mut self.scratch,
+        );
+    }
+}
+
+/// The coefficient for a one-pole filter that takes `time` seconds to reach roughly 63% of its
+/// target.
+fn coefficient(time: f32, sample_rate: f32) -> f32 {
+    if time <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (time * sample_rate)).exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    fn analyze(options: AnalysisOptions, samples: impl Iterator<Item = f32>) -> Features {
+        let mut analyzer = Analyzer::new(options, SAMPLE_RATE);
+        let samples: Vec<f32> = samples.take(8192).collect();
+        let mut features = Features::default();
+        for block in samples.chunks(256) {
+            features = analyzer.process(block.iter().copied());
+        }
+
+        features
+    }
+
+    #[test]
+    fn yin_finds_the_pitch_of_a_sine() {
+        let options = AnalysisOptions {
+            pitch: true,
+            ..Default::default()
+        };
+        for freq in [82.41, 220.0, 440.0, 1234.5] {
+            let sine = (0..).map(|i| 0.5 * (TAU * freq * i as f32 / SAMPLE_RATE).sin());
+            let features = analyze(options, sine);
+
+            let pitch = features.pitch.expect("A sine is pitched");
+            assert!(
+                (pitch / freq - 1.0).abs() < 0.002,
+                "Expected {freq} Hz, got {pitch} Hz"
+            );
+            assert!(features.pitch_confidence > 0.95);
+        }
+    }
+
+    #[test]
+    fn silence_has_no_pitch() {
+        let options = AnalysisOptions {
+            pitch: true,
+            ..Default::default()
+        };
+        let features = analyze(options, std::iter::repeat(0.0));
+
+        assert_eq!(features.pitch, None);
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        let options: Result<AnalysisOptions, _> = serde_json::from_str(r#"{ "pitch": true }"#);
+        assert!(options.unwrap().pitch);
+
+        let options: Result<AnalysisOptions, _> = serde_json::from_str(r#"{ "pich": true }"#);
+        assert!(options.is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! The bridge between the script's `console.log()`, `console.warn()` and `console.error()` and the
+//! editor's log panel. The script collects its messages during a block, and at the end of the block
+//! they're timestamped, rate limited, and sent to the editor as telemetry records. Messages that
+//! don't fit are counted instead.
+
+use nih_plug_egui::egui::{self, Color32, RichText};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::telemetry::{RecordKind, Telemetry};
+
+/// Longer messages are truncated to this many bytes.
+const MAX_TEXT_LEN: usize = 240;
+/// The rate limit. Up to `MAX_BURST` messages can be logged at once, after which the budget is
+/// refilled at `MESSAGES_PER_SECOND`.
+const MESSAGES_PER_SECOND: f32 = 50.0;
+const MAX_BURST: f32 = 100.0;
+/// The number of lines the log panel keeps.
+const MAX_LINES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Log,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// The levels in the order the script numbers them.
+    pub const ALL: [LogLevel; 3] = [LogLevel::Log, LogLevel::Warn, LogLevel::Error];
+
+    fn color(self) -> Color32 {
+        match self {
+            LogLevel::Log => Color32::from_gray(60),
+            LogLevel::Warn => Color32::from_rgb(190, 120, 0),
+            LogLevel::Error => Color32::from_rgb(200, 40, 40),
+        }
+    }
+}
+
+/// The host's position at the start of a block, which the messages logged during that block are
+/// timestamped with.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockPosition {
+    /// The script's time at the start of the block.
+    pub time: f64,
+    pub sample_rate: f32,
+    /// The position in samples, and in quarter notes if the host reports a tempo.
+    pub samples: i64,
+    pub beats: Option<f64>,
+    pub tempo: Option<f64>,
+}
+
+impl BlockPosition {
+    /// The position at the script's time `t`, somewhere in the block.
+    fn at(&self, t: f64) -> (i64, Option<f64>) {
+        let offset = ((t - self.time) * self.sample_rate as f64).round().max(0.0) as i64;
+        let beats = self
+            .beats
+            .zip(self.tempo)
+            .map(|(beats, tempo)| beats + offset as f64 * tempo / 60.0 / self.sample_rate as f64);
+
+        (self.samples + offset, beats)
+    }
+}
+
+/// The size of a console record's fixed fields: the level, the repeat count, and the position in
+/// samples and in beats.
+const HEADER_LEN: usize = 1 + 4 + 8 + 8;
+
+/// Encodes a message's fixed fields for a console record. The text follows them. The position in
+/// beats is NaN if the host didn't report it.
+fn encode(level: LogLevel, repeats: u32, samples: i64, beats: Option<f64>) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[0] = level as u8;
+    header[1..5].copy_from_slice(&repeats.to_le_bytes());
+    header[5..13].copy_from_slice(&samples.to_le_bytes());
+    header[13..].copy_from_slice(&beats.unwrap_or(f64::NAN).to_le_bytes());
+    header
+}
+
+/// Decodes a console record written by `Console::log()`.
+fn decode(payload: &[u8]) -> Option<LogLine> {
+    if payload.len() < HEADER_LEN {
+        return None;
+    }
+
+    let beats = f64::from_le_bytes(payload[13..21].try_into().unwrap());
+    Some(LogLine {
+        level: *LogLevel::ALL.get(payload[0] as usize)?,
+        text: String::from_utf8_lossy(&payload[HEADER_LEN..]).into_owned(),
+        samples: i64::from_le_bytes(payload[5..13].try_into().unwrap()),
+        beats: (!beats.is_nan()).then_some(beats),
+        count: u32::from_le_bytes(payload[1..5].try_into().unwrap()),
+    })
+}
+
+/// Writes console records from the audio thread, within the rate limit.
+pub struct Console {
+    telemetry: Arc<Telemetry>,
+    /// The number of messages that can be written right now.
+    budget: f32,
+}
+
+impl Console {
+    pub fn new(telemetry: Arc<Telemetry>) -> Self {
+        Self {
+            telemetry,
+            budget: MAX_BURST,
+        }
+    }
+
+    /// Refills the rate limit for a block's `duration` in seconds. Called before the messages
+    /// logged during the block are written.
+    pub fn refill(&mut self, duration: f32) {
+        self.budget = (self.budget + duration * MESSAGES_PER_SECOND).min(MAX_BURST);
+    }
+
+    /// Writes a single message that was logged at the script's time `t`. The plugin reports its own
+    /// errors through this as well.
+    pub fn log(
+        &mut self,
+        level: LogLevel,
+        text: &str,
+        position: &BlockPosition,
+        t: f64,
+        repeats: u32,
+    ) {
+        if self.budget < 1.0 {
+            self.telemetry.drop_messages(1);
+            return;
+        }
+
+        // Truncated on a character boundary
+        let mut len = text.len().min(MAX_TEXT_LEN);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.budget -= 1.0;
+        let (samples, beats) = position.at(t);
+        let header = encode(level, repeats, samples, beats);
+        if !self
+            .telemetry
+            .send(RecordKind::Console, &[&header, &text.as_bytes()[..len]])
+        {
+            self.telemetry.drop_messages(1);
+        }
+    }
+
+    /// Reports messages the script couldn't hand over, because it logged too many in a block.
+    pub fn dropped(&self, count: usize) {
+        self.telemetry.drop_messages(count);
+    }
+}
+
+struct LogLine {
+    level: LogLevel,
+    text: String,
+    samples: i64,
+    beats: Option<f64>,
+    /// The number of times this line was logged in a row.
+    count: u32,
+}
+
+/// The lines shown in the editor's log panel.
+#[derive(Default)]
+pub struct ConsoleLog {
+    lines: VecDeque<LogLine>,
+    /// Set when lines were added since the panel was last drawn.
+    scroll: bool,
+}
+
+impl ConsoleLog {
+    /// Adds a console record. A message that's identical to the last line is merged into it, with
+    /// the timestamp of the most recent one.
+    pub fn receive(&mut self, payload: &[u8]) {
+        let line = match decode(payload) {
+            Some(line) => line,
+            None => return,
+        };
+
+        self.scroll = true;
+        match self.lines.back_mut() {
+            Some(last) if last.level == line.level && last.text == line.text => {
+                last.count += line.count;
+                last.samples = line.samples;
+                last.beats = line.beats;
+            }
+            _ => {
+                if self.lines.len() >= MAX_LINES {
+                    self.lines.pop_front();
+                }
+                self.lines.push_back(line);
+            }
+        }
+    }
+}
+
+/// Draws the log panel, with the timestamps in samples and beats next to every line.
+pub fn console(ui: &mut egui::Ui, log: &mut ConsoleLog, telemetry: &Telemetry) {
+    let scroll = std::mem::take(&mut log.scroll);
+
+    ui.horizontal(|ui| {
+        ui.label("Console");
+        if ui.small_button("Clear").clicked() {
+            log.lines.clear();
+            telemetry.clear_dropped_messages();
+        }
+
+        let dropped = telemetry.dropped_messages();
+        if dropped > 0 {
+            ui.colored_label(
+                LogLevel::Warn.color(),
+                format!("{dropped} messages dropped"),
+            );
+        }
+    });
+
+    egui::ScrollArea::vertical()
+        .max_height(160.0)
+        .auto_shrink([false, true])
+        .show(ui, |ui| {
+            for line in &log.lines {
+                let beats = match line.beats {
+                    Some(beats) => format!("{beats:>9.3}"),
+                    None => format!("{:>9}", "-"),
+                };
+                let count = if line.count > 1 {
+                    format!(" (x{})", line.count)
+                } else {
+                    String::new()
+                };
+
+                ui.label(
+                    RichText::new(format!(
+                        "{:>10} {beats}  {}{count}",
+                        line.samples, line.text
+                    ))
+                    .monospace()
+                    .color(line.level.color()),
+                );
+            }
+
+            if scroll {
+                ui.scroll_to_cursor(Some(egui::Align::BOTTOM));
+            }
+        });
+
+    ui.ctx().request_repaint();
+}
@@ -0,0 +1,489 @@
+//! Controls declared by the script. A script can declare knobs, sliders, toggles, XY pads and
+//! buttons in a top-level `controls` object, and every control is bound to one of the plugin's
+//! control parameters in the order they're declared, or two for an XY pad. That way the host can
+//! automate them. The editor draws the controls next to the code, and the audio thread passes
+//! their values to the script's `ui` object at the start of every block.
+//!
+//! The controls are saved with the plugin's state, so a restored instance knows which parameters
+//! already belong to the script's controls. Controls that are new start at their
+//! defaults, and the ones the script already had keep their values when it's recompiled.
+
+use nih_plug::prelude::{FloatParam, Param, ParamSetter};
+use nih_plug_egui::egui::{self, Align2, Color32, FontId, Sense, Stroke};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The number of control parameters. Controls that don't fit are left out.
+pub const NUM_CONTROLS: usize = 8;
+
+const KNOB_SIZE: f32 = 48.0;
+const SLIDER_WIDTH: f32 = 160.0;
+const XY_SIZE: f32 = 120.0;
+/// How far the pointer has to move to turn a knob from its minimum to its maximum.
+const KNOB_DRAG_DISTANCE: f32 = 200.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ControlKind {
+    Knob,
+    Slider,
+    Toggle,
+    /// A pad that controls two values at once, `x` and `y`.
+    Xy,
+    /// On while it's pressed.
+    Button,
+}
+
+/// A control's value, or one of an XY pad's coordinates. Toggles and buttons go from 0 to 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Axis {
+    /// The control parameter this is bound to.
+    pub slot: usize,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    /// Whether the values are spread logarithmically, which only works when the range is positive.
+    pub log: bool,
+    /// Shown after the value, like ` Hz`.
+    pub unit: String,
+}
+
+impl Axis {
+    fn logarithmic(&self) -> bool {
+        self.log && self.min > 0.0 && self.max > 0.0
+    }
+
+    pub fn plain(&self, normalized: f32) -> f32 {
+        let normalized = normalized.clamp(0.0, 1.0);
+        if self.logarithmic() {
+            self.min * (self.max / self.min).powf(normalized)
+        } else {
+            self.min + (self.max - self.min) * normalized
+        }
+    }
+
+    pub fn normalized(&self, plain: f32) -> f32 {
+        let normalized = if self.min == self.max {
+            0.0
+        } else if self.logarithmic() {
+            (plain / self.min).ln() / (self.max / self.min).ln()
+        } else {
+            (plain - self.min) / (self.max - self.min)
+        };
+
+        normalized.clamp(0.0, 1.0)
+    }
+
+    fn format(&self, plain: f32) -> String {
+        let decimals = if plain.abs() >= 100.0 {
+            0
+        } else if plain.abs() >= 10.0 {
+            1
+        } else {
+            2
+        };
+
+        format!("{plain:.decimals$}{}", self.unit)
+    }
+}
+
+/// A control, as declared by the script and validated by the prelude.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Control {
+    /// The key in the script's `controls` and `ui` objects.
+    pub name: String,
+    pub label: String,
+    #[serde(rename = "type")]
+    pub kind: ControlKind,
+    /// One axis for every kind of control except XY pads, which have two.
+    pub axes: Vec<Axis>,
+}
+
+impl Control {
+    /// The value the script sees for a parameter's normalized value.
+    fn value(&self, axis: &Axis, normalized: f32) -> f32 {
+        match self.kind {
+            ControlKind::Toggle | ControlKind::Button if normalized >= 0.5 => 1.0,
+            ControlKind::Toggle | ControlKind::Button => 0.0,
+            _ => axis.plain(normalized),
+        }
+    }
+
+    /// Whether this control is bound to the same parameters as `other` in the same way, in which
+    /// case it keeps its values when the script is recompiled.
+    fn same_binding(&self, other: &Control) -> bool {
+        self.name == other.name
+            && self.kind == other.kind
+            && self.axes.len() == other.axes.len()
+            && self
+                .axes
+                .iter()
+                .zip(&other.axes)
+                .all(|(axis, other)| axis.slot == other.slot)
+    }
+}
+
+/// Counts the editor's button presses for every control parameter. A button that's pressed and
+/// released between two blocks has its parameter back at zero by the time the audio thread reads
+/// it, so the audio thread also takes the presses from here.
+#[derive(Default)]
+pub struct ButtonPresses {
+    presses: [AtomicU32; NUM_CONTROLS],
+}
+
+impl ButtonPresses {
+    pub fn press(&self, slot: usize) {
+        if let Some(presses) = self.presses.get(slot) {
+            presses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the button bound to `slot` was pressed since the last call.
+    pub fn take(&self, slot: usize) -> bool {
+        self.presses
+            .get(slot)
+            .map_or(false, |presses| presses.swap(0, Ordering::Relaxed) > 0)
+    }
+}
+
+/// The values the script sees for the control parameters, for the `controls` in `BlockInfo`.
+/// Parameters that aren't bound to a control are zero. Buttons that were pressed since the last
+/// block are on for this one, even if they were already released.
+pub fn values(
+    controls: &[Control],
+    normalized: impl Fn(usize) -> f32,
+    presses: &ButtonPresses,
+) -> [f32; NUM_CONTROLS] {
+    let mut values = [0.0; NUM_CONTROLS];
+    for control in controls {
+        for axis in &control.axes {
+            if let Some(value) = values.get_mut(axis.slot) {
+                *value = control.value(axis, normalized(axis.slot));
+                if control.kind == ControlKind::Button && presses.take(axis.slot) {
+                    *value = 1.0;
+                }
+            }
+        }
+    }
+
+    values
+}
+
+/// The values the script sees when all controls are at their defaults.
+pub fn defaults(controls: &[Control]) -> [f32; NUM_CONTROLS] {
+    let mut values = [0.0; NUM_CONTROLS];
+    for control in controls {
+        for axis in &control.axes {
+            if let Some(value) = values.get_mut(axis.slot) {
+                *value = axis.default;
+            }
+        }
+    }
+
+    values
+}
+
+/// The controls the running script declared. These are persisted in the plugin state.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ControlLayout {
+    controls: Vec<Control>,
+}
+
+impl ControlLayout {
+    pub fn controls(&self) -> &[Control] {
+        &self.controls
+    }
+
+    /// Replaces the controls, and calls `reset(slot, normalized)` with the default of every
+    /// parameter a new control is bound to.
+    pub fn update(&mut self, controls: &[Control], mut reset: impl FnMut(usize, f32)) {
+        for control in controls {
+            if self
+                .controls
+                .iter()
+                .any(|other| control.same_binding(other))
+            {
+                continue;
+            }
+
+            for axis in &control.axes {
+                reset(axis.slot, axis.normalized(axis.default));
+            }
+        }
+
+        self.controls.clear();
+        self.controls.extend_from_slice(controls);
+    }
+}
+
+/// Draws the script's controls. Changes go through `setter` so the host can record them as
+/// automation. Double-clicking a control resets it to its default. Every control's response is
+/// passed to `learnable` together with the parameters it's bound to, for MIDI learn. Button
+/// presses are also counted in `presses`.
+pub fn controls(
+    ui: &mut egui::Ui,
+    controls: &[Control],
+    params: &[&FloatParam; NUM_CONTROLS],
+    setter: &ParamSetter,
+    presses: &ButtonPresses,
+    mut learnable: impl FnMut(egui::Response, &[usize]),
+) {
+    for control in controls {
+        let bound: Vec<&FloatParam> = control
+            .axes
+            .iter()
+            .filter_map(|axis| params.get(axis.slot).copied())
+            .collect();
+        let axes = if control.kind == ControlKind::Xy {
+            2
+        } else {
+            1
+        };
+        if control.axes.len() != axes || bound.len() != axes {
+            continue;
+        }
+
+        ui.add_space(8.0);
+        let response = match control.kind {
+            ControlKind::Knob => knob(ui, control, &control.axes[0], bound[0], setter),
+            ControlKind::Slider => slider(ui, control, &control.axes[0], bound[0], setter),
+            ControlKind::Toggle => toggle(ui, control, bound[0], setter),
+            ControlKind::Xy => xy_pad(ui, control, bound[0], bound[1], setter),
+            ControlKind::Button => {
+                button(ui, control, control.axes[0].slot, bound[0], setter, presses)
+            }
+        };
+        let slots: Vec<usize> = control.axes.iter().map(|axis| axis.slot).collect();
+        learnable(response, &slots);
+    }
+}
+
+/// Sets a parameter from the editor in a single gesture.
+fn set(setter: &ParamSetter, param: &FloatParam, normalized: f32) {
+    setter.begin_set_parameter(param);
+    setter.set_parameter_normalized(param, normalized);
+    setter.end_set_parameter(param);
+}
+
+/// Turns dragging `response` into a gesture on `param`, with the new normalized value computed by
+/// `drag`. Double-clicking resets the parameter to `default`.
+fn drag_param(
+    response: &egui::Response,
+    setter: &ParamSetter,
+    param: &FloatParam,
+    default: f32,
+    drag: impl FnOnce(f32) -> f32,
+) {
+    if response.double_clicked() {
+        set(setter, param, default);
+        return;
+    }
+
+    if response.drag_started() {
+        setter.begin_set_parameter(param);
+    }
+    if response.dragged() {
+        let normalized = drag(param.unmodulated_normalized_value());
+        setter.set_parameter_normalized(param, normalized.clamp(0.0, 1.0));
+    }
+    if response.drag_released() {
+        setter.end_set_parameter(param);
+    }
+}
+
+fn knob(
+    ui: &mut egui::Ui,
+    control: &Control,
+    axis: &Axis,
+    param: &FloatParam,
+    setter: &ParamSetter,
+) -> egui::Response {
+    ui.horizontal(|ui| {
+        let (rect, response) =
+            ui.allocate_exact_size(egui::vec2(KNOB_SIZE, KNOB_SIZE), Sense::click_and_drag());
+        drag_param(
+            &response,
+            setter,
+            param,
+            axis.normalized(axis.default),
+            |normalized| normalized - response.drag_delta().y / KNOB_DRAG_DISTANCE,
+        );
+
+        // The knob turns from the bottom left to the bottom right
+        let normalized = param.unmodulated_normalized_value();
+        let angle = |normalized: f32| PI * (1.25 - 1.5 * normalized);
+        let point = |angle: f32, radius: f32| {
+            rect.center() + radius * egui::vec2(angle.cos(), -angle.sin())
+        };
+        let radius = KNOB_SIZE / 2.0 - 3.0;
+        let painter = ui.painter_at(rect);
+        painter.circle_filled(rect.center(), radius, Color32::from_gray(245));
+        let arc = |to: f32, stroke: Stroke| {
+            let points = (0..=32)
+                .map(|i| point(angle(to * i as f32 / 32.0), radius))
+                .collect();
+            painter.add(egui::Shape::line(points, stroke));
+        };
+        arc(1.0, Stroke::new(3.0, Color32::from_gray(220)));
+        arc(normalized, Stroke::new(3.0, Color32::from_rgb(40, 140, 60)));
+        painter.line_segment(
+            [rect.center(), point(angle(normalized), radius)],
+            Stroke::new(2.0, Color32::from_gray(60)),
+        );
+
+        ui.vertical(|ui| {
+            ui.label(control.label.as_str());
+            ui.weak(axis.format(axis.plain(normalized)));
+        });
+
+        response
+    })
+    .inner
+}
+
+fn slider(
+    ui: &mut egui::Ui,
+    control: &Control,
+    axis: &Axis,
+    param: &FloatParam,
+    setter: &ParamSetter,
+) -> egui::Response {
+    let normalized = param.unmodulated_normalized_value();
+    ui.horizontal(|ui| {
+        ui.label(control.label.as_str());
+        ui.weak(axis.format(axis.plain(normalized)));
+    });
+
+    let (rect, response) =
+        ui.allocate_exact_size(egui::vec2(SLIDER_WIDTH, 16.0), Sense::click_and_drag());
+    let pointer = response.interact_pointer_pos();
+    drag_param(
+        &response,
+        setter,
+        param,
+        axis.normalized(axis.default),
+        |normalized| match pointer {
+            Some(pointer) => (pointer.x - rect.left()) / rect.width(),
+            None => normalized,
+        },
+    );
+
+    let normalized = param.unmodulated_normalized_value();
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 2.0, Color32::from_gray(235));
+    let mut filled = rect;
+    filled.set_width(rect.width() * normalized);
+    painter.rect_filled(filled, 2.0, Color32::from_rgb(40, 140, 60));
+
+    response
+}
+
+fn toggle(
+    ui: &mut egui::Ui,
+    control: &Control,
+    param: &FloatParam,
+    setter: &ParamSetter,
+) -> egui::Response {
+    let on = param.unmodulated_normalized_value() >= 0.5;
+    let response = ui.selectable_label(on, control.label.as_str());
+    if response.clicked() {
+        set(setter, param, if on { 0.0 } else { 1.0 });
+    }
+
+    response
+}
+
+fn button(
+    ui: &mut egui::Ui,
+    control: &Control,
+    slot: usize,
+    param: &FloatParam,
+    setter: &ParamSetter,
+    presses: &ButtonPresses,
+) -> egui::Response {
+    let response = ui.add(egui::Button::new(control.label.as_str()).sense(Sense::click_and_drag()));
+    let pressed = response.is_pointer_button_down_on();
+    if pressed != (param.unmodulated_normalized_value() >= 0.5) {
+        if pressed {
+            presses.press(slot);
+        }
+        set(setter, param, if pressed { 1.0 } else { 0.0 });
+    }
+
+    response
+}
+
+fn xy_pad(
+    ui: &mut egui::Ui,
+    control: &Control,
+    x_param: &FloatParam,
+    y_param: &FloatParam,
+    setter: &ParamSetter,
+) -> egui::Response {
+    let (x_axis, y_axis) = (&control.axes[0], &control.axes[1]);
+    let (x, y) = (
+        x_param.unmodulated_normalized_value(),
+        y_param.unmodulated_normalized_value(),
+    );
+    ui.horizontal(|ui| {
+        ui.label(control.label.as_str());
+        ui.weak(format!(
+            "{}, {}",
+            x_axis.format(x_axis.plain(x)),
+            y_axis.format(y_axis.plain(y))
+        ));
+    });
+
+    let (rect, response) =
+        ui.allocate_exact_size(egui::vec2(XY_SIZE, XY_SIZE), Sense::click_and_drag());
+    // Up is the maximum, like on a slider
+    let pointer = response.interact_pointer_pos();
+    drag_param(
+        &response,
+        setter,
+        x_param,
+        x_axis.normalized(x_axis.default),
+        |x| match pointer {
+            Some(pointer) => (pointer.x - rect.left()) / rect.width(),
+            None => x,
+        },
+    );
+    drag_param(
+        &response,
+        setter,
+        y_param,
+        y_axis.normalized(y_axis.default),
+        |y| match pointer {
+            Some(pointer) => (rect.bottom() - pointer.y) / rect.height(),
+            None => y,
+        },
+    );
+
+    let position = egui::pos2(
+        rect.left() + rect.width() * x_param.unmodulated_normalized_value(),
+        rect.bottom() - rect.height() * y_param.unmodulated_normalized_value(),
+    );
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 2.0, Color32::from_gray(245));
+    let grid = Stroke::new(1.0, Color32::from_gray(220));
+    painter.vline(rect.center().x, rect.y_range(), grid);
+    painter.hline(rect.x_range(), rect.center().y, grid);
+    painter.circle_filled(position, 6.0, Color32::from_rgb(40, 140, 60));
+    painter.text(
+        rect.left_top() + egui::vec2(4.0, 2.0),
+        Align2::LEFT_TOP,
+        "y",
+        FontId::proportional(10.0),
+        Color32::from_gray(140),
+    );
+    painter.text(
+        rect.right_bottom() - egui::vec2(4.0, 2.0),
+        Align2::RIGHT_BOTTOM,
+        "x",
+        FontId::proportional(10.0),
+        Color32::from_gray(140),
+    );
+
+    response
+}
@@ -0,0 +1,214 @@
+//! The script's CPU usage: the time spent in the script per block as a fraction of the block's
+//! duration, which is the realtime budget. Optionally the script's functions are timed, which shows
+//! where that time goes. The clock the script can read only ticks once per millisecond, so the
+//! function times are only accurate on average, over many calls. They're sent to the editor in
+//! telemetry records, and the editor adds them up.
+
+use atomic_float::AtomicF32;
+use nih_plug_egui::egui::{self, Color32, Rounding, Sense, Stroke};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::script::ScriptEngine;
+use crate::telemetry::{RecordKind, Telemetry};
+
+/// The time constants of the current and average usage.
+const CURRENT_SECONDS: f32 = 0.1;
+const AVERAGE_SECONDS: f32 = 3.0;
+/// How often the profile is collected from the script.
+const PROFILE_INTERVAL: f32 = 0.5;
+/// The number of functions the profile shows.
+const PROFILE_ROWS: usize = 12;
+
+/// The usage shared with the editor, as fractions of the realtime budget.
+pub struct CpuReadings {
+    current: AtomicF32,
+    average: AtomicF32,
+    /// The highest usage of a single block since the last reset.
+    peak: AtomicF32,
+    /// The block size of the block with the highest usage.
+    peak_samples: AtomicF32,
+    /// Set in the editor to profile the script's functions.
+    profiling: AtomicBool,
+    /// Set in the editor to clear the peak.
+    reset: AtomicBool,
+}
+
+impl Default for CpuReadings {
+    fn default() -> Self {
+        Self {
+            current: AtomicF32::new(0.0),
+            average: AtomicF32::new(0.0),
+            peak: AtomicF32::new(0.0),
+            peak_samples: AtomicF32::new(0.0),
+            profiling: AtomicBool::new(false),
+            reset: AtomicBool::new(false),
+        }
+    }
+}
+
+impl CpuReadings {
+    pub fn profiling(&self) -> bool {
+        self.profiling.load(Ordering::Relaxed)
+    }
+}
+
+/// Measures the script's CPU usage on the audio thread.
+#[derive(Default)]
+pub struct CpuMeter {
+    current: f32,
+    average: f32,
+    peak: f32,
+    /// The time since the profile was last collected.
+    since_profile: f32,
+}
+
+impl CpuMeter {
+    /// Adds a block that took `elapsed` in the script, and lasted `num_samples` samples.
+    pub fn process(
+        &mut self,
+        elapsed: Duration,
+        num_samples: usize,
+        sample_rate: f32,
+        readings: &CpuReadings,
+    ) {
+        if readings.reset.swap(false, Ordering::Relaxed) {
+            self.peak = 0.0;
+        }
+
+        let duration = num_samples as f32 / sample_rate;
+        if duration <= 0.0 {
+            return;
+        }
+
+        let usage = elapsed.as_secs_f32() / duration;
+        self.current = usage + (self.current - usage) * (-duration / CURRENT_SECONDS).exp();
+        self.average = usage + (self.average - usage) * (-duration / AVERAGE_SECONDS).exp();
+        if usage > self.peak {
+            self.peak = usage;
+            readings
+                .peak_samples
+                .store(num_samples as f32, Ordering::Relaxed);
+        }
+
+        readings.current.store(self.current, Ordering::Relaxed);
+        readings.average.store(self.average, Ordering::Relaxed);
+        readings.peak.store(self.peak, Ordering::Relaxed);
+    }
+
+    /// Sends the script's profile every `PROFILE_INTERVAL` seconds while profiling.
+    pub fn profile(&mut self, duration: f32, telemetry: &Telemetry, engine: &mut ScriptEngine) {
+        self.since_profile += duration;
+        if self.since_profile < PROFILE_INTERVAL {
+            return;
+        }
+        self.since_profile = 0.0;
+
+        if let Some(json) = engine.profile() {
+            telemetry.send(RecordKind::Profile, &[json]);
+        }
+    }
+}
+
+/// The total time attributed to every function since profiling started, in milliseconds, sorted
+/// from most to least.
+#[derive(Default)]
+pub struct Profile {
+    times: Vec<(String, f64)>,
+}
+
+impl Profile {
+    /// Adds a profile record sent by `CpuMeter::profile()`.
+    pub fn receive(&mut self, payload: &[u8]) {
+        let times: Vec<(String, f64)> = match serde_json::from_slice(payload) {
+            Ok(times) => times,
+            Err(_) => return,
+        };
+
+        for (name, time) in times {
+            match self.times.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, total)) => *total += time,
+                None => self.times.push((name, time)),
+            }
+        }
+        self.times.sort_by(|a, b| b.1.total_cmp(&a.1));
+    }
+}
+
+/// Draws the CPU meter with the current usage as a bar, the average and peak as lines, and the
+/// profile below it while profiling.
+pub fn cpu(ui: &mut egui::Ui, readings: &CpuReadings, profile: &mut Profile) {
+    let current = readings.current.load(Ordering::Relaxed);
+    let average = readings.average.load(Ordering::Relaxed);
+    let peak = readings.peak.load(Ordering::Relaxed);
+    let peak_samples = readings.peak_samples.load(Ordering::Relaxed);
+
+    ui.horizontal(|ui| {
+        ui.add_sized([50.0, 16.0], egui::Label::new("Script"));
+
+        let (rect, _) = ui.allocate_exact_size(egui::vec2(240.0, 12.0), Sense::hover());
+        let x = |usage: f32| rect.left() + rect.width() * usage.clamp(0.0, 1.0);
+        let color = if peak >= 1.0 {
+            Color32::from_rgb(220, 40, 40)
+        } else if peak >= 0.5 {
+            Color32::from_rgb(230, 150, 30)
+        } else {
+            Color32::from_rgb(40, 140, 60)
+        };
+
+        let painter = ui.painter();
+        painter.rect_filled(rect, Rounding::none(), Color32::from_gray(230));
+        painter.rect_filled(
+            egui::Rect::from_min_max(rect.min, egui::pos2(x(current), rect.max.y)),
+            Rounding::none(),
+            color,
+        );
+        painter.vline(
+            x(average),
+            rect.y_range(),
+            Stroke::new(1.0, Color32::from_gray(60)),
+        );
+        painter.vline(x(peak), rect.y_range(), Stroke::new(2.0, color));
+
+        ui.monospace(format!(
+            "cpu {:>5.1}%  avg {:>5.1}%  peak {:>5.1}% ({} samples)",
+            current * 100.0,
+            average * 100.0,
+            peak * 100.0,
+            peak_samples as usize
+        ));
+
+        if ui.small_button("Reset").clicked() {
+            readings.reset.store(true, Ordering::Relaxed);
+            profile.times.clear();
+        }
+
+        let mut profiling = readings.profiling();
+        let response = ui.checkbox(&mut profiling, "Time functions").on_hover_text(
+            "Attributes every millisecond to the function that was running. Single calls are \
+             shorter than that, so the times only add up over a few seconds.",
+        );
+        if response.changed() {
+            readings.profiling.store(profiling, Ordering::Relaxed);
+            readings.reset.store(true, Ordering::Relaxed);
+            profile.times.clear();
+        }
+    });
+
+    if readings.profiling() {
+        let total: f64 = profile.times.iter().map(|(_, time)| time).sum();
+        egui::Grid::new("profile").striped(true).show(ui, |ui| {
+            for (name, time) in profile.times.iter().take(PROFILE_ROWS) {
+                ui.monospace(name);
+                ui.monospace(format!("{time:>8.0} ms"));
+                ui.monospace(format!("{:>5.1}%", time / total.max(1.0) * 100.0));
+                ui.end_row();
+            }
+        });
+        if profile.times.is_empty() {
+            ui.weak("Collecting...");
+        }
+    }
+
+    ui.ctx().request_repaint();
+}
@@ -0,0 +1,275 @@
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use std::sync::Arc;
+
+/// The impulse response is split into partitions of this many samples. The first partition is
+/// convolved directly in the time domain so the convolution doesn't add any latency. The other
+/// partitions are convolved using FFTs of twice this size.
+const PARTITION_SIZE: usize = 256;
+
+/// A single channel of an impulse response, prepared for [`Convolver`].
+pub struct ImpulseResponse {
+    /// The first partition's samples.
+    head: Vec<f32>,
+    /// The spectra of the other partitions, already scaled to compensate for the FFT's gain.
+    partitions: Vec<Vec<Complex<f32>>>,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+}
+
+impl ImpulseResponse {
+    pub fn new(samples: &[f32]) -> Self {
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(PARTITION_SIZE * 2);
+        let inverse = planner.plan_fft_inverse(PARTITION_SIZE * 2);
+
+        let mut time = forward.make_input_vec();
+        let mut scratch = forward.make_scratch_vec();
+        let scale = (PARTITION_SIZE * 2) as f32;
+        let partitions = samples
+            .chunks(PARTITION_SIZE)
+            .skip(1)
+            .map(|partition| {
+                time.fill(0.0);
+                time[..partition.len()].copy_from_slice(partition);
+
+                let mut spectrum = forward.make_output_vec();
+                forward
+                    .process_with_scratch(&mut time, &mut spectrum, &mut scratch)
+                    .expect("Buffers have the FFT's sizes");
+                for bin in &mut spectrum {
+                    *bin /= scale;
+                }
+
+                spectrum
+            })
+            .collect();
+
+        Self {
+            head: samples[..samples.len().min(PARTITION_SIZE)].to_vec(),
+            partitions,
+            forward,
+            inverse,
+        }
+    }
+}
+
+/// Uniformly partitioned overlap-save convolution, with the first partition computed directly so
+/// there's no latency.
+///
+/// Only the first partition after the head needs the block of input that was just completed. The
+/// others are applied to older blocks, so their multiply-accumulates are spread over the samples
+/// of the block before their result is needed, and a block boundary only costs two FFTs and a
+/// single partition's worth of work no matter how long the impulse response is.
+pub struct Convolver {
+    ir: Arc<ImpulseResponse>,
+    /// The input for the head, stored twice so the last `PARTITION_SIZE` samples are always
+    /// contiguous.
+    history: Vec<f32>,
+    history_pos: usize,
+    /// The previous block of input followed by the current block.
+    input: Vec<f32>,
+    /// The spectra of the most recent input blocks. `fdl_pos` is where the next one is stored.
+    fdl: Vec<Vec<Complex<f32>>>,
+    fdl_pos: usize,
+    /// The partitions' contribution to the current block's output.
+    tail: Vec<f32>,
+    /// The position within the current block.
+    pos: usize,
+    /// The sum of the partitions that have already been applied for the next block's output, the
+    /// next partition to apply, and how many of them to apply per sample so they're all done by
+    /// the end of the block.
+    pending: Vec<Complex<f32>>,
+    next_partition: usize,
+    partitions_per_sample: usize,
+
+    time: Vec<f32>,
+    accumulator: Vec<Complex<f32>>,
+    forward_scratch: Vec<Complex<f32>>,
+    inverse_scratch: Vec<Complex<f32>>,
+}
+
+impl Convolver {
+    pub fn new(ir: Arc<ImpulseResponse>) -> Self {
+        Self {
+            history: vec![0.0; PARTITION_SIZE * 2],
+            history_pos: 0,
+            input: vec![0.0; PARTITION_SIZE * 2],
+            fdl: (0..ir.partitions.len())
+                .map(|_| ir.forward.make_output_vec())
+                .collect(),
+            fdl_pos: 0,
+            tail: vec![0.0; PARTITION_SIZE],
+            pos: 0,
+            pending: ir.forward.make_output_vec(),
+            next_partition: 1,
+            partitions_per_sample: ir
+                .partitions
+                .len()
+                .saturating_sub(1)
+                .div_ceil(PARTITION_SIZE),
+
+            time: ir.forward.make_input_vec(),
+            accumulator: ir.forward.make_output_vec(),
+            forward_scratch: ir.forward.make_scratch_vec(),
+            inverse_scratch: ir.inverse.make_scratch_vec(),
+            ir,
+        }
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        self.history_pos = (self.history_pos + 1) % PARTITION_SIZE;
+        self.history[self.history_pos] = x;
+        self.history[self.history_pos + PARTITION_SIZE] = x;
+
+        // The last `PARTITION_SIZE` input samples, from oldest to newest
+        let recent = &self.history[self.history_pos + 1..=self.history_pos + PARTITION_SIZE];
+        let head: f32 = self
+            .ir
+            .head
+            .iter()
+            .zip(recent.iter().rev())
+            .map(|(h, x)| h * x)
+            .sum();
+        let y = head + self.tail[self.pos];
+
+        self.input[PARTITION_SIZE + self.pos] = x;
+        self.accumulate(self.partitions_per_sample);
+        self.pos += 1;
+        if self.pos == PARTITION_SIZE {
+            self.pos = 0;
+            self.process_block();
+        }
+
+        y
+    }
+
+    /// Applies the next `count` partitions to the input blocks they belong to, for the next
+    /// block's output. This skips the first partition, which needs the block that's still being
+    /// filled.
+    fn accumulate(&mut self, count: usize) {
+        let num_blocks = self.fdl.len();
+        let end = (self.next_partition + count).min(num_blocks);
+        for k in self.next_partition..end {
+            // `fdl_pos` is where the current block will be stored, so this is the block from `k`
+            // blocks ago
+            let block = &self.fdl[(self.fdl_pos + num_blocks - k) % num_blocks];
+            for ((acc, x), h) in self
+                .pending
+                .iter_mut()
+                .zip(block)
+                .zip(&self.ir.partitions[k])
+            {
+                *acc += x * h;
+            }
+        }
+        self.next_partition = end.max(self.next_partition);
+    }
+
+    /// Computes the partitions' contribution to the next block's output once a block of input is
+    /// complete. Partition `k` (counting from the first partition after the head) is applied to
+    /// the input block from `k` blocks ago. All but the first have already been applied.
+    fn process_block(&mut self) {
+        if !self.fdl.is_empty() {
+            self.accumulate(self.fdl.len());
+
+            self.time.copy_from_slice(&self.input);
+            let _ = self.ir.forward.process_with_scratch(
+                &mut self.time,
+                &mut self.fdl[self.fdl_pos],
+                &mut self.forward_scratch,
+            );
+
+            let block = &self.fdl[self.fdl_pos];
+            for (((acc, pending), x), h) in self
+                .accumulator
+                .iter_mut()
+                .zip(&self.pending)
+                .zip(block)
+                .zip(&self.ir.partitions[0])
+            {
+                *acc = pending + x * h;
+            }
+
+            // These are real in theory, but rounding errors would make the inverse FFT complain
+            if let Some(first) = self.accumulator.first_mut() {
+                first.im = 0.0;
+            }
+            if let Some(last) = self.accumulator.last_mut() {
+                last.im = 0.0;
+            }
+
+            let _ = self.ir.inverse.process_with_scratch(
+                &mut self.accumulator,
+                &mut self.time,
+                &mut self.inverse_scratch,
+            );
+
+            // Overlap-save: only the second half is free of circular aliasing
+            self.tail.copy_from_slice(&self.time[PARTITION_SIZE..]);
+            self.fdl_pos = (self.fdl_pos + 1) % self.fdl.len();
+            self.pending.fill(Complex::default());
+            self.next_partition = 1;
+        }
+
+        self.input.copy_within(PARTITION_SIZE.., 0);
+    }
+
+    pub fn reset(&mut self) {
+        self.history.fill(0.0);
+        self.input.fill(0.0);
+        for block in &mut self.fdl {
+            block.fill(Complex::default());
+        }
+        self.tail.fill(0.0);
+        self.pos = 0;
+        self.pending.fill(Complex::default());
+        self.next_partition = 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::oscillators::Noise;
+
+    #[test]
+    fn partitioned_convolution_matches_direct_convolution() {
+        let mut noise = Noise::new(1);
+        // Long enough for a head, several partitions and a partial last partition
+        let ir: Vec<f32> = (0..PARTITION_SIZE * 5 + 100)
+            .map(|i| noise.next_sample() * (-(i as f32) / 500.0).exp())
+            .collect();
+        let input: Vec<f32> = (0..PARTITION_SIZE * 12 + 17)
+            .map(|_| noise.next_sample())
+            .collect();
+
+        let mut convolver = Convolver::new(Arc::new(ImpulseResponse::new(&ir)));
+        for (n, x) in input.iter().enumerate() {
+            let expected: f32 = ir
+                .iter()
+                .enumerate()
+                .take(n + 1)
+                .map(|(k, h)| h * input[n - k])
+                .sum();
+            let y = convolver.process(*x);
+
+            assert!(
+                (y - expected).abs() < 1e-4,
+                "sample {n}: expected {expected}, got {y}"
+            );
+        }
+    }
+
+    #[test]
+    fn impulse_responses_shorter_than_a_partition_are_convolved_directly() {
+        let ir = [1.0, 0.5, 0.25];
+        let mut convolver = Convolver::new(Arc::new(ImpulseResponse::new(&ir)));
+        let output: Vec<f32> = [1.0, 0.0, 0.0, 0.0]
+            .iter()
+            .map(|x| convolver.process(*x))
+            .collect();
+
+        assert_eq!(output, [1.0, 0.5, 0.25, 0.0]);
+    }
+}
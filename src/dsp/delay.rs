@@ -0,0 +1,74 @@
+/// A fixed size delay line that supports fractional delay times.
+#[derive(Debug, Clone)]
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    /// The position the next sample will be written to.
+    write_pos: usize,
+}
+
+impl DelayLine {
+    /// Allocates a delay line that can delay by up to `max_delay` samples.
+    pub fn new(max_delay: usize) -> Self {
+        Self {
+            buffer: vec![0.0; max_delay + 2],
+            write_pos: 0,
+        }
+    }
+
+    pub fn max_delay(&self) -> usize {
+        self.buffer.len() - 2
+    }
+
+    pub fn write(&mut self, x: f32) {
+        self.buffer[self.write_pos] = x;
+        self.write_pos = (self.write_pos + 1) % self.buffer.len();
+    }
+
+    /// Reads the sample written `delay` samples ago, linearly interpolating between samples.
+    /// A delay of `1.0` returns the last written sample.
+    pub fn read(&self, delay: f32) -> f32 {
+        let delay = delay.clamp(1.0, self.max_delay() as f32 + 1.0);
+        let whole = delay.floor();
+        let frac = delay - whole;
+
+        let a = self.sample(whole as usize);
+        let b = self.sample(whole as usize + 1);
+
+        a + (b - a) * frac
+    }
+
+    fn sample(&self, delay: usize) -> f32 {
+        let len = self.buffer.len();
+        self.buffer[(self.write_pos + len - (delay % len)) % len]
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_delays_return_the_sample_written_that_long_ago() {
+        let mut delay = DelayLine::new(16);
+        for i in 0..16 {
+            delay.write(i as f32);
+        }
+
+        assert_eq!(delay.read(1.0), 15.0);
+        assert_eq!(delay.read(10.0), 6.0);
+        assert_eq!(delay.read(16.0), 0.0);
+    }
+
+    #[test]
+    fn fractional_delays_interpolate() {
+        let mut delay = DelayLine::new(4);
+        delay.write(0.0);
+        delay.write(1.0);
+
+        assert_eq!(delay.read(1.25), 0.75);
+    }
+}
@@ -0,0 +1,99 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// A linear ADSR envelope. All times are in seconds.
+#[derive(Debug, Clone, Copy)]
+pub struct Adsr {
+    stage: Stage,
+    level: f32,
+    /// The amount the level decreases by every sample during the release stage.
+    release_step: f32,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Self {
+            stage: Stage::Idle,
+            level: 0.0,
+            release_step: 0.0,
+        }
+    }
+}
+
+impl Adsr {
+    /// Starts the attack stage from the current level.
+    pub fn trigger(&mut self) {
+        self.stage = Stage::Attack;
+    }
+
+    pub fn release(&mut self, release: f32, sample_rate: f32) {
+        if self.stage != Stage::Idle {
+            self.stage = Stage::Release;
+            self.release_step = self.level / (release * sample_rate).max(1.0);
+        }
+    }
+
+    pub fn is_gated(&self) -> bool {
+        matches!(self.stage, Stage::Attack | Stage::Decay | Stage::Sustain)
+    }
+
+    pub fn next(&mut self, attack: f32, decay: f32, sustain: f32, sample_rate: f32) -> f32 {
+        let sustain = sustain.clamp(0.0, 1.0);
+        match self.stage {
+            Stage::Idle => (),
+            Stage::Attack => {
+                self.level += 1.0 / (attack * sample_rate).max(1.0);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= (1.0 - sustain) / (decay * sample_rate).max(1.0);
+                if self.level <= sustain {
+                    self.level = sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => self.level = sustain,
+            Stage::Release => {
+                self.level -= self.release_step;
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = Stage::Idle;
+                }
+            }
+        }
+
+        self.level
+    }
+}
+
+/// A one-pole lowpass filter for smoothing out control signals.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OnePole {
+    y: f32,
+}
+
+impl OnePole {
+    /// The filter coefficient for reaching about 63% of a new target value after `time` seconds.
+    pub fn coefficient(time: f32, sample_rate: f32) -> f32 {
+        if time <= 0.0 {
+            0.0
+        } else {
+            (-1.0 / (time * sample_rate)).exp()
+        }
+    }
+
+    pub fn process(&mut self, coefficient: f32, x: f32) -> f32 {
+        self.y = x + (self.y - x) * coefficient;
+
+        self.y
+    }
+}
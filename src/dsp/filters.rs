@@ -0,0 +1,269 @@
+use std::f32::consts::{PI, TAU};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BiquadMode {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+    Peak,
+    LowShelf,
+    HighShelf,
+}
+
+impl BiquadMode {
+    pub const ALL: [BiquadMode; 7] = [
+        BiquadMode::Lowpass,
+        BiquadMode::Highpass,
+        BiquadMode::Bandpass,
+        BiquadMode::Notch,
+        BiquadMode::Peak,
+        BiquadMode::LowShelf,
+        BiquadMode::HighShelf,
+    ];
+    /// The names scripts use, in the same order.
+    pub const NAMES: [&'static str; 7] = [
+        "lowpass",
+        "highpass",
+        "bandpass",
+        "notch",
+        "peak",
+        "lowShelf",
+        "highShelf",
+    ];
+}
+
+/// Normalized biquad coefficients, computed using the formulas from the Audio EQ Cookbook.
+#[derive(Debug, Clone, Copy)]
+pub struct BiquadCoefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Default for BiquadCoefficients {
+    fn default() -> Self {
+        Self {
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+        }
+    }
+}
+
+impl BiquadCoefficients {
+    pub fn new(mode: BiquadMode, sample_rate: f32, cutoff: f32, q: f32, gain_db: f32) -> Self {
+        let cutoff = clamp_cutoff(cutoff, sample_rate);
+        let q = q.max(0.01);
+
+        let omega = TAU * cutoff / sample_rate;
+        let (sin, cos) = omega.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let a = 10f32.powf(gain_db / 40.0);
+
+        let (b0, b1, b2, a0, a1, a2) = match mode {
+            BiquadMode::Lowpass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            BiquadMode::Highpass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            BiquadMode::Bandpass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            BiquadMode::Notch => (1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            BiquadMode::Peak => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            BiquadMode::LowShelf => {
+                let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha),
+                    (a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha,
+                )
+            }
+            BiquadMode::HighShelf => {
+                let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha),
+                    (a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha,
+                )
+            }
+        };
+
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+/// A transposed direct form II biquad filter.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Biquad {
+    s1: f32,
+    s2: f32,
+}
+
+impl Biquad {
+    pub fn process(&mut self, coefficients: &BiquadCoefficients, x: f32) -> f32 {
+        let c = coefficients;
+        let y = c.b0 * x + self.s1;
+        self.s1 = c.b1 * x - c.a1 * y + self.s2;
+        self.s2 = c.b2 * x - c.a2 * y;
+
+        y
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SvfMode {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+}
+
+impl SvfMode {
+    pub const ALL: [SvfMode; 4] = [
+        SvfMode::Lowpass,
+        SvfMode::Highpass,
+        SvfMode::Bandpass,
+        SvfMode::Notch,
+    ];
+    /// The names scripts use, in the same order.
+    pub const NAMES: [&'static str; 4] = ["lowpass", "highpass", "bandpass", "notch"];
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SvfCoefficients {
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+}
+
+impl SvfCoefficients {
+    pub fn new(sample_rate: f32, cutoff: f32, q: f32) -> Self {
+        let g = (PI * clamp_cutoff(cutoff, sample_rate) / sample_rate).tan();
+        let k = 1.0 / q.max(0.01);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        Self { k, a1, a2, a3 }
+    }
+}
+
+/// Andrew Simper's trapezoidal state variable filter. Unlike the biquad this can be modulated
+/// quickly without blowing up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Svf {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl Svf {
+    pub fn process(&mut self, mode: SvfMode, coefficients: &SvfCoefficients, x: f32) -> f32 {
+        let c = coefficients;
+        let v3 = x - self.ic2eq;
+        let v1 = c.a1 * self.ic1eq + c.a2 * v3;
+        let v2 = self.ic2eq + c.a2 * self.ic1eq + c.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        match mode {
+            SvfMode::Lowpass => v2,
+            SvfMode::Highpass => x - c.k * v1 - v2,
+            SvfMode::Bandpass => v1,
+            SvfMode::Notch => x - c.k * v1,
+        }
+    }
+}
+
+fn clamp_cutoff(cutoff: f32, sample_rate: f32) -> f32 {
+    cutoff.clamp(10.0, sample_rate * 0.49)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// The RMS level of a sine at `freq` after it has been filtered by `filter`, ignoring the
+    /// filter's settling time.
+    fn sine_level(freq: f32, mut filter: impl FnMut(f32) -> f32) -> f32 {
+        let output: Vec<f32> = (0..9600)
+            .map(|i| filter((TAU * freq * i as f32 / SAMPLE_RATE).sin()))
+            .skip(4800)
+            .collect();
+
+        (output.iter().map(|x| x * x).sum::<f32>() / output.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn biquad_lowpass_passes_low_and_attenuates_high_frequencies() {
+        let coefficients =
+            BiquadCoefficients::new(BiquadMode::Lowpass, SAMPLE_RATE, 1000.0, 0.707, 0.0);
+        let level = |freq| {
+            let mut biquad = Biquad::default();
+            sine_level(freq, |x| biquad.process(&coefficients, x))
+        };
+
+        assert!((level(100.0) - 0.707).abs() < 0.01);
+        assert!(level(10_000.0) < 0.01);
+    }
+
+    #[test]
+    fn biquad_peak_boosts_the_center_frequency() {
+        let coefficients = BiquadCoefficients::new(BiquadMode::Peak, SAMPLE_RATE, 1000.0, 1.0, 6.0);
+        let mut biquad = Biquad::default();
+        let gain = sine_level(1000.0, |x| biquad.process(&coefficients, x)) / 0.707;
+
+        assert!((gain - 10f32.powf(6.0 / 20.0)).abs() < 0.02);
+    }
+
+    #[test]
+    fn svf_modes_split_the_spectrum() {
+        let coefficients = SvfCoefficients::new(SAMPLE_RATE, 1000.0, 0.707);
+        let level = |mode, freq| {
+            let mut svf = Svf::default();
+            sine_level(freq, |x| svf.process(mode, &coefficients, x))
+        };
+
+        assert!(level(SvfMode::Lowpass, 100.0) > 0.7);
+        assert!(level(SvfMode::Lowpass, 10_000.0) < 0.01);
+        assert!(level(SvfMode::Highpass, 100.0) < 0.01);
+        assert!(level(SvfMode::Highpass, 10_000.0) > 0.7);
+        assert!(level(SvfMode::Notch, 1000.0) < 0.01);
+    }
+}
@@ -0,0 +1,231 @@
+use std::f32::consts::{FRAC_PI_4, PI};
+
+use super::oscillators::Noise;
+use crate::samples::SampleData;
+
+/// The number of grains that can play at the same time. New grains are dropped when they're all in
+/// use.
+const MAX_GRAINS: usize = 128;
+/// The number of grains a script can schedule per block, for all of its granular nodes together.
+pub const MAX_SCHEDULED_GRAINS: usize = 512;
+const MAX_GRAIN_SIZE: f32 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrainWindow {
+    Hann,
+    Triangle,
+    Gaussian,
+    /// Fades in and out over the first and last quarter of the grain.
+    Trapezoid,
+    Rectangular,
+}
+
+impl GrainWindow {
+    pub const ALL: [GrainWindow; 5] = [
+        GrainWindow::Hann,
+        GrainWindow::Triangle,
+        GrainWindow::Gaussian,
+        GrainWindow::Trapezoid,
+        GrainWindow::Rectangular,
+    ];
+    /// The names scripts use, in the same order.
+    pub const NAMES: [&'static str; 5] =
+        ["hann", "triangle", "gaussian", "trapezoid", "rectangular"];
+
+    /// The window's gain at `x` in `[0, 1]`.
+    fn gain(self, x: f32) -> f32 {
+        match self {
+            GrainWindow::Hann => 0.5 - 0.5 * (2.0 * PI * x).cos(),
+            GrainWindow::Triangle => 1.0 - (2.0 * x - 1.0).abs(),
+            GrainWindow::Gaussian => {
+                let d = (x - 0.5) / 0.15;
+                (-0.5 * d * d).exp()
+            }
+            GrainWindow::Trapezoid => (x.min(1.0 - x) * 4.0).min(1.0),
+            GrainWindow::Rectangular => 1.0,
+        }
+    }
+}
+
+/// The `granular` node's parameters. `position` and `jitter` are fractions of the sample's length,
+/// `size` is in seconds, `density` is in grains per second and `pitch` is in semitones.
+#[derive(Debug, Clone, Copy)]
+pub struct GranularParams {
+    pub size: f32,
+    pub density: f32,
+    pub position: f32,
+    pub pitch: f32,
+    pub pan: f32,
+    pub window: GrainWindow,
+    /// Randomizes every grain's position by up to this much.
+    pub jitter: f32,
+    /// Randomizes every grain's pan by up to this much.
+    pub spread: f32,
+    pub gain: f32,
+    /// Set when the script schedules the grains itself through an `onGrain` callback. The grains
+    /// for the current block are passed to [`Granulator::schedule()`] then.
+    pub callback: bool,
+}
+
+/// A single grain scheduled by the script. `at` is the time in seconds since the start of the
+/// block, the other fields work like the node's parameters.
+#[derive(Debug, Clone, Copy)]
+pub struct GrainParams {
+    pub at: f32,
+    pub position: f32,
+    pub size: f32,
+    pub pitch: f32,
+    pub pan: f32,
+    pub gain: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Grain {
+    active: bool,
+    /// The read position in the sample, in samples.
+    position: f64,
+    /// How far the read position moves per output sample.
+    rate: f64,
+    elapsed: f32,
+    length: f32,
+    gain: f32,
+    /// The panning gains for the left and right channels.
+    left: f32,
+    right: f32,
+}
+
+/// Schedules and plays the grains of a `granular` node.
+pub struct Granulator {
+    grains: Vec<Grain>,
+    /// The number of samples until the next grain starts, when the node schedules its own grains.
+    countdown: f32,
+    noise: Noise,
+    /// The grains the script scheduled for the current block, and the number of samples since the
+    /// start of the block.
+    scheduled: Vec<GrainParams>,
+    next_scheduled: usize,
+    elapsed: usize,
+}
+
+impl Default for Granulator {
+    fn default() -> Self {
+        Self {
+            grains: vec![Grain::default(); MAX_GRAINS],
+            countdown: 0.0,
+            noise: Noise::new(1),
+            scheduled: Vec::with_capacity(MAX_SCHEDULED_GRAINS),
+            next_scheduled: 0,
+            elapsed: 0,
+        }
+    }
+}
+
+impl Granulator {
+    /// Replaces the grains the script scheduled. Called at the start of every block.
+    pub fn schedule(&mut self, grains: impl Iterator<Item = GrainParams>) {
+        self.scheduled.clear();
+        self.scheduled.extend(grains.take(MAX_SCHEDULED_GRAINS));
+        // The stable sort would allocate
+        self.scheduled
+            .sort_unstable_by(|a, b| a.at.total_cmp(&b.at));
+        self.next_scheduled = 0;
+        self.elapsed = 0;
+    }
+
+    /// Processes a single sample, and writes a sample to every output channel.
+    pub fn process(
+        &mut self,
+        params: &GranularParams,
+        buffer: Option<&SampleData>,
+        sample_rate: f32,
+        outputs: &mut [f32],
+    ) {
+        outputs.fill(0.0);
+        let buffer = match buffer {
+            Some(buffer) if !buffer.is_empty() => buffer,
+            _ => return,
+        };
+
+        if params.callback {
+            while let Some(grain) = self.scheduled.get(self.next_scheduled) {
+                if grain.at * sample_rate > self.elapsed as f32 {
+                    break;
+                }
+
+                let grain = *grain;
+                self.spawn(&grain, buffer, sample_rate);
+                self.next_scheduled += 1;
+            }
+            self.elapsed += 1;
+        } else if params.density > 0.0 {
+            self.countdown -= 1.0;
+            if self.countdown <= 0.0 {
+                self.countdown += sample_rate / params.density.min(sample_rate);
+
+                let grain = GrainParams {
+                    at: 0.0,
+                    position: params.position + params.jitter * self.noise.next_sample(),
+                    size: params.size,
+                    pitch: params.pitch,
+                    pan: params.pan + params.spread * self.noise.next_sample(),
+                    gain: 1.0,
+                };
+                self.spawn(&grain, buffer, sample_rate);
+            }
+        }
+
+        let stereo = outputs.len() >= 2;
+        for grain in self.grains.iter_mut().filter(|grain| grain.active) {
+            let gain = params.window.gain(grain.elapsed / grain.length) * grain.gain * params.gain;
+            for (channel, output) in outputs.iter_mut().enumerate() {
+                let pan = match channel {
+                    0 if stereo => grain.left,
+                    1 => grain.right,
+                    // Mono outputs and surround channels don't get panned
+                    _ => 1.0,
+                };
+                *output += buffer.read(channel, grain.position) * gain * pan;
+            }
+
+            grain.position += grain.rate;
+            grain.elapsed += 1.0;
+            if grain.elapsed >= grain.length {
+                grain.active = false;
+            }
+        }
+    }
+
+    fn spawn(&mut self, params: &GrainParams, buffer: &SampleData, sample_rate: f32) {
+        let slot = match self.grains.iter_mut().find(|grain| !grain.active) {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let len = buffer.len() as f64;
+        let length = (params.size.clamp(0.0, MAX_GRAIN_SIZE) * sample_rate).max(1.0);
+        let rate =
+            2f64.powf(params.pitch as f64 / 12.0) * (buffer.sample_rate / sample_rate) as f64;
+
+        // Equal power panning
+        let angle = (params.pan.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+        *slot = Grain {
+            active: true,
+            position: (params.position.clamp(0.0, 1.0) as f64 * len).min(len - 1.0),
+            rate,
+            elapsed: 0.0,
+            length,
+            gain: params.gain,
+            left: angle.cos(),
+            right: angle.sin(),
+        };
+    }
+
+    pub fn reset(&mut self) {
+        for grain in &mut self.grains {
+            grain.active = false;
+        }
+        self.countdown = 0.0;
+        self.scheduled.clear();
+        self.next_scheduled = 0;
+    }
+}
@@ -0,0 +1,1182 @@
+//! Native DSP building blocks that scripts can instantiate through the `dsp` object. The script
+//! only holds handles to the nodes and sets their parameters, the audio itself is processed here.
+//! Parameter changes are picked up once per block.
+//!
+//! The script and the plugin share a node table with a row of numbers for every node, see
+//! [`NODE_ROW`]. The script writes a node's parameters to its row whenever they change. Creating a
+//! node allocates, so that's described separately by a [`NodeSpec`] and done on the background
+//! thread by the [`NodeBuilder`].
+
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::layouts::MAX_CHANNELS;
+use crate::samples::{SampleBank, SampleData};
+use convolution::{Convolver, ImpulseResponse};
+use delay::DelayLine;
+use envelopes::{Adsr, OnePole};
+use filters::{Biquad, BiquadCoefficients, BiquadMode, Svf, SvfCoefficients, SvfMode};
+use granular::{GrainParams, GrainWindow, GranularParams, Granulator};
+use oscillators::{Noise, Oscillator};
+use shapers::Shape;
+
+pub use granular::MAX_SCHEDULED_GRAINS;
+
+mod convolution;
+mod delay;
+mod envelopes;
+mod filters;
+mod granular;
+mod oscillators;
+mod shapers;
+
+/// The most nodes a script can create.
+pub const MAX_NODES: usize = 256;
+/// The most parameters any kind of node has in its row.
+const MAX_FIELDS: usize = 10;
+/// The length of a node's row in the node table: whether the row changed since it was last read,
+/// the ID of the node's input, the node's output level, and its parameters in the order of
+/// [`NodeKind::fields()`].
+pub const NODE_ROW: usize = 3 + MAX_FIELDS;
+/// The length of a grain's row in the grain table: the granular node's ID, followed by the fields
+/// of [`GrainParams`] in the order they're declared in.
+pub const GRAIN_ROW: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NodeKind {
+    Sine,
+    Saw,
+    Square,
+    Noise,
+    Biquad,
+    Svf,
+    Smooth,
+    Adsr,
+    Delay,
+    Shaper,
+    Player,
+    Wavetable,
+    Convolver,
+    Granular,
+}
+
+impl NodeKind {
+    pub const ALL: [NodeKind; 14] = [
+        NodeKind::Sine,
+        NodeKind::Saw,
+        NodeKind::Square,
+        NodeKind::Noise,
+        NodeKind::Biquad,
+        NodeKind::Svf,
+        NodeKind::Smooth,
+        NodeKind::Adsr,
+        NodeKind::Delay,
+        NodeKind::Shaper,
+        NodeKind::Player,
+        NodeKind::Wavetable,
+        NodeKind::Convolver,
+        NodeKind::Granular,
+    ];
+
+    /// The name scripts use, which is also the name of the function on the `dsp` object.
+    pub fn name(self) -> &'static str {
+        match self {
+            NodeKind::Sine => "sine",
+            NodeKind::Saw => "saw",
+            NodeKind::Square => "square",
+            NodeKind::Noise => "noise",
+            NodeKind::Biquad => "biquad",
+            NodeKind::Svf => "svf",
+            NodeKind::Smooth => "smooth",
+            NodeKind::Adsr => "adsr",
+            NodeKind::Delay => "delay",
+            NodeKind::Shaper => "shaper",
+            NodeKind::Player => "player",
+            NodeKind::Wavetable => "wavetable",
+            NodeKind::Convolver => "convolver",
+            NodeKind::Granular => "granular",
+        }
+    }
+
+    /// The parameters in a node's row, by the names scripts use. `callback` is set when a granular
+    /// node has an `onGrain` callback.
+    pub fn fields(self) -> &'static [&'static str] {
+        match self {
+            NodeKind::Sine | NodeKind::Saw => &["freq"],
+            NodeKind::Square => &["freq", "width"],
+            NodeKind::Noise => &[],
+            NodeKind::Biquad => &["mode", "cutoff", "q", "gain"],
+            NodeKind::Svf => &["mode", "cutoff", "q"],
+            NodeKind::Smooth => &["time", "value"],
+            NodeKind::Adsr => &["attack", "decay", "sustain", "release", "gate", "trigger"],
+            NodeKind::Delay => &["time", "feedback", "mix"],
+            NodeKind::Shaper => &["shape", "drive"],
+            NodeKind::Player => &["speed", "loop", "start", "trigger"],
+            NodeKind::Wavetable => &["freq", "position", "frameSize"],
+            NodeKind::Convolver => &["mix", "gain"],
+            NodeKind::Granular => &[
+                "size", "density", "position", "pitch", "pan", "window", "jitter", "spread",
+                "gain", "callback",
+            ],
+        }
+    }
+}
+
+/// The parameters that scripts set by name. Their rows contain the name's index in these lists.
+pub const NODE_ENUMS: [(NodeKind, &str, &[&str]); 4] = [
+    (NodeKind::Biquad, "mode", &BiquadMode::NAMES),
+    (NodeKind::Svf, "mode", &SvfMode::NAMES),
+    (NodeKind::Shaper, "shape", &Shape::NAMES),
+    (NodeKind::Granular, "window", &GrainWindow::NAMES),
+];
+
+/// A node's parameters as set by the script. All times are in seconds and all frequencies are in
+/// Hertz.
+#[derive(Debug, Clone, Copy)]
+pub enum NodeParams {
+    Sine {
+        freq: f32,
+    },
+    Saw {
+        freq: f32,
+    },
+    Square {
+        freq: f32,
+        width: f32,
+    },
+    Noise,
+    Biquad {
+        mode: BiquadMode,
+        cutoff: f32,
+        q: f32,
+        gain: f32,
+    },
+    Svf {
+        mode: SvfMode,
+        cutoff: f32,
+        q: f32,
+    },
+    /// Smooths the node's input, or `value` if it doesn't have an input.
+    Smooth {
+        time: f32,
+        value: f32,
+    },
+    /// Outputs the envelope multiplied by the node's input, or the envelope itself if it doesn't
+    /// have an input. `trigger` is incremented by the script to retrigger the envelope.
+    Adsr {
+        attack: f32,
+        decay: f32,
+        sustain: f32,
+        release: f32,
+        gate: bool,
+        trigger: u32,
+    },
+    Delay {
+        time: f32,
+        feedback: f32,
+        mix: f32,
+    },
+    Shaper {
+        shape: Shape,
+        drive: f32,
+    },
+    /// Plays the node's sample from `start` every time `trigger` is incremented. Negative speeds
+    /// play the sample in reverse.
+    Player {
+        speed: f32,
+        looping: bool,
+        start: f32,
+        trigger: u32,
+    },
+    /// Treats the node's sample as a series of single-cycle waveforms of `frameSize` samples each.
+    /// `position` morphs between the first and the last frame.
+    Wavetable {
+        freq: f32,
+        position: f32,
+        frame_size: usize,
+    },
+    /// Convolves the node's input with its sample, with a mix between the dry input and the wet
+    /// output. `gain` is applied to the wet output.
+    Convolver {
+        mix: f32,
+        gain: f32,
+    },
+    /// Plays many short, overlapping grains from the node's sample.
+    Granular(GranularParams),
+}
+
+impl NodeParams {
+    /// Reads the parameters from a node's row, in the order of [`NodeKind::fields()`].
+    fn read(kind: NodeKind, fields: &[f64]) -> Self {
+        let x = |i: usize| fields[i] as f32;
+        let flag = |i: usize| fields[i] != 0.0;
+        match kind {
+            NodeKind::Sine => NodeParams::Sine { freq: x(0) },
+            NodeKind::Saw => NodeParams::Saw { freq: x(0) },
+            NodeKind::Square => NodeParams::Square {
+                freq: x(0),
+                width: x(1),
+            },
+            NodeKind::Noise => NodeParams::Noise,
+            NodeKind::Biquad => NodeParams::Biquad {
+                mode: pick(&BiquadMode::ALL, fields[0]),
+                cutoff: x(1),
+                q: x(2),
+                gain: x(3),
+            },
+            NodeKind::Svf => NodeParams::Svf {
+                mode: pick(&SvfMode::ALL, fields[0]),
+                cutoff: x(1),
+                q: x(2),
+            },
+            NodeKind::Smooth => NodeParams::Smooth {
+                time: x(0),
+                value: x(1),
+            },
+            NodeKind::Adsr => NodeParams::Adsr {
+                attack: x(0),
+                decay: x(1),
+                sustain: x(2),
+                release: x(3),
+                gate: flag(4),
+                trigger: fields[5] as u32,
+            },
+            NodeKind::Delay => NodeParams::Delay {
+                time: x(0),
+                feedback: x(1),
+                mix: x(2),
+            },
+            NodeKind::Shaper => NodeParams::Shaper {
+                shape: pick(&Shape::ALL, fields[0]),
+                drive: x(1),
+            },
+            NodeKind::Player => NodeParams::Player {
+                speed: x(0),
+                looping: flag(1),
+                start: x(2),
+                trigger: fields[3] as u32,
+            },
+            NodeKind::Wavetable => NodeParams::Wavetable {
+                freq: x(0),
+                position: x(1),
+                frame_size: fields[2] as usize,
+            },
+            NodeKind::Convolver => NodeParams::Convolver {
+                mix: x(0),
+                gain: x(1),
+            },
+            NodeKind::Granular => NodeParams::Granular(GranularParams {
+                size: x(0),
+                density: x(1),
+                position: x(2),
+                pitch: x(3),
+                pan: x(4),
+                window: pick(&GrainWindow::ALL, fields[5]),
+                jitter: x(6),
+                spread: x(7),
+                gain: x(8),
+                callback: flag(9),
+            }),
+        }
+    }
+}
+
+/// Looks up a parameter that's set by name from its index, see [`NODE_ENUMS`].
+fn pick<T: Copy>(all: &[T], index: f64) -> T {
+    all.get(index as usize).copied().unwrap_or(all[0])
+}
+
+/// What's needed to create a node: its kind, and the parameters that can't change without
+/// recreating it. The script sends this for new nodes, and for nodes whose sample or maximum delay
+/// time changed. Samples are referred to by the name they were loaded under.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeSpec {
+    pub id: usize,
+    pub kind: NodeKind,
+    pub buffer: Option<String>,
+    pub max_time: f32,
+}
+
+/// The script's changes to its nodes, in the buffers that are shared with the script.
+pub struct DspUpdate<'a> {
+    /// The node table, with a row of [`NODE_ROW`] numbers for every node the script created.
+    pub nodes: &'a mut [f64],
+    /// The grains `onGrain` callbacks scheduled for the current block, a row of [`GRAIN_ROW`]
+    /// numbers each.
+    pub grains: &'a [f64],
+    /// The nodes that need to be created or recreated, as a JSON array of [`NodeSpec`]s.
+    pub specs: Option<&'a [u8]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    None,
+    Input,
+    Node(usize),
+}
+
+impl Source {
+    /// Reads the input from a node's row. That's the input node's ID, `-1` for the plugin's input,
+    /// or `-2` for no input.
+    fn read(id: f64) -> Self {
+        if id >= 0.0 {
+            Source::Node(id as usize)
+        } else if id == -1.0 {
+            Source::Input
+        } else {
+            Source::None
+        }
+    }
+}
+
+/// Coefficients that are derived from the parameters, so they don't need to be computed for every
+/// sample.
+#[derive(Debug, Clone, Copy)]
+enum Coefficients {
+    None,
+    Biquad(BiquadCoefficients),
+    Svf(SvfCoefficients),
+    OnePole(f32),
+}
+
+enum ChannelState {
+    Oscillator(Oscillator),
+    Noise(Noise),
+    Biquad(Biquad),
+    Svf(Svf),
+    Smooth(OnePole),
+    Adsr(Adsr),
+    Delay(DelayLine),
+    Player(Player),
+    /// The convolver is created once the impulse response is loaded.
+    Convolver(Option<Box<Convolver>>),
+    Stateless,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Player {
+    /// The playback position in samples.
+    position: f64,
+    playing: bool,
+}
+
+impl ChannelState {
+    fn new(spec: &NodeSpec, channel: usize, sample_rate: f32) -> Self {
+        match spec.kind {
+            NodeKind::Sine | NodeKind::Saw | NodeKind::Square | NodeKind::Wavetable => {
+                ChannelState::Oscillator(Oscillator::default())
+            }
+            NodeKind::Noise => ChannelState::Noise(Noise::new(channel as u32 + 1)),
+            NodeKind::Biquad => ChannelState::Biquad(Biquad::default()),
+            NodeKind::Svf => ChannelState::Svf(Svf::default()),
+            NodeKind::Smooth => ChannelState::Smooth(OnePole::default()),
+            NodeKind::Adsr => ChannelState::Adsr(Adsr::default()),
+            NodeKind::Delay => ChannelState::Delay(DelayLine::new(
+                (spec.max_time.clamp(0.0, 60.0) * sample_rate) as usize,
+            )),
+            NodeKind::Player => ChannelState::Player(Player::default()),
+            NodeKind::Convolver => ChannelState::Convolver(None),
+            NodeKind::Shaper | NodeKind::Granular => ChannelState::Stateless,
+        }
+    }
+}
+
+struct Node {
+    spec: NodeSpec,
+    /// Set until the node's row has been read for the first time.
+    fresh: bool,
+    input: Source,
+    params: NodeParams,
+    coefficients: Coefficients,
+    /// The sample named by the spec, if it has been loaded.
+    buffer: Option<Arc<SampleData>>,
+    channels: Vec<ChannelState>,
+    /// Granular nodes play their grains across all channels at once, so they have a single
+    /// granulator instead of per-channel state.
+    granulator: Option<Box<Granulator>>,
+    /// The node's most recent output sample for every channel.
+    outputs: [f32; MAX_CHANNELS],
+}
+
+impl Node {
+    fn new(
+        spec: &NodeSpec,
+        num_channels: usize,
+        sample_rate: f32,
+        buffer: Option<Arc<SampleData>>,
+    ) -> Self {
+        let mut node = Self {
+            spec: spec.clone(),
+            fresh: true,
+            input: Source::None,
+            params: NodeParams::read(spec.kind, &[0.0; MAX_FIELDS]),
+            coefficients: Coefficients::None,
+            buffer: None,
+            channels: (0..num_channels)
+                .map(|channel| ChannelState::new(spec, channel, sample_rate))
+                .collect(),
+            granulator: (spec.kind == NodeKind::Granular).then(|| Box::new(Granulator::default())),
+            outputs: [0.0; MAX_CHANNELS],
+        };
+        node.set_buffer(buffer);
+
+        node
+    }
+
+    /// Takes over the sample, and the impulse responses prepared for it, from a node that was built
+    /// for the same kind of node with another sample. Everything else about this node is kept.
+    fn adopt(&mut self, other: &mut Node) {
+        std::mem::swap(&mut self.spec, &mut other.spec);
+        std::mem::swap(&mut self.buffer, &mut other.buffer);
+        for (state, other) in self.channels.iter_mut().zip(&mut other.channels) {
+            if let (ChannelState::Convolver(convolver), ChannelState::Convolver(other)) =
+                (state, other)
+            {
+                std::mem::swap(convolver, other);
+            }
+        }
+    }
+
+    /// Sets the sample the node uses. Convolution nodes prepare their impulse responses here, which
+    /// allocates.
+    fn set_buffer(&mut self, buffer: Option<Arc<SampleData>>) {
+        if self.spec.kind == NodeKind::Convolver {
+            let irs: Vec<Arc<ImpulseResponse>> = buffer
+                .iter()
+                .flat_map(|buffer| &buffer.channels)
+                .map(|samples| Arc::new(ImpulseResponse::new(samples)))
+                .collect();
+
+            for (channel, state) in self.channels.iter_mut().enumerate() {
+                if let ChannelState::Convolver(convolver) = state {
+                    *convolver = irs
+                        .get(channel % irs.len().max(1))
+                        .map(|ir| Box::new(Convolver::new(ir.clone())));
+                }
+            }
+        }
+
+        self.buffer = buffer;
+    }
+
+    fn play(&mut self, start: f32, sample_rate: f32) {
+        for channel in &mut self.channels {
+            if let ChannelState::Player(player) = channel {
+                player.position = (start.max(0.0) * sample_rate) as f64;
+                player.playing = true;
+            }
+        }
+    }
+
+    fn update(&mut self, params: NodeParams, input: Source, sample_rate: f32) {
+        // Envelopes react to changes of their gate and trigger parameters
+        if let NodeParams::Adsr {
+            release,
+            gate,
+            trigger,
+            ..
+        } = params
+        {
+            let retriggered = match self.params {
+                NodeParams::Adsr {
+                    trigger: old_trigger,
+                    ..
+                } => old_trigger != trigger,
+                _ => false,
+            };
+
+            for channel in &mut self.channels {
+                if let ChannelState::Adsr(adsr) = channel {
+                    if gate && (retriggered || !adsr.is_gated()) {
+                        adsr.trigger();
+                    } else if !gate && adsr.is_gated() {
+                        adsr.release(release, sample_rate);
+                    }
+                }
+            }
+        }
+
+        // Players restart from their start position when they're retriggered, which includes
+        // players that were triggered right after being created
+        if let NodeParams::Player { start, trigger, .. } = params {
+            let retriggered = match self.params {
+                NodeParams::Player {
+                    trigger: old_trigger,
+                    ..
+                } => old_trigger != trigger,
+                _ => false,
+            };
+
+            if retriggered {
+                self.play(start, sample_rate);
+            }
+        }
+
+        self.input = input;
+        self.params = params;
+        self.coefficients = match self.params {
+            NodeParams::Biquad {
+                mode,
+                cutoff,
+                q,
+                gain,
+            } => Coefficients::Biquad(BiquadCoefficients::new(mode, sample_rate, cutoff, q, gain)),
+            NodeParams::Svf { cutoff, q, .. } => {
+                Coefficients::Svf(SvfCoefficients::new(sample_rate, cutoff, q))
+            }
+            NodeParams::Smooth { time, .. } => {
+                Coefficients::OnePole(OnePole::coefficient(time, sample_rate))
+            }
+            _ => Coefficients::None,
+        };
+    }
+
+    fn process(&mut self, inputs: &[f32], sample_rate: f32) {
+        if let (NodeParams::Granular(params), Some(granulator)) =
+            (&self.params, &mut self.granulator)
+        {
+            granulator.process(
+                params,
+                self.buffer.as_deref(),
+                sample_rate,
+                &mut self.outputs[..self.channels.len()],
+            );
+            return;
+        }
+
+        for (channel, state) in self.channels.iter_mut().enumerate() {
+            let x = inputs[channel];
+            self.outputs[channel] = match (&self.params, &self.coefficients, state) {
+                (NodeParams::Sine { freq }, _, ChannelState::Oscillator(osc)) => {
+                    osc.sine(*freq, sample_rate)
+                }
+                (NodeParams::Saw { freq }, _, ChannelState::Oscillator(osc)) => {
+                    osc.saw(*freq, sample_rate)
+                }
+                (NodeParams::Square { freq, width }, _, ChannelState::Oscillator(osc)) => {
+                    osc.square(*freq, *width, sample_rate)
+                }
+                (NodeParams::Noise, _, ChannelState::Noise(noise)) => noise.next_sample(),
+                (NodeParams::Biquad { .. }, Coefficients::Biquad(c), ChannelState::Biquad(f)) => {
+                    f.process(c, x)
+                }
+                (NodeParams::Svf { mode, .. }, Coefficients::Svf(c), ChannelState::Svf(f)) => {
+                    f.process(*mode, c, x)
+                }
+                (
+                    NodeParams::Smooth { value, .. },
+                    Coefficients::OnePole(c),
+                    ChannelState::Smooth(f),
+                ) => f.process(
+                    *c,
+                    if self.input == Source::None {
+                        *value
+                    } else {
+                        x
+                    },
+                ),
+                (
+                    NodeParams::Adsr {
+                        attack,
+                        decay,
+                        sustain,
+                        ..
+                    },
+                    _,
+                    ChannelState::Adsr(adsr),
+                ) => {
+                    let envelope = adsr.next(*attack, *decay, *sustain, sample_rate);
+                    if self.input == Source::None {
+                        envelope
+                    } else {
+                        envelope * x
+                    }
+                }
+                (
+                    NodeParams::Delay {
+                        time,
+                        feedback,
+                        mix,
+                        ..
+                    },
+                    _,
+                    ChannelState::Delay(delay),
+                ) => {
+                    let delayed = delay.read(time * sample_rate);
+                    delay.write(x + delayed * feedback.clamp(-0.999, 0.999));
+
+                    x + (delayed - x) * mix
+                }
+                (NodeParams::Shaper { shape, drive }, _, _) => shapers::shape(*shape, *drive, x),
+                (NodeParams::Player { speed, looping, .. }, _, ChannelState::Player(player)) => {
+                    match &self.buffer {
+                        Some(buffer) if player.playing && !buffer.is_empty() => {
+                            let out = buffer.read(channel, player.position);
+                            let len = buffer.len() as f64;
+                            player.position += (speed * buffer.sample_rate / sample_rate) as f64;
+                            if player.position >= len || player.position < 0.0 {
+                                if *looping {
+                                    player.position = player.position.rem_euclid(len);
+                                } else {
+                                    player.playing = false;
+                                }
+                            }
+
+                            out
+                        }
+                        _ => 0.0,
+                    }
+                }
+                (
+                    NodeParams::Wavetable {
+                        freq,
+                        position,
+                        frame_size,
+                        ..
+                    },
+                    _,
+                    ChannelState::Oscillator(osc),
+                ) => match &self.buffer {
+                    Some(buffer) => wavetable(
+                        buffer,
+                        channel,
+                        osc.next_phase(*freq, sample_rate),
+                        *position,
+                        *frame_size,
+                    ),
+                    None => 0.0,
+                },
+                (
+                    NodeParams::Convolver { mix, gain, .. },
+                    _,
+                    ChannelState::Convolver(convolver),
+                ) => {
+                    let wet = convolver.as_mut().map(|c| c.process(x)).unwrap_or(0.0);
+
+                    x + (wet * gain - x) * mix
+                }
+                _ => 0.0,
+            };
+        }
+    }
+
+    fn reset(&mut self) {
+        for channel in &mut self.channels {
+            match channel {
+                ChannelState::Oscillator(osc) => *osc = Oscillator::default(),
+                ChannelState::Biquad(f) => *f = Biquad::default(),
+                ChannelState::Svf(f) => *f = Svf::default(),
+                ChannelState::Smooth(f) => *f = OnePole::default(),
+                ChannelState::Adsr(adsr) => *adsr = Adsr::default(),
+                ChannelState::Delay(delay) => delay.clear(),
+                ChannelState::Player(player) => *player = Player::default(),
+                ChannelState::Convolver(convolver) => {
+                    if let Some(convolver) = convolver {
+                        convolver.reset();
+                    }
+                }
+                ChannelState::Noise(_) | ChannelState::Stateless => (),
+            }
+        }
+
+        if let Some(granulator) = &mut self.granulator {
+            granulator.reset();
+        }
+
+        self.outputs = [0.0; MAX_CHANNELS];
+    }
+}
+
+/// Reads a wavetable at `phase` in `[0, 1)`, interpolating between the two frames closest to
+/// `position` and between the samples within those frames.
+fn wavetable(
+    buffer: &SampleData,
+    channel: usize,
+    phase: f32,
+    position: f32,
+    frame_size: usize,
+) -> f32 {
+    if buffer.is_empty() {
+        return 0.0;
+    }
+
+    let len = buffer.len();
+    let samples = &buffer.channels[channel % buffer.channels.len()];
+    let frame_size = frame_size.clamp(1, len);
+    let num_frames = len / frame_size;
+    let frame = position.clamp(0.0, 1.0) * (num_frames - 1) as f32;
+    let frame_frac = frame.fract();
+
+    let offset = phase * frame_size as f32;
+    let idx = offset as usize % frame_size;
+    let next_idx = (idx + 1) % frame_size;
+    let sample_frac = offset.fract();
+    let read_frame = |frame: usize| {
+        let start = frame.min(num_frames - 1) * frame_size;
+        let a = samples[start + idx];
+        let b = samples[start + next_idx];
+
+        a + (b - a) * sample_frac
+    };
+
+    let a = read_frame(frame as usize);
+    let b = read_frame(frame as usize + 1);
+
+    a + (b - a) * frame_frac
+}
+
+/// The states of a [`NodeBuilder`].
+const IDLE: u8 = 0;
+const REQUESTED: u8 = 1;
+const BUILT: u8 = 2;
+
+/// Creates nodes on the background thread. Creating a node allocates, and convolution nodes also
+/// prepare their impulse responses, so the audio thread hands the script's specs over here and
+/// picks up the finished nodes in a later block. Nodes that are replaced or removed are handed
+/// back, so they're also dropped on the background thread.
+///
+/// The audio thread only touches the request while the builder is idle, and the background thread
+/// only touches it while it's requested, so the audio thread's `try_lock()`s always succeed.
+pub struct NodeBuilder {
+    state: AtomicU8,
+    /// Incremented when the script is recompiled, so nodes that were built for the previous script
+    /// are discarded.
+    generation: AtomicU32,
+    request: Mutex<NodeRequest>,
+    /// The nodes built for the last request. Their capacity is kept across requests.
+    built: Mutex<Vec<BuiltNode>>,
+    /// Nodes the audio thread no longer uses.
+    retired: Mutex<Vec<Node>>,
+}
+
+struct NodeRequest {
+    generation: u32,
+    sample_rate: f32,
+    num_channels: usize,
+    /// A JSON array of [`NodeSpec`]s, or nothing if only the samples changed.
+    specs: Vec<u8>,
+    /// The specs of the nodes that were built for the current generation, and the samples they
+    /// were built with, so nodes can be rebuilt when their sample changes.
+    known_generation: u32,
+    known: Vec<Option<(NodeSpec, Option<Weak<SampleData>>)>>,
+}
+
+struct BuiltNode {
+    generation: u32,
+    id: usize,
+    node: Node,
+    /// Whether the node replaces the existing node, or only brings a new sample for it.
+    replace: bool,
+}
+
+impl NodeBuilder {
+    /// `max_specs_len` is the longest JSON the script can send at once.
+    pub fn new(max_specs_len: usize) -> Self {
+        Self {
+            state: AtomicU8::new(IDLE),
+            generation: AtomicU32::new(0),
+            request: Mutex::new(NodeRequest {
+                generation: 0,
+                sample_rate: 1.0,
+                num_channels: 0,
+                specs: Vec::with_capacity(max_specs_len),
+                known_generation: 0,
+                known: Vec::new(),
+            }),
+            built: Mutex::new(Vec::with_capacity(MAX_NODES)),
+            retired: Mutex::new(Vec::with_capacity(MAX_NODES * 2)),
+        }
+    }
+
+    /// Whether the builder can take a new request.
+    pub fn is_idle(&self) -> bool {
+        self.state.load(Ordering::Acquire) == IDLE
+    }
+
+    /// Builds the requested nodes, and drops the retired ones. This blocks, so it should only be
+    /// called from the background thread.
+    pub fn run(&self, samples: &SampleBank) {
+        self.retired.lock().unwrap().clear();
+
+        let mut request = self.request.lock().unwrap();
+        if self.state.load(Ordering::Acquire) != REQUESTED {
+            return;
+        }
+
+        let request = &mut *request;
+        if request.known_generation != request.generation {
+            request.known_generation = request.generation;
+            request.known.clear();
+        }
+
+        let specs: Vec<NodeSpec> = serde_json::from_slice(&request.specs).unwrap_or_default();
+        let mut built = self.built.lock().unwrap();
+        for spec in specs.into_iter().filter(|spec| spec.id < MAX_NODES) {
+            if request.known.len() <= spec.id {
+                request.known.resize_with(spec.id + 1, || None);
+            }
+
+            let replace = !matches!(
+                &request.known[spec.id],
+                Some((known, _)) if known.kind == spec.kind && known.max_time == spec.max_time
+            );
+            let buffer = spec.buffer.as_deref().and_then(|name| samples.get(name));
+            built.push(BuiltNode {
+                generation: request.generation,
+                id: spec.id,
+                node: Node::new(
+                    &spec,
+                    request.num_channels,
+                    request.sample_rate,
+                    buffer.clone(),
+                ),
+                replace,
+            });
+            let id = spec.id;
+            request.known[id] = Some((spec, buffer.as_ref().map(Arc::downgrade)));
+        }
+
+        // Nodes whose sample was loaded, reloaded or removed since they were built
+        for (id, known) in request.known.iter_mut().enumerate() {
+            let (spec, used) = match known {
+                Some((spec, used)) if spec.buffer.is_some() => (spec, used),
+                _ => continue,
+            };
+
+            let buffer = spec.buffer.as_deref().and_then(|name| samples.get(name));
+            let unchanged = match (&*used, &buffer) {
+                (Some(used), Some(buffer)) => Weak::as_ptr(used) == Arc::as_ptr(buffer),
+                (None, None) => true,
+                _ => false,
+            };
+            if unchanged {
+                continue;
+            }
+
+            *used = buffer.as_ref().map(Arc::downgrade);
+            built.push(BuiltNode {
+                generation: request.generation,
+                id,
+                node: Node::new(spec, request.num_channels, request.sample_rate, buffer),
+                replace: false,
+            });
+        }
+
+        self.state.store(BUILT, Ordering::Release);
+    }
+}
+
+/// All of the nodes the current script has created. Node IDs are indices into `nodes`, and nodes
+/// are processed in that order. A node that uses a node with a higher ID as its input receives that
+/// node's output from the previous sample, which allows for feedback.
+pub struct NodePool {
+    sample_rate: f32,
+    num_channels: usize,
+    nodes: Vec<Option<Node>>,
+    /// The nodes that are mixed into the plugin's output, and at what level.
+    outputs: Vec<(usize, f32)>,
+    /// Scratch space for a node's input samples.
+    inputs: [f32; MAX_CHANNELS],
+    /// Nodes that were removed or replaced, until they're handed to the [`NodeBuilder`]. This has
+    /// room for two full node tables, and it's emptied whenever the builder has room for them.
+    retired: Vec<Node>,
+    /// Set when samples were loaded or removed, and the nodes' samples haven't been looked up again
+    /// yet.
+    refresh: bool,
+}
+
+impl Default for NodePool {
+    fn default() -> Self {
+        Self::new(1.0, 0)
+    }
+}
+
+impl NodePool {
+    pub fn new(sample_rate: f32, num_channels: usize) -> Self {
+        Self {
+            sample_rate,
+            num_channels: num_channels.min(MAX_CHANNELS),
+            nodes: Vec::with_capacity(MAX_NODES),
+            outputs: Vec::with_capacity(MAX_NODES),
+            inputs: [0.0; MAX_CHANNELS],
+            retired: Vec::with_capacity(MAX_NODES * 2),
+            refresh: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    /// Whether there's room to retire every node, which [`clear()`][Self::clear()] needs. The
+    /// retired nodes are handed to the builder in later blocks if there isn't.
+    pub fn can_clear(&self) -> bool {
+        self.retired.capacity() - self.retired.len() >= self.nodes.len()
+    }
+
+    /// Removes all nodes, and discards any nodes that are still being built. Called when the script
+    /// is recompiled, once [`can_clear()`][Self::can_clear()] allows it.
+    pub fn clear(&mut self, builder: &NodeBuilder) {
+        builder.generation.fetch_add(1, Ordering::AcqRel);
+        for node in self.nodes.drain(..).flatten() {
+            retire(&mut self.retired, node);
+        }
+        self.outputs.clear();
+    }
+
+    pub fn reset(&mut self) {
+        for node in self.nodes.iter_mut().flatten() {
+            node.reset();
+        }
+    }
+
+    /// Hands the nodes described by `specs`, a JSON array of [`NodeSpec`]s, to the builder. The
+    /// builder has to be idle. Returns whether the builder needs to run on the background thread.
+    pub fn request(&mut self, specs: &[u8], builder: &NodeBuilder) -> bool {
+        if !builder.is_idle() {
+            return false;
+        }
+        let mut request = match builder.request.try_lock() {
+            Ok(request) => request,
+            Err(_) => return false,
+        };
+
+        request.generation = builder.generation.load(Ordering::Acquire);
+        request.sample_rate = self.sample_rate;
+        request.num_channels = self.num_channels;
+        request.specs.clear();
+        let len = specs.len().min(request.specs.capacity());
+        request.specs.extend_from_slice(&specs[..len]);
+        drop(request);
+
+        // Every request also looks up the nodes' samples again
+        self.refresh = false;
+        builder.state.store(REQUESTED, Ordering::Release);
+
+        true
+    }
+
+    /// Looks up the samples used by the nodes again in a later block, after samples were loaded or
+    /// removed.
+    pub fn refresh_samples(&mut self) {
+        self.refresh = true;
+    }
+
+    /// Swaps in the nodes the builder finished, hands the retired nodes to the builder, and asks
+    /// for the nodes' samples to be looked up again if needed. Called once per block, before the
+    /// script's changes are read. Returns whether the builder needs to run on the background
+    /// thread.
+    pub fn install(&mut self, builder: &NodeBuilder) -> bool {
+        if builder.state.load(Ordering::Acquire) == BUILT {
+            if let Ok(mut built) = builder.built.try_lock() {
+                // Every node retires at most one other node, so only as many are installed as
+                // there's room for. The rest are installed in later blocks.
+                let generation = builder.generation.load(Ordering::Acquire);
+                let room = self.retired.capacity() - self.retired.len();
+                let count = built.len().min(room);
+                for built in built.drain(..count) {
+                    self.install_node(built, generation);
+                }
+                let done = built.is_empty();
+                drop(built);
+
+                if done {
+                    builder.state.store(IDLE, Ordering::Release);
+                }
+            }
+        }
+
+        let mut run = false;
+        if !self.retired.is_empty() {
+            if let Ok(mut retired) = builder.retired.try_lock() {
+                if retired.capacity() - retired.len() >= self.retired.len() {
+                    retired.append(&mut self.retired);
+                    run = true;
+                }
+            }
+        }
+
+        if self.refresh {
+            run |= self.request(&[], builder);
+        }
+
+        run
+    }
+
+    fn install_node(&mut self, mut built: BuiltNode, generation: u32) {
+        if built.generation != generation {
+            retire(&mut self.retired, built.node);
+            return;
+        }
+
+        if self.nodes.len() <= built.id {
+            self.nodes.resize_with(built.id + 1, || None);
+        }
+        match &mut self.nodes[built.id] {
+            Some(node) if !built.replace => {
+                node.adopt(&mut built.node);
+                retire(&mut self.retired, built.node);
+            }
+            slot => {
+                if let Some(old) = slot.replace(built.node) {
+                    retire(&mut self.retired, old);
+                }
+            }
+        }
+    }
+
+    /// Reads the rows of the nodes the script changed since the last block, and hands the grains
+    /// the script scheduled to the granular nodes. Rows of nodes that haven't been created yet are
+    /// read once they are.
+    pub fn update(&mut self, update: DspUpdate) {
+        self.outputs.clear();
+        for (id, row) in update.nodes.chunks_exact_mut(NODE_ROW).enumerate() {
+            let node = match self.nodes.get_mut(id) {
+                Some(Some(node)) => node,
+                _ => continue,
+            };
+
+            if row[0] != 0.0 || node.fresh {
+                row[0] = 0.0;
+                node.fresh = false;
+                let params = NodeParams::read(node.spec.kind, &row[3..]);
+                node.update(params, Source::read(row[1]), self.sample_rate);
+            }
+            if row[2] != 0.0 {
+                self.outputs.push((id, row[2] as f32));
+            }
+
+            if let (NodeParams::Granular(params), Some(granulator)) =
+                (&node.params, &mut node.granulator)
+            {
+                if params.callback {
+                    granulator.schedule(
+                        update
+                            .grains
+                            .chunks_exact(GRAIN_ROW)
+                            .filter(|grain| grain[0] as usize == id)
+                            .map(|grain| GrainParams {
+                                at: grain[1] as f32,
+                                position: grain[2] as f32,
+                                size: grain[3] as f32,
+                                pitch: grain[4] as f32,
+                                pan: grain[5] as f32,
+                                gain: grain[6] as f32,
+                            }),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Processes a single sample for every node. `inputs` contains the plugin's input samples.
+    pub fn tick(&mut self, inputs: &[f32]) {
+        for id in 0..self.nodes.len() {
+            let source = match &self.nodes[id] {
+                Some(node) => node.input,
+                None => continue,
+            };
+
+            for channel in 0..self.num_channels {
+                self.inputs[channel] = match source {
+                    Source::None => 0.0,
+                    Source::Input => inputs.get(channel).copied().unwrap_or(0.0),
+                    Source::Node(source_id) => match self.nodes.get(source_id) {
+                        Some(Some(node)) => node.outputs[channel],
+                        _ => 0.0,
+                    },
+                };
+            }
+
+            if let Some(node) = &mut self.nodes[id] {
+                node.process(&self.inputs, self.sample_rate);
+            }
+        }
+    }
+
+    /// The sum of the output nodes for a channel, for the last call to [`tick()`][Self::tick()].
+    pub fn output(&self, channel: usize) -> f32 {
+        if channel >= self.num_channels {
+            return 0.0;
+        }
+
+        self.outputs
+            .iter()
+            .map(|&(id, level)| match self.nodes.get(id) {
+                Some(Some(node)) => node.outputs[channel] * level,
+                _ => 0.0,
+            })
+            .sum()
+    }
+}
+
+/// Keeps a node that's no longer used until it can be dropped on the background thread. The callers
+/// make sure there's room, so the node is never dropped on the audio thread.
+fn retire(retired: &mut Vec<Node>, node: Node) {
+    debug_assert!(
+        retired.len() < retired.capacity(),
+        "no room to retire a node"
+    );
+    if retired.len() < retired.capacity() {
+        retired.push(node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every node the pool and the builder still hold on to.
+    fn held(pool: &NodePool, builder: &NodeBuilder) -> usize {
+        pool.nodes.iter().flatten().count()
+            + pool.retired.len()
+            + builder.built.lock().unwrap().len()
+            + builder.retired.lock().unwrap().len()
+    }
+
+    #[test]
+    fn replaced_nodes_are_only_dropped_in_the_background() {
+        let samples = SampleBank::default();
+        let builder = NodeBuilder::new(1 << 20);
+        let mut pool = NodePool::new(48000.0, 2);
+
+        // Node 0 is recreated with alternating kinds, so every node replaces the one before it,
+        // many more times than the retired nodes have room for
+        let count = MAX_NODES * 5;
+        let specs: Vec<String> = (0..count)
+            .map(|i| {
+                let kind = if i % 2 == 0 { "sine" } else { "saw" };
+                format!(r#"{{"id":0,"kind":"{kind}","buffer":null,"maxTime":0}}"#)
+            })
+            .collect();
+        assert!(pool.request(format!("[{}]", specs.join(",")).as_bytes(), &builder));
+        builder.run(&samples);
+        assert_eq!(held(&pool, &builder), count);
+
+        // Until the background thread gets to run, the audio thread holds on to everything it
+        // replaced
+        let mut run = false;
+        for _ in 0..4 {
+            run |= pool.install(&builder);
+            assert_eq!(held(&pool, &builder), count);
+        }
+        assert!(run && !builder.is_idle());
+
+        let mut blocks = 0;
+        while run || !builder.is_idle() {
+            builder.run(&samples);
+            let before = held(&pool, &builder);
+            run = pool.install(&builder);
+            assert_eq!(held(&pool, &builder), before);
+
+            blocks += 1;
+            assert!(blocks < 100, "the nodes were never all installed");
+        }
+
+        assert_eq!(held(&pool, &builder), 1);
+        assert_eq!(pool.nodes[0].as_ref().unwrap().spec.kind, NodeKind::Saw);
+    }
+}
@@ -0,0 +1,124 @@
+use std::f32::consts::TAU;
+
+/// A phase accumulator based oscillator. The saw and square waveforms are band-limited using
+/// PolyBLEP.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Oscillator {
+    /// The current phase in `[0, 1)`.
+    phase: f32,
+}
+
+impl Oscillator {
+    pub fn sine(&mut self, freq: f32, sample_rate: f32) -> f32 {
+        let out = (self.phase * TAU).sin();
+        self.advance(freq / sample_rate);
+
+        out
+    }
+
+    pub fn saw(&mut self, freq: f32, sample_rate: f32) -> f32 {
+        let dt = (freq / sample_rate).abs();
+        let out = 2.0 * self.phase - 1.0 - poly_blep(self.phase, dt);
+        self.advance(freq / sample_rate);
+
+        out
+    }
+
+    /// A pulse wave with a duty cycle of `width`, where `0.5` results in a square wave.
+    pub fn square(&mut self, freq: f32, width: f32, sample_rate: f32) -> f32 {
+        let dt = (freq / sample_rate).abs();
+        let width = width.clamp(0.01, 0.99);
+        let naive = if self.phase < width { 1.0 } else { -1.0 };
+        let out =
+            naive + poly_blep(self.phase, dt) - poly_blep((self.phase - width).rem_euclid(1.0), dt);
+        self.advance(freq / sample_rate);
+
+        out
+    }
+
+    /// Returns the current phase and advances it, for oscillators that compute their own waveform.
+    pub fn next_phase(&mut self, freq: f32, sample_rate: f32) -> f32 {
+        let phase = self.phase;
+        self.advance(freq / sample_rate);
+
+        phase
+    }
+
+    fn advance(&mut self, delta: f32) {
+        self.phase = (self.phase + delta).rem_euclid(1.0);
+    }
+}
+
+/// The polynomial correction for a discontinuity at phase zero, where `dt` is the phase increment
+/// per sample.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if dt <= 0.0 {
+        0.0
+    } else if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// White noise using a xorshift PRNG, so it doesn't need any allocations or locks.
+#[derive(Debug, Clone, Copy)]
+pub struct Noise {
+    state: u32,
+}
+
+impl Noise {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            // The state must never be zero
+            state: seed.wrapping_mul(0x9e37_79b9) | 1,
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+
+        (self.state as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sine_completes_one_cycle_per_period() {
+        let mut oscillator = Oscillator::default();
+        let output: Vec<f32> = (0..100).map(|_| oscillator.sine(480.0, 48_000.0)).collect();
+
+        assert!(output[0].abs() < 1e-6);
+        assert!((output[25] - 1.0).abs() < 1e-4);
+        assert!((output[75] + 1.0).abs() < 1e-4);
+        assert!((oscillator.sine(480.0, 48_000.0)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn band_limited_waveforms_stay_in_range() {
+        let mut saw = Oscillator::default();
+        let mut square = Oscillator::default();
+        for _ in 0..48_000 {
+            assert!(saw.saw(1234.5, 48_000.0).abs() <= 1.01);
+            assert!(square.square(1234.5, 0.3, 48_000.0).abs() <= 1.01);
+        }
+    }
+
+    #[test]
+    fn noise_is_bipolar_and_roughly_centered() {
+        let mut noise = Noise::new(0);
+        let samples: Vec<f32> = (0..48_000).map(|_| noise.next_sample()).collect();
+
+        assert!(samples.iter().all(|x| (-1.0..=1.0).contains(x)));
+        assert!((samples.iter().sum::<f32>() / samples.len() as f32).abs() < 0.02);
+    }
+}
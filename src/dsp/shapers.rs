@@ -0,0 +1,28 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    Tanh,
+    /// A cubic soft clipper.
+    SoftClip,
+    HardClip,
+    /// Folds the signal back when it exceeds `[-1, 1]`.
+    Fold,
+}
+
+impl Shape {
+    pub const ALL: [Shape; 4] = [Shape::Tanh, Shape::SoftClip, Shape::HardClip, Shape::Fold];
+    /// The names scripts use, in the same order.
+    pub const NAMES: [&'static str; 4] = ["tanh", "softClip", "hardClip", "fold"];
+}
+
+pub fn shape(shape: Shape, drive: f32, x: f32) -> f32 {
+    let x = x * drive;
+    match shape {
+        Shape::Tanh => x.tanh(),
+        Shape::SoftClip => {
+            let x = x.clamp(-1.0, 1.0);
+            1.5 * x - 0.5 * x * x * x
+        }
+        Shape::HardClip => x.clamp(-1.0, 1.0),
+        Shape::Fold => 1.0 - ((x + 1.0).rem_euclid(4.0) - 2.0).abs(),
+    }
+}
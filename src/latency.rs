@@ -0,0 +1,82 @@
+/// Delays the input by the latency a script declares. The script itself still gets to see the live
+/// input through `lookahead(ch, n)`, everything else only sees the delayed input so it stays
+/// aligned with the script's output.
+pub struct InputDelay {
+    /// A ring buffer per channel, all with a length of the latency.
+    buffers: Vec<Vec<f32>>,
+    pos: usize,
+}
+
+impl InputDelay {
+    pub fn new(latency: u32, num_channels: usize) -> Self {
+        Self {
+            buffers: vec![vec![0.0; latency.max(1) as usize]; num_channels],
+            pos: 0,
+        }
+    }
+
+    pub fn latency_samples(&self) -> u32 {
+        self.buffers.first().map(Vec::len).unwrap_or(0) as u32
+    }
+
+    /// Delays a single sample for every channel in place.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for (sample, buffer) in samples.iter_mut().zip(&mut self.buffers) {
+            std::mem::swap(sample, &mut buffer[self.pos]);
+        }
+
+        if let Some(len) = self.buffers.first().map(Vec::len) {
+            self.pos = (self.pos + 1) % len;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for buffer in &mut self.buffers {
+            buffer.fill(0.0);
+        }
+    }
+}
+
+/// Delays signals that don't go through the latent processing, so they stay aligned with the ones
+/// that do. The latency can change while processing, up to a maximum that's allocated up front.
+pub struct Compensation {
+    /// A ring buffer per channel, one sample longer than the maximum latency.
+    buffers: Vec<Vec<f32>>,
+    pos: usize,
+    latency: usize,
+}
+
+impl Compensation {
+    pub fn new(max_latency: usize, num_channels: usize) -> Self {
+        Self {
+            buffers: vec![vec![0.0; max_latency + 1]; num_channels],
+            pos: 0,
+            latency: 0,
+        }
+    }
+
+    /// Sets the latency in samples. Latencies beyond the maximum are cut off at the maximum.
+    pub fn set_latency(&mut self, latency: u32) {
+        let max_latency = self.buffers.first().map(Vec::len).unwrap_or(1) - 1;
+        self.latency = (latency as usize).min(max_latency);
+    }
+
+    /// Delays a single sample for every channel in place.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for (sample, buffer) in samples.iter_mut().zip(&mut self.buffers) {
+            let len = buffer.len();
+            buffer[self.pos] = *sample;
+            *sample = buffer[(self.pos + len - self.latency) % len];
+        }
+
+        if let Some(len) = self.buffers.first().map(Vec::len) {
+            self.pos = (self.pos + 1) % len;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for buffer in &mut self.buffers {
+            buffer.fill(0.0);
+        }
+    }
+}
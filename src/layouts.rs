@@ -0,0 +1,46 @@
+use nih_plug::prelude::*;
+
+/// The most main input and output channels any of Rjv's layouts have.
+pub const MAX_CHANNELS: usize = 16;
+
+const SIDECHAIN: &[NonZeroU32] = &[new_nonzero_u32(2)];
+
+/// The first layout is used as the default, so that's plain stereo without a sidechain. The named
+/// multichannel layouts are listed before the generic ones so hosts that pick the first layout
+/// matching a channel count use those names.
+pub const AUDIO_IO_LAYOUTS: &[AudioIOLayout] = &[
+    layout(2, "Stereo", false),
+    layout(2, "Stereo with sidechain", true),
+    layout(1, "Mono", false),
+    layout(1, "Mono with sidechain", true),
+    layout(4, "Quad", false),
+    layout(6, "5.1", false),
+    layout(8, "7.1", false),
+    layout(3, "3 channels", false),
+    layout(5, "5 channels", false),
+    layout(7, "7 channels", false),
+    layout(9, "9 channels", false),
+    layout(10, "10 channels", false),
+    layout(11, "11 channels", false),
+    layout(12, "12 channels", false),
+    layout(13, "13 channels", false),
+    layout(14, "14 channels", false),
+    layout(15, "15 channels", false),
+    layout(16, "16 channels", false),
+];
+
+/// A layout with the same number of main input and output channels, and optionally a stereo
+/// sidechain input.
+const fn layout(channels: u32, name: &'static str, sidechain: bool) -> AudioIOLayout {
+    AudioIOLayout {
+        main_input_channels: NonZeroU32::new(channels),
+        main_output_channels: NonZeroU32::new(channels),
+        aux_input_ports: if sidechain { SIDECHAIN } else { &[] },
+        names: PortNames {
+            layout: Some(name),
+            aux_inputs: if sidechain { &["Sidechain"] } else { &[] },
+            ..PortNames::const_default()
+        },
+        ..AudioIOLayout::const_default()
+    }
+}
@@ -1,44 +1,227 @@
-use atomic_float::AtomicF32;
+use analysis::Analyzer;
 use code_editor::code_editor;
-use js_sandbox::Script;
+use console::{BlockPosition, Console, ConsoleLog, LogLevel};
+use controls::{ButtonPresses, ControlLayout, NUM_CONTROLS};
+use cpu::{CpuMeter, Profile};
+use dsp::{NodeBuilder, NodePool};
+use latency::{Compensation, InputDelay};
+use layouts::MAX_CHANNELS;
+use looper::{
+    Looper, LooperButton, LooperCommand, LooperHandoff, LooperRemote, MAX_CAPTURE_SECONDS,
+};
+use meters::Meter;
+use midi::{ControlSlot, MidiLearn, MidiMap, MidiOut, MidiRealtime, MidiTarget, PendingValues};
 use nih_plug::prelude::*;
+use nih_plug::util::permit_alloc;
 use nih_plug_egui::{
     create_egui_editor,
     egui::{self, epaint::Shadow, Color32, FontData, FontDefinitions},
-    EguiState,
+    widgets, EguiState,
 };
-use std::sync::{Arc, Mutex};
-
+use oversampling::{Oversampler, Oversampling};
+use plot::{PlotWindow, Plotter};
+use samples::{SampleBank, SampleSource, SampleStatus, SampleTask};
+use scope::{ScopeTrigger, MAX_WINDOW_MS};
+use script::{BlockInfo, Script, ScriptBuilder, ScriptConfig, ScriptEngine, MAX_EVENTS, TEXT_LEN};
+use spectral::{SpectralOptions, Stft};
+use spectrum::{SpectrumAnalyzer, MAX_SMOOTHING_MS};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use telemetry::{RecordKind, Telemetry};
+#[cfg(not(feature = "test-hooks"))]
+use transport::HostTransport;
+use voices::{VoiceFrame, VoiceManager, VoiceStealing, MAX_VOICES};
+use watch::{WatchValues, Watcher, Watches};
+
+mod analysis;
 mod code_editor;
+mod console;
+mod controls;
+mod cpu;
+mod dsp;
+mod latency;
+mod layouts;
+mod looper;
+mod meters;
+mod midi;
+mod oversampling;
+mod plot;
+mod runtime;
+mod samples;
+mod scope;
+mod script;
+mod spectral;
+mod spectrum;
+mod telemetry;
+mod transport;
+mod voices;
+mod watch;
+
+#[cfg(feature = "test-hooks")]
+pub use transport::HostTransport;
 
 // This is a shortened version of the gain example with most comments removed, check out
 // https://github.com/robbert-vdh/nih-plug/blob/master/plugins/examples/gain/src/lib.rs to get
 // started
 
-/// The time it takes for the peak meter to decay by 12 dB after switching to complete silence.
-const PEAK_METER_DECAY_MS: f64 = 150.0;
+/// How far back scripts can look with `input(ch, n)` and `output(ch, n)`. This is also the longest
+/// latency the dry signal is delayed by, which is more than the script, the STFT and oversampling
+/// can add together.
+const HISTORY_SECONDS: f32 = 2.0;
+
+/// The polyphonic modulation ID for the gain parameter, used to modulate the gain of individual
+/// voices.
+const GAIN_POLY_MOD_ID: u32 = 0;
 
 pub struct Rjv {
     params: Arc<RjvParams>,
     sample_rate: f32,
 
-    time_s: f32,
-
-    /// Needed to normalize the peak meter's response based on the sample rate.
-    peak_meter_decay_weight: f32,
-    /// The current data for the peak meter. This is stored as an [`Arc`] so we can share it between
-    /// the GUI and the audio processing parts. If you have more state to share, then it's a good
-    /// idea to put all of that in a struct behind a single `Arc`.
-    ///
-    /// This is stored as voltage gain.
-    peak_meter: Arc<AtomicF32>,
-
-    display: Arc<Mutex<String>>,
+    /// The number of samples processed so far. The script's time is derived from this, so it
+    /// doesn't lose precision over long sessions.
+    time_samples: u64,
+
+    /// Everything reported to the editor: the script's status, the meters' readings, the script's
+    /// CPU usage, the oscilloscope's ring buffer, the current values for the function plot, and
+    /// the console, watch and profile records.
+    telemetry: Arc<Telemetry>,
+    input_meter: Meter,
+    output_meter: Meter,
+    cpu_meter: CpuMeter,
+    /// Scratch space for a single input sample per channel before any processing, and a single
+    /// output sample per channel, for the looper and the meters.
+    input_samples: [f32; MAX_CHANNELS],
+    output_samples: [f32; MAX_CHANNELS],
+    /// Scratch space for the input delayed by the plugin's latency, which is mixed with the
+    /// processed output.
+    dry_samples: [f32; MAX_CHANNELS],
+    dry_delay: Compensation,
+    /// Scratch space for the voices, the DSP nodes and the looper, which don't go through the
+    /// oversampled `process()`. These are delayed by the oversampler's latency so they stay aligned
+    /// with its output.
+    parallel_samples: [f32; MAX_CHANNELS],
+    oversampling_delay: Compensation,
+
+    /// Sends the messages the script logs to the editor.
+    console: Console,
+    /// Set when the script was recompiled, so its status and any compile error are reported in the
+    /// next block.
+    report_compile: bool,
+    /// Whether the editor was open during the last block. Console messages are only sent while
+    /// it's open, so they don't pile up in the telemetry while nobody reads them.
+    editor_was_open: bool,
+    /// Set by the tests to process as if the editor was open.
+    simulate_editor: bool,
+    /// The editor's watch expressions, and the state for evaluating them.
+    watches: Arc<Watches>,
+    watcher: Watcher,
+
+    engine: ScriptEngine,
+    /// Incremented by the editor whenever it edits the code.
+    code_generation: Arc<AtomicU32>,
+    /// The preset and code generation the script was last requested for. The background thread
+    /// only reads and compiles the code when either of them changed.
+    code_version: Option<(i32, u32)>,
+    /// Compiles the script on the background thread.
+    script_builder: Arc<ScriptBuilder>,
+    /// Set when the script was recompiled and its controls haven't been published yet.
+    publish_controls: bool,
+    /// The native DSP nodes created by the script.
+    dsp: NodePool,
+    /// Creates the script's DSP nodes on the background thread.
+    node_builder: Arc<NodeBuilder>,
+    voices: VoiceManager,
+    /// Scratch space for passing the active voices to the script.
+    voice_frames: [VoiceFrame; MAX_VOICES],
+    /// The voice capacity that was last reported to the host.
+    voice_capacity: u32,
+
+    /// The number of main input and output channels.
+    channels: usize,
+    /// The name of the current channel layout, like `5.1`.
+    layout: Option<&'static str>,
+    /// The number of channels in the sidechain input, if the host has connected one.
+    sidechain_channels: usize,
+    /// Scratch space for the input samples passed to the script's `process()` function.
+    process_inputs: Vec<f32>,
+    /// Scratch space for the undelayed input samples, when the script declared a latency.
+    live_inputs: Vec<f32>,
+    /// Set when the script declared a latency.
+    input_delay: Option<InputDelay>,
+    /// Set when oversampling is enabled and the script defines `process()`.
+    oversampler: Option<Oversampler>,
+    /// The oversamplers for the other factors. They're all built when the plugin is initialized, so
+    /// switching factors doesn't allocate.
+    spare_oversamplers: Vec<Oversampler>,
+    /// Scratch space for the script's results. These are filled by the script engine, and keep
+    /// their capacity across blocks.
+    process_outputs: Vec<f32>,
+    voice_outputs: Vec<f32>,
+    done_voices: Vec<usize>,
+    midi_out: Vec<MidiOut>,
+    /// The voice events from this block, which are sent together with the script's MIDI at the
+    /// end of the block.
+    voice_events: Vec<PluginNoteEvent<Self>>,
+    looper_commands: Vec<LooperCommand>,
+
+    /// Set when the script defines `spectral(t, frames)`.
+    stft: Option<Stft>,
+    /// Scratch space for the samples passed through the STFT.
+    stft_samples: [f32; MAX_CHANNELS],
+    /// Set when the script defines `analysisOptions`.
+    analyzer: Option<Analyzer>,
+    /// The latency that was last reported to the host, in samples.
+    latency: u32,
+
+    midi_learn: Arc<MidiLearn>,
+    /// Parameter values from MIDI and the defaults of new controls, waiting to be set by the
+    /// editor.
+    pending_values: Arc<PendingValues>,
+    /// The editor's button presses, so presses shorter than a block still reach the script.
+    button_presses: Arc<ButtonPresses>,
+
+    /// The samples loaded through the editor.
+    samples: Arc<SampleBank>,
+    /// The sample bank's generation the script and the DSP nodes were last updated for, or `None`
+    /// if they need to be updated.
+    samples_generation: Option<u32>,
+    /// Set when the samples need to be loaded again at a new sample rate or from restored state.
+    reload_samples: bool,
+
+    looper: Looper,
+    /// Builds loopers and publishes finished loops on the background thread.
+    looper_handoff: Arc<LooperHandoff>,
+    /// Shared with the editor for the looper's buttons and status.
+    looper_remote: Arc<LooperRemote>,
+    /// Scratch space for the looper's output samples.
+    looper_outputs: [f32; MAX_CHANNELS],
 }
 
 struct UIState {
     preset: i32,
     code: String,
+    sample_path: String,
+    sample_name: String,
+    embed_sample: bool,
+    /// Scratch space for the telemetry records the audio thread sends.
+    payload: Vec<u8>,
+    console: ConsoleLog,
+    watch_input: String,
+    watch_values: WatchValues,
+    profile: Profile,
+    plot_window: PlotWindow,
+    plotter: Plotter,
+    scope_ms: f32,
+    scope_trigger: ScopeTrigger,
+    scope_input: bool,
+    /// Scratch space for the window the scope shows.
+    scope_input_samples: Vec<f32>,
+    scope_output_samples: Vec<f32>,
+    spectrum: SpectrumAnalyzer,
+    spectrum_smoothing_ms: f32,
+    spectrum_peak_hold: bool,
+    spectrum_input: bool,
 }
 
 #[derive(Params)]
@@ -48,6 +231,18 @@ struct RjvParams {
     #[persist = "editor-state"]
     editor_state: Arc<EguiState>,
 
+    /// The CCs bound to parameters through MIDI learn.
+    #[persist = "midi-map"]
+    midi_map: RwLock<MidiMap>,
+
+    /// The controls the script declared, see `controls.rs`.
+    #[persist = "controls"]
+    control_layout: RwLock<ControlLayout>,
+
+    /// The loaded samples' file paths, or their audio if they're embedded.
+    #[persist = "samples"]
+    samples: RwLock<Vec<SampleSource>>,
+
     /// The parameter's ID is used to identify the parameter in the wrappred plugin API. As long as
     /// these IDs remain constant, you can rename and reorder these fields as you wish. The
     /// parameters are exposed to the host in the same order they were defined. In this case, this
@@ -55,9 +250,61 @@ struct RjvParams {
     #[id = "gain"]
     pub gain: FloatParam,
 
+    /// How much of the processed signal is mixed with the dry input.
+    #[id = "mix"]
+    pub mix: FloatParam,
+
     #[id = "preset"]
     pub preset: IntParam,
 
+    /// The maximum number of voices the script's `voice()` function is called for.
+    #[id = "voices"]
+    pub voices: IntParam,
+
+    #[id = "voice_stealing"]
+    pub voice_stealing: EnumParam<VoiceStealing>,
+
+    /// How long voices keep sounding after their note-off event, in milliseconds.
+    #[id = "release"]
+    pub release: FloatParam,
+
+    /// Runs the script's `process()` function at a higher sample rate to reduce aliasing.
+    #[id = "oversampling"]
+    pub oversampling: EnumParam<Oversampling>,
+
+    /// How many seconds of input the looper keeps around, which is also the longest possible loop.
+    #[id = "capture_length"]
+    pub capture_length: IntParam,
+
+    /// Whether the looper starts and stops on bar lines while the host is playing.
+    #[id = "loop_sync"]
+    pub loop_sync: BoolParam,
+
+    /// The values of the controls the script declares, normalized to `[0, 1]`. See `controls.rs`.
+    #[id = "control_1"]
+    pub control_1: FloatParam,
+
+    #[id = "control_2"]
+    pub control_2: FloatParam,
+
+    #[id = "control_3"]
+    pub control_3: FloatParam,
+
+    #[id = "control_4"]
+    pub control_4: FloatParam,
+
+    #[id = "control_5"]
+    pub control_5: FloatParam,
+
+    #[id = "control_6"]
+    pub control_6: FloatParam,
+
+    #[id = "control_7"]
+    pub control_7: FloatParam,
+
+    #[id = "control_8"]
+    pub control_8: FloatParam,
+
     #[id = "code_1"]
     pub code_1: StringParam,
 
@@ -93,20 +340,123 @@ impl RjvParams {
             &self.code_6
         }
     }
+
+    fn control_params(&self) -> [&FloatParam; NUM_CONTROLS] {
+        [
+            &self.control_1,
+            &self.control_2,
+            &self.control_3,
+            &self.control_4,
+            &self.control_5,
+            &self.control_6,
+            &self.control_7,
+            &self.control_8,
+        ]
+    }
+
+    /// Sets a parameter to a normalized value from MIDI. The editor passes its `setter` so the host
+    /// is told about the change. Without an editor there's no way to tell the host, so the value
+    /// is set directly.
+    fn set_normalized(&self, target: MidiTarget, normalized: f32, setter: Option<&ParamSetter>) {
+        match (target, setter) {
+            (MidiTarget::Gain, Some(setter)) => set_param(setter, &self.gain, normalized),
+            (MidiTarget::Gain, None) => self.gain.set_value(self.gain.preview_plain(normalized)),
+            (MidiTarget::Mix, Some(setter)) => set_param(setter, &self.mix, normalized),
+            (MidiTarget::Mix, None) => self.mix.set_value(self.mix.preview_plain(normalized)),
+            (MidiTarget::Preset, Some(setter)) => set_param(setter, &self.preset, normalized),
+            (MidiTarget::Preset, None) => {
+                self.preset.set_value(self.preset.preview_plain(normalized))
+            }
+            (MidiTarget::Voices, Some(setter)) => set_param(setter, &self.voices, normalized),
+            (MidiTarget::Voices, None) => {
+                self.voices.set_value(self.voices.preview_plain(normalized))
+            }
+            (MidiTarget::Release, Some(setter)) => set_param(setter, &self.release, normalized),
+            (MidiTarget::Release, None) => self
+                .release
+                .set_value(self.release.preview_plain(normalized)),
+            (MidiTarget::Control(slot), setter) => {
+                let param = self.control_params()[slot.get()];
+                match setter {
+                    Some(setter) => set_param(setter, param, normalized),
+                    None => param.set_value(normalized),
+                }
+            }
+        }
+    }
 }
 
 impl Default for Rjv {
     fn default() -> Self {
+        let telemetry = Arc::new(Telemetry::default());
+
         Self {
             params: Arc::new(RjvParams::default()),
             sample_rate: 1.0,
 
-            time_s: 0.0,
-
-            peak_meter_decay_weight: 1.0,
-            peak_meter: Arc::new(AtomicF32::new(util::MINUS_INFINITY_DB)),
-
-            display: Arc::new(Mutex::new("hi".to_string())),
+            time_samples: 0,
+
+            input_meter: Meter::default(),
+            output_meter: Meter::default(),
+            cpu_meter: CpuMeter::default(),
+            input_samples: [0.0; MAX_CHANNELS],
+            output_samples: [0.0; MAX_CHANNELS],
+            dry_samples: [0.0; MAX_CHANNELS],
+            dry_delay: Compensation::new(0, 0),
+            parallel_samples: [0.0; MAX_CHANNELS],
+            oversampling_delay: Compensation::new(0, 0),
+
+            console: Console::new(telemetry.clone()),
+            telemetry,
+            report_compile: false,
+            editor_was_open: false,
+            simulate_editor: false,
+            watches: Arc::new(Watches::default()),
+            watcher: Watcher::default(),
+
+            engine: ScriptEngine::default(),
+            code_generation: Arc::new(AtomicU32::new(0)),
+            code_version: None,
+            script_builder: Arc::new(ScriptBuilder::default()),
+            publish_controls: false,
+            dsp: NodePool::default(),
+            node_builder: Arc::new(NodeBuilder::new(TEXT_LEN)),
+            voices: VoiceManager::default(),
+            voice_frames: Default::default(),
+            voice_capacity: 0,
+
+            channels: 0,
+            layout: None,
+            sidechain_channels: 0,
+            process_inputs: Vec::new(),
+            live_inputs: Vec::new(),
+            input_delay: None,
+            oversampler: None,
+            spare_oversamplers: Vec::new(),
+            process_outputs: Vec::with_capacity(MAX_CHANNELS),
+            voice_outputs: Vec::with_capacity(MAX_VOICES),
+            done_voices: Vec::with_capacity(MAX_VOICES),
+            midi_out: Vec::with_capacity(MAX_EVENTS),
+            voice_events: Vec::with_capacity(MAX_EVENTS),
+            looper_commands: Vec::with_capacity(MAX_EVENTS),
+
+            stft: None,
+            stft_samples: [0.0; MAX_CHANNELS],
+            analyzer: None,
+            latency: 0,
+
+            midi_learn: Arc::new(MidiLearn::default()),
+            pending_values: Arc::new(PendingValues::default()),
+            button_presses: Arc::new(ButtonPresses::default()),
+
+            samples: Arc::new(SampleBank::default()),
+            samples_generation: None,
+            reload_samples: false,
+
+            looper: Looper::default(),
+            looper_handoff: Arc::new(LooperHandoff::default()),
+            looper_remote: Arc::new(LooperRemote::default()),
+            looper_outputs: [0.0; MAX_CHANNELS],
         }
     }
 }
@@ -114,7 +464,11 @@ impl Default for Rjv {
 impl Default for RjvParams {
     fn default() -> Self {
         Self {
-            editor_state: EguiState::from_size(800, 600),
+            editor_state: EguiState::from_size(1100, 600),
+
+            midi_map: RwLock::new(MidiMap::default()),
+            control_layout: RwLock::new(ControlLayout::default()),
+            samples: RwLock::new(Vec::new()),
 
             // This gain is stored as linear gain. NIH-plug comes with useful conversion functions
             // to treat these kinds of parameters as if we were dealing with decibels. Storing this
@@ -133,6 +487,7 @@ impl Default for RjvParams {
             // Because the gain parameter is stored as linear gain instead of storing the value as
             // decibels, we need logarithmic smoothing
             .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_poly_modulation_id(GAIN_POLY_MOD_ID)
             .with_unit(" dB")
             // There are many predefined formatters we can use here. If the gain was stored as
             // decibels instead of as a linear gain value, we could have also used the
@@ -140,8 +495,58 @@ impl Default for RjvParams {
             .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
             .with_string_to_value(formatters::s2v_f32_gain_to_db()),
 
+            mix: FloatParam::new("Mix", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(20.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+
             preset: IntParam::new("Preset", 1, IntRange::Linear { min: 1, max: 6 }),
 
+            voices: IntParam::new(
+                "Voices",
+                8,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_VOICES as i32,
+                },
+            ),
+            voice_stealing: EnumParam::new("Voice Stealing", VoiceStealing::Oldest),
+            release: FloatParam::new(
+                "Release",
+                500.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 10_000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(formatters::v2s_f32_rounded(0)),
+
+            oversampling: EnumParam::new("Oversampling", Oversampling::Off),
+
+            capture_length: IntParam::new(
+                "Capture Length",
+                30,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_CAPTURE_SECONDS,
+                },
+            )
+            .with_unit(" s")
+            .non_automatable(),
+            loop_sync: BoolParam::new("Loop Sync", true),
+
+            control_1: control_param("Control 1"),
+            control_2: control_param("Control 2"),
+            control_3: control_param("Control 3"),
+            control_4: control_param("Control 4"),
+            control_5: control_param("Control 5"),
+            control_6: control_param("Control 6"),
+            control_7: control_param("Control 7"),
+            control_8: control_param("Control 8"),
+
             code_1: StringParam::new("Code 1", "fn bla() { 5 + \"hello\" }".to_string()),
             code_2: StringParam::new("Code 2", "20".to_string()),
             code_3: StringParam::new("Code 3", "30".to_string()),
@@ -152,6 +557,766 @@ impl Default for RjvParams {
     }
 }
 
+/// Sets a parameter from the editor in a single gesture.
+fn set_param<P: Param>(setter: &ParamSetter, param: &P, normalized: f32) {
+    setter.begin_set_parameter(param);
+    setter.set_parameter_normalized(param, normalized);
+    setter.end_set_parameter(param);
+}
+
+/// The controls map these values to their own ranges, so the parameters are all the same.
+fn control_param(name: &str) -> FloatParam {
+    FloatParam::new(name, 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+}
+
+/// Work that's done on the background thread.
+#[doc(hidden)]
+#[derive(Debug)]
+pub enum Task {
+    Samples(SampleTask),
+    /// Builds the DSP nodes the script created, and drops the ones it no longer uses.
+    Nodes,
+    /// Builds a looper with a new capture length, and makes finished loops available as samples.
+    Looper,
+    /// Compiles the current code, and drops the script it replaced.
+    Script,
+}
+
+/// Holds on to a voice event until the end of the block, so it can be sent in order with the
+/// script's MIDI. If an unusual number of voices ended during the block, the event is sent right
+/// away instead.
+fn queue_event(
+    events: &mut Vec<PluginNoteEvent<Rjv>>,
+    event: PluginNoteEvent<Rjv>,
+    context: &mut impl ProcessContext<Rjv>,
+) {
+    if events.len() < events.capacity() {
+        events.push(event);
+    } else {
+        context.send_event(event);
+    }
+}
+
+/// Lets the tests drive the plugin the way a host and the editor would.
+#[cfg(feature = "test-hooks")]
+impl Rjv {
+    /// Creates the plugin with `code` as the first preset's script. Used by the tests.
+    pub fn with_code(code: &str) -> Self {
+        let plugin = Self::default();
+        plugin.params.code_1.set_value(code.to_string());
+        plugin
+    }
+
+    /// Changes the current preset's script, the way the editor does. Used by the tests.
+    pub fn set_code(&self, code: &str) {
+        self.params.code().set_value(code.to_string());
+        self.code_generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Sets the oversampling factor for `process()`, rounded up to a supported factor. Used by the
+    /// tests.
+    pub fn set_oversampling(&self, factor: usize) {
+        let oversampling = [Oversampling::Off, Oversampling::X2, Oversampling::X4]
+            .into_iter()
+            .find(|oversampling| oversampling.factor() >= factor)
+            .unwrap_or(Oversampling::X8);
+        self.params.oversampling.set_value(oversampling);
+    }
+
+    /// Processes as if the editor was open, so the meters, the scope and the watches are updated.
+    /// Used by the tests.
+    pub fn simulate_editor(&mut self, open: bool) {
+        self.simulate_editor = open;
+    }
+
+    /// Adds a watch expression, the way the editor does. Used by the tests.
+    pub fn add_watch(&self, expression: &str) {
+        self.watches.add(expression);
+    }
+
+    /// The background task that loads a sample from a file, like the editor's sample panel. Used
+    /// by the tests.
+    pub fn load_sample(name: &str, path: PathBuf) -> Task {
+        Task::Samples(SampleTask::Load {
+            name: name.to_string(),
+            path,
+            embed: false,
+        })
+    }
+
+    /// Processes a block with a transport the test made up. Used by the tests.
+    pub fn process_with_transport(
+        &mut self,
+        buffer: &mut Buffer,
+        aux: &mut AuxiliaryBuffers,
+        transport: &HostTransport,
+        context: &mut impl ProcessContext<Self>,
+    ) -> ProcessStatus {
+        self.process_block(buffer, aux, transport, context)
+    }
+}
+
+impl Rjv {
+    fn editor_open(&self) -> bool {
+        self.simulate_editor || self.params.editor_state.is_open()
+    }
+
+    /// Sets the pending values while the editor is closed. While it's open the editor sets them,
+    /// so the host hears about them.
+    fn set_pending_values(&self) {
+        if !self.editor_open() {
+            let params = &self.params;
+            self.pending_values
+                .take(|target, normalized| params.set_normalized(target, normalized, None));
+        }
+    }
+
+    /// The selected preset, and the generation of the code.
+    fn current_code_version(&self) -> (i32, u32) {
+        (
+            self.params.preset.value(),
+            self.code_generation.load(Ordering::Acquire),
+        )
+    }
+
+    /// Switches to a newly compiled script, and sets up the processing it asked for. What the new
+    /// script replaced is left in `script`, so it can be dropped on the background thread.
+    fn install_script(&mut self, script: &mut Script) {
+        self.engine.install(script);
+        self.report_compile = true;
+        self.publish_controls = true;
+        self.watcher.invalidate();
+        // The script was compiled with the samples that were loaded at the time
+        self.samples_generation = None;
+
+        self.dsp.clear(&self.node_builder);
+
+        let info = self.engine.info();
+        if info.spectral.map(SpectralOptions::normalized) != self.stft.as_ref().map(Stft::options) {
+            std::mem::swap(&mut self.stft, &mut script.stft);
+        }
+
+        if info.analysis != self.analyzer.as_ref().map(Analyzer::options) {
+            std::mem::swap(&mut self.analyzer, &mut script.analyzer);
+        }
+
+        let delay_latency = self.input_delay.as_ref().map(InputDelay::latency_samples);
+        if Some(info.latency).filter(|&latency| latency > 0) != delay_latency {
+            std::mem::swap(&mut self.input_delay, &mut script.input_delay);
+        }
+    }
+
+    /// Sets up oversampling for the factor the script was built for, which changes with the
+    /// parameter, and when the script starts or stops defining `process()`.
+    fn sync_oversampling(&mut self) {
+        let factor = self.engine.factor();
+
+        if factor
+            != self
+                .oversampler
+                .as_ref()
+                .map(Oversampler::factor)
+                .unwrap_or(1)
+        {
+            // The spares have room for every oversampler, so this doesn't allocate
+            if let Some(oversampler) = self.oversampler.take() {
+                self.spare_oversamplers.push(oversampler);
+            }
+            if let Some(idx) = self
+                .spare_oversamplers
+                .iter()
+                .position(|oversampler| oversampler.factor() == factor)
+            {
+                let mut oversampler = self.spare_oversamplers.swap_remove(idx);
+                oversampler.reset();
+                self.oversampler = Some(oversampler);
+            }
+            self.oversampling_delay.set_latency(
+                self.oversampler
+                    .as_ref()
+                    .map(Oversampler::latency_samples)
+                    .unwrap_or(0),
+            );
+        }
+    }
+
+    /// The total latency of the spectral processing, the script's declared latency and
+    /// oversampling.
+    fn latency_samples(&self) -> u32 {
+        self.stft.as_ref().map(Stft::latency_samples).unwrap_or(0)
+            + self
+                .input_delay
+                .as_ref()
+                .map(InputDelay::latency_samples)
+                .unwrap_or(0)
+            + self
+                .oversampler
+                .as_ref()
+                .map(Oversampler::latency_samples)
+                .unwrap_or(0)
+    }
+
+    /// Processes a block with the host's transport copied from the process context, or with a
+    /// transport the tests made up.
+    fn process_block(
+        &mut self,
+        buffer: &mut Buffer,
+        aux: &mut AuxiliaryBuffers,
+        transport: &HostTransport,
+        context: &mut impl ProcessContext<Self>,
+    ) -> ProcessStatus {
+        self.set_pending_values();
+
+        // Scripts are compiled on the background thread when the code was edited or another
+        // preset was selected, and take over at the start of the next block once they're built. The
+        // old script's DSP nodes are dropped in the background too, so a new script waits until
+        // there's room to retire them.
+        let script = if self.dsp.can_clear() {
+            self.script_builder.take()
+        } else {
+            None
+        };
+        if let Some(mut script) = script {
+            self.install_script(&mut script);
+            self.script_builder.retire(script);
+            context.execute_background(Task::Script);
+        }
+        // The history of a script that defines `process()` is sized for the oversampling factor,
+        // so the script is built again when that changes
+        let factor = self.params.oversampling.value().factor();
+        let code_version = self.current_code_version();
+        let rebuild = self.engine.info().process && factor != self.engine.factor();
+        if (self.code_version != Some(code_version) || rebuild)
+            && self
+                .engine
+                .request(&self.script_builder, self.sample_rate, factor)
+        {
+            self.code_version = Some(code_version);
+            context.execute_background(Task::Script);
+        }
+        self.sync_oversampling();
+        self.engine.set_profiling(self.telemetry.cpu.profiling());
+
+        let latency = self.latency_samples();
+        if latency != self.latency {
+            self.latency = latency;
+            self.dry_delay.set_latency(latency);
+            context.set_latency_samples(latency);
+        }
+
+        // A new capture length discards the current loop once the background thread built the new
+        // capture buffer. Finished loops are copied over a couple of blocks, and made available to
+        // the script's DSP nodes in the background.
+        let capture_length = self.params.capture_length.value();
+        if self.looper.sync(capture_length, &self.looper_handoff) {
+            context.execute_background(Task::Looper);
+        }
+
+        if self.reload_samples {
+            self.reload_samples = false;
+            context.execute_background(Task::Samples(SampleTask::Reload));
+        }
+
+        let samples_generation = self.samples.generation();
+        if self.samples_generation != Some(samples_generation) {
+            let engine = &mut self.engine;
+            if self
+                .samples
+                .index(|index| engine.set_samples(index))
+                .is_some()
+            {
+                self.dsp.refresh_samples();
+                self.samples_generation = Some(samples_generation);
+            }
+        }
+
+        let voice_capacity = self.params.voices.value() as u32;
+        if voice_capacity != self.voice_capacity {
+            self.voice_capacity = voice_capacity;
+            context.set_current_voice_capacity(voice_capacity);
+        }
+
+        let voice_stealing = self.params.voice_stealing.value();
+        let release_samples = (self.params.release.value() / 1000.0 * self.sample_rate) as u32;
+        let info = self.engine.info();
+        // Scripts that only define `process()` or `spectral()` shouldn't be muted by their missing
+        // `gain()`
+        let default_gain = if (info.process || info.spectral.is_some()) && !info.gain {
+            1.0
+        } else {
+            0.0
+        };
+        let sidechain = aux.inputs.first().map(|b| b.as_slice_immutable());
+
+        // The features are computed on the main input, mixed down to mono
+        let analysis = self.analyzer.as_mut().map(|analyzer| {
+            let inputs = buffer.as_slice_immutable();
+            let num_channels = inputs.len().max(1) as f32;
+            analyzer.process(
+                (0..buffer.samples())
+                    .map(|i| inputs.iter().map(|channel| channel[i]).sum::<f32>() / num_channels),
+            )
+        });
+
+        let block_start = self.time_samples as f64 / self.sample_rate as f64;
+        let block_duration = buffer.samples() as f32 / self.sample_rate;
+        let position = BlockPosition {
+            time: block_start,
+            sample_rate: self.sample_rate,
+            samples: transport.pos_samples.unwrap_or(self.time_samples as i64),
+            beats: transport.pos_beats,
+            tempo: transport.tempo,
+        };
+
+        // New controls start at their defaults, which are set like values from MIDI. The editor
+        // may be drawing the controls, in which case this tries again in the next block.
+        if self.publish_controls {
+            let (params, engine, pending_values) =
+                (&self.params, &self.engine, &self.pending_values);
+            self.publish_controls = !permit_alloc(|| match params.control_layout.try_write() {
+                Ok(mut layout) => {
+                    layout.update(engine.controls(), |slot, normalized| {
+                        if let Some(slot) = ControlSlot::new(slot) {
+                            pending_values.set(MidiTarget::Control(slot), normalized);
+                        }
+                    });
+                    true
+                }
+                Err(_) => false,
+            });
+            if !self.publish_controls {
+                self.set_pending_values();
+            }
+        }
+
+        // A compile error that happened while the editor was closed is logged once it opens
+        let editor_open = self.editor_open();
+        if editor_open && !self.editor_was_open {
+            self.report_compile = true;
+        }
+        self.editor_was_open = editor_open;
+
+        if std::mem::take(&mut self.report_compile) {
+            let error = self.engine.error();
+            self.telemetry
+                .status
+                .publish(&self.engine.info(), error.is_none());
+            if let (Some(err), true) = (error, editor_open) {
+                self.console
+                    .log(LogLevel::Error, err, &position, block_start, 1);
+            }
+        }
+
+        let oversampling = self
+            .oversampler
+            .as_ref()
+            .map(Oversampler::factor)
+            .unwrap_or(1);
+        let control_params = self.params.control_params();
+        self.engine.block(
+            block_start,
+            &BlockInfo {
+                duration: block_duration,
+                sample_rate: self.sample_rate * oversampling as f32,
+                oversampling,
+                playing: transport.playing,
+                tempo: transport.tempo,
+                beats: transport.pos_beats,
+                channels: self.channels,
+                layout: self.layout,
+                sidechain: self.sidechain_channels,
+                analysis,
+                looper: self.looper.status(),
+                controls: controls::values(
+                    self.engine.controls(),
+                    |slot| control_params[slot].value(),
+                    &self.button_presses,
+                ),
+            },
+        );
+
+        // Nodes are created on the background thread. The nodes the script created in an earlier
+        // block may be ready now, and the script keeps new nodes to itself until the builder can
+        // take them.
+        if self.dsp.install(&self.node_builder) {
+            context.execute_background(Task::Nodes);
+        }
+        if let Some(mut update) = self.engine.dsp(self.node_builder.is_idle()) {
+            if let Some(specs) = update.specs.take() {
+                if self.dsp.request(specs, &self.node_builder) {
+                    context.execute_background(Task::Nodes);
+                }
+            }
+            self.dsp.update(update);
+        }
+
+        self.engine.looper(&mut self.looper_commands);
+        for &command in &self.looper_commands {
+            self.looper.command(command);
+        }
+        if let Some(button) = self.looper_remote.take() {
+            self.looper.press(button);
+        }
+
+        // The host's position in quarter notes and how far that moves per sample, for the scope
+        let beats = match (transport.pos_beats, transport.tempo) {
+            (Some(beats), Some(tempo)) if transport.playing => {
+                Some((beats, tempo / 60.0 / self.sample_rate as f64))
+            }
+            _ => None,
+        };
+
+        // The time signature defaults to 4/4, the tempo for the plot to 120 BPM
+        let beats_per_bar = match (transport.time_sig_numerator, transport.time_sig_denominator) {
+            (Some(numerator), Some(denominator)) if numerator > 0 && denominator > 0 => {
+                numerator as f64 * 4.0 / denominator as f64
+            }
+            _ => 4.0,
+        };
+        self.telemetry
+            .live
+            .set_transport(transport.tempo, beats_per_bar);
+
+        // The looper's position in bars and how far that moves per sample, when it's synced to the
+        // host's transport
+        let bars = match (transport.pos_beats, transport.tempo) {
+            (Some(beats), Some(tempo)) if transport.playing && self.params.loop_sync.value() => {
+                Some((
+                    beats / beats_per_bar,
+                    tempo / 60.0 / self.sample_rate as f64 / beats_per_bar,
+                ))
+            }
+            _ => None,
+        };
+
+        // The host is told about the voices that were cut off by a reset
+        self.voices
+            .terminate_reset(|voice| queue_event(&mut self.voice_events, voice.event(0), context));
+        // And so are the voices that don't fit anymore after the voices parameter was lowered
+        self.voices
+            .limit(voice_capacity as usize, voice_stealing, |voice| {
+                queue_event(&mut self.voice_events, voice.event(0), context)
+            });
+
+        let mut next_event = context.next_event();
+        for (sample_id, channel_samples) in buffer.iter_samples().enumerate() {
+            while let Some(event) = next_event {
+                if event.timing() > sample_id as u32 {
+                    break;
+                }
+
+                match event {
+                    NoteEvent::NoteOn {
+                        timing,
+                        voice_id,
+                        channel,
+                        note,
+                        velocity,
+                    } => {
+                        if let Some(stolen) = self.voices.note_on(
+                            voice_id,
+                            channel,
+                            note,
+                            velocity,
+                            voice_capacity as usize,
+                            voice_stealing,
+                        ) {
+                            queue_event(&mut self.voice_events, stolen.event(timing), context);
+                        }
+                    }
+                    NoteEvent::NoteOff {
+                        voice_id,
+                        channel,
+                        note,
+                        ..
+                    } => self.voices.note_off(voice_id, channel, note),
+                    NoteEvent::Choke {
+                        timing,
+                        voice_id,
+                        channel,
+                        note,
+                    } => self.voices.choke(voice_id, channel, note, |voice| {
+                        queue_event(&mut self.voice_events, voice.event(timing), context)
+                    }),
+                    NoteEvent::MidiCC {
+                        channel, cc, value, ..
+                    } => {
+                        if let Some(target) = self.midi_learn.take() {
+                            match self.params.midi_map.try_write() {
+                                Ok(mut midi_map) => midi_map.bind(channel, cc, target),
+                                // We'll try again on the next CC
+                                Err(_) => self.midi_learn.arm(target),
+                            }
+                        }
+
+                        if let Ok(midi_map) = self.params.midi_map.try_read() {
+                            for target in midi_map.targets(channel, cc) {
+                                self.pending_values.set(target, value);
+                            }
+                        }
+                    }
+                    // Program changes 0 through 5 select the six presets
+                    NoteEvent::MidiProgramChange { program, .. } => {
+                        if program < 6 {
+                            let preset = &self.params.preset;
+                            self.pending_values.set(
+                                MidiTarget::Preset,
+                                preset.preview_normalized(program as i32 + 1),
+                            );
+                        }
+                    }
+                    NoteEvent::PolyModulation {
+                        voice_id,
+                        poly_modulation_id: GAIN_POLY_MOD_ID,
+                        normalized_offset,
+                        ..
+                    } => self.voices.modulate_gain(voice_id, normalized_offset),
+                    _ => (),
+                }
+
+                next_event = context.next_event();
+            }
+
+            let time = (self.time_samples + sample_id as u64) as f64 / self.sample_rate as f64;
+
+            let num_samples = channel_samples.len();
+
+            // The looper and the input meter see the plugin's input as it comes in
+            for (input_sample, sample) in self
+                .input_samples
+                .iter_mut()
+                .zip(channel_samples.iter_mut())
+            {
+                *input_sample = *sample;
+            }
+
+            // The dry signal is delayed by the plugin's latency, so it lines up with the processed
+            // signal it's mixed with
+            self.dry_samples[..num_samples].copy_from_slice(&self.input_samples[..num_samples]);
+            self.dry_delay.process(&mut self.dry_samples[..num_samples]);
+            self.looper.process(
+                &self.input_samples[..num_samples],
+                &mut self.looper_outputs[..num_samples],
+                bars.map(|(bar, bars_per_sample)| bar + sample_id as f64 * bars_per_sample),
+            );
+
+            // Smoothing is optionally built into the parameters themselves
+            let gain = self.params.gain.smoothed.next();
+            let mix = self.params.mix.smoothed.next();
+
+            // Spectral processing comes first, everything else sees its delayed output
+            if let Some(stft) = &mut self.stft {
+                let engine = &mut self.engine;
+                let samples = &mut self.stft_samples[..num_samples];
+                for (stft_sample, sample) in samples.iter_mut().zip(channel_samples.iter_mut()) {
+                    *stft_sample = *sample;
+                }
+
+                stft.process(samples, |frames| engine.spectral(time, frames));
+
+                for (sample, stft_sample) in channel_samples.iter_mut().zip(samples.iter()) {
+                    *sample = *stft_sample;
+                }
+            }
+
+            // The native DSP nodes see the main input channels, and the script's `process()`
+            // function sees those followed by the sidechain channels
+            self.process_inputs.clear();
+            self.process_inputs
+                .extend(channel_samples.iter_mut().map(|sample| *sample));
+            if let Some(sidechain) = sidechain {
+                self.process_inputs
+                    .extend(sidechain.iter().map(|channel| channel[sample_id]));
+            }
+
+            // With a declared latency, everything except for the script's lookahead sees the
+            // delayed input
+            self.live_inputs.clear();
+            if let Some(input_delay) = &mut self.input_delay {
+                self.live_inputs.extend_from_slice(&self.process_inputs);
+                input_delay.process(&mut self.process_inputs);
+                for (sample, delayed) in channel_samples.iter_mut().zip(&self.process_inputs) {
+                    *sample = *delayed;
+                }
+            }
+
+            // `process()` records its own history, at its own sample rate
+            if !info.process {
+                self.engine.record_inputs(if self.live_inputs.is_empty() {
+                    &self.process_inputs
+                } else {
+                    &self.live_inputs
+                });
+            }
+            let gain_processed = self.engine.gain(time).unwrap_or(default_gain);
+
+            if !self.dsp.is_empty() {
+                self.dsp.tick(&self.process_inputs);
+            }
+
+            let mut processed = false;
+            if info.process {
+                let outputs = &mut self.process_outputs;
+                processed = match &mut self.oversampler {
+                    Some(oversampler) => {
+                        let engine = &mut self.engine;
+                        let step = 1.0 / (self.sample_rate as f64 * oversampler.factor() as f64);
+                        oversampler.process(
+                            &self.process_inputs,
+                            &self.live_inputs,
+                            outputs,
+                            |i, inputs, live, outputs| {
+                                engine.process(time + i as f64 * step, inputs, live, outputs)
+                            },
+                        )
+                    }
+                    None => {
+                        self.engine
+                            .process(time, &self.process_inputs, &self.live_inputs, outputs)
+                    }
+                };
+            }
+
+            // Voices are rendered by the script and then mixed with the (processed) input
+            let mut voice_output = 0.0;
+            self.done_voices.clear();
+            if info.voice {
+                let num_frames = self.voices.frames(self.sample_rate, &mut self.voice_frames);
+                let frames = &self.voice_frames[..num_frames];
+                if self
+                    .engine
+                    .voices(time, frames, &mut self.voice_outputs, &mut self.done_voices)
+                {
+                    let gain_param = &self.params.gain;
+                    voice_output =
+                        self.voices
+                            .mix(frames, &self.voice_outputs, |offset| match offset {
+                                Some(offset) => gain_param.preview_modulated(offset),
+                                None => gain,
+                            });
+                }
+            }
+
+            self.voices
+                .advance(release_samples, &self.done_voices, |voice| {
+                    queue_event(
+                        &mut self.voice_events,
+                        voice.event(sample_id as u32),
+                        context,
+                    )
+                });
+
+            for (channel, parallel) in self.parallel_samples[..num_samples].iter_mut().enumerate() {
+                *parallel = voice_output + self.dsp.output(channel) + self.looper_outputs[channel];
+            }
+            self.oversampling_delay
+                .process(&mut self.parallel_samples[..num_samples]);
+
+            for (channel, sample) in channel_samples.iter_mut().enumerate() {
+                // A script that returns fewer outputs than there are channels has its outputs
+                // repeated, so returning a single number works for any layout
+                let processed_sample = match (processed, self.process_outputs.len()) {
+                    (false, _) => *sample,
+                    (true, 0) => 0.0,
+                    (true, len) => self.process_outputs[channel % len],
+                };
+
+                let wet = processed_sample * gain_processed + self.parallel_samples[channel];
+                let dry = self.dry_samples[channel];
+                *sample = dry + (wet - dry) * mix;
+                self.output_samples[channel] = *sample;
+            }
+            if !info.process {
+                self.engine
+                    .record_outputs(&self.output_samples[..num_samples]);
+            }
+
+            // To save resources, a plugin can (and probably should!) only perform expensive
+            // calculations that are only displayed on the GUI while the GUI is open
+            if editor_open {
+                self.input_meter.process(&self.input_samples[..num_samples]);
+                self.output_meter
+                    .process(&self.output_samples[..num_samples]);
+
+                let mix = |samples: &[f32]| samples.iter().sum::<f32>() / num_samples.max(1) as f32;
+                let sample_beats = beats
+                    .map(|(beats, beats_per_sample)| beats + sample_id as f64 * beats_per_sample);
+                self.telemetry.scope.push(
+                    mix(&self.input_samples[..num_samples]),
+                    mix(&self.output_samples[..num_samples]),
+                    sample_beats,
+                );
+                self.telemetry.live.publish(
+                    time as f32,
+                    sample_beats,
+                    gain_processed,
+                    mix(&self.input_samples[..num_samples]),
+                    mix(&self.output_samples[..num_samples]),
+                );
+            }
+        }
+
+        if editor_open {
+            self.input_meter.publish(&self.telemetry.meters.input);
+            self.output_meter.publish(&self.telemetry.meters.output);
+        }
+
+        // The script's MIDI is merged with the voice events, so the host gets them in order
+        let block_end =
+            (self.time_samples + buffer.samples() as u64) as f64 / self.sample_rate as f64;
+        self.engine.midi(block_end, &mut self.midi_out);
+        let mut voice_events = self.voice_events.drain(..).peekable();
+        for event in &self.midi_out {
+            let event = event.event(self.time_samples, self.sample_rate, buffer.samples());
+            while let Some(voice_event) = voice_events.next_if(|e| e.timing() <= event.timing()) {
+                context.send_event(voice_event);
+            }
+            context.send_event(event);
+        }
+        voice_events.for_each(|event| context.send_event(event));
+
+        // The script's messages are taken every block, but they're discarded while the editor is
+        // closed
+        self.console.refill(block_duration);
+        let console = &mut self.console;
+        let dropped = self.engine.console(|level, text, t, repeats| {
+            if editor_open {
+                console.log(level, text, &position, t, repeats)
+            }
+        });
+        if editor_open {
+            self.console.dropped(dropped);
+        }
+
+        if editor_open {
+            self.watcher.update(
+                &self.watches,
+                &mut self.engine,
+                &self.telemetry,
+                block_duration,
+            );
+        }
+
+        // Everything the script did during this block counts towards its CPU usage, including
+        // evaluating the watch expressions
+        self.cpu_meter.process(
+            self.engine.take_elapsed(),
+            buffer.samples(),
+            self.sample_rate,
+            &self.telemetry.cpu,
+        );
+        if editor_open && self.telemetry.cpu.profiling() {
+            self.cpu_meter
+                .profile(block_duration, &self.telemetry, &mut self.engine);
+        }
+
+        self.time_samples += buffer.samples() as u64;
+        self.looper_remote.publish(self.looper.status());
+
+        ProcessStatus::Normal
+    }
+}
+
 impl Plugin for Rjv {
     const NAME: &'static str = "Rjv";
     const VENDOR: &'static str = "Kelley van Evert";
@@ -162,38 +1327,69 @@ impl Plugin for Rjv {
 
     // The first audio IO layout is used as the default. The other layouts may be selected either
     // explicitly or automatically by the host or the user depending on the plugin API/backend.
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
-        AudioIOLayout {
-            main_input_channels: NonZeroU32::new(2),
-            main_output_channels: NonZeroU32::new(2),
-            ..AudioIOLayout::const_default()
-        },
-        AudioIOLayout {
-            main_input_channels: NonZeroU32::new(1),
-            main_output_channels: NonZeroU32::new(1),
-            ..AudioIOLayout::const_default()
-        },
-    ];
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = layouts::AUDIO_IO_LAYOUTS;
 
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
+    const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
-    type SysExMessage = ();
-    type BackgroundTask = ();
+    type SysExMessage = MidiRealtime;
+    type BackgroundTask = Task;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
-    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let params = self.params.clone();
+        let samples = self.samples.clone();
+        let node_builder = self.node_builder.clone();
+        let looper_handoff = self.looper_handoff.clone();
+        let script_builder = self.script_builder.clone();
+
+        Box::new(move |task| match task {
+            Task::Samples(task) => samples.run(task, &params.samples),
+            Task::Nodes => node_builder.run(&samples),
+            Task::Looper => looper_handoff.run(&samples),
+            Task::Script => script_builder.run(&params.code().value(), &samples),
+        })
+    }
+
+    fn editor(&mut self, async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         let params = self.params.clone();
-        // let peak_meter = self.peak_meter.clone();
-        let display = self.display.clone();
+        let telemetry = self.telemetry.clone();
+        let code_generation = self.code_generation.clone();
+        let watches = self.watches.clone();
+        let midi_learn = self.midi_learn.clone();
+        let pending_values = self.pending_values.clone();
+        let button_presses = self.button_presses.clone();
+        let samples = self.samples.clone();
+        let looper_remote = self.looper_remote.clone();
 
         create_egui_editor(
             self.params.editor_state.clone(),
             UIState {
                 preset: params.preset.value(),
                 code: params.code().value(),
+                sample_path: String::new(),
+                sample_name: String::new(),
+                embed_sample: false,
+                payload: Vec::new(),
+                console: ConsoleLog::default(),
+                watch_input: String::new(),
+                watch_values: WatchValues::default(),
+                profile: Profile::default(),
+                plot_window: PlotWindow::Bar,
+                plotter: Plotter::default(),
+                scope_ms: 20.0,
+                scope_trigger: ScopeTrigger::Free,
+                scope_input: false,
+                scope_input_samples: Vec::new(),
+                scope_output_samples: Vec::new(),
+                spectrum: SpectrumAnalyzer::default(),
+                spectrum_smoothing_ms: 300.0,
+                spectrum_peak_hold: true,
+                spectrum_input: false,
             },
             |egui_ctx, _| {
                 let mut fonts = FontDefinitions::default();
@@ -238,71 +1434,211 @@ impl Plugin for Rjv {
                 // egui::TextStyle::Name("footing".into())
                 egui_ctx.set_fonts(fonts);
             },
-            move |egui_ctx, _setter, state| {
+            move |egui_ctx, setter, state| {
+                pending_values.take(|target, normalized| {
+                    params.set_normalized(target, normalized, Some(setter))
+                });
+
+                // The preset can also be changed by the host or through MIDI program changes
                 if state.preset != params.preset.value() {
+                    state.preset = params.preset.value();
                     state.code = params.code().value();
                 }
 
+                while let Some(kind) = telemetry.receive(&mut state.payload) {
+                    match kind {
+                        RecordKind::Console => state.console.receive(&state.payload),
+                        RecordKind::Watch => state.watch_values.receive(&state.payload),
+                        RecordKind::Profile => state.profile.receive(&state.payload),
+                    }
+                }
+
+                let frame = egui::containers::Frame {
+                    outer_margin: egui::style::Margin::same(0.),
+                    inner_margin: egui::style::Margin::same(20.),
+                    rounding: egui::Rounding::same(0.),
+                    shadow: Shadow::big_light(),
+                    fill: Color32::WHITE,
+                    stroke: egui::Stroke::new(0., Color32::WHITE),
+                };
+
+                egui::SidePanel::right("spectrum")
+                    .resizable(false)
+                    .default_width(300.0)
+                    .frame(frame)
+                    .show(egui_ctx, |ui| {
+                        ui.heading("Spectrum");
+
+                        ui.horizontal(|ui| {
+                            ui.label("Smoothing");
+                            ui.add(
+                                egui::Slider::new(
+                                    &mut state.spectrum_smoothing_ms,
+                                    0.0..=MAX_SMOOTHING_MS,
+                                )
+                                .suffix(" ms"),
+                            );
+                        });
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut state.spectrum_peak_hold, "Peak hold");
+                            ui.checkbox(&mut state.spectrum_input, "Show input");
+                        });
+
+                        let dt = ui.input().stable_dt.min(0.1);
+                        state.spectrum.update(
+                            &telemetry.scope,
+                            state.spectrum_smoothing_ms,
+                            dt,
+                            state.spectrum_input,
+                        );
+                        spectrum::spectrum(
+                            ui,
+                            &mut state.spectrum,
+                            state.spectrum_input,
+                            state.spectrum_peak_hold,
+                        );
+
+                        ui.add_space(12.0);
+                        ui.heading("Watch");
+                        egui::ScrollArea::vertical().show(ui, |ui| {
+                            watch::watches(
+                                ui,
+                                &watches,
+                                &state.watch_values,
+                                &mut state.watch_input,
+                            );
+                        });
+                    });
+
+                let control_layout = params.control_layout.read().unwrap();
+                if !control_layout.controls().is_empty() {
+                    egui::SidePanel::left("controls")
+                        .resizable(false)
+                        .default_width(200.0)
+                        .frame(frame)
+                        .show(egui_ctx, |ui| {
+                            ui.heading("Controls");
+                            egui::ScrollArea::vertical().show(ui, |ui| {
+                                controls::controls(
+                                    ui,
+                                    control_layout.controls(),
+                                    &params.control_params(),
+                                    setter,
+                                    &button_presses,
+                                    |response, slots| {
+                                        let targets: Vec<MidiTarget> = slots
+                                            .iter()
+                                            .copied()
+                                            .filter_map(ControlSlot::new)
+                                            .map(MidiTarget::Control)
+                                            .collect();
+                                        midi_learnable(
+                                            response,
+                                            &targets,
+                                            &midi_learn,
+                                            &params.midi_map,
+                                        );
+                                    },
+                                );
+                            });
+                        });
+                }
+                drop(control_layout);
+
                 egui::CentralPanel::default()
-                    .frame(egui::containers::Frame {
-                        outer_margin: egui::style::Margin::same(0.),
-                        inner_margin: egui::style::Margin::same(20.),
-                        rounding: egui::Rounding::same(0.),
-                        shadow: Shadow::big_light(),
-                        fill: Color32::WHITE,
-                        stroke: egui::Stroke::new(0., Color32::WHITE),
-                    })
+                    .frame(frame)
                     .show(egui_ctx, |ui| {
                         ui.heading("JS code");
 
                         if code_editor(ui, &mut state.code, ui.available_width()).changed() {
                             params.code().set_value(state.code.clone());
+                            code_generation.fetch_add(1, Ordering::Release);
                         }
+                        telemetry::status(ui, &telemetry.status);
+
+                        ui.horizontal(|ui| {
+                            ui.label("Plot");
+                            for window in PlotWindow::ALL {
+                                ui.selectable_value(&mut state.plot_window, window, window.label());
+                            }
+                        });
+                        state.plotter.update(
+                            &state.code,
+                            state.plot_window,
+                            &telemetry.live,
+                            telemetry.scope.sample_rate(),
+                            &samples,
+                        );
+                        plot::plot(ui, &state.plotter, &telemetry.live);
 
                         ui.horizontal(|ui| {
-                            if ui
-                                .selectable_value(&mut state.preset, 1, "Preset 1")
-                                .clicked()
+                            if midi_learnable(
+                                ui.selectable_value(&mut state.preset, 1, "Preset 1"),
+                                &[MidiTarget::Preset],
+                                &midi_learn,
+                                &params.midi_map,
+                            )
+                            .clicked()
                             {
                                 params.preset.set_value(1);
                                 state.code = params.code().value();
                             }
 
-                            if ui
-                                .selectable_value(&mut state.preset, 2, "Preset 2")
-                                .clicked()
+                            if midi_learnable(
+                                ui.selectable_value(&mut state.preset, 2, "Preset 2"),
+                                &[MidiTarget::Preset],
+                                &midi_learn,
+                                &params.midi_map,
+                            )
+                            .clicked()
                             {
                                 params.preset.set_value(2);
                                 state.code = params.code().value();
                             }
 
-                            if ui
-                                .selectable_value(&mut state.preset, 3, "Preset 3")
-                                .clicked()
+                            if midi_learnable(
+                                ui.selectable_value(&mut state.preset, 3, "Preset 3"),
+                                &[MidiTarget::Preset],
+                                &midi_learn,
+                                &params.midi_map,
+                            )
+                            .clicked()
                             {
                                 params.preset.set_value(3);
                                 state.code = params.code().value();
                             }
 
-                            if ui
-                                .selectable_value(&mut state.preset, 4, "Preset 4")
-                                .clicked()
+                            if midi_learnable(
+                                ui.selectable_value(&mut state.preset, 4, "Preset 4"),
+                                &[MidiTarget::Preset],
+                                &midi_learn,
+                                &params.midi_map,
+                            )
+                            .clicked()
                             {
                                 params.preset.set_value(4);
                                 state.code = params.code().value();
                             }
 
-                            if ui
-                                .selectable_value(&mut state.preset, 5, "Preset 5")
-                                .clicked()
+                            if midi_learnable(
+                                ui.selectable_value(&mut state.preset, 5, "Preset 5"),
+                                &[MidiTarget::Preset],
+                                &midi_learn,
+                                &params.midi_map,
+                            )
+                            .clicked()
                             {
                                 params.preset.set_value(5);
                                 state.code = params.code().value();
                             }
 
-                            if ui
-                                .selectable_value(&mut state.preset, 6, "Preset 6")
-                                .clicked()
+                            if midi_learnable(
+                                ui.selectable_value(&mut state.preset, 6, "Preset 6"),
+                                &[MidiTarget::Preset],
+                                &midi_learn,
+                                &params.midi_map,
+                            )
+                            .clicked()
                             {
                                 params.preset.set_value(6);
                                 state.code = params.code().value();
@@ -310,9 +1646,200 @@ impl Plugin for Rjv {
                         });
 
                         ui.add_space(12.0);
-                        ui.label("Yeeaah...! Let's go and evaluate some JS code :)");
-                        ui.add_space(8.0);
-                        ui.label(display.as_ref().lock().unwrap().clone());
+                        ui.horizontal(|ui| {
+                            ui.label("Gain");
+                            midi_learnable(
+                                ui.add(widgets::ParamSlider::for_param(&params.gain, setter)),
+                                &[MidiTarget::Gain],
+                                &midi_learn,
+                                &params.midi_map,
+                            );
+                            ui.label("Mix");
+                            midi_learnable(
+                                ui.add(widgets::ParamSlider::for_param(&params.mix, setter)),
+                                &[MidiTarget::Mix],
+                                &midi_learn,
+                                &params.midi_map,
+                            );
+                            ui.label("Oversampling");
+                            ui.add(widgets::ParamSlider::for_param(
+                                &params.oversampling,
+                                setter,
+                            ));
+                        });
+
+                        ui.horizontal(|ui| {
+                            ui.label("Voices");
+                            midi_learnable(
+                                ui.add(widgets::ParamSlider::for_param(&params.voices, setter)),
+                                &[MidiTarget::Voices],
+                                &midi_learn,
+                                &params.midi_map,
+                            );
+                            ui.add(widgets::ParamSlider::for_param(
+                                &params.voice_stealing,
+                                setter,
+                            ));
+                            ui.label("Release");
+                            midi_learnable(
+                                ui.add(widgets::ParamSlider::for_param(&params.release, setter)),
+                                &[MidiTarget::Release],
+                                &midi_learn,
+                                &params.midi_map,
+                            );
+                        });
+
+                        ui.horizontal(|ui| {
+                            match midi_learn.armed() {
+                                Some(target) => {
+                                    ui.label(format!(
+                                        "MIDI learn: move a controller for {target:?}"
+                                    ));
+                                    if ui.button("Cancel").clicked() {
+                                        midi_learn.cancel();
+                                    }
+                                }
+                                None => {
+                                    ui.label("Right-click a parameter to MIDI learn it");
+                                }
+                            }
+
+                            if ui.button("Clear MIDI mappings").clicked() {
+                                params.midi_map.write().unwrap().clear();
+                            }
+                        });
+
+                        ui.add_space(12.0);
+                        meters::meter(ui, "Input", &telemetry.meters.input);
+                        meters::meter(ui, "Output", &telemetry.meters.output);
+                        cpu::cpu(ui, &telemetry.cpu, &mut state.profile);
+
+                        ui.add_space(12.0);
+                        ui.horizontal(|ui| {
+                            ui.label("Scope");
+                            ui.add(
+                                egui::Slider::new(&mut state.scope_ms, 1.0..=MAX_WINDOW_MS)
+                                    .logarithmic(true)
+                                    .suffix(" ms"),
+                            );
+                            for trigger in ScopeTrigger::ALL {
+                                ui.selectable_value(
+                                    &mut state.scope_trigger,
+                                    trigger,
+                                    trigger.label(),
+                                );
+                            }
+                            ui.checkbox(&mut state.scope_input, "Show input");
+                        });
+                        telemetry.scope.read(
+                            state.scope_ms,
+                            state.scope_trigger,
+                            &mut state.scope_input_samples,
+                            &mut state.scope_output_samples,
+                        );
+                        scope::scope(
+                            ui,
+                            state
+                                .scope_input
+                                .then_some(state.scope_input_samples.as_slice()),
+                            &state.scope_output_samples,
+                        );
+
+                        ui.add_space(12.0);
+                        ui.horizontal(|ui| {
+                            ui.label("Looper");
+                            for button in LooperButton::ALL {
+                                if ui.button(button.label()).clicked() {
+                                    looper_remote.press(button);
+                                }
+                            }
+                        });
+
+                        ui.horizontal(|ui| {
+                            let status = looper_remote.status();
+                            ui.label(format!(
+                                "{:?}{} {:.2} / {:.2} s at {}x{}",
+                                status.state,
+                                if status.pending {
+                                    " (waiting for the bar)"
+                                } else {
+                                    ""
+                                },
+                                status.position,
+                                status.length,
+                                status.speed,
+                                if status.reverse { ", reversed" } else { "" },
+                            ));
+                            ui.label("Capture");
+                            ui.add(widgets::ParamSlider::for_param(
+                                &params.capture_length,
+                                setter,
+                            ));
+                            ui.label("Sync");
+                            ui.add(widgets::ParamSlider::for_param(&params.loop_sync, setter));
+                        });
+
+                        ui.add_space(12.0);
+                        ui.horizontal(|ui| {
+                            ui.label("Sample");
+                            ui.add(
+                                egui::TextEdit::singleline(&mut state.sample_path)
+                                    .hint_text("Path to a WAV, FLAC or AIFF file")
+                                    .desired_width(320.0),
+                            );
+                            ui.add(
+                                egui::TextEdit::singleline(&mut state.sample_name)
+                                    .hint_text("Name")
+                                    .desired_width(100.0),
+                            );
+                            ui.checkbox(&mut state.embed_sample, "Embed")
+                                .on_hover_text("Store the audio in the plugin state");
+
+                            let path = PathBuf::from(state.sample_path.trim());
+                            if ui.button("Load").clicked() && path.is_file() {
+                                // Samples are named after their file unless a name is given
+                                let name = match state.sample_name.trim() {
+                                    "" => path
+                                        .file_stem()
+                                        .map(|stem| stem.to_string_lossy().into_owned())
+                                        .unwrap_or_default(),
+                                    name => name.to_string(),
+                                };
+
+                                async_executor.execute_background(Task::Samples(
+                                    SampleTask::Load {
+                                        name,
+                                        path,
+                                        embed: state.embed_sample,
+                                    },
+                                ));
+                                state.sample_path.clear();
+                                state.sample_name.clear();
+                            }
+                        });
+
+                        for (name, status) in samples.statuses() {
+                            ui.horizontal(|ui| {
+                                ui.monospace(format!("buffers[{name:?}]"));
+                                ui.label(match status {
+                                    SampleStatus::Loading => "Loading...".to_string(),
+                                    SampleStatus::Loaded(data) => format!(
+                                        "{:.2} s, {} channel(s)",
+                                        data.len() as f32 / data.sample_rate,
+                                        data.channels.len()
+                                    ),
+                                    SampleStatus::Failed(err) => format!("Failed: {err}"),
+                                });
+
+                                if ui.small_button("Remove").clicked() {
+                                    params.samples.write().unwrap().retain(|s| s.name != name);
+                                    samples.remove(&name);
+                                }
+                            });
+                        }
+
+                        ui.add_space(12.0);
+                        console::console(ui, &mut state.console, &telemetry);
                     });
             },
         )
@@ -320,17 +1847,83 @@ impl Plugin for Rjv {
 
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
+        audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
-        // After `PEAK_METER_DECAY_MS` milliseconds of pure silence, the peak meter's value should
-        // have dropped by 12 dB
-        self.peak_meter_decay_weight = 0.25f64
-            .powf((buffer_config.sample_rate as f64 * PEAK_METER_DECAY_MS / 1000.0).recip())
-            as f32;
-
         self.sample_rate = buffer_config.sample_rate;
+        self.telemetry.scope.set_sample_rate(self.sample_rate);
+
+        self.voice_capacity = self.params.voices.value() as u32;
+        context.set_current_voice_capacity(self.voice_capacity);
+
+        self.channels = audio_io_layout
+            .main_output_channels
+            .map(NonZeroU32::get)
+            .unwrap_or(0) as usize;
+        self.layout = audio_io_layout.names.layout;
+        self.sidechain_channels = audio_io_layout
+            .aux_input_ports
+            .first()
+            .map(|channels| channels.get())
+            .unwrap_or(0) as usize;
+        self.process_inputs = Vec::with_capacity(self.channels + self.sidechain_channels);
+        self.live_inputs = Vec::with_capacity(self.channels + self.sidechain_channels);
+
+        // The script's DSP nodes and history buffers depend on the sample rate and the channel
+        // count, so the script starts from scratch
+        self.engine = ScriptEngine::new(ScriptConfig {
+            history_length: (HISTORY_SECONDS * self.sample_rate) as usize,
+            channels: self.channels,
+            sidechain: self.sidechain_channels,
+        });
+        self.dsp = NodePool::new(self.sample_rate, self.channels);
+        self.stft = None;
+        self.analyzer = None;
+        self.input_delay = None;
+        self.oversampler = None;
+        self.spare_oversamplers = [Oversampling::X2, Oversampling::X4, Oversampling::X8]
+            .into_iter()
+            .map(|oversampling| {
+                Oversampler::new(
+                    oversampling.factor(),
+                    self.channels + self.sidechain_channels,
+                    self.channels,
+                )
+            })
+            .collect();
+        self.looper = Looper::new(
+            self.params.capture_length.value(),
+            self.sample_rate,
+            self.channels,
+        );
+        self.looper_handoff.prepare(&self.looper);
+        self.input_meter = Meter::new(self.sample_rate, self.channels);
+        self.output_meter = Meter::new(self.sample_rate, self.channels);
+        self.oversampling_delay = Compensation::new(oversampling::LATENCY as usize, self.channels);
+
+        // The script is compiled here so its latency can be reported right away. Code changes
+        // are compiled on the background thread, and picked up in `process()`.
+        self.script_builder.prepare();
+        self.code_version = Some(self.current_code_version());
+        let mut script = self.engine.build(
+            &self.params.code().value(),
+            self.sample_rate,
+            self.params.oversampling.value().factor(),
+        );
+        self.install_script(&mut script);
+        self.sync_oversampling();
+        self.latency = self.latency_samples();
+        self.dry_delay =
+            Compensation::new((HISTORY_SECONDS * self.sample_rate) as usize, self.channels);
+        self.dry_delay.set_latency(self.latency);
+        context.set_latency_samples(self.latency);
+
+        // This is also called after the plugin state has been restored, so this loads the samples
+        // from the state. That can take a while, so it happens on the background thread.
+        self.samples.set_sample_rate(self.sample_rate);
+        self.samples_generation = None;
+        self.reload_samples = true;
 
         // Resize buffers and perform other potentially expensive initialization operations here.
         // The `reset()` function is always called right after this function. You can remove this
@@ -341,60 +1934,36 @@ impl Plugin for Rjv {
     fn reset(&mut self) {
         // Reset buffers and envelopes here. This can be called from the audio thread and may not
         // allocate. You can remove this function if you do not need it.
+        self.voices.reset();
+        self.engine.clear_history();
+        self.dsp.reset();
+        if let Some(stft) = &mut self.stft {
+            stft.reset();
+        }
+        if let Some(analyzer) = &mut self.analyzer {
+            analyzer.reset();
+        }
+        if let Some(input_delay) = &mut self.input_delay {
+            input_delay.reset();
+        }
+        if let Some(oversampler) = &mut self.oversampler {
+            oversampler.reset();
+        }
+        self.looper.reset();
+        self.dry_delay.reset();
+        self.oversampling_delay.reset();
+        self.input_meter.reset();
+        self.output_meter.reset();
     }
 
     fn process(
         &mut self,
         buffer: &mut Buffer, // 1s
-        _aux: &mut AuxiliaryBuffers,
-        _context: &mut impl ProcessContext<Self>,
+        aux: &mut AuxiliaryBuffers,
+        context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        *self.display.lock().unwrap() = format!("code <{}>", self.params.code().value());
-
-        let js_code = format!(
-            "function gain(t) {{ return {}; }}",
-            self.params.code().value()
-        );
-        // let js_code = "function bla([t, g]) { return Math.sin(t) * g; }";
-        let mut script = Script::from_string(&js_code).ok();
-
-        for (sample_id, channel_samples) in buffer.iter_samples().enumerate() {
-            let time = self.time_s + ((sample_id as f32) / self.sample_rate);
-
-            let mut amplitude = 0.0;
-            let num_samples = channel_samples.len();
-
-            // Smoothing is optionally built into the parameters themselves
-            // let gain = self.params.gain.smoothed.next();
-            let gain_processed: Option<f32> =
-                script.as_mut().and_then(|s| s.call("gain", &time).ok());
-            // let gain_processed: f32 = script.call("gain", &time).expect("JS runs");
-
-            for sample in channel_samples {
-                *sample *= gain_processed.unwrap_or(0.0);
-                amplitude += *sample;
-            }
-
-            // To save resources, a plugin can (and probably should!) only perform expensive
-            // calculations that are only displayed on the GUI while the GUI is open
-            if self.params.editor_state.is_open() {
-                amplitude = (amplitude / num_samples as f32).abs();
-                let current_peak_meter = self.peak_meter.load(std::sync::atomic::Ordering::Relaxed);
-                let new_peak_meter = if amplitude > current_peak_meter {
-                    amplitude
-                } else {
-                    current_peak_meter * self.peak_meter_decay_weight
-                        + amplitude * (1.0 - self.peak_meter_decay_weight)
-                };
-
-                self.peak_meter
-                    .store(new_peak_meter, std::sync::atomic::Ordering::Relaxed)
-            }
-        }
-
-        self.time_s += (buffer.samples() as f32) / self.sample_rate;
-
-        ProcessStatus::Normal
+        let transport = HostTransport::new(context.transport());
+        self.process_block(buffer, aux, &transport, context)
     }
 }
 
@@ -403,19 +1972,78 @@ impl ClapPlugin for Rjv {
     const CLAP_DESCRIPTION: Option<&'static str> = Some("Rust, JS, VST");
     const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
     const CLAP_SUPPORT_URL: Option<&'static str> = None;
+    const CLAP_POLY_MODULATION_CONFIG: Option<PolyModulationConfig> = Some(PolyModulationConfig {
+        max_voice_capacity: MAX_VOICES as u32,
+        supports_overlapping_voices: true,
+    });
     const CLAP_FEATURES: &'static [ClapFeature] = &[
         ClapFeature::AudioEffect,
         ClapFeature::Stereo,
         ClapFeature::Mono,
+        ClapFeature::Surround,
         ClapFeature::Utility,
     ];
 }
 
 impl Vst3Plugin for Rjv {
     const VST3_CLASS_ID: [u8; 16] = *b"rjv_klve_1234567";
-    const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] =
-        &[Vst3SubCategory::Fx, Vst3SubCategory::Tools];
+    const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] = &[
+        Vst3SubCategory::Fx,
+        Vst3SubCategory::Tools,
+        Vst3SubCategory::Surround,
+    ];
 }
 
 nih_export_clap!(Rjv);
 nih_export_vst3!(Rjv);
+
+/// Right-clicking a parameter's widget arms MIDI learn for that parameter. Right-clicking it again
+/// cancels it. Widgets that control more than one parameter, like XY pads, arm their `targets` one
+/// after the other.
+fn midi_learnable(
+    response: egui::Response,
+    targets: &[MidiTarget],
+    midi_learn: &MidiLearn,
+    midi_map: &RwLock<MidiMap>,
+) -> egui::Response {
+    let armed = midi_learn
+        .armed()
+        .and_then(|armed| targets.iter().position(|target| *target == armed));
+    if response.secondary_clicked() {
+        match armed.map(|idx| idx + 1) {
+            Some(next) if next == targets.len() => midi_learn.cancel(),
+            Some(next) => midi_learn.arm(targets[next]),
+            None => midi_learn.arm(targets[0]),
+        }
+    }
+
+    let midi_map = midi_map.read().unwrap();
+    let hover_text = targets
+        .iter()
+        .enumerate()
+        .map(|(idx, target)| {
+            let line = if armed == Some(idx) && idx + 1 < targets.len() {
+                "Waiting for a MIDI CC, right-click to skip".to_string()
+            } else if armed == Some(idx) {
+                "Waiting for a MIDI CC, right-click to cancel".to_string()
+            } else {
+                match midi_map.mapping(*target) {
+                    Some(mapping) => format!(
+                        "MIDI CC {} on channel {}, right-click to learn again",
+                        mapping.cc,
+                        mapping.channel + 1
+                    ),
+                    None => "Right-click to MIDI learn".to_string(),
+                }
+            };
+
+            match targets.len() {
+                1 => line,
+                _ => format!("{target:?}: {line}"),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    response.on_hover_text(hover_text)
+}
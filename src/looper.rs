@@ -0,0 +1,770 @@
+//! A rolling capture buffer of the plugin's main input, and a looper on top of it. Scripts control
+//! the looper through the `looper` object and the editor through its buttons. While the host is
+//! playing and loop sync is enabled, recording and playback start and stop on the next bar line.
+
+use atomic_float::AtomicF32;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::samples::{SampleBank, SampleData, SampleStatus};
+
+/// The longest capture buffer, in seconds. The loop can be as long as the capture buffer.
+pub const MAX_CAPTURE_SECONDS: i32 = 300;
+
+/// The name the loop is available under in `buffers`, for players and granular nodes.
+pub const LOOP_BUFFER: &str = "loop";
+
+/// How many samples per channel of a finished loop are copied for the background thread per block.
+const EXPORT_CHUNK: usize = 1 << 14;
+
+const IDLE: u8 = 0;
+const REQUESTED: u8 = 1;
+const BUILT: u8 = 2;
+/// The audio thread swapped in the built looper, and the old one waits to be dropped.
+const RETIRED: u8 = 3;
+
+/// The export buffer belongs to the audio thread while it's idle, and to the background thread
+/// while it's ready.
+const EXPORT_READY: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LooperState {
+    #[default]
+    Empty,
+    Recording,
+    Playing,
+    Overdubbing,
+    Stopped,
+}
+
+impl LooperState {
+    const ALL: [LooperState; 5] = [
+        LooperState::Empty,
+        LooperState::Recording,
+        LooperState::Playing,
+        LooperState::Overdubbing,
+        LooperState::Stopped,
+    ];
+}
+
+/// A command sent by the script's `looper` object.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LooperCommand {
+    /// Starts recording a new loop, or finishes the recording and plays it.
+    Record,
+    /// Toggles recording on top of the loop while it plays.
+    Overdub,
+    /// Plays the loop from the start.
+    Play,
+    Stop,
+    Clear,
+    Reverse {
+        reverse: bool,
+    },
+    Speed {
+        speed: f32,
+    },
+    Level {
+        level: f32,
+    },
+    /// Turns the last `seconds` of the capture buffer into the loop, and plays it.
+    Capture {
+        seconds: f32,
+    },
+}
+
+impl LooperCommand {
+    /// Decodes a command the script wrote as its type and its argument. The types are numbered in
+    /// the order of the variants.
+    pub fn decode(command: f64, arg: f64) -> Option<Self> {
+        Some(match command as u8 {
+            0 => LooperCommand::Record,
+            1 => LooperCommand::Overdub,
+            2 => LooperCommand::Play,
+            3 => LooperCommand::Stop,
+            4 => LooperCommand::Clear,
+            5 => LooperCommand::Reverse {
+                reverse: arg != 0.0,
+            },
+            6 => LooperCommand::Speed { speed: arg as f32 },
+            7 => LooperCommand::Level { level: arg as f32 },
+            8 => LooperCommand::Capture {
+                seconds: arg as f32,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Whether the command waits for the next bar line when the looper is synced.
+    fn is_quantized(&self) -> bool {
+        matches!(
+            self,
+            LooperCommand::Record
+                | LooperCommand::Overdub
+                | LooperCommand::Play
+                | LooperCommand::Stop
+                | LooperCommand::Capture { .. }
+        )
+    }
+}
+
+/// The editor's looper buttons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LooperButton {
+    Record,
+    Overdub,
+    Play,
+    Stop,
+    Clear,
+    Reverse,
+    HalfSpeed,
+    NormalSpeed,
+    DoubleSpeed,
+}
+
+impl LooperButton {
+    pub const ALL: [LooperButton; 9] = [
+        LooperButton::Record,
+        LooperButton::Overdub,
+        LooperButton::Play,
+        LooperButton::Stop,
+        LooperButton::Clear,
+        LooperButton::Reverse,
+        LooperButton::HalfSpeed,
+        LooperButton::NormalSpeed,
+        LooperButton::DoubleSpeed,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            LooperButton::Record => "Record",
+            LooperButton::Overdub => "Overdub",
+            LooperButton::Play => "Play",
+            LooperButton::Stop => "Stop",
+            LooperButton::Clear => "Clear",
+            LooperButton::Reverse => "Reverse",
+            LooperButton::HalfSpeed => "0.5x",
+            LooperButton::NormalSpeed => "1x",
+            LooperButton::DoubleSpeed => "2x",
+        }
+    }
+}
+
+/// The looper's state, as passed to the script and shown in the editor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LooperStatus {
+    pub state: LooperState,
+    /// The playback position and the loop's length in seconds.
+    pub position: f32,
+    pub length: f32,
+    pub speed: f32,
+    pub reverse: bool,
+    pub level: f32,
+    /// Whether a command is waiting for the next bar line.
+    pub pending: bool,
+}
+
+/// Shared between the editor and the audio thread. The editor presses buttons, and the audio
+/// thread publishes the looper's status once per block.
+#[derive(Default)]
+pub struct LooperRemote {
+    /// The index of the pressed button in [`LooperButton::ALL`] plus one, or zero when no button
+    /// was pressed since the last block.
+    pressed: AtomicU8,
+    state: AtomicU8,
+    position: AtomicF32,
+    length: AtomicF32,
+    speed: AtomicF32,
+    reverse: AtomicBool,
+    level: AtomicF32,
+    pending: AtomicBool,
+}
+
+impl LooperRemote {
+    pub fn press(&self, button: LooperButton) {
+        let idx = LooperButton::ALL.iter().position(|b| *b == button).unwrap();
+        self.pressed.store(idx as u8 + 1, Ordering::Relaxed);
+    }
+
+    /// Returns the button that was pressed since the last call, if any.
+    pub fn take(&self) -> Option<LooperButton> {
+        (self.pressed.swap(0, Ordering::Relaxed) as usize)
+            .checked_sub(1)
+            .and_then(|idx| LooperButton::ALL.get(idx).copied())
+    }
+
+    pub fn publish(&self, status: LooperStatus) {
+        let state = LooperState::ALL
+            .iter()
+            .position(|s| *s == status.state)
+            .unwrap();
+        self.state.store(state as u8, Ordering::Relaxed);
+        self.position.store(status.position, Ordering::Relaxed);
+        self.length.store(status.length, Ordering::Relaxed);
+        self.speed.store(status.speed, Ordering::Relaxed);
+        self.reverse.store(status.reverse, Ordering::Relaxed);
+        self.level.store(status.level, Ordering::Relaxed);
+        self.pending.store(status.pending, Ordering::Relaxed);
+    }
+
+    pub fn status(&self) -> LooperStatus {
+        LooperStatus {
+            state: LooperState::ALL
+                .get(self.state.load(Ordering::Relaxed) as usize)
+                .copied()
+                .unwrap_or_default(),
+            position: self.position.load(Ordering::Relaxed),
+            length: self.length.load(Ordering::Relaxed),
+            speed: self.speed.load(Ordering::Relaxed),
+            reverse: self.reverse.load(Ordering::Relaxed),
+            level: self.level.load(Ordering::Relaxed),
+            pending: self.pending.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct Looper {
+    sample_rate: f32,
+    capture_seconds: i32,
+    /// A ring buffer per channel with the most recent input.
+    capture: Vec<Vec<f32>>,
+    capture_pos: usize,
+    /// The loop per channel, with room for as many samples as the capture buffer. Only the first
+    /// `len` samples are part of the loop.
+    buffer: Vec<Vec<f32>>,
+    len: usize,
+    /// The playback position in samples.
+    position: f64,
+    state: LooperState,
+    speed: f32,
+    reverse: bool,
+    level: f32,
+    /// A command that's waiting for the next bar line.
+    pending: Option<LooperCommand>,
+    last_bar: Option<i64>,
+    /// Set when a recording finished or the loop was cleared, so the loop can be made available
+    /// to the script's DSP nodes.
+    changed: bool,
+    /// How far the loop has been copied to the [`LooperHandoff`], and how long it was when the copy
+    /// started. `None` when there's nothing to copy.
+    export: Option<(usize, usize)>,
+}
+
+impl Default for Looper {
+    fn default() -> Self {
+        Self::new(0, 1.0, 0)
+    }
+}
+
+impl Looper {
+    /// Allocates the capture buffer and the loop. This can take a lot of memory for long capture
+    /// buffers.
+    pub fn new(capture_seconds: i32, sample_rate: f32, num_channels: usize) -> Self {
+        let capture_seconds = capture_seconds.clamp(0, MAX_CAPTURE_SECONDS);
+        let len = (capture_seconds as f32 * sample_rate) as usize;
+
+        Self {
+            sample_rate,
+            capture_seconds,
+            capture: vec![vec![0.0; len]; num_channels],
+            capture_pos: 0,
+            buffer: vec![vec![0.0; len]; num_channels],
+            len: 0,
+            position: 0.0,
+            state: LooperState::Empty,
+            speed: 1.0,
+            reverse: false,
+            level: 1.0,
+            pending: None,
+            last_bar: None,
+            changed: false,
+            export: None,
+        }
+    }
+
+    pub fn capture_seconds(&self) -> i32 {
+        self.capture_seconds
+    }
+
+    fn max_len(&self) -> usize {
+        self.buffer.first().map(Vec::len).unwrap_or(0)
+    }
+
+    pub fn status(&self) -> LooperStatus {
+        LooperStatus {
+            state: self.state,
+            position: self.position as f32 / self.sample_rate,
+            length: self.len as f32 / self.sample_rate,
+            speed: self.speed,
+            reverse: self.reverse,
+            level: self.level,
+            pending: self.pending.is_some(),
+        }
+    }
+
+    /// Handles a command. Starting and stopping waits for the next bar line when the looper is
+    /// synced to the host's transport.
+    pub fn command(&mut self, command: LooperCommand) {
+        if command.is_quantized() {
+            self.pending = Some(command);
+        } else {
+            self.apply(command);
+        }
+    }
+
+    pub fn press(&mut self, button: LooperButton) {
+        self.command(match button {
+            LooperButton::Record => LooperCommand::Record,
+            LooperButton::Overdub => LooperCommand::Overdub,
+            LooperButton::Play => LooperCommand::Play,
+            LooperButton::Stop => LooperCommand::Stop,
+            LooperButton::Clear => LooperCommand::Clear,
+            LooperButton::Reverse => LooperCommand::Reverse {
+                reverse: !self.reverse,
+            },
+            LooperButton::HalfSpeed => LooperCommand::Speed { speed: 0.5 },
+            LooperButton::NormalSpeed => LooperCommand::Speed { speed: 1.0 },
+            LooperButton::DoubleSpeed => LooperCommand::Speed { speed: 2.0 },
+        });
+    }
+
+    fn apply(&mut self, command: LooperCommand) {
+        match command {
+            LooperCommand::Record => match self.state {
+                LooperState::Recording => self.finish(LooperState::Playing),
+                _ => self.start_recording(),
+            },
+            LooperCommand::Overdub => match self.state {
+                LooperState::Recording => self.finish(LooperState::Overdubbing),
+                LooperState::Overdubbing => self.finish(LooperState::Playing),
+                _ if self.len == 0 => self.start_recording(),
+                _ => self.state = LooperState::Overdubbing,
+            },
+            LooperCommand::Play => match self.state {
+                LooperState::Recording | LooperState::Overdubbing => {
+                    self.finish(LooperState::Playing)
+                }
+                _ if self.len > 0 => {
+                    self.position = self.start_position();
+                    self.state = LooperState::Playing;
+                }
+                _ => (),
+            },
+            LooperCommand::Stop => match self.state {
+                LooperState::Recording | LooperState::Overdubbing => {
+                    self.finish(LooperState::Stopped)
+                }
+                _ if self.len > 0 => self.state = LooperState::Stopped,
+                _ => (),
+            },
+            LooperCommand::Clear => {
+                self.len = 0;
+                self.position = 0.0;
+                self.state = LooperState::Empty;
+                self.pending = None;
+                self.changed = true;
+            }
+            LooperCommand::Reverse { reverse } => self.reverse = reverse,
+            LooperCommand::Speed { speed } => self.speed = speed.clamp(0.0, 8.0),
+            LooperCommand::Level { level } => self.level = level.max(0.0),
+            LooperCommand::Capture { seconds } => {
+                let capture_len = self.capture.first().map(Vec::len).unwrap_or(0);
+                let len = ((seconds.max(0.0) * self.sample_rate) as usize).min(capture_len);
+                for (capture, buffer) in self.capture.iter().zip(&mut self.buffer) {
+                    // The oldest of the last `len` samples
+                    let start = (self.capture_pos + capture_len - len) % capture_len.max(1);
+                    for (i, sample) in buffer[..len].iter_mut().enumerate() {
+                        *sample = capture[(start + i) % capture_len];
+                    }
+                }
+
+                self.len = len;
+                if len > 0 {
+                    self.position = self.start_position();
+                    self.state = LooperState::Playing;
+                } else {
+                    self.state = LooperState::Empty;
+                }
+                self.changed = true;
+            }
+        }
+    }
+
+    fn start_recording(&mut self) {
+        self.len = 0;
+        self.position = 0.0;
+        self.state = if self.max_len() > 0 {
+            LooperState::Recording
+        } else {
+            LooperState::Empty
+        };
+    }
+
+    /// Finishes recording or overdubbing.
+    fn finish(&mut self, state: LooperState) {
+        if self.state == LooperState::Recording {
+            self.position = self.start_position();
+        }
+
+        self.state = if self.len > 0 {
+            state
+        } else {
+            LooperState::Empty
+        };
+        if self.state != LooperState::Overdubbing {
+            self.changed = true;
+        }
+    }
+
+    /// Reversed loops start at their end.
+    fn start_position(&self) -> f64 {
+        if self.reverse && self.len > 0 {
+            (self.len - 1) as f64
+        } else {
+            0.0
+        }
+    }
+
+    /// Called once per block. Swaps in a looper with a new capture length once the background
+    /// thread built it, requests one when `capture_seconds` changed, and copies part of a finished
+    /// loop to the handoff. Returns whether [`LooperHandoff::run()`] should be called on the
+    /// background thread. This never blocks or allocates.
+    pub fn sync(&mut self, capture_seconds: i32, handoff: &LooperHandoff) -> bool {
+        let mut run = false;
+
+        if handoff.state.load(Ordering::Acquire) == BUILT {
+            if let Ok(mut built) = handoff.built.try_lock() {
+                if let Some(looper) = built.as_mut() {
+                    // This discards the current loop, and the old looper is dropped in the background
+                    std::mem::swap(self, looper);
+                    self.changed = true;
+                }
+                handoff.state.store(RETIRED, Ordering::Release);
+                run = true;
+            }
+        }
+
+        let capture_seconds = capture_seconds.clamp(0, MAX_CAPTURE_SECONDS);
+        if capture_seconds != self.capture_seconds && handoff.state.load(Ordering::Acquire) == IDLE
+        {
+            if let Ok(mut request) = handoff.request.try_lock() {
+                request.capture_seconds = capture_seconds;
+                request.sample_rate = self.sample_rate;
+                request.num_channels = self.buffer.len();
+                handoff.state.store(REQUESTED, Ordering::Release);
+                run = true;
+            }
+        }
+
+        if std::mem::take(&mut self.changed) {
+            self.export = Some((0, self.len));
+        }
+        if let Some((pos, len)) = self.export {
+            if handoff.export_state.load(Ordering::Acquire) != EXPORT_READY {
+                if let Ok(mut export) = handoff.export.try_lock() {
+                    // The export buffer shrinks when a looper with a shorter capture length is built
+                    let len = len.min(export.capacity);
+                    let (pos, end) = (pos.min(len), (pos + EXPORT_CHUNK).min(len));
+                    for (buffer, channel) in self.buffer.iter().zip(&mut export.channels) {
+                        channel[pos..end].copy_from_slice(&buffer[pos..end]);
+                    }
+
+                    if end == len {
+                        export.len = len;
+                        export.sample_rate = self.sample_rate;
+                        handoff.export_state.store(EXPORT_READY, Ordering::Release);
+                        self.export = None;
+                        run = true;
+                    } else {
+                        self.export = Some((end, len));
+                    }
+                }
+            }
+        }
+
+        run
+    }
+
+    /// Records and plays a single sample for every channel. `bar` is the host's position in bars
+    /// when the looper is synced to the transport, and `None` otherwise.
+    pub fn process(&mut self, inputs: &[f32], outputs: &mut [f32], bar: Option<f64>) {
+        let capture_len = self.capture.first().map(Vec::len).unwrap_or(0);
+        if capture_len > 0 {
+            for (channel, capture) in self.capture.iter_mut().enumerate() {
+                capture[self.capture_pos] = inputs.get(channel).copied().unwrap_or(0.0);
+            }
+            self.capture_pos = (self.capture_pos + 1) % capture_len;
+        }
+
+        let bar_idx = bar.map(|bar| bar.floor() as i64);
+        let on_bar = match (bar, bar_idx, self.last_bar) {
+            (None, _, _) => true,
+            (Some(_), Some(idx), Some(last)) => idx != last,
+            (Some(bar), _, None) => bar.fract() == 0.0,
+            _ => false,
+        };
+        self.last_bar = bar_idx;
+        if on_bar {
+            if let Some(command) = self.pending.take() {
+                self.apply(command);
+            }
+        }
+
+        outputs.fill(0.0);
+        match self.state {
+            LooperState::Recording => {
+                if self.len < self.max_len() {
+                    for (channel, buffer) in self.buffer.iter_mut().enumerate() {
+                        buffer[self.len] = inputs.get(channel).copied().unwrap_or(0.0);
+                    }
+                    self.len += 1;
+                } else {
+                    self.finish(LooperState::Playing);
+                }
+            }
+            LooperState::Playing | LooperState::Overdubbing if self.len > 0 => {
+                let len = self.len;
+                let idx = (self.position as usize).min(len - 1);
+                let frac = (self.position - idx as f64) as f32;
+                for (channel, (buffer, output)) in
+                    self.buffer.iter_mut().zip(outputs.iter_mut()).enumerate()
+                {
+                    let a = buffer[idx];
+                    let b = buffer[(idx + 1) % len];
+                    *output = (a + (b - a) * frac) * self.level;
+
+                    if self.state == LooperState::Overdubbing {
+                        buffer[idx] += inputs.get(channel).copied().unwrap_or(0.0);
+                    }
+                }
+
+                let step = if self.reverse {
+                    -self.speed
+                } else {
+                    self.speed
+                };
+                self.position = (self.position + step as f64).rem_euclid(len as f64);
+            }
+            _ => (),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for buffer in &mut self.capture {
+            buffer.fill(0.0);
+        }
+        self.pending = None;
+        self.last_bar = None;
+    }
+}
+
+/// Builds loopers with a new capture length and drops the old ones on the background thread, and
+/// turns finished loops into samples for the script's DSP nodes.
+pub struct LooperHandoff {
+    state: AtomicU8,
+    request: Mutex<LooperRequest>,
+    /// The looper built for the last request, and after the swap the looper it replaced.
+    built: Mutex<Option<Looper>>,
+    export_state: AtomicU8,
+    export: Mutex<LoopExport>,
+}
+
+struct LooperRequest {
+    capture_seconds: i32,
+    sample_rate: f32,
+    num_channels: usize,
+}
+
+/// A copy of the finished loop, with room for the longest loop the current looper can record.
+struct LoopExport {
+    sample_rate: f32,
+    channels: Vec<Vec<f32>>,
+    capacity: usize,
+    len: usize,
+}
+
+impl Default for LooperHandoff {
+    fn default() -> Self {
+        Self {
+            state: AtomicU8::new(IDLE),
+            request: Mutex::new(LooperRequest {
+                capture_seconds: 0,
+                sample_rate: 1.0,
+                num_channels: 0,
+            }),
+            built: Mutex::new(None),
+            export_state: AtomicU8::new(IDLE),
+            export: Mutex::new(LoopExport {
+                sample_rate: 1.0,
+                channels: Vec::new(),
+                capacity: 0,
+                len: 0,
+            }),
+        }
+    }
+}
+
+impl LooperHandoff {
+    /// Discards any unfinished work, and makes room for `looper`'s loops. This allocates, so it's
+    /// called when the plugin is initialized.
+    pub fn prepare(&self, looper: &Looper) {
+        *self.built.lock().unwrap() = None;
+        self.state.store(IDLE, Ordering::Release);
+        self.export
+            .lock()
+            .unwrap()
+            .resize(looper.buffer.len(), looper.max_len());
+        self.export_state.store(IDLE, Ordering::Release);
+    }
+
+    /// Publishes the finished loop, drops the looper that was replaced, and builds the requested
+    /// looper. This blocks, so it should only be called from the background thread.
+    pub fn run(&self, samples: &SampleBank) {
+        if self.export_state.load(Ordering::Acquire) == EXPORT_READY {
+            let mut export = self.export.lock().unwrap();
+            let data = SampleData {
+                sample_rate: export.sample_rate,
+                channels: export
+                    .channels
+                    .iter()
+                    .map(|channel| channel[..export.len].to_vec())
+                    .collect(),
+            };
+            export.len = 0;
+            self.export_state.store(IDLE, Ordering::Release);
+            drop(export);
+
+            samples.set(LOOP_BUFFER, SampleStatus::Loaded(Arc::new(data)));
+        }
+
+        let mut built = self.built.lock().unwrap();
+        match self.state.load(Ordering::Acquire) {
+            RETIRED => {
+                *built = None;
+                self.state.store(IDLE, Ordering::Release);
+            }
+            REQUESTED => {
+                let request = self.request.lock().unwrap();
+                let looper = Looper::new(
+                    request.capture_seconds,
+                    request.sample_rate,
+                    request.num_channels,
+                );
+                self.export
+                    .lock()
+                    .unwrap()
+                    .resize(looper.buffer.len(), looper.max_len());
+                *built = Some(looper);
+                self.state.store(BUILT, Ordering::Release);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl LoopExport {
+    fn resize(&mut self, num_channels: usize, capacity: usize) {
+        self.channels = vec![vec![0.0; capacity]; num_channels];
+        self.capacity = capacity;
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A low sample rate keeps the buffers small.
+    const SAMPLE_RATE: f32 = 8.0;
+
+    fn run(looper: &mut Looper, input: f32, samples: usize, bar: impl Fn(usize) -> Option<f64>) {
+        let mut outputs = [0.0];
+        for i in 0..samples {
+            looper.process(&[input], &mut outputs, bar(i));
+        }
+    }
+
+    #[test]
+    fn commands_wait_for_the_next_bar() {
+        let mut looper = Looper::new(4, SAMPLE_RATE, 1);
+        // Two bars per second, starting halfway through a bar
+        let bar = |i: usize| Some(0.5 + i as f64 * 2.0 / SAMPLE_RATE as f64);
+
+        looper.command(LooperCommand::Record);
+        run(&mut looper, 1.0, 2, bar);
+        assert_eq!(looper.status().state, LooperState::Empty);
+        assert!(looper.status().pending);
+
+        // The third sample is the first one of the next bar
+        let mut outputs = [0.0];
+        looper.process(&[1.0], &mut outputs, bar(2));
+        assert_eq!(looper.status().state, LooperState::Recording);
+        assert!(!looper.status().pending);
+
+        // Finishing the recording waits for the bar after that, so the loop is one bar long
+        looper.command(LooperCommand::Record);
+        run(&mut looper, 1.0, 3, |i| bar(i + 3));
+        assert_eq!(looper.status().state, LooperState::Recording);
+        looper.process(&[1.0], &mut outputs, bar(6));
+        assert_eq!(looper.status().state, LooperState::Playing);
+        assert_eq!(looper.status().length, 4.0 / SAMPLE_RATE);
+
+        // Without sync, commands apply on the next sample
+        looper.command(LooperCommand::Stop);
+        looper.process(&[1.0], &mut outputs, None);
+        assert_eq!(looper.status().state, LooperState::Stopped);
+    }
+
+    #[test]
+    fn overdubs_add_to_the_loop() {
+        let mut looper = Looper::new(4, SAMPLE_RATE, 1);
+        looper.command(LooperCommand::Record);
+        run(&mut looper, 1.0, 4, |_| None);
+        looper.command(LooperCommand::Overdub);
+        // The command is applied before the sample is played
+        run(&mut looper, 0.5, 4, |_| None);
+        assert_eq!(looper.status().state, LooperState::Overdubbing);
+        looper.command(LooperCommand::Overdub);
+
+        let mut outputs = [0.0];
+        for _ in 0..8 {
+            looper.process(&[0.25], &mut outputs, None);
+            assert_eq!(looper.status().state, LooperState::Playing);
+            assert_eq!(outputs[0], 1.5);
+        }
+    }
+
+    #[test]
+    fn handoff_swaps_loopers_and_publishes_loops() {
+        let handoff = LooperHandoff::default();
+        let samples = SampleBank::default();
+        let mut looper = Looper::new(1, SAMPLE_RATE, 2);
+        handoff.prepare(&looper);
+
+        // The loop is copied in chunks, and published once it's complete
+        looper.command(LooperCommand::Record);
+        let mut outputs = [0.0; 2];
+        for _ in 0..5 {
+            looper.process(&[1.0, -1.0], &mut outputs, None);
+        }
+        looper.command(LooperCommand::Record);
+        looper.process(&[0.0, 0.0], &mut outputs, None);
+        assert!(looper.sync(1, &handoff));
+        handoff.run(&samples);
+        let sample = samples.get(LOOP_BUFFER).unwrap();
+        assert_eq!(sample.channels, vec![vec![1.0; 5], vec![-1.0; 5]]);
+        assert!(!looper.sync(1, &handoff));
+
+        // A new capture length is built in the background, and the loop is discarded after the swap
+        assert!(looper.sync(2, &handoff));
+        assert_eq!(looper.capture_seconds(), 1);
+        handoff.run(&samples);
+        assert!(looper.sync(2, &handoff));
+        assert_eq!(looper.capture_seconds(), 2);
+        assert_eq!(looper.status().state, LooperState::Empty);
+        handoff.run(&samples);
+        assert!(samples.get(LOOP_BUFFER).unwrap().is_empty());
+        assert!(handoff.built.lock().unwrap().is_none());
+        assert!(!looper.sync(2, &handoff));
+    }
+}
@@ -0,0 +1,372 @@
+//! Level meters for the plugin's input and output: peak with peak-hold, RMS, short-term loudness
+//! according to ITU-R BS.1770, and a clip indicator. The audio thread only meters while the editor
+//! is open.
+
+use atomic_float::AtomicF32;
+use nih_plug::prelude::util;
+use nih_plug_egui::egui::{self, Color32, Rounding, Sense, Stroke};
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The time it takes for the peak meter to decay by 12 dB after switching to complete silence.
+const PEAK_METER_DECAY_MS: f64 = 150.0;
+/// The time constant of the RMS meter.
+const RMS_WINDOW_MS: f32 = 300.0;
+/// How long the highest peak stays visible.
+const PEAK_HOLD_SECONDS: f32 = 2.0;
+/// Short-term loudness is measured over 3 seconds, in 100 ms steps.
+const LOUDNESS_BINS: usize = 30;
+const LOUDNESS_BIN_SECONDS: f32 = 0.1;
+
+/// The range the meters display, in dB.
+const MIN_DB: f32 = -60.0;
+const MAX_DB: f32 = 6.0;
+
+/// The readings of a single meter, shared between the audio thread and the editor. Levels are
+/// stored as linear gain, and loudness in LUFS.
+pub struct MeterReadings {
+    peak: AtomicF32,
+    peak_hold: AtomicF32,
+    rms: AtomicF32,
+    loudness: AtomicF32,
+    /// Set when a sample reached 0 dBFS, until it's cleared in the editor.
+    clipped: AtomicBool,
+}
+
+impl Default for MeterReadings {
+    fn default() -> Self {
+        Self {
+            peak: AtomicF32::new(0.0),
+            peak_hold: AtomicF32::new(0.0),
+            rms: AtomicF32::new(0.0),
+            loudness: AtomicF32::new(f32::NEG_INFINITY),
+            clipped: AtomicBool::new(false),
+        }
+    }
+}
+
+impl MeterReadings {
+    pub fn clear_clip(&self) {
+        self.clipped.store(false, Ordering::Relaxed);
+    }
+}
+
+/// The meters shown in the editor.
+#[derive(Default)]
+pub struct Meters {
+    pub input: MeterReadings,
+    pub output: MeterReadings,
+}
+
+/// A biquad in direct form I, for the K-weighting filters.
+#[derive(Debug, Clone, Copy, Default)]
+struct KFilter {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl KFilter {
+    /// The high shelf that models the acoustic effect of the head.
+    fn shelf(sample_rate: f64) -> Self {
+        let f0 = 1681.974450955533;
+        let gain = 3.999843853973347;
+        let q = 0.7071752369554196;
+
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+
+        Self {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        }
+    }
+
+    /// The revised low-frequency B-curve highpass.
+    fn highpass(sample_rate: f64) -> Self {
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+
+        Self {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct KFilterState {
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl KFilterState {
+    fn process(&mut self, filter: &KFilter, x: f64) -> f64 {
+        let y = filter.b[0] * x + filter.b[1] * self.x[0] + filter.b[2] * self.x[1]
+            - filter.a[0] * self.y[0]
+            - filter.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+
+        y
+    }
+}
+
+/// Measures a signal on the audio thread.
+pub struct Meter {
+    peak_decay: f32,
+    rms_coefficient: f32,
+    hold_samples: usize,
+
+    peak: f32,
+    peak_hold: f32,
+    since_peak_hold: usize,
+    mean_square: f32,
+    clipped: bool,
+
+    shelf: KFilter,
+    highpass: KFilter,
+    /// The K-weighting filters' state for every channel.
+    filters: Vec<(KFilterState, KFilterState)>,
+    /// The sums of the K-weighted squares over the last 3 seconds, per 100 ms.
+    loudness_bins: [f64; LOUDNESS_BINS],
+    loudness_bin: usize,
+    bin_len: usize,
+    bin_pos: usize,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self::new(1.0, 0)
+    }
+}
+
+impl Meter {
+    pub fn new(sample_rate: f32, num_channels: usize) -> Self {
+        let sr = sample_rate as f64;
+
+        Self {
+            // After `PEAK_METER_DECAY_MS` milliseconds of pure silence, the peak meter's value
+            // should have dropped by 12 dB
+            peak_decay: 0.25f64.powf((sr * PEAK_METER_DECAY_MS / 1000.0).recip()) as f32,
+            rms_coefficient: (-1000.0 / (RMS_WINDOW_MS * sample_rate)).exp(),
+            hold_samples: (PEAK_HOLD_SECONDS * sample_rate) as usize,
+
+            peak: 0.0,
+            peak_hold: 0.0,
+            since_peak_hold: 0,
+            mean_square: 0.0,
+            clipped: false,
+
+            shelf: KFilter::shelf(sr),
+            highpass: KFilter::highpass(sr),
+            filters: vec![Default::default(); num_channels],
+            loudness_bins: [0.0; LOUDNESS_BINS],
+            loudness_bin: 0,
+            bin_len: ((LOUDNESS_BIN_SECONDS * sample_rate) as usize).max(1),
+            bin_pos: 0,
+        }
+    }
+
+    /// Measures a single sample for every channel.
+    pub fn process(&mut self, samples: &[f32]) {
+        let mut peak: f32 = 0.0;
+        let mut square_sum = 0.0;
+        let mut weighted_sum = 0.0;
+        for (sample, (shelf, highpass)) in samples.iter().zip(&mut self.filters) {
+            peak = peak.max(sample.abs());
+            square_sum += sample * sample;
+
+            let weighted =
+                highpass.process(&self.highpass, shelf.process(&self.shelf, *sample as f64));
+            weighted_sum += weighted * weighted;
+        }
+
+        self.clipped |= peak >= 1.0;
+        self.peak = if peak > self.peak {
+            peak
+        } else {
+            self.peak * self.peak_decay + peak * (1.0 - self.peak_decay)
+        };
+
+        self.since_peak_hold += 1;
+        if peak >= self.peak_hold || self.since_peak_hold > self.hold_samples {
+            self.peak_hold = peak.max(self.peak);
+            self.since_peak_hold = 0;
+        }
+
+        let mean_square = square_sum / samples.len().max(1) as f32;
+        self.mean_square = mean_square + (self.mean_square - mean_square) * self.rms_coefficient;
+
+        self.loudness_bins[self.loudness_bin] += weighted_sum;
+        self.bin_pos += 1;
+        if self.bin_pos >= self.bin_len {
+            self.bin_pos = 0;
+            self.loudness_bin = (self.loudness_bin + 1) % LOUDNESS_BINS;
+            self.loudness_bins[self.loudness_bin] = 0.0;
+        }
+    }
+
+    /// The short-term loudness in LUFS. The bin that's currently being filled is left out.
+    fn loudness(&self) -> f32 {
+        let sum: f64 = self
+            .loudness_bins
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != self.loudness_bin)
+            .map(|(_, sum)| sum)
+            .sum();
+        let mean = sum / ((LOUDNESS_BINS - 1) * self.bin_len) as f64;
+
+        (-0.691 + 10.0 * mean.log10()) as f32
+    }
+
+    /// Makes the readings available to the editor.
+    pub fn publish(&mut self, readings: &MeterReadings) {
+        readings.peak.store(self.peak, Ordering::Relaxed);
+        readings.peak_hold.store(self.peak_hold, Ordering::Relaxed);
+        readings
+            .rms
+            .store(self.mean_square.sqrt(), Ordering::Relaxed);
+        readings.loudness.store(self.loudness(), Ordering::Relaxed);
+        if std::mem::take(&mut self.clipped) {
+            readings.clipped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.peak = 0.0;
+        self.peak_hold = 0.0;
+        self.mean_square = 0.0;
+        self.filters.fill(Default::default());
+        self.loudness_bins = [0.0; LOUDNESS_BINS];
+    }
+}
+
+/// Draws a horizontal meter with the RMS level inside the peak level, a line for the held peak,
+/// and the numbers next to it. Clicking the clip indicator clears it.
+pub fn meter(ui: &mut egui::Ui, label: &str, readings: &MeterReadings) {
+    let peak = readings.peak.load(Ordering::Relaxed);
+    let peak_hold = readings.peak_hold.load(Ordering::Relaxed);
+    let rms = readings.rms.load(Ordering::Relaxed);
+    let loudness = readings.loudness.load(Ordering::Relaxed);
+    let clipped = readings.clipped.load(Ordering::Relaxed);
+
+    ui.horizontal(|ui| {
+        ui.add_sized([50.0, 16.0], egui::Label::new(label));
+
+        let (rect, _) = ui.allocate_exact_size(egui::vec2(240.0, 12.0), Sense::hover());
+        let x = |gain: f32| {
+            let db = util::gain_to_db(gain).clamp(MIN_DB, MAX_DB);
+            rect.left() + rect.width() * (db - MIN_DB) / (MAX_DB - MIN_DB)
+        };
+        let bar = |gain: f32| egui::Rect::from_min_max(rect.min, egui::pos2(x(gain), rect.max.y));
+
+        let painter = ui.painter();
+        painter.rect_filled(rect, Rounding::none(), Color32::from_gray(230));
+        painter.rect_filled(
+            bar(peak),
+            Rounding::none(),
+            Color32::from_rgb(130, 200, 130),
+        );
+        painter.rect_filled(bar(rms), Rounding::none(), Color32::from_rgb(40, 140, 60));
+        painter.vline(
+            x(1.0),
+            rect.y_range(),
+            Stroke::new(1.0, Color32::from_gray(150)),
+        );
+        if peak_hold > 0.0 {
+            painter.vline(
+                x(peak_hold),
+                rect.y_range(),
+                Stroke::new(2.0, Color32::from_rgb(20, 80, 30)),
+            );
+        }
+
+        ui.monospace(format!(
+            "peak {:>6.1} dB  rms {:>6.1} dB  {:>6.1} LUFS",
+            util::gain_to_db(peak_hold).max(-99.9),
+            util::gain_to_db(rms).max(-99.9),
+            loudness.max(-99.9)
+        ));
+
+        let clip = egui::Button::new("CLIP").fill(if clipped {
+            Color32::from_rgb(220, 40, 40)
+        } else {
+            Color32::from_gray(230)
+        });
+        if ui.add(clip).on_hover_text("Click to clear").clicked() {
+            readings.clear_clip();
+        }
+    });
+
+    // Meters keep moving without any input events
+    ui.ctx().request_repaint();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    #[test]
+    fn a_stereo_sine_at_minus_20_dbfs_reads_minus_20_lufs() {
+        let mut meter = Meter::new(SAMPLE_RATE, 2);
+        for i in 0..(4.0 * SAMPLE_RATE) as usize {
+            let x = 0.1 * (std::f32::consts::TAU * 1000.0 * i as f32 / SAMPLE_RATE).sin();
+            meter.process(&[x, x]);
+        }
+
+        let readings = MeterReadings::default();
+        meter.publish(&readings);
+        let loudness = readings.loudness.load(Ordering::Relaxed);
+        assert!((loudness + 20.0).abs() < 0.1, "{loudness} LUFS");
+    }
+
+    #[test]
+    fn the_peak_drops_12_db_over_the_decay_time() {
+        let mut meter = Meter::new(SAMPLE_RATE, 2);
+        meter.process(&[0.5, -0.5]);
+        let decay_samples = (SAMPLE_RATE as f64 * PEAK_METER_DECAY_MS / 1000.0) as usize;
+        for _ in 0..decay_samples {
+            meter.process(&[0.0, 0.0]);
+        }
+
+        let readings = MeterReadings::default();
+        meter.publish(&readings);
+        let peak = readings.peak.load(Ordering::Relaxed);
+        assert!((peak - 0.125).abs() < 1e-3, "{peak}");
+        // The held peak doesn't decay
+        assert_eq!(readings.peak_hold.load(Ordering::Relaxed), 0.5);
+    }
+
+    #[test]
+    fn the_clip_flag_latches_until_it_is_cleared() {
+        let mut meter = Meter::new(SAMPLE_RATE, 2);
+        let readings = MeterReadings::default();
+        meter.process(&[0.99, -0.99]);
+        meter.publish(&readings);
+        assert!(!readings.clipped.load(Ordering::Relaxed));
+
+        meter.process(&[0.0, -1.5]);
+        meter.publish(&readings);
+        for _ in 0..SAMPLE_RATE as usize {
+            meter.process(&[0.0, 0.0]);
+        }
+        meter.publish(&readings);
+        assert!(readings.clipped.load(Ordering::Relaxed));
+
+        readings.clear_clip();
+        meter.publish(&readings);
+        assert!(!readings.clipped.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,522 @@
+use atomic_float::AtomicF32;
+use nih_plug::prelude::*;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::controls::NUM_CONTROLS;
+
+/// A MIDI event emitted by a script through the `midi` object. `at` is the time in seconds on the
+/// same time line as `gain(t)`, and velocities and CC values are normalized to `[0, 1]`.
+#[derive(Debug, Clone, Copy)]
+pub enum MidiOut {
+    NoteOn {
+        at: f64,
+        channel: u8,
+        note: u8,
+        velocity: f32,
+    },
+    NoteOff {
+        at: f64,
+        channel: u8,
+        note: u8,
+        velocity: f32,
+    },
+    Cc {
+        at: f64,
+        channel: u8,
+        cc: u8,
+        value: f32,
+    },
+    Clock {
+        at: f64,
+    },
+    Start {
+        at: f64,
+    },
+    Stop {
+        at: f64,
+    },
+}
+
+impl MidiOut {
+    /// The number of numbers per event in [`decode()`][Self::decode()].
+    pub const LEN: usize = 5;
+
+    /// Decodes an event the script wrote as its type, time, channel and two data values. The types
+    /// are numbered in the order of the variants.
+    pub fn decode(row: &[f64]) -> Option<Self> {
+        let at = row[1];
+        let channel = row[2] as u8;
+        Some(match row[0] as u8 {
+            0 => MidiOut::NoteOn {
+                at,
+                channel,
+                note: row[3] as u8,
+                velocity: row[4] as f32,
+            },
+            1 => MidiOut::NoteOff {
+                at,
+                channel,
+                note: row[3] as u8,
+                velocity: row[4] as f32,
+            },
+            2 => MidiOut::Cc {
+                at,
+                channel,
+                cc: row[3] as u8,
+                value: row[4] as f32,
+            },
+            3 => MidiOut::Clock { at },
+            4 => MidiOut::Start { at },
+            5 => MidiOut::Stop { at },
+            _ => return None,
+        })
+    }
+
+    fn at(&self) -> f64 {
+        match *self {
+            MidiOut::NoteOn { at, .. }
+            | MidiOut::NoteOff { at, .. }
+            | MidiOut::Cc { at, .. }
+            | MidiOut::Clock { at }
+            | MidiOut::Start { at }
+            | MidiOut::Stop { at } => at,
+        }
+    }
+
+    /// Converts the event to a note event for a block starting at sample `block_start` on the
+    /// script's time line. Events that were scheduled before the block are sent at its first
+    /// sample.
+    pub fn event(
+        &self,
+        block_start: u64,
+        sample_rate: f32,
+        block_len: usize,
+    ) -> NoteEvent<MidiRealtime> {
+        let timing = (self.at() * sample_rate as f64 - block_start as f64)
+            .round()
+            .clamp(0.0, block_len.saturating_sub(1) as f64) as u32;
+
+        match *self {
+            MidiOut::NoteOn {
+                channel,
+                note,
+                velocity,
+                ..
+            } => NoteEvent::NoteOn {
+                timing,
+                voice_id: None,
+                channel: channel.min(15),
+                note: note.min(127),
+                velocity: velocity.clamp(0.0, 1.0),
+            },
+            MidiOut::NoteOff {
+                channel,
+                note,
+                velocity,
+                ..
+            } => NoteEvent::NoteOff {
+                timing,
+                voice_id: None,
+                channel: channel.min(15),
+                note: note.min(127),
+                velocity: velocity.clamp(0.0, 1.0),
+            },
+            MidiOut::Cc {
+                channel, cc, value, ..
+            } => NoteEvent::MidiCC {
+                timing,
+                channel: channel.min(15),
+                cc: cc.min(127),
+                value: value.clamp(0.0, 1.0),
+            },
+            MidiOut::Clock { .. } => NoteEvent::MidiSysEx {
+                timing,
+                message: MidiRealtime::Clock,
+            },
+            MidiOut::Start { .. } => NoteEvent::MidiSysEx {
+                timing,
+                message: MidiRealtime::Start,
+            },
+            MidiOut::Stop { .. } => NoteEvent::MidiSysEx {
+                timing,
+                message: MidiRealtime::Stop,
+            },
+        }
+    }
+}
+
+/// MIDI system real-time messages. NIH-plug doesn't have dedicated note events for these, so they
+/// are sent as single byte SysEx messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiRealtime {
+    Clock,
+    Start,
+    Continue,
+    Stop,
+}
+
+impl SysExMessage for MidiRealtime {
+    type Buffer = [u8; 1];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        match buffer {
+            [0xf8] => Some(MidiRealtime::Clock),
+            [0xfa] => Some(MidiRealtime::Start),
+            [0xfb] => Some(MidiRealtime::Continue),
+            [0xfc] => Some(MidiRealtime::Stop),
+            _ => None,
+        }
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let status = match self {
+            MidiRealtime::Clock => 0xf8,
+            MidiRealtime::Start => 0xfa,
+            MidiRealtime::Continue => 0xfb,
+            MidiRealtime::Stop => 0xfc,
+        };
+
+        ([status], 1)
+    }
+}
+
+/// A parameter that can be controlled with a MIDI CC through MIDI learn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MidiTarget {
+    Gain,
+    Mix,
+    Preset,
+    Voices,
+    Release,
+    /// One of the control parameters the script's controls are bound to.
+    Control(ControlSlot),
+}
+
+impl MidiTarget {
+    const ALL: [MidiTarget; 5 + NUM_CONTROLS] = [
+        MidiTarget::Gain,
+        MidiTarget::Mix,
+        MidiTarget::Preset,
+        MidiTarget::Voices,
+        MidiTarget::Release,
+        MidiTarget::Control(ControlSlot(0)),
+        MidiTarget::Control(ControlSlot(1)),
+        MidiTarget::Control(ControlSlot(2)),
+        MidiTarget::Control(ControlSlot(3)),
+        MidiTarget::Control(ControlSlot(4)),
+        MidiTarget::Control(ControlSlot(5)),
+        MidiTarget::Control(ControlSlot(6)),
+        MidiTarget::Control(ControlSlot(7)),
+    ];
+
+    /// The target's position in [`MidiTarget::ALL`].
+    fn index(self) -> usize {
+        match self {
+            MidiTarget::Gain => 0,
+            MidiTarget::Mix => 1,
+            MidiTarget::Preset => 2,
+            MidiTarget::Voices => 3,
+            MidiTarget::Release => 4,
+            MidiTarget::Control(slot) => 5 + slot.get(),
+        }
+    }
+}
+
+/// The index of a control parameter, which is always less than [`NUM_CONTROLS`]. Mappings with
+/// other indices, like from an edited preset, are dropped when the map is loaded.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "usize", into = "usize")]
+pub struct ControlSlot(usize);
+
+impl ControlSlot {
+    pub fn new(slot: usize) -> Option<Self> {
+        (slot < NUM_CONTROLS).then_some(Self(slot))
+    }
+
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl TryFrom<usize> for ControlSlot {
+    type Error = String;
+
+    fn try_from(slot: usize) -> Result<Self, Self::Error> {
+        Self::new(slot).ok_or_else(|| format!("there are only {NUM_CONTROLS} control parameters"))
+    }
+}
+
+impl From<ControlSlot> for usize {
+    fn from(slot: ControlSlot) -> Self {
+        slot.0
+    }
+}
+
+impl std::fmt::Debug for ControlSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MidiMapping {
+    pub channel: u8,
+    pub cc: u8,
+    pub target: MidiTarget,
+}
+
+/// The learned CC mappings. These are persisted in the plugin state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MidiMap {
+    /// At most one mapping per target. This always has room for every target, so binding a CC on
+    /// the audio thread doesn't allocate.
+    #[serde(deserialize_with = "deserialize_mappings")]
+    mappings: Vec<MidiMapping>,
+}
+
+impl Default for MidiMap {
+    fn default() -> Self {
+        Self {
+            mappings: Vec::with_capacity(MidiTarget::ALL.len()),
+        }
+    }
+}
+
+/// A mapping as it's stored, which may target a parameter that doesn't exist.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredMapping {
+    Valid(MidiMapping),
+    Invalid(IgnoredAny),
+}
+
+fn deserialize_mappings<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<MidiMapping>, D::Error> {
+    let mut mappings: Vec<MidiMapping> = Vec::<StoredMapping>::deserialize(deserializer)?
+        .into_iter()
+        .filter_map(|mapping| match mapping {
+            StoredMapping::Valid(mapping) => Some(mapping),
+            StoredMapping::Invalid(_) => None,
+        })
+        .collect();
+    mappings.reserve(MidiTarget::ALL.len().saturating_sub(mappings.len()));
+
+    Ok(mappings)
+}
+
+impl MidiMap {
+    /// Binds a CC to a parameter. Both the CC's old mapping and the parameter's old mapping are
+    /// replaced.
+    pub fn bind(&mut self, channel: u8, cc: u8, target: MidiTarget) {
+        self.mappings
+            .retain(|m| m.target != target && (m.channel, m.cc) != (channel, cc));
+        self.mappings.push(MidiMapping {
+            channel,
+            cc,
+            target,
+        });
+    }
+
+    /// Removes all mappings, keeping the room for them.
+    pub fn clear(&mut self) {
+        self.mappings.clear();
+    }
+
+    pub fn mapping(&self, target: MidiTarget) -> Option<&MidiMapping> {
+        self.mappings.iter().find(|m| m.target == target)
+    }
+
+    pub fn targets(&self, channel: u8, cc: u8) -> impl Iterator<Item = MidiTarget> + '_ {
+        self.mappings
+            .iter()
+            .filter(move |m| m.channel == channel && m.cc == cc)
+            .map(|m| m.target)
+    }
+}
+
+/// The parameter that's waiting for a CC to be bound to, shared between the editor and the audio
+/// thread.
+#[derive(Debug, Default)]
+pub struct MidiLearn {
+    /// The index of the armed target in [`MidiTarget::ALL`] plus one, or zero when MIDI learn is
+    /// not active.
+    armed: AtomicU8,
+}
+
+impl MidiLearn {
+    pub fn arm(&self, target: MidiTarget) {
+        self.armed
+            .store(target.index() as u8 + 1, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self) {
+        self.armed.store(0, Ordering::Relaxed);
+    }
+
+    pub fn armed(&self) -> Option<MidiTarget> {
+        Self::target(self.armed.load(Ordering::Relaxed))
+    }
+
+    /// Disarms MIDI learn and returns the target that was armed, if any.
+    pub fn take(&self) -> Option<MidiTarget> {
+        Self::target(self.armed.swap(0, Ordering::Relaxed))
+    }
+
+    fn target(armed: u8) -> Option<MidiTarget> {
+        (armed as usize)
+            .checked_sub(1)
+            .and_then(|idx| MidiTarget::ALL.get(idx).copied())
+    }
+}
+
+/// Parameter values the audio thread wants to set, from MIDI CCs and program changes, or from new
+/// controls starting at their defaults. Parameters set on the audio thread don't tell the host, so
+/// while the editor is open it sets these through its `ParamSetter` instead. Only the latest value
+/// for every parameter is kept.
+pub struct PendingValues {
+    /// The normalized value for every target in [`MidiTarget::ALL`], or NaN if there's nothing to
+    /// set.
+    values: [AtomicF32; MidiTarget::ALL.len()],
+}
+
+impl Default for PendingValues {
+    fn default() -> Self {
+        Self {
+            values: std::array::from_fn(|_| AtomicF32::new(f32::NAN)),
+        }
+    }
+}
+
+impl PendingValues {
+    pub fn set(&self, target: MidiTarget, normalized: f32) {
+        self.values[target.index()].store(normalized, Ordering::Relaxed);
+    }
+
+    /// Takes the values that were set since the last call.
+    pub fn take(&self, mut set: impl FnMut(MidiTarget, f32)) {
+        for (target, value) in MidiTarget::ALL.iter().zip(&self.values) {
+            let normalized = value.swap(f32::NAN, Ordering::Relaxed);
+            if !normalized.is_nan() {
+                set(*target, normalized);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(map: &MidiMap, channel: u8, cc: u8) -> Vec<MidiTarget> {
+        map.targets(channel, cc).collect()
+    }
+
+    #[test]
+    fn learning_binds_the_next_cc_to_the_armed_target() {
+        let learn = MidiLearn::default();
+        let mut map = MidiMap::default();
+        let control = MidiTarget::Control(ControlSlot::new(3).unwrap());
+        assert_eq!(learn.take(), None);
+
+        learn.arm(control);
+        assert_eq!(learn.armed(), Some(control));
+        let target = learn.take().unwrap();
+        map.bind(0, 74, target);
+        assert_eq!(learn.armed(), None);
+
+        assert_eq!(targets(&map, 0, 74), [control]);
+        assert!(targets(&map, 1, 74).is_empty());
+        assert_eq!(
+            map.mapping(control),
+            Some(&MidiMapping {
+                channel: 0,
+                cc: 74,
+                target: control
+            })
+        );
+
+        // Every target survives being armed
+        for target in MidiTarget::ALL {
+            learn.arm(target);
+            assert_eq!(learn.take(), Some(target));
+        }
+        learn.arm(MidiTarget::Gain);
+        learn.cancel();
+        assert_eq!(learn.take(), None);
+    }
+
+    #[test]
+    fn binding_replaces_the_old_mappings() {
+        let mut map = MidiMap::default();
+        map.bind(0, 1, MidiTarget::Gain);
+        map.bind(0, 2, MidiTarget::Mix);
+
+        // A CC that's already mapped moves to the new target
+        map.bind(0, 1, MidiTarget::Release);
+        assert_eq!(targets(&map, 0, 1), [MidiTarget::Release]);
+        assert_eq!(map.mapping(MidiTarget::Gain), None);
+
+        // And a target that's learned again loses its old CC
+        map.bind(0, 3, MidiTarget::Mix);
+        assert!(targets(&map, 0, 2).is_empty());
+        assert_eq!(targets(&map, 0, 3), [MidiTarget::Mix]);
+    }
+
+    #[test]
+    fn clearing_keeps_the_room_for_the_mappings() {
+        let mut map = MidiMap::default();
+        for (cc, target) in MidiTarget::ALL.into_iter().enumerate() {
+            map.bind(0, cc as u8, target);
+        }
+        let capacity = map.mappings.capacity();
+
+        map.clear();
+        assert!(targets(&map, 0, 0).is_empty());
+        assert_eq!(map.mapping(MidiTarget::Gain), None);
+        assert_eq!(map.mappings.capacity(), capacity);
+    }
+
+    #[test]
+    fn loading_drops_mappings_to_missing_controls() {
+        let json = r#"{"mappings": [
+            {"channel": 0, "cc": 1, "target": "gain"},
+            {"channel": 0, "cc": 2, "target": {"control": 7}},
+            {"channel": 0, "cc": 3, "target": {"control": 8}},
+            {"channel": 0, "cc": 4, "target": "tempo"}
+        ]}"#;
+        let map: MidiMap = serde_json::from_str(json).unwrap();
+        assert_eq!(targets(&map, 0, 1), [MidiTarget::Gain]);
+        assert_eq!(
+            targets(&map, 0, 2),
+            [MidiTarget::Control(ControlSlot::new(7).unwrap())]
+        );
+        assert!(targets(&map, 0, 3).is_empty());
+        assert!(targets(&map, 0, 4).is_empty());
+        assert!(map.mappings.capacity() >= MidiTarget::ALL.len());
+
+        // Controls are stored by their index
+        let json = serde_json::to_string(&map).unwrap();
+        assert!(json.contains(r#""target":{"control":7}"#), "{json}");
+        assert_eq!(ControlSlot::new(NUM_CONTROLS), None);
+    }
+
+    #[test]
+    fn pending_values_are_taken_once() {
+        let pending = PendingValues::default();
+        let control = MidiTarget::Control(ControlSlot::new(0).unwrap());
+        pending.set(MidiTarget::Mix, 0.25);
+        pending.set(control, 0.5);
+        // Only the latest value is kept
+        pending.set(control, 0.75);
+
+        let mut taken = Vec::new();
+        pending.take(|target, normalized| taken.push((target, normalized)));
+        assert_eq!(taken, [(MidiTarget::Mix, 0.25), (control, 0.75)]);
+
+        pending.take(|_, _| panic!("the values were already taken"));
+    }
+}
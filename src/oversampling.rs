@@ -0,0 +1,362 @@
+use nih_plug::prelude::*;
+use std::f32::consts::{PI, TAU};
+use std::sync::Arc;
+
+/// The number of taps in every polyphase branch of the filters. The up- and downsampling filters
+/// together delay the audio by this many samples at the host's sample rate, regardless of the
+/// oversampling factor.
+const TAPS_PER_PHASE: usize = 32;
+
+/// The latency of the up- and downsampling filters at the host's sample rate, for any factor.
+pub const LATENCY: u32 = TAPS_PER_PHASE as u32;
+
+pub const MAX_FACTOR: usize = 8;
+
+/// How many times the sample rate is multiplied for the script's `process()` function.
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Oversampling {
+    #[id = "off"]
+    #[name = "Off"]
+    Off,
+    #[id = "2x"]
+    #[name = "2x"]
+    X2,
+    #[id = "4x"]
+    #[name = "4x"]
+    X4,
+    #[id = "8x"]
+    #[name = "8x"]
+    X8,
+}
+
+impl Oversampling {
+    pub fn factor(self) -> usize {
+        match self {
+            Oversampling::Off => 1,
+            Oversampling::X2 => 2,
+            Oversampling::X4 => 4,
+            Oversampling::X8 => MAX_FACTOR,
+        }
+    }
+}
+
+/// A linear phase lowpass filter at the host's Nyquist frequency, used both for interpolation and
+/// for decimation. `phases[p]` contains the taps `p, p + factor, p + 2 * factor, ...`.
+struct Filter {
+    taps: Vec<f32>,
+    phases: Vec<Vec<f32>>,
+}
+
+impl Filter {
+    /// A Blackman windowed sinc with a cutoff slightly below the host's Nyquist frequency.
+    fn new(factor: usize) -> Self {
+        let len = factor * TAPS_PER_PHASE + 1;
+        let center = (len - 1) as f32 / 2.0;
+        let cutoff = 0.45 / factor as f32;
+
+        let mut taps: Vec<f32> = (0..len)
+            .map(|i| {
+                let x = i as f32 - center;
+                let sinc = if x == 0.0 {
+                    2.0 * cutoff
+                } else {
+                    (TAU * cutoff * x).sin() / (PI * x)
+                };
+                let phase = TAU * i as f32 / (len - 1) as f32;
+                let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+
+                sinc * window
+            })
+            .collect();
+
+        let sum: f32 = taps.iter().sum();
+        for tap in &mut taps {
+            *tap /= sum;
+        }
+
+        let phases = (0..factor)
+            .map(|p| taps.iter().skip(p).step_by(factor).copied().collect())
+            .collect();
+
+        Self { taps, phases }
+    }
+}
+
+/// Turns every input sample into `factor` samples.
+struct Upsampler {
+    filter: Arc<Filter>,
+    /// The recent input samples, stored twice so they're always contiguous.
+    history: Vec<f32>,
+    pos: usize,
+}
+
+impl Upsampler {
+    fn new(filter: Arc<Filter>) -> Self {
+        Self {
+            history: vec![0.0; (TAPS_PER_PHASE + 1) * 2],
+            pos: 0,
+            filter,
+        }
+    }
+
+    fn process(&mut self, x: f32, outputs: &mut [f32]) {
+        let len = TAPS_PER_PHASE + 1;
+        self.pos = (self.pos + 1) % len;
+        self.history[self.pos] = x;
+        self.history[self.pos + len] = x;
+
+        // From newest to oldest
+        let recent = self.history[self.pos + 1..=self.pos + len].iter().rev();
+        let gain = self.filter.phases.len() as f32;
+        for (output, phase) in outputs.iter_mut().zip(&self.filter.phases) {
+            *output = phase
+                .iter()
+                .zip(recent.clone())
+                .map(|(h, x)| h * x)
+                .sum::<f32>()
+                * gain;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.history.fill(0.0);
+    }
+}
+
+/// Turns every `factor` input samples into a single sample.
+struct Downsampler {
+    filter: Arc<Filter>,
+    history: Vec<f32>,
+    pos: usize,
+}
+
+impl Downsampler {
+    fn new(filter: Arc<Filter>) -> Self {
+        Self {
+            history: vec![0.0; filter.taps.len() * 2],
+            pos: 0,
+            filter,
+        }
+    }
+
+    /// Takes `factor` samples and returns the filtered first sample. That keeps the total latency
+    /// at a whole number of samples at the host's sample rate.
+    fn process(&mut self, inputs: &[f32]) -> f32 {
+        let mut output = 0.0;
+        for (i, x) in inputs.iter().enumerate() {
+            self.push(*x);
+            if i == 0 {
+                // The filter is symmetric, so the order doesn't matter
+                let len = self.filter.taps.len();
+                output = self.history[self.pos + 1..=self.pos + len]
+                    .iter()
+                    .zip(&self.filter.taps)
+                    .map(|(x, h)| x * h)
+                    .sum();
+            }
+        }
+
+        output
+    }
+
+    fn push(&mut self, x: f32) {
+        let len = self.filter.taps.len();
+        self.pos = (self.pos + 1) % len;
+        self.history[self.pos] = x;
+        self.history[self.pos + len] = x;
+    }
+
+    fn reset(&mut self) {
+        self.history.fill(0.0);
+    }
+}
+
+/// Runs the script's `process()` function at a multiple of the host's sample rate.
+pub struct Oversampler {
+    factor: usize,
+    inputs: Vec<Upsampler>,
+    live_inputs: Vec<Upsampler>,
+    outputs: Vec<Downsampler>,
+
+    /// Scratch space for the upsampled inputs, per channel, and for a single oversampled frame.
+    upsampled: Vec<[f32; MAX_FACTOR]>,
+    live_upsampled: Vec<[f32; MAX_FACTOR]>,
+    frame: Vec<f32>,
+    live_frame: Vec<f32>,
+    /// Scratch space for the script's outputs for a single oversampled frame, and for all of them
+    /// per channel.
+    frame_outputs: Vec<f32>,
+    processed: Vec<[f32; MAX_FACTOR]>,
+}
+
+impl Oversampler {
+    pub fn new(factor: usize, num_inputs: usize, num_outputs: usize) -> Self {
+        let factor = factor.clamp(1, MAX_FACTOR);
+        let filter = Arc::new(Filter::new(factor));
+
+        Self {
+            factor,
+            inputs: (0..num_inputs)
+                .map(|_| Upsampler::new(filter.clone()))
+                .collect(),
+            live_inputs: (0..num_inputs)
+                .map(|_| Upsampler::new(filter.clone()))
+                .collect(),
+            outputs: (0..num_outputs)
+                .map(|_| Downsampler::new(filter.clone()))
+                .collect(),
+
+            upsampled: vec![[0.0; MAX_FACTOR]; num_inputs],
+            live_upsampled: vec![[0.0; MAX_FACTOR]; num_inputs],
+            frame: Vec::with_capacity(num_inputs),
+            live_frame: Vec::with_capacity(num_inputs),
+            frame_outputs: Vec::with_capacity(num_outputs),
+            processed: vec![[0.0; MAX_FACTOR]; num_outputs],
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    /// The latency of the up- and downsampling filters at the host's sample rate.
+    pub fn latency_samples(&self) -> u32 {
+        LATENCY
+    }
+
+    /// Upsamples a single frame of input, calls `process` for every oversampled frame, and
+    /// downsamples the results to a sample per output channel in `outputs`. `process` receives the
+    /// index of the oversampled frame, its inputs, its live inputs if there are any, and the buffer
+    /// to write its outputs to. Returns false if `process` fails.
+    pub fn process(
+        &mut self,
+        inputs: &[f32],
+        live_inputs: &[f32],
+        outputs: &mut Vec<f32>,
+        mut process: impl FnMut(usize, &[f32], &[f32], &mut Vec<f32>) -> bool,
+    ) -> bool {
+        let factor = self.factor;
+        for ((x, upsampler), upsampled) in
+            inputs.iter().zip(&mut self.inputs).zip(&mut self.upsampled)
+        {
+            upsampler.process(*x, &mut upsampled[..factor]);
+        }
+        for ((x, upsampler), upsampled) in live_inputs
+            .iter()
+            .zip(&mut self.live_inputs)
+            .zip(&mut self.live_upsampled)
+        {
+            upsampler.process(*x, &mut upsampled[..factor]);
+        }
+
+        let mut failed = false;
+        for i in 0..factor {
+            self.frame.clear();
+            self.frame
+                .extend(self.upsampled.iter().take(inputs.len()).map(|s| s[i]));
+            self.live_frame.clear();
+            self.live_frame.extend(
+                self.live_upsampled
+                    .iter()
+                    .take(live_inputs.len())
+                    .map(|s| s[i]),
+            );
+
+            let succeeded = process(i, &self.frame, &self.live_frame, &mut self.frame_outputs);
+            let frame_outputs = &self.frame_outputs;
+            for (channel, processed) in self.processed.iter_mut().enumerate() {
+                processed[i] = match frame_outputs.len() {
+                    len if succeeded && len > 0 => frame_outputs[channel % len],
+                    _ => 0.0,
+                };
+            }
+            failed |= !succeeded;
+        }
+
+        outputs.clear();
+        outputs.extend(
+            self.outputs
+                .iter_mut()
+                .zip(&self.processed)
+                .map(|(downsampler, processed)| downsampler.process(&processed[..factor])),
+        );
+
+        !failed
+    }
+
+    pub fn reset(&mut self) {
+        for upsampler in self.inputs.iter_mut().chain(&mut self.live_inputs) {
+            upsampler.reset();
+        }
+        for downsampler in &mut self.outputs {
+            downsampler.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// Runs a sine at `freq` through an oversampler, with `process` generating the oversampled
+    /// output from the index of the oversampled frame and its inputs. Returns the input and the
+    /// output.
+    fn oversample(
+        factor: usize,
+        freq: f32,
+        mut process: impl FnMut(usize, f32) -> f32,
+    ) -> (Vec<f32>, Vec<f32>) {
+        let mut oversampler = Oversampler::new(factor, 1, 1);
+        let mut outputs = Vec::with_capacity(1);
+        let mut n = 0;
+        let input: Vec<f32> = (0..4800)
+            .map(|i| (TAU * freq * i as f32 / SAMPLE_RATE).sin())
+            .collect();
+        let output = input
+            .iter()
+            .map(|x| {
+                oversampler.process(&[*x], &[], &mut outputs, |_, inputs, _, outputs| {
+                    outputs.clear();
+                    outputs.push(process(n, inputs[0]));
+                    n += 1;
+                    true
+                });
+                outputs[0]
+            })
+            .collect();
+
+        (input, output)
+    }
+
+    #[test]
+    fn passband_is_delayed_by_the_reported_latency() {
+        for factor in [2, 4, MAX_FACTOR] {
+            let latency = Oversampler::new(factor, 1, 1).latency_samples() as usize;
+            for freq in [100.0, 1000.0, 10_000.0, 15_000.0] {
+                let (input, output) = oversample(factor, freq, |_, x| x);
+                let error = output[1000..]
+                    .iter()
+                    .zip(&input[1000 - latency..])
+                    .map(|(y, x)| (y - x).abs())
+                    .fold(0.0, f32::max);
+
+                assert!(error < 0.01, "{factor}x at {freq} Hz: off by {error}");
+            }
+        }
+    }
+
+    #[test]
+    fn content_above_the_host_nyquist_frequency_is_filtered_out() {
+        for factor in [2, 4, MAX_FACTOR] {
+            let oversampled_rate = SAMPLE_RATE * factor as f32;
+            let (_, output) = oversample(factor, 0.0, |n, _| {
+                (TAU * 30_000.0 * n as f32 / oversampled_rate).sin()
+            });
+            let peak = output[1000..].iter().map(|y| y.abs()).fold(0.0, f32::max);
+
+            assert!(peak < 0.01, "{factor}x: {peak}");
+        }
+    }
+}
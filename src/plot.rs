@@ -0,0 +1,456 @@
+//! The editor's function plot. The code in the editor is compiled and evaluated on a separate
+//! thread, so a slow script or one that doesn't compile never holds up the editor or the audio
+//! thread. The audio thread only shares the values for the live cursor.
+
+use atomic_float::AtomicF32;
+use nih_plug_egui::egui::{self, Align2, Color32, FontId, Sense, Stroke};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::controls;
+use crate::looper::LooperStatus;
+use crate::runtime::Watchdog;
+use crate::samples::SampleBank;
+use crate::script::{BlockInfo, ScriptConfig, ScriptEngine};
+
+/// The number of times the script is evaluated per plot.
+const POINTS: usize = 2048;
+/// The tempo the plot assumes when the host doesn't report one.
+const DEFAULT_TEMPO: f32 = 120.0;
+/// How long compiling and evaluating the script for a plot can take before it's terminated.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlotWindow {
+    /// Plots `gain(t)` over one bar at the host's tempo.
+    Bar,
+    /// Plots `gain(t)` over one second.
+    Second,
+    /// Plots `process()`'s output for inputs from -1 to 1, for waveshapers.
+    Shaper,
+}
+
+impl PlotWindow {
+    pub const ALL: [PlotWindow; 3] = [PlotWindow::Bar, PlotWindow::Second, PlotWindow::Shaper];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            PlotWindow::Bar => "One bar",
+            PlotWindow::Second => "One second",
+            PlotWindow::Shaper => "Shaper",
+        }
+    }
+}
+
+/// The values the audio thread shares with the plot. The input and output are mixed down to mono.
+pub struct LiveValues {
+    time: AtomicF32,
+    /// The host's position within the current bar from 0 to 1, or NaN while it's not playing.
+    bar_position: AtomicF32,
+    gain: AtomicF32,
+    input: AtomicF32,
+    output: AtomicF32,
+    tempo: AtomicF32,
+    beats_per_bar: AtomicF32,
+}
+
+impl Default for LiveValues {
+    fn default() -> Self {
+        Self {
+            time: AtomicF32::new(0.0),
+            bar_position: AtomicF32::new(f32::NAN),
+            gain: AtomicF32::new(0.0),
+            input: AtomicF32::new(0.0),
+            output: AtomicF32::new(0.0),
+            tempo: AtomicF32::new(DEFAULT_TEMPO),
+            beats_per_bar: AtomicF32::new(4.0),
+        }
+    }
+}
+
+impl LiveValues {
+    /// `beats` is the host's position in quarter notes while it's playing.
+    pub fn publish(&self, time: f32, beats: Option<f64>, gain: f32, input: f32, output: f32) {
+        let beats_per_bar = self.beats_per_bar.load(Ordering::Relaxed) as f64;
+        let bar_position = beats.map(|beats| beats.rem_euclid(beats_per_bar) / beats_per_bar);
+        self.time.store(time, Ordering::Relaxed);
+        self.bar_position
+            .store(bar_position.unwrap_or(f64::NAN) as f32, Ordering::Relaxed);
+        self.gain.store(gain, Ordering::Relaxed);
+        self.input.store(input, Ordering::Relaxed);
+        self.output.store(output, Ordering::Relaxed);
+    }
+
+    pub fn set_transport(&self, tempo: Option<f64>, beats_per_bar: f64) {
+        let tempo = tempo
+            .filter(|&tempo| tempo > 0.0)
+            .unwrap_or(DEFAULT_TEMPO as f64);
+        self.tempo.store(tempo as f32, Ordering::Relaxed);
+        self.beats_per_bar
+            .store(beats_per_bar as f32, Ordering::Relaxed);
+    }
+}
+
+struct PlotRequest {
+    code: String,
+    window: PlotWindow,
+    /// The window's length in seconds, for the time based windows.
+    duration: f32,
+    tempo: f32,
+    sample_rate: f32,
+    /// The loaded samples as JSON, see [`SampleBank::index()`].
+    samples: Vec<u8>,
+}
+
+struct Plot {
+    window: PlotWindow,
+    duration: f32,
+    /// The script's output at `POINTS` evenly spaced points in the window, or the reason it
+    /// couldn't be plotted.
+    values: Result<Vec<f32>, String>,
+}
+
+/// Passes requests and plots between the editor and the plot thread. Only the most recent request
+/// and plot are kept, so the plot thread skips requests that came in while it was busy.
+#[derive(Default)]
+struct PlotQueue {
+    request: Mutex<Option<PlotRequest>>,
+    requested: Condvar,
+    /// Set when the editor closes, which stops the plot thread.
+    closed: AtomicBool,
+    plot: Mutex<Option<Plot>>,
+}
+
+/// Sends the editor's code to the plot thread whenever it or the window changes, and holds on to
+/// the most recent plot.
+pub struct Plotter {
+    queue: Arc<PlotQueue>,
+    /// The code, window, duration and sample bank generation of the last request.
+    requested: Option<(String, PlotWindow, f32, u32)>,
+    plot: Option<Plot>,
+}
+
+impl Default for Plotter {
+    fn default() -> Self {
+        let queue = Arc::new(PlotQueue::default());
+        thread::spawn({
+            let queue = queue.clone();
+            move || run(&queue)
+        });
+
+        Self {
+            queue,
+            requested: None,
+            plot: None,
+        }
+    }
+}
+
+impl Drop for Plotter {
+    fn drop(&mut self) {
+        // The flag is set while holding the lock so the plot thread can't miss the notification
+        let _request = self.queue.request.lock().unwrap();
+        self.queue.closed.store(true, Ordering::Relaxed);
+        self.queue.requested.notify_one();
+    }
+}
+
+impl Plotter {
+    /// Requests a new plot if anything changed, and picks up finished plots. Called every frame.
+    pub fn update(
+        &mut self,
+        code: &str,
+        window: PlotWindow,
+        live: &LiveValues,
+        sample_rate: f32,
+        samples: &SampleBank,
+    ) {
+        let tempo = live.tempo.load(Ordering::Relaxed);
+        let duration = match window {
+            PlotWindow::Bar => live.beats_per_bar.load(Ordering::Relaxed) * 60.0 / tempo,
+            PlotWindow::Second | PlotWindow::Shaper => 1.0,
+        };
+        let generation = samples.generation();
+
+        let changed = match &self.requested {
+            Some((c, w, d, g)) => c != code || *w != window || *d != duration || *g != generation,
+            None => true,
+        };
+        if changed {
+            self.requested = Some((code.to_string(), window, duration, generation));
+            *self.queue.request.lock().unwrap() = Some(PlotRequest {
+                code: code.to_string(),
+                window,
+                duration,
+                tempo,
+                sample_rate,
+                samples: samples.index(<[u8]>::to_vec).unwrap_or_default(),
+            });
+            self.queue.requested.notify_one();
+        }
+
+        if let Some(plot) = self.queue.plot.lock().unwrap().take() {
+            self.plot = Some(plot);
+        }
+    }
+}
+
+/// The plot thread. Evaluates the most recent request until the editor closes.
+fn run(queue: &PlotQueue) {
+    loop {
+        let request = {
+            let mut request = queue.request.lock().unwrap();
+            loop {
+                if queue.closed.load(Ordering::Relaxed) {
+                    return;
+                }
+                if let Some(request) = request.take() {
+                    break request;
+                }
+                request = queue.requested.wait(request).unwrap();
+            }
+        };
+
+        let plot = Plot {
+            window: request.window,
+            duration: request.duration,
+            values: evaluate(&request),
+        };
+        *queue.plot.lock().unwrap() = Some(plot);
+    }
+}
+
+/// Evaluates the script on this thread, and terminates it from another one if that takes too long,
+/// so a script with an endless loop doesn't keep the plot thread from picking up the fixed code.
+fn evaluate(request: &PlotRequest) -> Result<Vec<f32>, String> {
+    Watchdog::run(TIMEOUT, |watchdog| evaluate_points(request, watchdog)).unwrap_or_else(|| {
+        Err(format!(
+            "The script took longer than {} seconds to plot",
+            TIMEOUT.as_secs()
+        ))
+    })
+}
+
+/// Compiles a fresh copy of the script, so state the script keeps in its globals starts from
+/// scratch for every plot. Once `watchdog` expired the remaining points are skipped.
+fn evaluate_points(request: &PlotRequest, watchdog: &Arc<Watchdog>) -> Result<Vec<f32>, String> {
+    let mut engine = ScriptEngine::new(ScriptConfig {
+        history_length: 0,
+        channels: 1,
+        sidechain: 0,
+    });
+    engine.set_watchdog(watchdog.clone());
+    engine.set_samples(&request.samples);
+    engine.sync(&request.code);
+    if let Some(err) = engine.error() {
+        return Err(err.to_string());
+    }
+
+    let info = engine.info();
+    engine.block(
+        0.0,
+        &BlockInfo {
+            duration: request.duration,
+            sample_rate: request.sample_rate,
+            oversampling: 1,
+            playing: false,
+            tempo: Some(request.tempo as f64),
+            beats: Some(0.0),
+            channels: 1,
+            layout: None,
+            sidechain: 0,
+            analysis: None,
+            looper: LooperStatus::default(),
+            controls: controls::defaults(engine.controls()),
+        },
+    );
+
+    let position = |i: usize| i as f32 / (POINTS - 1) as f32;
+    match request.window {
+        PlotWindow::Shaper if info.process => {
+            let mut outputs = Vec::new();
+            Ok((0..POINTS)
+                .map(|i| {
+                    let x = position(i) * 2.0 - 1.0;
+                    if !watchdog.expired() && engine.process(0.0, &[x], &[], &mut outputs) {
+                        outputs.first().copied().unwrap_or(f32::NAN)
+                    } else {
+                        f32::NAN
+                    }
+                })
+                .collect())
+        }
+        PlotWindow::Shaper if info.gain => {
+            let gain = engine.gain(0.0).unwrap_or(f32::NAN);
+            Ok((0..POINTS)
+                .map(|i| (position(i) * 2.0 - 1.0) * gain)
+                .collect())
+        }
+        PlotWindow::Shaper => Err("The script doesn't define process() or gain(t)".to_string()),
+        _ if info.gain => Ok((0..POINTS)
+            .map(|i| {
+                if watchdog.expired() {
+                    return f32::NAN;
+                }
+                engine
+                    .gain((position(i) * request.duration) as f64)
+                    .unwrap_or(f32::NAN)
+            })
+            .collect()),
+        _ => Err("The script doesn't define gain(t)".to_string()),
+    }
+}
+
+/// Draws the most recent plot with the live value as a cursor. Negative values are shaded for the
+/// time based windows, since a negative gain inverts the signal.
+pub fn plot(ui: &mut egui::Ui, plotter: &Plotter, live: &LiveValues) {
+    let size = egui::vec2(ui.available_width(), 120.0);
+    let (rect, _) = ui.allocate_exact_size(size, Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, Color32::from_gray(245));
+    ui.ctx().request_repaint();
+
+    let text = Color32::from_gray(140);
+    let plot = match &plotter.plot {
+        Some(plot) => plot,
+        None => {
+            painter.text(
+                rect.center(),
+                Align2::CENTER_CENTER,
+                "Evaluating...",
+                FontId::proportional(12.0),
+                text,
+            );
+            return;
+        }
+    };
+    let values = match &plot.values {
+        Ok(values) => values,
+        Err(err) => {
+            painter.text(
+                rect.center(),
+                Align2::CENTER_CENTER,
+                err,
+                FontId::proportional(12.0),
+                Color32::from_rgb(200, 40, 40),
+            );
+            return;
+        }
+    };
+
+    // The range always includes 0 to 1 for gains and -1 to 1 for shapers
+    let shaper = plot.window == PlotWindow::Shaper;
+    let finite = || values.iter().copied().filter(|value| value.is_finite());
+    let min = finite().fold(if shaper { -1.0 } else { 0.0 }, f32::min);
+    let max = finite().fold(1.0, f32::max);
+    let x = |position: f32| rect.left() + rect.width() * position;
+    let y = |value: f32| rect.bottom() - rect.height() * (value - min) / (max - min);
+
+    if !shaper && min < 0.0 {
+        painter.rect_filled(
+            egui::Rect::from_min_max(egui::pos2(rect.left(), y(0.0)), rect.max),
+            0.0,
+            Color32::from_rgb(250, 225, 225),
+        );
+    }
+    painter.hline(
+        rect.x_range(),
+        y(0.0),
+        Stroke::new(1.0, Color32::from_gray(210)),
+    );
+    if shaper {
+        painter.vline(
+            x(0.5),
+            rect.y_range(),
+            Stroke::new(1.0, Color32::from_gray(210)),
+        );
+    }
+
+    for (value, anchor, pos) in [
+        (max, Align2::LEFT_TOP, rect.left_top()),
+        (min, Align2::LEFT_BOTTOM, rect.left_bottom()),
+    ] {
+        painter.text(
+            pos + egui::vec2(2.0, 0.0),
+            anchor,
+            format!("{value:.2}"),
+            FontId::proportional(10.0),
+            text,
+        );
+    }
+    painter.text(
+        rect.right_bottom() - egui::vec2(2.0, 0.0),
+        Align2::RIGHT_BOTTOM,
+        if shaper {
+            "input from -1 to 1".to_string()
+        } else {
+            format!("t from 0 to {:.2} s", plot.duration)
+        },
+        FontId::proportional(10.0),
+        text,
+    );
+
+    // Every pixel column shows the lowest and highest value in it, so fast oscillations show up as
+    // a filled band instead of aliasing. Values that aren't numbers are left out.
+    let columns = (rect.width().max(1.0) as usize).min(POINTS);
+    let points: Vec<_> = (0..columns)
+        .flat_map(|column| {
+            let chunk =
+                &values[column * values.len() / columns..(column + 1) * values.len() / columns];
+            let min = chunk
+                .iter()
+                .copied()
+                .filter(|v| v.is_finite())
+                .reduce(f32::min);
+            let max = chunk
+                .iter()
+                .copied()
+                .filter(|v| v.is_finite())
+                .reduce(f32::max);
+            let x = x(column as f32 / (columns - 1).max(1) as f32);
+
+            min.zip(max)
+                .into_iter()
+                .flat_map(move |(min, max)| [egui::pos2(x, y(min)), egui::pos2(x, y(max))])
+        })
+        .collect();
+    painter.add(egui::Shape::line(
+        points,
+        Stroke::new(1.5, Color32::from_rgb(30, 90, 200)),
+    ));
+
+    let invalid = values.iter().filter(|value| !value.is_finite()).count();
+    if invalid > 0 {
+        painter.text(
+            rect.right_top() - egui::vec2(2.0, 0.0),
+            Align2::RIGHT_TOP,
+            format!("{invalid} points aren't numbers"),
+            FontId::proportional(10.0),
+            Color32::from_rgb(200, 40, 40),
+        );
+    }
+
+    let cursor = Color32::from_rgb(230, 120, 40);
+    let (cursor_x, cursor_y) = if shaper {
+        (
+            (live.input.load(Ordering::Relaxed).clamp(-1.0, 1.0) + 1.0) / 2.0,
+            live.output.load(Ordering::Relaxed),
+        )
+    } else {
+        // The bar window follows the host's bars while it's playing
+        let bar_position = live.bar_position.load(Ordering::Relaxed);
+        let position = if plot.window == PlotWindow::Bar && bar_position.is_finite() {
+            bar_position
+        } else {
+            live.time.load(Ordering::Relaxed).rem_euclid(plot.duration) / plot.duration
+        };
+        (position, live.gain.load(Ordering::Relaxed))
+    };
+    if cursor_y.is_finite() {
+        let cursor_y = cursor_y.clamp(min, max);
+        painter.vline(x(cursor_x), rect.y_range(), Stroke::new(1.0, cursor));
+        painter.circle_filled(egui::pos2(x(cursor_x), y(cursor_y)), 3.0, cursor);
+    }
+}
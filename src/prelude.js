@@ -0,0 +1,1133 @@
+// Runtime glue that is evaluated right before the user's code. Everything in here is prefixed with
+// `__rjv` so it doesn't clash with whatever the script defines.
+//
+// The native side and the script share three typed arrays, which `__rjv_init()` receives: numbers
+// for the block info, the voices, the DSP nodes and everything the script hands back, samples for
+// the audio that's processed one sample at a time, and bytes for UTF-8 text. The native side
+// writes the arguments to these before it calls one of the functions below, and reads the results
+// from them afterwards. The layout of the arrays is decided by the native side, see `script.rs`.
+
+const __rjv = {
+  // The time of the sample that's currently being processed, used as the default time for MIDI
+  // events
+  time: 0,
+  // Per-voice state objects, indexed by the voice manager's slot
+  voices: [],
+  // MIDI events that haven't been sent to the host yet
+  midi: [],
+  // Looper commands that haven't been sent to the native side yet
+  looper: [],
+  // Console messages that haven't been sent to the native side yet, and the number of messages
+  // that didn't fit
+  console: [],
+  consoleDropped: 0,
+  // All native DSP nodes, indexed by their ID, and the ones whose spec still needs to be sent to
+  // the native side
+  nodes: [],
+  specs: new Set(),
+  // Views into the native ring buffers with the live inputs and the outputs of `process()`, or the
+  // plugin's outputs for scripts without it. The index of the current sample and the number of
+  // recorded samples are in the numbers.
+  history: { length: 0, inputs: [], outputs: [] },
+  // The script's declared latency in samples. `process()` sees the input delayed by this much.
+  latency: 0,
+  // The oversampling factor. The history and `input()` and `lookahead()` work in oversampled
+  // samples, while the latency is declared in samples at the host's sample rate.
+  oversampling: 1,
+  // The current block's duration in seconds
+  duration: 0,
+  // The controls the script declared, with the control parameters they're bound to
+  controls: [],
+  // The profiler's state while it's enabled: the names of the functions that are running, the
+  // last time it looked at the clock, and the milliseconds attributed to every function
+  profile: null,
+
+  // The shared arrays and where everything is in them, set by `__rjv_init()`
+  layout: null,
+  numbers: null,
+  samples: null,
+  text: null,
+  // Views into `samples` for `process()`'s inputs and outputs
+  inputs: null,
+  outputs: null,
+  // The `{ magnitude, phase }` views passed to `spectral()`, one per channel
+  frames: [],
+  // Set by `__rjv_encode()` when the text didn't fit
+  truncated: false,
+  // The object passed to `block()`, which is updated in place
+  info: null,
+};
+
+// Reads `n` samples back from one of the history ring buffers, interpolating linearly between
+// samples for fractional values of `n`
+function __rjv_read(buffer, n) {
+  const length = __rjv.history.length;
+  const pos = __rjv.numbers[__rjv.layout.numbers.historyPos];
+  const filled = __rjv.numbers[__rjv.layout.numbers.historyFilled];
+  if (!buffer || length < 2) {
+    return 0;
+  }
+
+  // Samples from before the history was last cleared are silent
+  n = Math.min(Math.max(Number(n) || 0, 0), length - 2);
+  const whole = Math.floor(n);
+  const frac = n - whole;
+  const a = whole < filled ? buffer[(pos - whole + length) % length] : 0;
+  const b = whole + 1 < filled ? buffer[(pos - whole - 1 + length) % length] : 0;
+
+  return a + (b - a) * frac;
+}
+
+// The input sample on channel `ch` from `n` samples ago, where `n = 0` is the current sample.
+// Sidechain channels come after the main channels.
+function input(ch, n = 0) {
+  return __rjv_read(__rjv.history.inputs[ch], n + __rjv.latency * __rjv.oversampling);
+}
+
+// The input sample on channel `ch` from `n` samples ahead of the current sample. This can look
+// ahead as far as the script's declared `latency`, which is also the default.
+function lookahead(ch, n = __rjv.latency * __rjv.oversampling) {
+  const latency = __rjv.latency * __rjv.oversampling;
+  const ahead = Math.min(Math.max(n, 0), latency);
+  return __rjv_read(__rjv.history.inputs[ch], latency - ahead);
+}
+
+// The output sample `process()` returned for channel `ch` `n` samples ago, where `n = 1` is the
+// previous sample. Scripts without `process()` see the plugin's output.
+function output(ch, n = 1) {
+  return __rjv_read(__rjv.history.outputs[ch], Math.max(n, 1));
+}
+
+// Scripts can send MIDI to the host through this object. All functions take an optional `at` time
+// in seconds, which defaults to the current sample. Events can be scheduled ahead of time. The event
+// types are numbered in the order of `MidiOut::decode()` on the native side.
+const midi = {
+  noteOn(note, velocity = 1, { channel = 0, at = __rjv.time } = {}) {
+    __rjv.midi.push({ type: 0, at, channel, a: note, b: velocity });
+  },
+  noteOff(note, { velocity = 0, channel = 0, at = __rjv.time } = {}) {
+    __rjv.midi.push({ type: 1, at, channel, a: note, b: velocity });
+  },
+  cc(cc, value, { channel = 0, at = __rjv.time } = {}) {
+    __rjv.midi.push({ type: 2, at, channel, a: cc, b: value });
+  },
+  clock({ at = __rjv.time } = {}) {
+    __rjv.midi.push({ type: 3, at, channel: 0, a: 0, b: 0 });
+  },
+  start({ at = __rjv.time } = {}) {
+    __rjv.midi.push({ type: 4, at, channel: 0, a: 0, b: 0 });
+  },
+  stop({ at = __rjv.time } = {}) {
+    __rjv.midi.push({ type: 5, at, channel: 0, a: 0, b: 0 });
+  },
+};
+
+class __RjvNode {
+  constructor(kind, params) {
+    const { maxNodes } = __rjv.layout;
+    if (__rjv.nodes.length >= maxNodes) {
+      throw new Error(`A script can create at most ${maxNodes} DSP nodes`);
+    }
+
+    this.id = __rjv.nodes.length;
+    this.kind = kind;
+    this.params = params;
+    // The output level, see `dsp.out()`
+    this.level = 0;
+    // The spec the native side last received, see `__rjv_spec()`
+    this.spec = null;
+
+    __rjv.nodes.push(this);
+    __rjv_write_node(this);
+  }
+
+  set(params) {
+    Object.assign(this.params, params);
+    __rjv_write_node(this);
+
+    return this;
+  }
+
+  // Envelopes and sample players
+  trigger() {
+    this.params.trigger += 1;
+    return "gate" in this.params ? this.set({ gate: true }) : this.set({});
+  }
+
+  release() {
+    return this.set({ gate: false });
+  }
+}
+
+// What the native side needs to create a node: its kind, and the parameters that can't change
+// without recreating it. The sample a node uses is prepared in the background.
+function __rjv_spec(node) {
+  const { buffer = null, maxTime = 0 } = node.params;
+  return { id: node.id, kind: node.kind, buffer: buffer === null ? null : String(buffer), maxTime };
+}
+
+// Writes a node's row in the node table: a flag that tells the native side the row changed, the
+// input node's ID, the output level, and the numeric parameters in the order the native side
+// expects them. Parameters with names, like a filter's mode, are written as indices.
+function __rjv_write_node(node) {
+  const { nodeRow, nodeFields, nodeEnums, numbers: offsets } = __rjv.layout;
+  const numbers = __rjv.numbers;
+  const row = offsets.nodes + node.id * nodeRow;
+  const { input } = node.params;
+
+  numbers[row] = 1;
+  numbers[row + 1] = input ? input.id : -2;
+  numbers[row + 2] = node.level;
+  nodeFields[node.kind].forEach((field, i) => {
+    const value =
+      field === "callback" ? typeof node.params.onGrain === "function" : node.params[field];
+    const names = nodeEnums[`${node.kind}.${field}`];
+    numbers[row + 3 + i] = names ? Math.max(names.indexOf(value), 0) : Number(value) || 0;
+  });
+
+  const spec = __rjv_spec(node);
+  if (!node.spec || node.spec.buffer !== spec.buffer || node.spec.maxTime !== spec.maxTime) {
+    node.spec = spec;
+    __rjv.specs.add(node);
+  }
+}
+
+// Native DSP building blocks. Creating a node returns a handle, and `node.set({ ... })` changes its
+// parameters. Changes are picked up by the native side at the start of every block. Nodes with an
+// `input` process another node's output, or the plugin's input when that's `dsp.input`.
+const dsp = {
+  input: { id: -1 },
+
+  sine: (params) => new __RjvNode("sine", { freq: 440, ...params }),
+  saw: (params) => new __RjvNode("saw", { freq: 440, ...params }),
+  square: (params) => new __RjvNode("square", { freq: 440, width: 0.5, ...params }),
+  noise: (params) => new __RjvNode("noise", { ...params }),
+  biquad: (params) =>
+    new __RjvNode("biquad", { mode: "lowpass", cutoff: 1000, q: Math.SQRT1_2, gain: 0, ...params }),
+  svf: (params) => new __RjvNode("svf", { mode: "lowpass", cutoff: 1000, q: Math.SQRT1_2, ...params }),
+  smooth: (params) => new __RjvNode("smooth", { time: 0.01, value: 0, ...params }),
+  adsr: (params) =>
+    new __RjvNode("adsr", {
+      attack: 0.01,
+      decay: 0.1,
+      sustain: 0.7,
+      release: 0.3,
+      gate: false,
+      trigger: 0,
+      ...params,
+    }),
+  delay: (params) => new __RjvNode("delay", { time: 0.25, feedback: 0.3, mix: 0.5, maxTime: 2, ...params }),
+  shaper: (params) => new __RjvNode("shaper", { shape: "tanh", drive: 1, ...params }),
+  player: (params) =>
+    new __RjvNode("player", { buffer: "", speed: 1, loop: false, start: 0, trigger: 0, ...params }),
+  wavetable: (params) =>
+    new __RjvNode("wavetable", { buffer: "", freq: 440, position: 0, frameSize: 2048, ...params }),
+  convolver: (params) => new __RjvNode("convolver", { buffer: "", mix: 1, gain: 1, ...params }),
+  granular: (params) =>
+    new __RjvNode("granular", {
+      buffer: "",
+      size: 0.1,
+      density: 20,
+      position: 0,
+      pitch: 0,
+      pan: 0,
+      window: "hann",
+      jitter: 0,
+      spread: 0,
+      gain: 1,
+      ...params,
+    }),
+
+  // Mixes a node into the plugin's output. A level of 0 removes it again.
+  out(node, level = 1) {
+    if (node instanceof __RjvNode) {
+      node.level = Number(level) || 0;
+      __rjv_write_node(node);
+    }
+  },
+};
+
+// The looper's commands and states, in the order of the variants of `LooperCommand` and
+// `LooperState` on the native side
+const __RJV_LOOPER_COMMANDS = [
+  "record",
+  "overdub",
+  "play",
+  "stop",
+  "clear",
+  "reverse",
+  "speed",
+  "level",
+  "capture",
+];
+const __RJV_LOOPER_STATES = ["empty", "recording", "playing", "overdubbing", "stopped"];
+
+// Controls the looper. Starting and stopping waits for the next bar line when loop sync is enabled
+// and the host is playing. `looper.status` describes the looper at the start of the block.
+const looper = {
+  status: {
+    state: "empty",
+    position: 0,
+    length: 0,
+    speed: 1,
+    reverse: false,
+    level: 1,
+    pending: false,
+  },
+
+  record() {
+    __rjv.looper.push(0, 0);
+  },
+  overdub() {
+    __rjv.looper.push(1, 0);
+  },
+  play() {
+    __rjv.looper.push(2, 0);
+  },
+  stop() {
+    __rjv.looper.push(3, 0);
+  },
+  clear() {
+    __rjv.looper.push(4, 0);
+  },
+  reverse(reverse = true) {
+    __rjv.looper.push(5, reverse ? 1 : 0);
+  },
+  speed(speed) {
+    __rjv.looper.push(6, Number(speed));
+  },
+  level(level) {
+    __rjv.looper.push(7, Number(level));
+  },
+  // Turns the last `seconds` of input into the loop
+  capture(seconds) {
+    __rjv.looper.push(8, Number(seconds));
+  },
+};
+
+// Writes the looper commands issued since the last call as pairs of a command and its argument.
+// Returns the number of commands.
+function __rjv_looper() {
+  const { numbers: offsets, maxEvents } = __rjv.layout;
+  const commands = __rjv.looper;
+  const count = Math.min(commands.length / 2, maxEvents);
+  __rjv.numbers.set(commands.slice(0, count * 2), offsets.looper);
+  commands.length = 0;
+
+  return count;
+}
+
+// Encodes `text` as UTF-8 into the shared bytes at `offset`, stopping before `limit` on a character
+// boundary. Returns the number of bytes written, and sets `__rjv.truncated` if not all of them fit.
+function __rjv_encode(text, offset, limit) {
+  const bytes = __rjv.text;
+  let at = offset;
+  let i = 0;
+  for (; i < text.length; i++) {
+    let c = text.charCodeAt(i);
+    if (c >= 0xd800 && c < 0xdc00 && i + 1 < text.length) {
+      const low = text.charCodeAt(i + 1);
+      if (low >= 0xdc00 && low < 0xe000) {
+        c = 0x10000 + ((c - 0xd800) << 10) + (low - 0xdc00);
+        i++;
+      } else {
+        c = 0xfffd;
+      }
+    } else if (c >= 0xd800 && c < 0xe000) {
+      c = 0xfffd;
+    }
+
+    const len = c < 0x80 ? 1 : c < 0x800 ? 2 : c < 0x10000 ? 3 : 4;
+    if (at + len > limit) {
+      break;
+    }
+    if (len === 1) {
+      bytes[at++] = c;
+    } else if (len === 2) {
+      bytes[at++] = 0xc0 | (c >> 6);
+      bytes[at++] = 0x80 | (c & 0x3f);
+    } else if (len === 3) {
+      bytes[at++] = 0xe0 | (c >> 12);
+      bytes[at++] = 0x80 | ((c >> 6) & 0x3f);
+      bytes[at++] = 0x80 | (c & 0x3f);
+    } else {
+      bytes[at++] = 0xf0 | (c >> 18);
+      bytes[at++] = 0x80 | ((c >> 12) & 0x3f);
+      bytes[at++] = 0x80 | ((c >> 6) & 0x3f);
+      bytes[at++] = 0x80 | (c & 0x3f);
+    }
+  }
+  __rjv.truncated = i < text.length;
+
+  return at - offset;
+}
+
+// Decodes `len` bytes of UTF-8 from the shared bytes at `offset`
+function __rjv_decode(offset, len) {
+  const bytes = __rjv.text;
+  let text = "";
+  for (let i = offset; i < offset + len; ) {
+    const b = bytes[i];
+    const size = b < 0x80 ? 1 : b < 0xe0 ? 2 : b < 0xf0 ? 3 : 4;
+    let c = size === 1 ? b : b & (0xff >> (size + 1));
+    for (let j = 1; j < size; j++) {
+      c = (c << 6) | (bytes[i + j] & 0x3f);
+    }
+    text += String.fromCodePoint(c);
+    i += size;
+  }
+
+  return text;
+}
+
+// Writes `text` to the start of the shared bytes. Returns its length in bytes, or -1 if it doesn't
+// fit.
+function __rjv_write_text(text) {
+  const len = __rjv_encode(text, 0, __rjv.text.length);
+  return __rjv.truncated ? -1 : len;
+}
+
+// The most messages the script can log per block, and the most bytes per message. Identical
+// messages logged in a row only count once.
+const __RJV_MAX_CONSOLE_MESSAGES = 64;
+const __RJV_MAX_CONSOLE_BYTES = 1024;
+
+function __rjv_format(value) {
+  if (typeof value === "string") {
+    return value;
+  }
+  if (typeof value === "function") {
+    return `[Function ${value.name || "anonymous"}]`;
+  }
+  if (ArrayBuffer.isView(value)) {
+    value = Array.from(value);
+  }
+  if (typeof value === "object" && value !== null && !(value instanceof Error)) {
+    try {
+      return JSON.stringify(value);
+    } catch (err) {
+      // Circular structures fall back to `String()`
+    }
+  }
+
+  return String(value);
+}
+
+// The log levels, in the order of `LogLevel::ALL` on the native side
+const __RJV_LOG_LEVELS = { log: 0, warn: 1, error: 2 };
+
+function __rjv_log(level, args) {
+  const text = args.map(__rjv_format).join(" ");
+  const last = __rjv.console[__rjv.console.length - 1];
+  if (last && last.level === level && last.text === text) {
+    last.repeats++;
+  } else if (__rjv.console.length < __RJV_MAX_CONSOLE_MESSAGES) {
+    __rjv.console.push({ level, text, t: __rjv.time, repeats: 1 });
+  } else {
+    __rjv.consoleDropped++;
+  }
+}
+
+// Replaces the runtime's console, which doesn't print anywhere. Messages show up in the editor's
+// console panel.
+globalThis.console = {
+  log: (...args) => __rjv_log(0, args),
+  info: (...args) => __rjv_log(0, args),
+  debug: (...args) => __rjv_log(0, args),
+  warn: (...args) => __rjv_log(1, args),
+  error: (...args) => __rjv_log(2, args),
+};
+
+// Writes the messages logged since the last call: their level, time, repeat count and length in
+// bytes as numbers, and their text one after the other as bytes. Returns the number of messages,
+// and writes the number of messages that were dropped to the argument slot.
+function __rjv_console() {
+  const { numbers: offsets } = __rjv.layout;
+  const numbers = __rjv.numbers;
+  const messages = __rjv.console;
+
+  let offset = 0;
+  messages.forEach(({ level, text, t, repeats }, i) => {
+    const len = __rjv_encode(text, offset, offset + __RJV_MAX_CONSOLE_BYTES);
+    numbers.set([level, t, repeats, len], offsets.console + i * 4);
+    offset += len;
+  });
+  numbers[offsets.arg] = __rjv.consoleDropped;
+
+  __rjv.console = [];
+  __rjv.consoleDropped = 0;
+
+  return messages.length;
+}
+
+// Watched values are cut off at this many entries and levels, and after this many values in total.
+// Arrays of numbers are sampled down to this many values for their sparklines.
+const __RJV_WATCH_ENTRIES = 32;
+const __RJV_WATCH_DEPTH = 3;
+const __RJV_WATCH_NODES = 256;
+const __RJV_WATCH_POINTS = 1024;
+
+// Watches are a global variable followed by properties and indices, like `state.level` or
+// `steps[3]`. They're read without calling getters, so watching a value never runs the script's
+// code or changes its state.
+const __RJV_WATCH_PATH = /^([A-Za-z_$][\w$]*)((?:\.[A-Za-z_$][\w$]*|\[\d+\])*)$/;
+const __RJV_WATCH_KEY = /\.([A-Za-z_$][\w$]*)|\[(\d+)\]/g;
+
+// Reads a data property, including inherited ones. Getters are reported instead of called.
+function __rjv_watch_get(value, key) {
+  if (value === null || value === undefined) {
+    throw new TypeError(`Cannot read properties of ${value} (reading '${key}')`);
+  }
+  for (let object = Object(value); object !== null; object = Object.getPrototypeOf(object)) {
+    const descriptor = Object.getOwnPropertyDescriptor(object, key);
+    if (descriptor) {
+      if (!("value" in descriptor)) {
+        throw new TypeError(`'${key}' is a getter, which isn't called by watches`);
+      }
+      return descriptor.value;
+    }
+  }
+  return undefined;
+}
+
+// Turns an expression into a function that reads the value, or the error to show instead
+function __rjv_watch_parse(expression) {
+  const match = __RJV_WATCH_PATH.exec(expression.trim());
+  if (!match) {
+    const message = "Only variables and their properties can be watched, like state.level or steps[3]";
+    return { error: JSON.stringify({ type: "error", message }) };
+  }
+
+  // The variable is looked up in the global scope, so `let` and `const` declarations are found too.
+  // It's a plain identifier, so the lookup can't run any code.
+  const root = (0, eval)(`() => ${match[1]}`);
+  const keys = [...match[2].matchAll(__RJV_WATCH_KEY)].map(([, name, index]) => name ?? index);
+  return { read: () => keys.reduce(__rjv_watch_get, root()), json: null };
+}
+
+// Whether a value is inspected as a tree. Those are only inspected again every few frames.
+function __rjv_watch_structured(value) {
+  return (
+    typeof value === "object" &&
+    value !== null &&
+    !(ArrayBuffer.isView(value) && !(value instanceof DataView))
+  );
+}
+
+function __rjv_inspect(value, depth, budget) {
+  budget.nodes--;
+
+  if (typeof value === "number") {
+    return Number.isFinite(value)
+      ? { type: "number", value }
+      : { type: "text", text: String(value) };
+  }
+
+  // Plain arrays count as numbers if the values that are sampled are numbers
+  const typed = ArrayBuffer.isView(value) && !(value instanceof DataView);
+  if (typed || (Array.isArray(value) && value.length > 0)) {
+    const stride = Math.max(1, Math.ceil(value.length / __RJV_WATCH_POINTS));
+    const values = [];
+    let invalid = 0;
+    for (let i = 0; i < value.length; i += stride) {
+      const x = typed ? value[i] : Object.getOwnPropertyDescriptor(value, i)?.value;
+      if (typeof x !== "number") {
+        break;
+      }
+      if (!Number.isFinite(x)) {
+        invalid++;
+      }
+      values.push(Number.isFinite(x) ? x : 0);
+    }
+    if (typed || values.length === Math.ceil(value.length / stride)) {
+      return { type: "numbers", values, length: value.length, invalid };
+    }
+  }
+
+  const expand = depth < __RJV_WATCH_DEPTH && budget.nodes > 0;
+  if (Array.isArray(value)) {
+    const items = [];
+    for (let i = 0; expand && i < Math.min(value.length, __RJV_WATCH_ENTRIES); i++) {
+      if (budget.nodes <= 0) {
+        break;
+      }
+      items.push(__rjv_inspect_property(value, i, depth, budget));
+    }
+    return { type: "list", items, length: value.length };
+  }
+
+  if (value instanceof Map) {
+    const entries = [];
+    for (const [key, x] of value) {
+      if (!expand || entries.length >= __RJV_WATCH_ENTRIES || budget.nodes <= 0) {
+        break;
+      }
+      entries.push([String(key), __rjv_inspect(x, depth + 1, budget)]);
+    }
+    return { type: "object", entries, size: value.size };
+  }
+
+  if (typeof value === "object" && value !== null && !(value instanceof Error)) {
+    const keys = Object.keys(value);
+    const entries = [];
+    for (const key of keys) {
+      if (!expand || entries.length >= __RJV_WATCH_ENTRIES || budget.nodes <= 0) {
+        break;
+      }
+      entries.push([key, __rjv_inspect_property(value, key, depth, budget)]);
+    }
+    return { type: "object", entries, size: keys.length };
+  }
+
+  if (typeof value === "string") {
+    return { type: "text", text: JSON.stringify(value) };
+  }
+  if (value instanceof Error) {
+    const message = Object.getOwnPropertyDescriptor(value, "message")?.value;
+    return { type: "text", text: `[Error: ${typeof message === "string" ? message : ""}]` };
+  }
+  return { type: "text", text: __rjv_format(value) };
+}
+
+// Inspects an own property of an array or object without calling its getter
+function __rjv_inspect_property(object, key, depth, budget) {
+  const descriptor = Object.getOwnPropertyDescriptor(object, key);
+  if (descriptor && !("value" in descriptor)) {
+    return { type: "text", text: "[getter]" };
+  }
+  return __rjv_inspect(descriptor?.value, depth + 1, budget);
+}
+
+// The parsed watch expressions, set by the native side whenever they change
+let __rjv_watches = [];
+
+// Reads the watch expressions, which the native side wrote to the shared bytes one per line
+function __rjv_watch_set() {
+  const len = __rjv.numbers[__rjv.layout.numbers.arg];
+  __rjv_watches = len > 0 ? __rjv_decode(0, len).split("\n").map(__rjv_watch_parse) : [];
+}
+
+// Writes the values as JSON, which the editor parses, and returns the JSON's length. Numbers are
+// read every time, but trees are only inspected again when the argument slot is set, and otherwise
+// their last JSON is reused.
+function __rjv_watch() {
+  const deep = __rjv.numbers[__rjv.layout.numbers.arg] !== 0;
+  const budget = { nodes: __RJV_WATCH_NODES };
+  const values = __rjv_watches.map((watch) => {
+    if (watch.error) {
+      return watch.error;
+    }
+
+    let value;
+    try {
+      value = watch.read();
+    } catch (err) {
+      watch.json = null;
+      return JSON.stringify({ type: "error", message: String(err) });
+    }
+    if (!deep && watch.json !== null && __rjv_watch_structured(value)) {
+      return watch.json;
+    }
+
+    const json = JSON.stringify(__rjv_inspect(value, 0, budget));
+    watch.json = __rjv_watch_structured(value) ? json : null;
+    return json;
+  });
+
+  return __rjv_write_text(`[${values.join(",")}]`);
+}
+
+// The function timer wraps the script's top-level function declarations, and attributes every
+// millisecond tick of `Date.now()` to the function that was running. That's the clock's resolution,
+// so a single call rarely spans a tick, but on average the ticks add up to the time spent in every
+// function. Time spent outside of the script's functions doesn't count.
+function __rjv_profile_tick(profile) {
+  const now = Date.now();
+  if (now !== profile.last) {
+    const name = profile.current;
+    if (name !== null) {
+      profile.times[name] = (profile.times[name] || 0) + (now - profile.last);
+    }
+    profile.last = now;
+  }
+}
+
+// The wrapper passes `arguments` on as they are and remembers its caller in a local, so it doesn't
+// allocate per call
+function __rjv_profiled(name, f) {
+  const wrapped = function () {
+    const profile = __rjv.profile;
+    const caller = profile.current;
+    __rjv_profile_tick(profile);
+    profile.current = name;
+    try {
+      return f.apply(this, arguments);
+    } finally {
+      __rjv_profile_tick(profile);
+      profile.current = caller;
+    }
+  };
+  wrapped.__rjv_original = f;
+
+  return wrapped;
+}
+
+function __rjv_profile(enabled) {
+  const prelude = ["input", "lookahead", "output"];
+  const user = Object.keys(globalThis).filter(
+    (name) =>
+      typeof globalThis[name] === "function" && !name.startsWith("__rjv") && !prelude.includes(name)
+  );
+
+  for (const name of user) {
+    const original = globalThis[name].__rjv_original || globalThis[name];
+    globalThis[name] = enabled ? __rjv_profiled(name, original) : original;
+  }
+
+  __rjv.profile = enabled ? { current: null, last: Date.now(), times: {} } : null;
+}
+
+function __rjv_profile_on() {
+  __rjv_profile(true);
+}
+
+function __rjv_profile_off() {
+  __rjv_profile(false);
+}
+
+// Writes the times as JSON, which the editor parses and adds up, and returns the JSON's length
+function __rjv_profile_take() {
+  if (!__rjv.profile) {
+    return -1;
+  }
+
+  const times = Object.entries(__rjv.profile.times);
+  __rjv.profile.times = {};
+
+  return __rjv_write_text(JSON.stringify(times));
+}
+
+// The samples loaded in the editor, by name. The audio itself stays on the native side, these only
+// describe the samples.
+const buffers = {};
+
+// Reads the list of samples, which the native side wrote to the shared bytes as JSON
+function __rjv_buffers() {
+  const len = __rjv.numbers[__rjv.layout.numbers.arg];
+  const list = len > 0 ? JSON.parse(__rjv_decode(0, len)) : [];
+
+  for (const name of Object.keys(buffers)) {
+    delete buffers[name];
+  }
+  for (const info of list) {
+    buffers[info.name] = info;
+  }
+}
+
+// Called once before the script's own code runs, with the shared arrays and their layout as JSON.
+function __rjv_init(numbers, samples, text, layout) {
+  layout = JSON.parse(layout);
+  __rjv.layout = layout;
+  __rjv.numbers = numbers;
+  __rjv.samples = samples;
+  __rjv.text = text;
+
+  const { channels, sidechain, samples: offsets } = layout;
+  const io = channels + sidechain;
+  __rjv.inputs = samples.subarray(offsets.inputs, offsets.inputs + io);
+  __rjv.outputs = samples.subarray(offsets.outputs, offsets.outputs + channels);
+
+  __rjv.info = {
+    duration: 0,
+    sampleRate: 0,
+    oversampling: 1,
+    playing: false,
+    tempo: null,
+    beats: null,
+    channels,
+    layout: null,
+    sidechain,
+    analysis: null,
+    looper: looper.status,
+    controls: [],
+  };
+}
+
+// Called after the script's code ran, with the history buffers `length` samples each. The history
+// is kept by the plugin across recompiles, with a buffer per input channel followed by one per
+// output channel. Its length depends on the oversampling factor `process()` runs at, if the script
+// defines it.
+function __rjv_history(history, length) {
+  const { channels, sidechain } = __rjv.layout;
+  const io = channels + sidechain;
+  const buffers = (from, count) =>
+    Array.from({ length: count }, (_, ch) =>
+      history.subarray((from + ch) * length, (from + ch + 1) * length),
+    );
+  __rjv.history = {
+    length,
+    inputs: buffers(0, io),
+    outputs: buffers(io, channels),
+  };
+}
+
+// Called after `spectral()` was found, with the shared spectra. Every channel has a magnitude and a
+// phase array of `bins` values each.
+function __rjv_spectral_init(spectra, bins) {
+  __rjv.frames = Array.from({ length: __rjv.layout.channels }, (_, ch) => ({
+    magnitude: spectra.subarray(ch * 2 * bins, ch * 2 * bins + bins),
+    phase: spectra.subarray(ch * 2 * bins + bins, ch * 2 * bins + 2 * bins),
+  }));
+}
+
+// Scripts declare their latency with a top-level `latency` variable, in samples at the host's sample
+// rate. It can be at most half of the history, so `input(ch, n)` still has the other half to look
+// back at.
+function __rjv_latency() {
+  if (typeof latency !== "number") {
+    return 0;
+  }
+
+  const maxLatency = Math.floor(__rjv.layout.historyLength / 2);
+  return Math.min(Math.max(Math.round(latency) || 0, 0), maxLatency);
+}
+
+// Called once after the script's code ran. Returns the script's entry points and options.
+function __rjv_info() {
+  __rjv.latency = __rjv_latency();
+
+  return {
+    gain: typeof gain === "function",
+    voice: typeof voice === "function",
+    process: typeof process === "function",
+    block: typeof block === "function",
+    spectral:
+      typeof spectral === "function"
+        ? {
+            size: 2048,
+            hop: 512,
+            window: "hann",
+            ...(typeof spectralOptions === "object" ? spectralOptions : {}),
+          }
+        : null,
+    latency: __rjv.latency,
+    analysis: typeof analysisOptions !== "undefined" ? analysisOptions : null,
+  };
+}
+
+// The features of the current block's input, if the script defines `analysisOptions`.
+const analysis = {
+  peak: 0,
+  rms: 0,
+  envelope: 0,
+  zeroCrossingRate: 0,
+  pitch: null,
+  pitchConfidence: 0,
+  onset: false,
+};
+
+// The number of control parameters, `NUM_CONTROLS` on the native side. An XY pad takes two.
+const __RJV_MAX_CONTROLS = 8;
+const __RJV_CONTROL_TYPES = ["knob", "slider", "toggle", "xy", "button"];
+
+// The values of the controls the script declares in `controls`, by name. These are updated at the
+// start of every block.
+const ui = {};
+
+function __rjv_axis(options = {}) {
+  const min = Number(options.min ?? 0);
+  const max = Number(options.max ?? 1);
+  const fallback = options.log && min > 0 ? Math.sqrt(min * max) : (min + max) / 2;
+
+  return {
+    min,
+    max,
+    default: Number(options.default ?? fallback),
+    log: Boolean(options.log),
+    unit: String(options.unit ?? ""),
+  };
+}
+
+function __rjv_switch(options) {
+  return { min: 0, max: 1, default: options.default ? 1 : 0, log: false, unit: "" };
+}
+
+// Validates the script's `controls` and binds them to the control parameters in the order they're
+// declared. Called once after the script is compiled.
+function __rjv_controls() {
+  __rjv.controls = [];
+  if (typeof controls !== "object" || controls === null) {
+    return [];
+  }
+
+  let slot = 0;
+  for (const [name, options] of Object.entries(controls)) {
+    const type = options && options.type;
+    if (!__RJV_CONTROL_TYPES.includes(type)) {
+      console.warn(`Control "${name}" should have a type, one of ${__RJV_CONTROL_TYPES.join(", ")}`);
+      continue;
+    }
+
+    const axes =
+      type === "xy"
+        ? [__rjv_axis(options.x), __rjv_axis(options.y)]
+        : type === "toggle" || type === "button"
+        ? [__rjv_switch(options)]
+        : [__rjv_axis(options)];
+    if (slot + axes.length > __RJV_MAX_CONTROLS) {
+      console.warn(`Control "${name}" doesn't fit, there are only ${__RJV_MAX_CONTROLS} parameters`);
+      continue;
+    }
+
+    for (const axis of axes) {
+      axis.slot = slot++;
+    }
+    __rjv.controls.push({ name, label: String(options.label ?? name), type, axes });
+  }
+
+  return __rjv.controls;
+}
+
+// Updates `ui` with the control parameters' values, and calls the buttons' `onPress(t)` when
+// they're pressed
+function __rjv_ui(t, values) {
+  for (const { name, type, axes } of __rjv.controls) {
+    const x = values[axes[0].slot];
+    if (type === "xy") {
+      const value = ui[name] || (ui[name] = { x: 0, y: 0 });
+      value.x = x;
+      value.y = values[axes[1].slot];
+    } else if (type === "toggle") {
+      ui[name] = x > 0.5;
+    } else if (type === "button") {
+      const pressed = x > 0.5;
+      if (pressed && !ui[name] && typeof controls[name].onPress === "function") {
+        controls[name].onPress(t);
+      }
+      ui[name] = pressed;
+    } else {
+      ui[name] = x;
+    }
+  }
+}
+
+// Reads the block info, in the order `BlockInfo::write()` writes it. Numbers that can be missing are
+// NaN when they are.
+function __rjv_block() {
+  const { numbers: offsets, layouts } = __rjv.layout;
+  const numbers = __rjv.numbers;
+  const info = __rjv.info;
+  const t = numbers[offsets.time];
+  const optional = (x) => (Number.isNaN(x) ? null : x);
+  let i = offsets.block;
+
+  info.duration = numbers[i++];
+  info.sampleRate = numbers[i++];
+  info.oversampling = numbers[i++];
+  info.playing = numbers[i++] !== 0;
+  info.tempo = optional(numbers[i++]);
+  info.beats = optional(numbers[i++]);
+  info.channels = numbers[i++];
+  info.layout = layouts[numbers[i++]] ?? null;
+  info.sidechain = numbers[i++];
+
+  const analyzed = numbers[i++] !== 0;
+  analysis.peak = numbers[i++];
+  analysis.rms = numbers[i++];
+  analysis.envelope = numbers[i++];
+  analysis.zeroCrossingRate = numbers[i++];
+  analysis.pitch = optional(numbers[i++]);
+  analysis.pitchConfidence = numbers[i++];
+  analysis.onset = numbers[i++] !== 0;
+  info.analysis = analyzed ? analysis : null;
+
+  const status = looper.status;
+  status.state = __RJV_LOOPER_STATES[numbers[i++]];
+  status.position = numbers[i++];
+  status.length = numbers[i++];
+  status.speed = numbers[i++];
+  status.reverse = numbers[i++] !== 0;
+  status.level = numbers[i++];
+  status.pending = numbers[i++] !== 0;
+
+  for (let slot = 0; slot < __RJV_MAX_CONTROLS; slot++) {
+    info.controls[slot] = numbers[i++];
+  }
+
+  __rjv.time = t;
+  __rjv.oversampling = info.oversampling;
+  __rjv.duration = info.duration;
+  __rjv_ui(t, info.controls);
+  if (typeof block === "function") {
+    block(t, info);
+  }
+}
+
+function __rjv_gain() {
+  const t = __rjv.numbers[0];
+  __rjv.time = t;
+  return gain(t);
+}
+
+// Scripts may return either an array with a sample per output channel, or a single number. The
+// plugin records the inputs and outputs in the history. Returns the number of outputs.
+function __rjv_process() {
+  const t = __rjv.numbers[0];
+  __rjv.time = t;
+
+  const out = process(t, __rjv.inputs);
+  const outputs = __rjv.outputs;
+  let count;
+  if (typeof out === "object" && out !== null && typeof out.length === "number") {
+    count = Math.min(out.length, outputs.length);
+    for (let ch = 0; ch < count; ch++) {
+      outputs[ch] = Number(out[ch]) || 0;
+    }
+  } else {
+    count = 1;
+    outputs[0] = Number(out) || 0;
+  }
+
+  return count;
+}
+
+// Scripts may modify the frames in place, or return new ones. Returned frames are copied into the
+// shared spectra when they have the right sizes.
+function __rjv_spectral() {
+  const t = __rjv.numbers[0];
+  __rjv.time = t;
+
+  const frames = __rjv.frames;
+  const out = spectral(t, frames);
+  if (Array.isArray(out) && out !== frames) {
+    frames.forEach((frame, ch) => {
+      const { magnitude, phase } = out[ch] || {};
+      if (magnitude && magnitude.length === frame.magnitude.length) {
+        frame.magnitude.set(magnitude);
+      }
+      if (phase && phase.length === frame.phase.length) {
+        frame.phase.set(phase);
+      }
+    });
+  }
+}
+
+// Renders a sample for every voice. The voices are written as rows of their slot, whether they're
+// new, their age, note, velocity and release time, which is NaN while the note is held. The
+// argument slot has the number of voices.
+function __rjv_voices() {
+  const { numbers: offsets, samples: sampleOffsets } = __rjv.layout;
+  const numbers = __rjv.numbers;
+  const samples = __rjv.samples;
+  const t = numbers[0];
+  const count = numbers[offsets.arg];
+  __rjv.time = t;
+
+  for (let i = 0; i < count; i++) {
+    const row = offsets.voices + i * 6;
+    const slot = numbers[row];
+    if (numbers[row + 1] !== 0 || !__rjv.voices[slot]) {
+      __rjv.voices[slot] = {};
+    }
+
+    const released = numbers[row + 5];
+    const state = __rjv.voices[slot];
+    state.time = t;
+    state.gate = Number.isNaN(released);
+    state.released = state.gate ? null : released;
+
+    samples[sampleOffsets.voiceOutputs + i] =
+      Number(voice(numbers[row + 2], numbers[row + 3], numbers[row + 4], state)) || 0;
+    samples[sampleOffsets.voiceDone + i] = state.done ? 1 : 0;
+  }
+}
+
+// Writes the MIDI events scheduled before the end time in the argument slot, in order, as rows of
+// their type, time, channel and two data values. Later events are kept for the next blocks. Returns
+// the number of events.
+function __rjv_midi() {
+  const { numbers: offsets, maxEvents } = __rjv.layout;
+  const numbers = __rjv.numbers;
+  const end = numbers[offsets.arg];
+  if (__rjv.midi.length === 0) {
+    return 0;
+  }
+
+  const due = __rjv.midi.filter((e) => e.at < end).sort((a, b) => a.at - b.at);
+  const kept = __rjv.midi.filter((e) => e.at >= end);
+  // Events that don't fit are sent in the next block
+  __rjv.midi = [...due.slice(maxEvents), ...kept];
+
+  const count = Math.min(due.length, maxEvents);
+  for (let i = 0; i < count; i++) {
+    const { type, at, channel, a, b } = due[i];
+    numbers.set([type, at, channel, a, b], offsets.midi + i * 5);
+  }
+
+  return count;
+}
+
+// Schedules a granular node's grains for the current block by calling its `onGrain(grain, t)`
+// callback for every grain. The callback can change the grain's fields, or return `false` to skip
+// it. The grains are written as rows of the node's ID and the grain's fields, starting at row
+// `count`. Returns the new number of grains.
+function __rjv_grains(node, count) {
+  const { numbers: offsets, maxGrains } = __rjv.layout;
+  const { density, position, size, pitch, pan, jitter, spread, onGrain } = node.params;
+  if (!(density > 0)) {
+    return count;
+  }
+
+  let at = node.nextGrain || 0;
+  for (; at < __rjv.duration; at += 1 / density) {
+    const grain = {
+      at,
+      position: position + jitter * (Math.random() * 2 - 1),
+      size,
+      pitch,
+      pan: pan + spread * (Math.random() * 2 - 1),
+      gain: 1,
+    };
+    if (onGrain(grain, __rjv.time + at) !== false && count < maxGrains) {
+      const g = [node.id, grain.at, grain.position, grain.size, grain.pitch, grain.pan, grain.gain];
+      __rjv.numbers.set(g.map((x) => Number(x) || 0), offsets.grains + count * 7);
+      count++;
+    }
+  }
+  node.nextGrain = at - __rjv.duration;
+
+  return count;
+}
+
+// Hands the DSP changes to the native side. Parameters are already in the node table. The specs of
+// new nodes, and of nodes whose sample changed, are written to the shared bytes as JSON when the
+// argument slot is set. Otherwise the native side can't take them right now, and they're sent in a
+// later block. Writes the number of grains scheduled by `onGrain` callbacks to the argument slot,
+// and returns the length of the JSON.
+function __rjv_dsp() {
+  const { numbers: offsets } = __rjv.layout;
+  const numbers = __rjv.numbers;
+  const accepting = numbers[offsets.arg] !== 0;
+
+  let grains = 0;
+  for (const node of __rjv.nodes) {
+    if (node.kind === "granular" && typeof node.params.onGrain === "function") {
+      grains = __rjv_grains(node, grains);
+    }
+  }
+  numbers[offsets.nodeCount] = __rjv.nodes.length;
+  numbers[offsets.arg] = grains;
+
+  if (__rjv.specs.size === 0 || !accepting) {
+    return 0;
+  }
+
+  // Specs that don't fit are sent in a later block
+  const len = __rjv_write_text(JSON.stringify([...__rjv.specs].map(__rjv_spec)));
+  if (len < 0) {
+    return 0;
+  }
+  __rjv.specs.clear();
+
+  return len;
+}
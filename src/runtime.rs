@@ -0,0 +1,396 @@
+//! A thin wrapper around a V8 isolate with a single context, which is what a compiled script runs
+//! in. Arguments and results are passed through typed arrays whose memory is shared between V8 and
+//! the plugin, see [`Shared`], so calling into the script doesn't serialize anything or allocate on
+//! the plugin's side.
+//!
+//! The isolate is created on whichever thread compiles the script, and then used from the audio
+//! thread and, for the plot, from the plot thread. It's entered for every call and exited again
+//! afterwards, so it's never entered on two threads at once.
+
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+/// The most memory a script's heap can use. Scripts that use more than this are terminated by V8.
+const HEAP_LIMIT: usize = 256 << 20;
+
+static INIT: Once = Once::new();
+
+fn initialize_v8() {
+    INIT.call_once(|| {
+        v8::V8::initialize_platform(v8::new_default_platform(0, false).make_shared());
+        v8::V8::initialize();
+    });
+}
+
+/// Numbers that can be shared with the script through a typed array.
+pub trait Element: Copy {
+    /// Creates the typed array for `len` elements, starting at the start of `buffer`.
+    fn typed_array<'s>(
+        scope: &mut v8::HandleScope<'s>,
+        buffer: v8::Local<'s, v8::ArrayBuffer>,
+        len: usize,
+    ) -> Option<v8::Local<'s, v8::Value>>;
+}
+
+impl Element for f64 {
+    fn typed_array<'s>(
+        scope: &mut v8::HandleScope<'s>,
+        buffer: v8::Local<'s, v8::ArrayBuffer>,
+        len: usize,
+    ) -> Option<v8::Local<'s, v8::Value>> {
+        v8::Float64Array::new(scope, buffer, 0, len).map(Into::into)
+    }
+}
+
+impl Element for f32 {
+    fn typed_array<'s>(
+        scope: &mut v8::HandleScope<'s>,
+        buffer: v8::Local<'s, v8::ArrayBuffer>,
+        len: usize,
+    ) -> Option<v8::Local<'s, v8::Value>> {
+        v8::Float32Array::new(scope, buffer, 0, len).map(Into::into)
+    }
+}
+
+impl Element for u8 {
+    fn typed_array<'s>(
+        scope: &mut v8::HandleScope<'s>,
+        buffer: v8::Local<'s, v8::ArrayBuffer>,
+        len: usize,
+    ) -> Option<v8::Local<'s, v8::Value>> {
+        v8::Uint8Array::new(scope, buffer, 0, len).map(Into::into)
+    }
+}
+
+/// A buffer that's shared between the plugin and the script. V8 owns the memory, and the plugin
+/// reads and writes it directly while the script isn't running.
+pub struct Shared<T> {
+    store: v8::SharedRef<v8::BackingStore>,
+    ptr: *mut T,
+    len: usize,
+}
+
+impl<T: Element> Shared<T> {
+    /// Allocates a buffer of `len` zeroed elements on the plugin's side. Unlike the buffers from
+    /// [`Runtime::share()`], this doesn't belong to an isolate, so it can outlive the runtime and be
+    /// shared with the next one.
+    pub fn new(len: usize) -> Self {
+        initialize_v8();
+
+        let layout = Layout::array::<T>(len.max(1)).expect("the buffer is too large");
+        // SAFETY: The layout isn't empty, and zeroed memory is a valid value for every element
+        //         type. V8 hands the memory back to `dealloc_shared()` with the same size, and with
+        //         the alignment passed as its deleter data.
+        let store = unsafe {
+            let ptr = alloc_zeroed(layout);
+            if ptr.is_null() {
+                handle_alloc_error(layout);
+            }
+            v8::ArrayBuffer::new_backing_store_from_ptr(
+                ptr.cast(),
+                layout.size(),
+                dealloc_shared,
+                layout.align() as *mut c_void,
+            )
+        }
+        .make_shared();
+        let bytes: &[Cell<u8>] = &store;
+        let ptr = bytes.as_ptr() as *mut T;
+
+        Shared { store, ptr, len }
+    }
+
+    /// The typed array the script sees.
+    pub fn typed_array<'s>(
+        &self,
+        scope: &mut v8::HandleScope<'s>,
+    ) -> Option<v8::Local<'s, v8::Value>> {
+        let buffer = v8::ArrayBuffer::with_backing_store(scope, &self.store);
+        T::typed_array(scope, buffer, self.len)
+    }
+
+    /// A handle to the same memory that can only be passed on to a script, so a script compiled on
+    /// another thread can share the buffer too. The plugin keeps reading and writing the buffer
+    /// through `self` in the meantime.
+    pub fn view(&self) -> SharedView<T> {
+        SharedView {
+            store: self.store.clone(),
+            len: self.len,
+            element: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> &[T] {
+        // SAFETY: The backing store lives as long as `self`, is at least `len` elements long and
+        //         suitably aligned, and the script can't touch it while `self` is borrowed because
+        //         calling into the script needs the runtime to be borrowed mutably
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn get_mut(&mut self) -> &mut [T] {
+        // SAFETY: See above
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+/// A handle to a [`Shared`] buffer from another thread, see [`Shared::view()`].
+pub struct SharedView<T> {
+    store: v8::SharedRef<v8::BackingStore>,
+    len: usize,
+    element: PhantomData<T>,
+}
+
+impl<T: Element> SharedView<T> {
+    /// The typed array the script sees.
+    pub fn typed_array<'s>(
+        &self,
+        scope: &mut v8::HandleScope<'s>,
+    ) -> Option<v8::Local<'s, v8::Value>> {
+        let buffer = v8::ArrayBuffer::with_backing_store(scope, &self.store);
+        T::typed_array(scope, buffer, self.len)
+    }
+}
+
+/// Frees the memory of a buffer from [`Shared::new()`], once V8 and the plugin are both done with
+/// it.
+unsafe extern "C" fn dealloc_shared(data: *mut c_void, byte_length: usize, align: *mut c_void) {
+    dealloc(
+        data.cast(),
+        Layout::from_size_align_unchecked(byte_length, align as usize),
+    );
+}
+
+/// The script's global functions the plugin calls, looked up once after compiling.
+pub struct Function(v8::Global<v8::Function>);
+
+/// An isolate with the script's context.
+pub struct Runtime {
+    // The handles need to be dropped before the isolate they belong to
+    context: v8::Global<v8::Context>,
+    isolate: v8::OwnedIsolate,
+}
+
+// SAFETY: The isolate is only ever entered by the thread that holds a mutable reference to the
+//         runtime, and exited again before that reference is released. The shared buffers are
+//         owned by the same thread as the runtime they belong to.
+unsafe impl Send for Runtime {}
+unsafe impl<T: Send> Send for Shared<T> {}
+// SAFETY: A view never touches the memory itself, it only hands it to the script it's passed to
+unsafe impl<T: Send> Send for SharedView<T> {}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        // Dropping the isolate exits it first
+        unsafe { self.isolate.enter() };
+    }
+}
+
+impl Runtime {
+    pub fn new() -> Self {
+        initialize_v8();
+
+        let mut isolate = v8::Isolate::new(v8::CreateParams::default().heap_limits(0, HEAP_LIMIT));
+        let context = {
+            let scope = &mut v8::HandleScope::new(&mut isolate);
+            let context = v8::Context::new(scope);
+            v8::Global::new(scope, context)
+        };
+        // Creating the isolate entered it on this thread
+        unsafe { isolate.exit() };
+
+        Self { context, isolate }
+    }
+
+    /// Enters the isolate and the context, and runs `f` with a scope that catches exceptions. Every
+    /// call into the script goes through here, so the scopes V8 needs are only ever allocated the
+    /// first time, which is while compiling.
+    pub fn with_scope<R>(&mut self, f: impl FnOnce(&mut v8::TryCatch<v8::HandleScope>) -> R) -> R {
+        unsafe { self.isolate.enter() };
+        let result = {
+            let scope = &mut v8::HandleScope::with_context(&mut self.isolate, &self.context);
+            let scope = &mut v8::TryCatch::new(scope);
+            f(scope)
+        };
+        unsafe { self.isolate.exit() };
+
+        result
+    }
+
+    /// Runs `code` as a script in the global scope.
+    pub fn run(&mut self, code: &str) -> Result<(), String> {
+        self.with_scope(|scope| {
+            let result = v8::String::new(scope, code)
+                .and_then(|code| v8::Script::compile(scope, code, None))
+                .and_then(|script| script.run(scope));
+
+            match result {
+                Some(_) => Ok(()),
+                None => Err(exception(scope)),
+            }
+        })
+    }
+
+    /// Allocates a buffer of `len` elements that's shared with the script.
+    pub fn share<T: Element>(&mut self, len: usize) -> Shared<T> {
+        unsafe { self.isolate.enter() };
+        let store = v8::ArrayBuffer::new_backing_store(
+            &mut self.isolate,
+            len.max(1) * std::mem::size_of::<T>(),
+        )
+        .make_shared();
+        unsafe { self.isolate.exit() };
+
+        // New backing stores are zeroed
+        let bytes: &[Cell<u8>] = &store;
+        let ptr = bytes.as_ptr() as *mut T;
+
+        Shared { store, ptr, len }
+    }
+
+    /// Looks up a global function by name.
+    pub fn function(&mut self, name: &str) -> Option<Function> {
+        self.with_scope(|scope| {
+            let key = v8::String::new(scope, name)?;
+            let global = scope.get_current_context().global(scope);
+            let value = global.get(scope, key.into())?;
+            let function = v8::Local::<v8::Function>::try_from(value).ok()?;
+
+            Some(Function(v8::Global::new(scope, function)))
+        })
+    }
+
+    /// Calls a function without arguments, and returns its result as a number. This is `None` if
+    /// the function threw. Results that aren't numbers are NaN.
+    pub fn call(&mut self, function: &Function) -> Option<f64> {
+        self.with_scope(|scope| {
+            let function = v8::Local::new(scope, &function.0);
+            let recv = v8::undefined(scope).into();
+            function
+                .call(scope, recv, &[])
+                .and_then(|result| result.number_value(scope))
+        })
+    }
+
+    /// Calls a function with JSON and shared buffers as its arguments, and returns its result as
+    /// JSON. This allocates, so it's only used while compiling.
+    pub fn call_json(&mut self, name: &str, args: &[Arg]) -> Result<String, String> {
+        self.with_scope(|scope| {
+            let key = v8::String::new(scope, name).unwrap();
+            let global = scope.get_current_context().global(scope);
+            let function = global
+                .get(scope, key.into())
+                .and_then(|value| v8::Local::<v8::Function>::try_from(value).ok())
+                .ok_or_else(|| format!("{name} is not defined"))?;
+
+            let args: Vec<v8::Local<v8::Value>> = args
+                .iter()
+                .filter_map(|arg| match arg {
+                    Arg::Json(json) => v8::String::new(scope, json).map(Into::into),
+                    Arg::Number(x) => Some(v8::Number::new(scope, *x).into()),
+                    Arg::Numbers(shared) => shared.typed_array(scope),
+                    Arg::Samples(shared) => shared.typed_array(scope),
+                    Arg::SamplesView(view) => view.typed_array(scope),
+                    Arg::Bytes(shared) => shared.typed_array(scope),
+                })
+                .collect();
+            let recv = v8::undefined(scope).into();
+            let result = function
+                .call(scope, recv, &args)
+                .ok_or_else(|| exception(scope))?;
+            if result.is_undefined() {
+                return Ok("null".to_string());
+            }
+
+            v8::json::stringify(scope, result)
+                .map(|json| json.to_rust_string_lossy(scope))
+                .ok_or_else(|| exception(scope))
+        })
+    }
+}
+
+/// Stops scripts that run for too long, from another thread. Runtimes attach themselves to it when
+/// they're created, and once it expired every script they run is terminated, including the ones
+/// started afterwards.
+#[derive(Default)]
+pub struct Watchdog {
+    isolate: Mutex<Option<v8::IsolateHandle>>,
+    expired: AtomicBool,
+}
+
+impl Watchdog {
+    /// Runs `f` with a fresh watchdog that expires after `timeout`, from a timer thread, so a script
+    /// with an endless loop doesn't block the calling thread forever. Returns `None` if the watchdog
+    /// expired, in which case whatever `f` returned is dropped.
+    pub fn run<R>(timeout: Duration, f: impl FnOnce(&Arc<Watchdog>) -> R) -> Option<R> {
+        let watchdog = Arc::new(Watchdog::default());
+        let (done, finished) = mpsc::channel::<()>();
+        let timer = thread::spawn({
+            let watchdog = watchdog.clone();
+            move || {
+                if finished.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                    watchdog.expire();
+                }
+            }
+        });
+
+        let result = f(&watchdog);
+        drop(done);
+        let _ = timer.join();
+
+        if watchdog.expired() {
+            None
+        } else {
+            Some(result)
+        }
+    }
+
+    /// Makes the watchdog terminate `runtime`'s scripts instead of those of the last runtime.
+    pub fn attach(&self, runtime: &mut Runtime) {
+        let handle = runtime.isolate.thread_safe_handle();
+        let mut isolate = self.isolate.lock().unwrap();
+        if self.expired() {
+            handle.terminate_execution();
+        }
+        *isolate = Some(handle);
+    }
+
+    /// Terminates the script that's running, if any.
+    pub fn expire(&self) {
+        // The flag is set while holding the lock so a runtime that's being attached can't miss it
+        let isolate = self.isolate.lock().unwrap();
+        self.expired.store(true, Ordering::Relaxed);
+        if let Some(isolate) = &*isolate {
+            isolate.terminate_execution();
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+}
+
+/// An argument for [`Runtime::call_json()`].
+pub enum Arg<'a> {
+    /// A string, which the script parses as JSON.
+    Json(&'a str),
+    Number(f64),
+    Numbers(&'a Shared<f64>),
+    Samples(&'a Shared<f32>),
+    SamplesView(&'a SharedView<f32>),
+    Bytes(&'a Shared<u8>),
+}
+
+/// Formats the exception that was caught, like `ReferenceError: foo is not defined`.
+fn exception(scope: &mut v8::TryCatch<v8::HandleScope>) -> String {
+    match scope.exception() {
+        Some(exception) => exception.to_rust_string_lossy(scope),
+        None => "The script was terminated".to_string(),
+    }
+}
@@ -0,0 +1,442 @@
+//! Audio files that are loaded through the editor and that scripts can use by name. Files are
+//! decoded and resampled to the session's sample rate on a background thread. The plugin state
+//! only stores where a sample came from, or the decoded audio itself if the sample is embedded.
+
+use atomic_float::AtomicF32;
+use rubato::{FftFixedIn, Resampler};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// The number of input frames the resampler processes at a time.
+const RESAMPLE_CHUNK_SIZE: usize = 1024;
+
+/// A sample as it's stored in the plugin state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SampleSource {
+    pub name: String,
+    pub path: PathBuf,
+    /// The decoded audio, if the sample is stored in the plugin state instead of being loaded from
+    /// `path`.
+    pub embedded: Option<DecodedAudio>,
+}
+
+/// Audio at the file's original sample rate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecodedAudio {
+    pub sample_rate: u32,
+    #[serde(with = "base64_channels")]
+    pub channels: Vec<Vec<f32>>,
+}
+
+/// Embedded audio is stored as a base64 string of little-endian `f32` samples per channel, which is
+/// a lot smaller than an array of numbers and doesn't lose any precision. States saved before then
+/// stored arrays of numbers, which can still be read.
+mod base64_channels {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::ser::SerializeSeq;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Channel {
+        Base64(String),
+        Samples(Vec<f32>),
+    }
+
+    pub fn serialize<S: Serializer>(
+        channels: &[Vec<f32>],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(channels.len()))?;
+        for channel in channels {
+            let bytes: Vec<u8> = channel.iter().flat_map(|x| x.to_le_bytes()).collect();
+            seq.serialize_element(&STANDARD.encode(bytes))?;
+        }
+
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<f32>>, D::Error> {
+        Vec::<Channel>::deserialize(deserializer)?
+            .into_iter()
+            .map(|channel| match channel {
+                Channel::Base64(encoded) => {
+                    let bytes = STANDARD.decode(encoded).map_err(D::Error::custom)?;
+                    if bytes.len() % 4 != 0 {
+                        return Err(D::Error::custom(
+                            "Embedded audio isn't a whole number of samples",
+                        ));
+                    }
+
+                    Ok(bytes
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect())
+                }
+                Channel::Samples(samples) => Ok(samples),
+            })
+            .collect()
+    }
+}
+
+/// A sample that has been resampled to the session's sample rate.
+#[derive(Debug, Clone)]
+pub struct SampleData {
+    pub sample_rate: f32,
+    pub channels: Vec<Vec<f32>>,
+}
+
+impl SampleData {
+    /// The length in samples.
+    pub fn len(&self) -> usize {
+        self.channels.first().map(Vec::len).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads a channel at a fractional position, interpolating linearly between samples. Channels
+    /// wrap around, so mono samples can be used with any layout. Positions outside of the sample
+    /// read as silence.
+    pub fn read(&self, channel: usize, position: f64) -> f32 {
+        if self.channels.is_empty() || position < 0.0 {
+            return 0.0;
+        }
+
+        let samples = &self.channels[channel % self.channels.len()];
+        let whole = position as usize;
+        let frac = (position - whole as f64) as f32;
+        let a = samples.get(whole).copied().unwrap_or(0.0);
+        let b = samples.get(whole + 1).copied().unwrap_or(0.0);
+
+        a + (b - a) * frac
+    }
+}
+
+/// Describes a loaded sample to the script.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SampleInfo {
+    pub name: String,
+    pub length: usize,
+    pub duration: f32,
+    pub sample_rate: f32,
+    pub channels: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum SampleStatus {
+    Loading,
+    Loaded(Arc<SampleData>),
+    Failed(String),
+}
+
+/// Work that's done on the background thread.
+#[derive(Debug, Clone)]
+pub enum SampleTask {
+    /// Loads a file, and adds it to the plugin state under `name`.
+    Load {
+        name: String,
+        path: PathBuf,
+        embed: bool,
+    },
+    /// Loads all samples from the plugin state again, for instance after the sample rate changed.
+    Reload,
+}
+
+/// The loaded samples, shared between the editor, the background thread and the audio thread.
+/// The audio thread only ever uses `try_read()`, and checks the generation counter to find out
+/// whether anything changed.
+pub struct SampleBank {
+    sample_rate: AtomicF32,
+    samples: RwLock<Vec<(String, SampleStatus)>>,
+    /// The [`SampleInfo`] of every loaded sample as JSON, which is what scripts get. This is
+    /// updated whenever the samples change, so the audio thread only has to copy it.
+    index: RwLock<Vec<u8>>,
+    generation: AtomicU32,
+}
+
+impl Default for SampleBank {
+    fn default() -> Self {
+        Self {
+            sample_rate: AtomicF32::new(44_100.0),
+            samples: RwLock::new(Vec::new()),
+            index: RwLock::new(b"[]".to_vec()),
+            generation: AtomicU32::new(0),
+        }
+    }
+}
+
+impl SampleBank {
+    pub fn set_sample_rate(&self, sample_rate: f32) {
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    /// Incremented every time a sample is added, removed or finishes loading.
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Looks up a loaded sample. Returns `None` if the bank is being written to.
+    pub fn get(&self, name: &str) -> Option<Arc<SampleData>> {
+        let samples = self.samples.try_read().ok()?;
+        samples.iter().find_map(|(n, status)| match status {
+            SampleStatus::Loaded(data) if n == name => Some(data.clone()),
+            _ => None,
+        })
+    }
+
+    /// Passes the descriptions of all loaded samples to `f`, as a JSON array of [`SampleInfo`]s.
+    /// Returns `None` if the bank is being written to.
+    pub fn index<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let index = self.index.try_read().ok()?;
+        Some(f(&index))
+    }
+
+    /// The names and statuses of all samples, for the editor.
+    pub fn statuses(&self) -> Vec<(String, SampleStatus)> {
+        self.samples.read().unwrap().clone()
+    }
+
+    pub fn set(&self, name: &str, status: SampleStatus) {
+        self.update(
+            |samples| match samples.iter_mut().find(|(n, _)| n == name) {
+                Some((_, s)) => *s = status,
+                None => samples.push((name.to_string(), status)),
+            },
+        );
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.update(|samples| samples.retain(|(n, _)| n != name));
+    }
+
+    /// Changes the samples, and then updates the index and the generation to match.
+    fn update(&self, f: impl FnOnce(&mut Vec<(String, SampleStatus)>)) {
+        let mut samples = self.samples.write().unwrap();
+        f(&mut samples);
+
+        let info: Vec<SampleInfo> = samples
+            .iter()
+            .filter_map(|(name, status)| match status {
+                SampleStatus::Loaded(data) => Some(SampleInfo {
+                    name: name.clone(),
+                    length: data.len(),
+                    duration: data.len() as f32 / data.sample_rate,
+                    sample_rate: data.sample_rate,
+                    channels: data.channels.len(),
+                }),
+                _ => None,
+            })
+            .collect();
+        *self.index.write().unwrap() = serde_json::to_vec(&info).unwrap_or_else(|_| b"[]".to_vec());
+        drop(samples);
+
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Runs a [`SampleTask`]. This blocks, so it should only be called from the background thread.
+    pub fn run(&self, task: SampleTask, sources: &RwLock<Vec<SampleSource>>) {
+        match task {
+            SampleTask::Load { name, path, embed } => {
+                self.set(&name, SampleStatus::Loading);
+
+                match decode(&path) {
+                    Ok(audio) => {
+                        let source = SampleSource {
+                            name: name.clone(),
+                            path,
+                            embedded: embed.then(|| audio.clone()),
+                        };
+
+                        let mut sources = sources.write().unwrap();
+                        sources.retain(|s| s.name != name);
+                        sources.push(source);
+                        drop(sources);
+
+                        self.set(&name, self.prepare(audio));
+                    }
+                    Err(err) => self.set(&name, SampleStatus::Failed(err)),
+                }
+            }
+            SampleTask::Reload => {
+                let sources = sources.read().unwrap().clone();
+                self.update(|samples| {
+                    samples.retain(|(name, _)| sources.iter().any(|s| &s.name == name))
+                });
+
+                for source in &sources {
+                    self.set(&source.name, SampleStatus::Loading);
+                }
+
+                for source in sources {
+                    let audio = match source.embedded {
+                        Some(audio) => Ok(audio),
+                        None => decode(&source.path),
+                    };
+
+                    let status = match audio {
+                        Ok(audio) => self.prepare(audio),
+                        Err(err) => SampleStatus::Failed(err),
+                    };
+                    self.set(&source.name, status);
+                }
+            }
+        }
+    }
+
+    /// Resamples decoded audio to the session's sample rate.
+    fn prepare(&self, audio: DecodedAudio) -> SampleStatus {
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        match resample(audio, sample_rate) {
+            Ok(channels) => SampleStatus::Loaded(Arc::new(SampleData {
+                sample_rate,
+                channels,
+            })),
+            Err(err) => SampleStatus::Failed(err),
+        }
+    }
+}
+
+/// Decodes a WAV, FLAC or AIFF file.
+pub fn decode(path: &Path) -> Result<DecodedAudio, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|err| err.to_string())?
+        .format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| "The file doesn't contain any audio".to_string())?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or_else(|| "The file's sample rate is unknown".to_string())?;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|err| err.to_string())?;
+
+    let mut channels: Vec<Vec<f32>> = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break
+            }
+            Err(err) => return Err(err.to_string()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Corrupt packets are skipped
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(err) => return Err(err.to_string()),
+        };
+
+        let spec = *decoded.spec();
+        let num_channels = spec.channels.count();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+
+        channels.resize_with(num_channels, Vec::new);
+        for frame in buffer.samples().chunks_exact(num_channels) {
+            for (channel, sample) in channels.iter_mut().zip(frame) {
+                channel.push(*sample);
+            }
+        }
+    }
+
+    if channels.is_empty() {
+        return Err("The file doesn't contain any audio".to_string());
+    }
+
+    Ok(DecodedAudio {
+        sample_rate,
+        channels,
+    })
+}
+
+fn resample(audio: DecodedAudio, sample_rate: f32) -> Result<Vec<Vec<f32>>, String> {
+    let from = audio.sample_rate as usize;
+    let to = sample_rate.round() as usize;
+    if from == to {
+        return Ok(audio.channels);
+    }
+
+    let mut resampler =
+        FftFixedIn::<f32>::new(from, to, RESAMPLE_CHUNK_SIZE, 2, audio.channels.len())
+            .map_err(|err| err.to_string())?;
+
+    let len = audio.channels[0].len();
+    let delay = resampler.output_delay();
+    let expected_len = (len as f64 * to as f64 / from as f64).round() as usize;
+    let mut channels = vec![Vec::with_capacity(expected_len + delay); audio.channels.len()];
+
+    let mut pos = 0;
+    while pos < len {
+        let chunk_size = resampler.input_frames_next();
+        let end = (pos + chunk_size).min(len);
+        let chunk: Vec<&[f32]> = audio.channels.iter().map(|c| &c[pos..end]).collect();
+        let output = if end - pos == chunk_size {
+            resampler.process(&chunk, None)
+        } else {
+            resampler.process_partial(Some(&chunk), None)
+        }
+        .map_err(|err| err.to_string())?;
+
+        for (channel, output) in channels.iter_mut().zip(output) {
+            channel.extend(output);
+        }
+        pos = end;
+    }
+
+    // The resampler delays its output, so it needs to be flushed to get the end of the sample
+    while channels[0].len() < expected_len + delay {
+        let output = resampler
+            .process_partial::<&[f32]>(None, None)
+            .map_err(|err| err.to_string())?;
+        for (channel, output) in channels.iter_mut().zip(output) {
+            channel.extend(output);
+        }
+    }
+
+    for channel in &mut channels {
+        channel.drain(..delay);
+        channel.truncate(expected_len);
+    }
+
+    Ok(channels)
+}
@@ -0,0 +1,201 @@
+//! The editor's oscilloscope. The audio thread writes the input and output, mixed down to mono, to
+//! a lock-free ring buffer while the editor is open, and the editor reads the most recent window
+//! from it. A window that's being overwritten while it's read just shows a glitch for a frame.
+
+use atomic_float::AtomicF32;
+use nih_plug_egui::egui::{self, Color32, Sense, Stroke};
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+/// The ring buffer's length in samples. The scope can show up to half of this.
+const CAPACITY: usize = 1 << 18;
+
+/// The longest window the scope shows, in milliseconds.
+pub const MAX_WINDOW_MS: f32 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeTrigger {
+    /// Shows the most recent samples.
+    Free,
+    /// Starts the window where the output crosses zero going up.
+    Rising,
+    /// Starts the window on a beat while the host is playing.
+    Beat,
+}
+
+impl ScopeTrigger {
+    pub const ALL: [ScopeTrigger; 3] =
+        [ScopeTrigger::Free, ScopeTrigger::Rising, ScopeTrigger::Beat];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ScopeTrigger::Free => "Free",
+            ScopeTrigger::Rising => "Rising edge",
+            ScopeTrigger::Beat => "Beat",
+        }
+    }
+}
+
+pub struct Scope {
+    input: Vec<AtomicF32>,
+    output: Vec<AtomicF32>,
+    /// The number of samples written so far. The next sample goes to `written % CAPACITY`.
+    written: AtomicUsize,
+    /// The values of `written` at the last two beats, most recent first, or `usize::MAX`.
+    beats: [AtomicUsize; 2],
+    /// The index of the beat the last sample was in, or `i64::MIN` if the host isn't playing.
+    last_beat: AtomicI64,
+    sample_rate: AtomicF32,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self {
+            input: (0..CAPACITY).map(|_| AtomicF32::new(0.0)).collect(),
+            output: (0..CAPACITY).map(|_| AtomicF32::new(0.0)).collect(),
+            written: AtomicUsize::new(0),
+            beats: [AtomicUsize::new(usize::MAX), AtomicUsize::new(usize::MAX)],
+            last_beat: AtomicI64::new(i64::MIN),
+            sample_rate: AtomicF32::new(1.0),
+        }
+    }
+}
+
+impl Scope {
+    pub fn set_sample_rate(&self, sample_rate: f32) {
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    /// Writes a single input and output sample. `beats` is the host's position in quarter notes
+    /// while it's playing. Only the audio thread writes to the scope.
+    pub fn push(&self, input: f32, output: f32, beats: Option<f64>) {
+        let written = self.written.load(Ordering::Relaxed);
+        self.input[written % CAPACITY].store(input, Ordering::Relaxed);
+        self.output[written % CAPACITY].store(output, Ordering::Relaxed);
+
+        let beat = beats.map(|beats| beats.floor() as i64).unwrap_or(i64::MIN);
+        if beat != i64::MIN && beat != self.last_beat.load(Ordering::Relaxed) {
+            let previous = self.beats[0].load(Ordering::Relaxed);
+            self.beats[1].store(previous, Ordering::Relaxed);
+            self.beats[0].store(written, Ordering::Relaxed);
+        }
+        self.last_beat.store(beat, Ordering::Relaxed);
+
+        self.written.store(written + 1, Ordering::Release);
+    }
+
+    /// Reads a window of `window_ms` milliseconds into `input` and `output`, starting where the
+    /// trigger says. Falls back to the most recent samples if the trigger doesn't find a start.
+    pub fn read(
+        &self,
+        window_ms: f32,
+        trigger: ScopeTrigger,
+        input: &mut Vec<f32>,
+        output: &mut Vec<f32>,
+    ) {
+        let written = self.written.load(Ordering::Acquire);
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        let len = ((window_ms.clamp(1.0, MAX_WINDOW_MS) / 1000.0 * sample_rate) as usize)
+            .clamp(2, CAPACITY / 2)
+            .min(written);
+        let latest = written - len;
+        // Samples older than this may be overwritten while they're read
+        let oldest = written.saturating_sub(CAPACITY / 2);
+
+        let start = match trigger {
+            ScopeTrigger::Free => None,
+            // Searches backwards for up to one window
+            ScopeTrigger::Rising => (oldest.max(latest.saturating_sub(len)) + 1..=latest)
+                .rev()
+                .find(|&i| {
+                    let previous = self.output[(i - 1) % CAPACITY].load(Ordering::Relaxed);
+                    let current = self.output[i % CAPACITY].load(Ordering::Relaxed);
+                    previous < 0.0 && current >= 0.0
+                }),
+            ScopeTrigger::Beat => self
+                .beats
+                .iter()
+                .map(|beat| beat.load(Ordering::Relaxed))
+                .find(|&beat| beat != usize::MAX && beat <= latest && beat >= oldest),
+        }
+        .unwrap_or(latest);
+
+        self.copy(start, len, input, output);
+    }
+
+    /// Reads the last `len` samples into `input` and `output`, padded with silence at the start
+    /// when fewer samples have been written.
+    pub fn latest(&self, len: usize, input: &mut Vec<f32>, output: &mut Vec<f32>) {
+        let written = self.written.load(Ordering::Acquire);
+        let len = len.min(CAPACITY / 2);
+        let available = len.min(written);
+
+        self.copy(written - available, available, input, output);
+        for samples in [input, output] {
+            samples.splice(0..0, std::iter::repeat(0.0).take(len - available));
+        }
+    }
+
+    fn copy(&self, start: usize, len: usize, input: &mut Vec<f32>, output: &mut Vec<f32>) {
+        input.clear();
+        output.clear();
+        for i in start..start + len {
+            input.push(self.input[i % CAPACITY].load(Ordering::Relaxed));
+            output.push(self.output[i % CAPACITY].load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Draws the input and output waveforms, from -1 to 1.
+pub fn scope(ui: &mut egui::Ui, input: Option<&[f32]>, output: &[f32]) {
+    let size = egui::vec2(ui.available_width(), 120.0);
+    let (rect, _) = ui.allocate_exact_size(size, Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, Color32::from_gray(245));
+    painter.hline(
+        rect.x_range(),
+        rect.center().y,
+        Stroke::new(1.0, Color32::from_gray(210)),
+    );
+
+    let line = |samples: &[f32], color: Color32| {
+        if samples.len() < 2 {
+            return;
+        }
+
+        let x = |i: usize| rect.left() + rect.width() * i as f32 / (samples.len() - 1) as f32;
+        let y = |sample: f32| rect.center().y - sample.clamp(-1.0, 1.0) * rect.height() / 2.0;
+
+        // Long windows are drawn as the minimum and maximum per pixel
+        let columns = rect.width().max(1.0) as usize;
+        let points = if samples.len() > columns * 2 {
+            samples
+                .chunks(samples.len() / columns)
+                .enumerate()
+                .flat_map(|(column, chunk)| {
+                    let min = chunk.iter().copied().fold(f32::INFINITY, f32::min);
+                    let max = chunk.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                    let x = x(column * (samples.len() / columns));
+                    [egui::pos2(x, y(min)), egui::pos2(x, y(max))]
+                })
+                .collect()
+        } else {
+            samples
+                .iter()
+                .enumerate()
+                .map(|(i, sample)| egui::pos2(x(i), y(*sample)))
+                .collect()
+        };
+        painter.add(egui::Shape::line(points, Stroke::new(1.0, color)));
+    };
+
+    if let Some(input) = input {
+        line(input, Color32::from_gray(160));
+    }
+    line(output, Color32::from_rgb(30, 90, 200));
+
+    ui.ctx().request_repaint();
+}
@@ -0,0 +1,1328 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::analysis::{AnalysisOptions, Analyzer, Features};
+use crate::console::LogLevel;
+use crate::controls::{Control, NUM_CONTROLS};
+use crate::dsp::{
+    DspUpdate, NodeKind, GRAIN_ROW, MAX_NODES, MAX_SCHEDULED_GRAINS, NODE_ENUMS, NODE_ROW,
+};
+use crate::latency::InputDelay;
+use crate::layouts::AUDIO_IO_LAYOUTS;
+use crate::looper::{LooperCommand, LooperStatus};
+use crate::midi::MidiOut;
+use crate::runtime::{Arg, Function, Runtime, Shared, SharedView, Watchdog};
+use crate::samples::SampleBank;
+use crate::spectral::{SpectralFrame, SpectralOptions, Stft};
+use crate::voices::{VoiceFrame, MAX_VOICES};
+
+/// The JS glue code that's evaluated before the user's script.
+const PRELUDE: &str = include_str!("./prelude.js");
+
+/// How long a script's top level code can run when it's compiled before it's terminated.
+const COMPILE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const IDLE: u8 = 0;
+const REQUESTED: u8 = 1;
+const BUILT: u8 = 2;
+/// The audio thread swapped in the built script, and the old one waits to be dropped.
+const RETIRED: u8 = 3;
+
+/// The most MIDI events and looper commands the script can hand over per block. Later MIDI events
+/// are sent in the next block.
+pub const MAX_EVENTS: usize = 256;
+/// The most console messages the script can hand over per block, and the most bytes per message.
+/// These match `__RJV_MAX_CONSOLE_MESSAGES` and `__RJV_MAX_CONSOLE_BYTES` in the prelude.
+const MAX_MESSAGES: usize = 64;
+const MAX_MESSAGE_LEN: usize = 1024;
+/// The size of the shared bytes, which hold the console messages, the watched values, the profile
+/// and the specs of new DSP nodes.
+pub const TEXT_LEN: usize = 1 << 18;
+
+// Where everything is in the shared numbers. The prelude gets these through `__rjv_init()`.
+/// The script's time, which every function reads.
+const TIME: usize = 0;
+/// A single number that's passed to or returned from a function, like the number of voices.
+const ARG: usize = 1;
+/// The number of nodes the script created, written by `__rjv_dsp()`.
+const NODE_COUNT: usize = 2;
+/// The index of the current sample in the history, see [`History`].
+const HISTORY_POS: usize = 3;
+/// The number of samples recorded in the history since it was last cleared.
+const HISTORY_FILLED: usize = 4;
+/// The block info, see [`BlockInfo::write()`].
+const BLOCK: usize = 5;
+const BLOCK_LEN: usize = 24 + NUM_CONTROLS;
+const VOICES: usize = BLOCK + BLOCK_LEN;
+const MIDI: usize = VOICES + MAX_VOICES * VoiceFrame::LEN;
+const LOOPER: usize = MIDI + MAX_EVENTS * MidiOut::LEN;
+const CONSOLE: usize = LOOPER + MAX_EVENTS * 2;
+const GRAINS: usize = CONSOLE + MAX_MESSAGES * 4;
+const NODES: usize = GRAINS + MAX_SCHEDULED_GRAINS * GRAIN_ROW;
+const NUMBERS_LEN: usize = NODES + MAX_NODES * NODE_ROW;
+
+/// The entry points a script defines.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct ScriptInfo {
+    pub gain: bool,
+    pub voice: bool,
+    pub process: bool,
+    pub block: bool,
+    /// Set if the script defines `spectral(t, frames)`.
+    #[serde(skip)]
+    pub spectral: Option<SpectralOptions>,
+    /// The latency the script declared, in samples. `process()`'s inputs are delayed by this much,
+    /// and `lookahead(ch, n)` can look ahead as far.
+    pub latency: u32,
+    /// Set if the script defines `analysisOptions`.
+    #[serde(skip)]
+    pub analysis: Option<AnalysisOptions>,
+}
+
+impl ScriptInfo {
+    /// Parses the info `__rjv_info()` returns. The options objects are parsed separately, so a
+    /// mistake in one is reported with the object's name.
+    fn parse(json: &str) -> Result<Self, String> {
+        let mut value: serde_json::Value =
+            serde_json::from_str(json).map_err(|err| err.to_string())?;
+        let mut info: Self =
+            serde_json::from_value(value.clone()).map_err(|err| err.to_string())?;
+        info.spectral = options(&mut value, "spectral", "spectralOptions")?;
+        info.analysis = options(&mut value, "analysis", "analysisOptions")?;
+
+        Ok(info)
+    }
+
+    fn has_entry_point(&self) -> bool {
+        self.gain || self.voice || self.process || self.block || self.spectral.is_some()
+    }
+}
+
+/// Passed to the script's `block(t, info)` function at the start of every block.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockInfo {
+    /// The block's duration in seconds.
+    pub duration: f32,
+    /// The sample rate `process()` runs at, which includes oversampling.
+    pub sample_rate: f32,
+    /// The oversampling factor, or 1 if `process()` runs at the host's sample rate.
+    pub oversampling: usize,
+    pub playing: bool,
+    pub tempo: Option<f64>,
+    /// The host's transport position in quarter notes.
+    pub beats: Option<f64>,
+    /// The number of main input and output channels.
+    pub channels: usize,
+    /// The channel layout's name, like `Stereo` or `5.1`.
+    pub layout: Option<&'static str>,
+    /// The number of sidechain channels at the end of the inputs passed to `process()`.
+    pub sidechain: usize,
+    /// The features of the block's main input, if the script asked for them.
+    pub analysis: Option<Features>,
+    pub looper: LooperStatus,
+    /// The values of the control parameters, as the script's controls see them.
+    pub controls: [f32; NUM_CONTROLS],
+}
+
+impl BlockInfo {
+    /// Writes the info in the order `__rjv_block()` reads it. Numbers that can be missing are NaN
+    /// when they are, and the layout is written as its index in the layout names.
+    fn write(&self, numbers: &mut [f64]) {
+        let optional = |x: Option<f64>| x.unwrap_or(f64::NAN);
+        let flag = |x: bool| if x { 1.0 } else { 0.0 };
+        let analysis = self.analysis.unwrap_or_default();
+        let looper = &self.looper;
+        let layout = layout_names().position(|name| Some(name) == self.layout);
+
+        let values: [f64; 24] = [
+            self.duration as f64,
+            self.sample_rate as f64,
+            self.oversampling as f64,
+            flag(self.playing),
+            optional(self.tempo),
+            optional(self.beats),
+            self.channels as f64,
+            optional(layout.map(|idx| idx as f64)),
+            self.sidechain as f64,
+            flag(self.analysis.is_some()),
+            analysis.peak as f64,
+            analysis.rms as f64,
+            analysis.envelope as f64,
+            analysis.zero_crossing_rate as f64,
+            optional(analysis.pitch.map(f64::from)),
+            analysis.pitch_confidence as f64,
+            flag(analysis.onset),
+            looper.state as u8 as f64,
+            looper.position as f64,
+            looper.length as f64,
+            looper.speed as f64,
+            flag(looper.reverse),
+            looper.level as f64,
+            flag(looper.pending),
+        ];
+        numbers[..values.len()].copy_from_slice(&values);
+        for (number, value) in numbers[values.len()..].iter_mut().zip(self.controls) {
+            *number = value as f64;
+        }
+    }
+}
+
+/// Takes one of the options objects from the script's info, if the script defined it.
+fn options<T: DeserializeOwned>(
+    info: &mut serde_json::Value,
+    key: &str,
+    name: &str,
+) -> Result<Option<T>, String> {
+    match info.get_mut(key).map(serde_json::Value::take) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(value) => serde_json::from_value(value)
+            .map(Some)
+            .map_err(|err| format!("Invalid `{name}`: {err}")),
+    }
+}
+
+/// The names of the channel layouts, which the block info refers to by index.
+fn layout_names() -> impl Iterator<Item = &'static str> {
+    AUDIO_IO_LAYOUTS
+        .iter()
+        .filter_map(|layout| layout.names.layout)
+}
+
+/// Decides how large the script's input and output history buffers are, and how many channels
+/// `process()` sees.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScriptConfig {
+    /// The number of samples of input and output history the script can access, at the host's
+    /// sample rate. The history is recorded at the rate `process()` runs at, so the buffers are
+    /// this many times the oversampling factor long.
+    pub history_length: usize,
+    pub channels: usize,
+    pub sidechain: usize,
+}
+
+/// Where `process()`'s inputs and outputs and the voices' outputs are in the shared samples. These
+/// depend on the number of channels.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SampleOffsets {
+    inputs: usize,
+    outputs: usize,
+    voice_outputs: usize,
+    voice_done: usize,
+    #[serde(skip)]
+    len: usize,
+}
+
+impl SampleOffsets {
+    fn new(config: &ScriptConfig) -> Self {
+        let io = config.channels + config.sidechain;
+
+        Self {
+            inputs: 0,
+            outputs: io,
+            voice_outputs: io + config.channels,
+            voice_done: io + config.channels + MAX_VOICES,
+            len: io + config.channels + 2 * MAX_VOICES,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NumberOffsets {
+    time: usize,
+    arg: usize,
+    node_count: usize,
+    history_pos: usize,
+    history_filled: usize,
+    block: usize,
+    voices: usize,
+    midi: usize,
+    looper: usize,
+    console: usize,
+    grains: usize,
+    nodes: usize,
+}
+
+/// Everything the prelude needs to know about the shared arrays, passed to `__rjv_init()` as JSON.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Layout {
+    channels: usize,
+    sidechain: usize,
+    /// The length of the history at the host's sample rate. The buffers themselves are bound
+    /// after the script ran, once it's known whether it defines `process()`.
+    history_length: usize,
+    numbers: NumberOffsets,
+    samples: SampleOffsets,
+    max_events: usize,
+    max_grains: usize,
+    max_nodes: usize,
+    node_row: usize,
+    /// The parameters in a node's row, by the node's kind.
+    node_fields: BTreeMap<&'static str, &'static [&'static str]>,
+    /// The names of the parameters that are set by name, like `biquad.mode`.
+    node_enums: BTreeMap<String, &'static [&'static str]>,
+    layouts: Vec<&'static str>,
+}
+
+impl Layout {
+    fn new(config: &ScriptConfig, samples: SampleOffsets) -> Self {
+        Self {
+            channels: config.channels,
+            sidechain: config.sidechain,
+            history_length: config.history_length,
+            numbers: NumberOffsets {
+                time: TIME,
+                arg: ARG,
+                node_count: NODE_COUNT,
+                history_pos: HISTORY_POS,
+                history_filled: HISTORY_FILLED,
+                block: BLOCK,
+                voices: VOICES,
+                midi: MIDI,
+                looper: LOOPER,
+                console: CONSOLE,
+                grains: GRAINS,
+                nodes: NODES,
+            },
+            samples,
+            max_events: MAX_EVENTS,
+            max_grains: MAX_SCHEDULED_GRAINS,
+            max_nodes: MAX_NODES,
+            node_row: NODE_ROW,
+            node_fields: NodeKind::ALL
+                .iter()
+                .map(|kind| (kind.name(), kind.fields()))
+                .collect(),
+            node_enums: NODE_ENUMS
+                .iter()
+                .map(|(kind, field, names)| (format!("{}.{field}", kind.name()), *names))
+                .collect(),
+            layouts: layout_names().collect(),
+        }
+    }
+}
+
+/// The prelude's functions the plugin calls, looked up once after compiling.
+struct Functions {
+    block: Function,
+    gain: Function,
+    process: Function,
+    spectral: Function,
+    voices: Function,
+    dsp: Function,
+    midi: Function,
+    looper: Function,
+    console: Function,
+    buffers: Function,
+    watch_set: Function,
+    watch: Function,
+    profile_on: Function,
+    profile_off: Function,
+    profile_take: Function,
+}
+
+impl Functions {
+    fn new(runtime: &mut Runtime) -> Result<Self, String> {
+        let mut function = |name: &str| {
+            runtime
+                .function(name)
+                .ok_or_else(|| format!("{name} is not defined"))
+        };
+
+        Ok(Self {
+            block: function("__rjv_block")?,
+            gain: function("__rjv_gain")?,
+            process: function("__rjv_process")?,
+            spectral: function("__rjv_spectral")?,
+            voices: function("__rjv_voices")?,
+            dsp: function("__rjv_dsp")?,
+            midi: function("__rjv_midi")?,
+            looper: function("__rjv_looper")?,
+            console: function("__rjv_console")?,
+            buffers: function("__rjv_buffers")?,
+            watch_set: function("__rjv_watch_set")?,
+            watch: function("__rjv_watch")?,
+            profile_on: function("__rjv_profile_on")?,
+            profile_off: function("__rjv_profile_off")?,
+            profile_take: function("__rjv_profile_take")?,
+        })
+    }
+}
+
+/// A compiled script, with the arrays it shares with the plugin. Arguments are written to these
+/// before calling one of the script's functions, and results are read from them afterwards, so
+/// calling into the script doesn't allocate on the plugin's side.
+struct Compiled {
+    // These belong to the runtime's isolate, so they're dropped before it
+    functions: Functions,
+    numbers: Shared<f64>,
+    samples: Shared<f32>,
+    text: Shared<u8>,
+    /// Every channel's magnitudes followed by its phases, if the script defines `spectral()`.
+    spectra: Option<Shared<f32>>,
+    offsets: SampleOffsets,
+    runtime: Runtime,
+}
+
+/// The ring buffers `input(ch, n)` and `output(ch, n)` read from. These are kept by the engine
+/// instead of by the compiled script, so a recompiled script can still look back at what came
+/// before it. They're only replaced when the oversampling factor `process()` runs at changes. The
+/// plugin records every sample, whether or not the script defines `process()`.
+struct History {
+    /// The input channels' buffers followed by the output channels', `length` samples each.
+    samples: Shared<f32>,
+    length: usize,
+    inputs: usize,
+    /// The index of the current sample.
+    pos: usize,
+    /// The number of samples recorded since the history was cleared, up to `length`. The script
+    /// treats the rest as silence, so clearing doesn't have to touch the buffers.
+    filled: usize,
+}
+
+/// A handle to a [`History`]'s buffers for binding them to a script on another thread.
+struct HistoryView {
+    samples: SharedView<f32>,
+    length: usize,
+}
+
+impl History {
+    /// Allocates the buffers for `process()` running at `factor` times the host's sample rate.
+    fn new(config: &ScriptConfig, factor: usize) -> Self {
+        let inputs = config.channels + config.sidechain;
+        let length = config.history_length * factor;
+
+        Self {
+            samples: Shared::new((inputs + config.channels) * length),
+            length,
+            inputs,
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    fn view(&self) -> HistoryView {
+        HistoryView {
+            samples: self.samples.view(),
+            length: self.length,
+        }
+    }
+
+    /// Tells the script where the current sample is, and how much of the history is recorded.
+    fn write_pos(&self, numbers: &mut [f64]) {
+        numbers[HISTORY_POS] = self.pos as f64;
+        numbers[HISTORY_FILLED] = self.filled as f64;
+    }
+
+    /// Advances to the next sample and records its inputs. Missing channels are silent.
+    fn record_inputs(&mut self, inputs: &[f32]) {
+        if self.length == 0 {
+            return;
+        }
+
+        self.pos = (self.pos + 1) % self.length;
+        self.filled = (self.filled + 1).min(self.length);
+        let (length, pos) = (self.length, self.pos);
+        for (ch, buffer) in self.samples.get_mut()[..self.inputs * length]
+            .chunks_exact_mut(length)
+            .enumerate()
+        {
+            buffer[pos] = inputs.get(ch).copied().unwrap_or(0.0);
+        }
+    }
+
+    /// Records the current sample's outputs. Like `process()`'s outputs, these are repeated when
+    /// there are fewer of them than there are channels.
+    fn record_outputs(&mut self, outputs: &[f32]) {
+        if self.length == 0 {
+            return;
+        }
+
+        let (length, pos) = (self.length, self.pos);
+        for (ch, buffer) in self.samples.get_mut()[self.inputs * length..]
+            .chunks_exact_mut(length)
+            .enumerate()
+        {
+            buffer[pos] = match outputs.len() {
+                0 => 0.0,
+                len => outputs[ch % len],
+            };
+        }
+    }
+
+    fn clear(&mut self) {
+        self.pos = 0;
+        self.filled = 0;
+    }
+}
+
+/// A script compiled away from the engine, along with the processing it asked for. The engine
+/// swaps it in with [`ScriptEngine::install()`], which leaves the script it replaced in here.
+pub struct Script {
+    compiled: Option<Compiled>,
+    code: String,
+    info: ScriptInfo,
+    controls: Vec<Control>,
+    error: Option<String>,
+    /// The oversampling factor `process()` runs at, or 1 if the script doesn't define it.
+    factor: usize,
+    /// Set when the script needs a history of a different length than the engine's.
+    history: Option<History>,
+    /// Set when the script defines `spectral(t, frames)`.
+    pub stft: Option<Stft>,
+    /// Set when the script defines `analysisOptions`.
+    pub analyzer: Option<Analyzer>,
+    /// Set when the script declared a latency.
+    pub input_delay: Option<InputDelay>,
+}
+
+impl Script {
+    /// Compiles `code` for `process()` running at `factor` times the host's sample rate. The
+    /// script is bound to `history` when that has the right length, and to a new history
+    /// otherwise. A script that doesn't compile keeps the error, and no controls.
+    fn compile(
+        code: &str,
+        config: &ScriptConfig,
+        factor: usize,
+        history: &HistoryView,
+        samples: &[u8],
+        watchdog: Option<&Watchdog>,
+    ) -> Self {
+        let (mut compiled, info) = match compile(code, config, samples, watchdog) {
+            Ok(result) => result,
+            Err(err) => return Self::failed(code, err),
+        };
+
+        // Without `process()` the history is recorded at the host's sample rate
+        let factor = if info.process { factor } else { 1 };
+        let new_history = (config.history_length * factor != history.length)
+            .then(|| History::new(config, factor));
+        let view = new_history.as_ref().map(History::view);
+        let view = view.as_ref().unwrap_or(history);
+        if let Err(err) = compiled.runtime.call_json(
+            "__rjv_history",
+            &[
+                Arg::SamplesView(&view.samples),
+                Arg::Number(view.length as f64),
+            ],
+        ) {
+            return Self::failed(code, err);
+        }
+
+        let controls = compiled
+            .runtime
+            .call_json("__rjv_controls", &[])
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+
+        Self {
+            compiled: Some(compiled),
+            code: code.to_string(),
+            info,
+            controls,
+            error: None,
+            factor,
+            history: new_history,
+            stft: None,
+            analyzer: None,
+            input_delay: None,
+        }
+    }
+
+    fn failed(code: &str, err: String) -> Self {
+        Self {
+            compiled: None,
+            code: code.to_string(),
+            info: ScriptInfo::default(),
+            controls: Vec::new(),
+            error: Some(err),
+            factor: 1,
+            history: None,
+            stft: None,
+            analyzer: None,
+            input_delay: None,
+        }
+    }
+
+    /// Compiles `code` and builds the processing it asks for. A script whose top level code runs
+    /// for longer than [`COMPILE_TIMEOUT`] is terminated, and reported as an error.
+    fn build(
+        code: &str,
+        config: &ScriptConfig,
+        factor: usize,
+        history: &HistoryView,
+        samples: &[u8],
+        sample_rate: f32,
+    ) -> Self {
+        let mut script = Watchdog::run(COMPILE_TIMEOUT, |watchdog| {
+            Self::compile(
+                code,
+                config,
+                factor,
+                history,
+                samples,
+                Some(watchdog.as_ref()),
+            )
+        })
+        .unwrap_or_else(|| {
+            Self::failed(
+                code,
+                format!(
+                    "The script's top level code took longer than {} seconds to run",
+                    COMPILE_TIMEOUT.as_secs()
+                ),
+            )
+        });
+
+        let info = script.info;
+        script.stft = info
+            .spectral
+            .map(|options| Stft::new(options.normalized(), config.channels));
+        script.analyzer = info
+            .analysis
+            .map(|options| Analyzer::new(options, sample_rate));
+        script.input_delay = (info.latency > 0)
+            .then(|| InputDelay::new(info.latency, config.channels + config.sidechain));
+
+        script
+    }
+}
+
+/// Compiles scripts on the background thread, and drops the scripts they replaced there too, so the
+/// audio thread never waits for the compiler or for a slow script's top level code.
+pub struct ScriptBuilder {
+    state: AtomicU8,
+    request: Mutex<ScriptRequest>,
+    /// The script built for the last request, and after the swap the script it replaced.
+    built: Mutex<Option<Script>>,
+}
+
+struct ScriptRequest {
+    config: ScriptConfig,
+    /// The engine's history, which the new script shares if it has the right length. This is
+    /// replaced here when a script with a new history is built, so the old view is dropped on the
+    /// background thread.
+    history: Option<HistoryView>,
+    sample_rate: f32,
+    /// The oversampling factor `process()` should run at.
+    factor: usize,
+    /// A hash of the code of the engine's script, so code that didn't actually change isn't
+    /// compiled again. This is `None` if the script needs to be rebuilt for a new factor.
+    running: Option<u64>,
+}
+
+impl Default for ScriptBuilder {
+    fn default() -> Self {
+        Self {
+            state: AtomicU8::new(IDLE),
+            request: Mutex::new(ScriptRequest {
+                config: ScriptConfig::default(),
+                history: None,
+                sample_rate: 1.0,
+                factor: 1,
+                running: None,
+            }),
+            built: Mutex::new(None),
+        }
+    }
+}
+
+impl ScriptBuilder {
+    /// Discards any unfinished work, which belongs to an engine that's being replaced. This blocks
+    /// while a script is being compiled, so it's called when the plugin is initialized.
+    pub fn prepare(&self) {
+        *self.built.lock().unwrap() = None;
+        self.request.lock().unwrap().history = None;
+        self.state.store(IDLE, Ordering::Release);
+    }
+
+    /// Takes the script the background thread built, if it's done. What's left of the script after
+    /// installing it has to be handed back with [`retire()`][Self::retire()]. This never blocks or
+    /// allocates.
+    pub fn take(&self) -> Option<Script> {
+        if self.state.load(Ordering::Acquire) != BUILT {
+            return None;
+        }
+
+        // The background thread doesn't touch the built script until it's retired
+        self.built.try_lock().ok()?.take()
+    }
+
+    /// Hands back a script from [`take()`][Self::take()], so whatever it holds is dropped on the
+    /// background thread. [`run()`][Self::run()] should be called afterwards. This never blocks or
+    /// allocates.
+    pub fn retire(&self, script: Script) {
+        if let Ok(mut built) = self.built.try_lock() {
+            *built = Some(script);
+        }
+        self.state.store(RETIRED, Ordering::Release);
+    }
+
+    /// Drops the script that was replaced, and compiles the requested one. This blocks, so it
+    /// should only be called from the background thread.
+    pub fn run(&self, code: &str, samples: &SampleBank) {
+        let mut built = self.built.lock().unwrap();
+        match self.state.load(Ordering::Acquire) {
+            RETIRED => {
+                *built = None;
+                self.state.store(IDLE, Ordering::Release);
+            }
+            REQUESTED => {
+                let mut request = self.request.lock().unwrap();
+                let script = match &request.history {
+                    Some(history) if Some(hash(code)) != request.running => {
+                        // If the samples are being written to, the audio thread sends them again
+                        // once it installed the script
+                        let index = samples.index(<[u8]>::to_vec).unwrap_or_default();
+                        Script::build(
+                            code,
+                            &request.config,
+                            request.factor,
+                            history,
+                            &index,
+                            request.sample_rate,
+                        )
+                    }
+                    _ => {
+                        self.state.store(IDLE, Ordering::Release);
+                        return;
+                    }
+                };
+
+                if let Some(history) = &script.history {
+                    request.history = Some(history.view());
+                }
+                *built = Some(script);
+                self.state.store(BUILT, Ordering::Release);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn hash(code: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    code.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Holds on to the compiled script so it, and any state the script keeps in its globals, survives
+/// across process calls. The script is only recompiled when the code changes.
+pub struct ScriptEngine {
+    compiled: Option<Compiled>,
+    code: String,
+    config: ScriptConfig,
+    history: History,
+    /// The oversampling factor the script's `process()` runs at, which the history is sized for.
+    factor: usize,
+    /// The loaded samples as a JSON array of `SampleInfo`s, which are also passed to scripts when
+    /// they're compiled so they can be used in the script's top level code. This has room for as
+    /// much as fits in the shared bytes.
+    samples: Vec<u8>,
+    info: ScriptInfo,
+    /// The controls the script declared. These are kept when the script doesn't compile, so the
+    /// controls don't lose their values while the script is being edited.
+    controls: Vec<Control>,
+    error: Option<String>,
+    /// The time spent in the script since the last call to `take_elapsed()`.
+    elapsed: Duration,
+    /// Set when the script's functions should be profiled. This carries over to recompiled scripts.
+    profiling: bool,
+    /// Terminates scripts that run for too long, if the engine has one.
+    watchdog: Option<Arc<Watchdog>>,
+}
+
+impl Default for ScriptEngine {
+    fn default() -> Self {
+        Self::new(ScriptConfig::default())
+    }
+}
+
+impl ScriptEngine {
+    pub fn new(config: ScriptConfig) -> Self {
+        Self {
+            compiled: None,
+            code: String::new(),
+            config,
+            history: History::new(&config, 1),
+            factor: 1,
+            samples: Vec::with_capacity(TEXT_LEN),
+            info: ScriptInfo::default(),
+            controls: Vec::new(),
+            error: Some("Not compiled yet".to_string()),
+            elapsed: Duration::ZERO,
+            profiling: false,
+            watchdog: None,
+        }
+    }
+
+    /// Lets `watchdog` terminate the scripts this engine compiles from now on.
+    pub fn set_watchdog(&mut self, watchdog: Arc<Watchdog>) {
+        self.watchdog = Some(watchdog);
+    }
+
+    /// Recompiles the script if `code` differs from the code that was last compiled. Returns
+    /// whether that happened. This allocates.
+    pub fn sync(&mut self, code: &str) -> bool {
+        if self.code == code && (self.compiled.is_some() || self.error.is_some()) {
+            return false;
+        }
+
+        let history = self.history.view();
+        let watchdog = self.watchdog.as_deref();
+        let mut script = Script::compile(
+            code,
+            &self.config,
+            self.factor,
+            &history,
+            &self.samples,
+            watchdog,
+        );
+        self.install(&mut script);
+
+        true
+    }
+
+    /// Compiles `code` for this engine and builds the processing it asks for, with a timeout. This
+    /// allocates, so it's only called when the plugin is initialized. Afterwards scripts are built
+    /// by a [`ScriptBuilder`].
+    pub fn build(&self, code: &str, sample_rate: f32, factor: usize) -> Script {
+        let history = self.history.view();
+        Script::build(
+            code,
+            &self.config,
+            factor,
+            &history,
+            &self.samples,
+            sample_rate,
+        )
+    }
+
+    /// Asks `builder` to compile the current code on the background thread, unless it's still busy
+    /// with the last request. A script that defines `process()` is compiled again even if the code
+    /// didn't change when `factor` differs from the factor it runs at. Returns whether
+    /// [`ScriptBuilder::run()`] should be called. This never blocks or allocates.
+    pub fn request(&self, builder: &ScriptBuilder, sample_rate: f32, factor: usize) -> bool {
+        if builder.state.load(Ordering::Acquire) != IDLE {
+            return false;
+        }
+
+        match builder.request.try_lock() {
+            Ok(mut request) => {
+                request.config = self.config;
+                if request.history.is_none() {
+                    request.history = Some(self.history.view());
+                }
+                request.sample_rate = sample_rate;
+                request.factor = factor;
+                request.running =
+                    (!self.info.process || factor == self.factor).then(|| hash(&self.code));
+                builder.state.store(REQUESTED, Ordering::Release);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Switches to a script from [`build()`][Self::build()] or a [`ScriptBuilder`], and leaves the
+    /// script it replaced in `script`. The controls are kept when the new script doesn't compile.
+    /// This doesn't allocate.
+    pub fn install(&mut self, script: &mut Script) {
+        std::mem::swap(&mut self.compiled, &mut script.compiled);
+        std::mem::swap(&mut self.code, &mut script.code);
+        std::mem::swap(&mut self.info, &mut script.info);
+        std::mem::swap(&mut self.error, &mut script.error);
+        if self.error.is_none() {
+            std::mem::swap(&mut self.controls, &mut script.controls);
+        }
+        std::mem::swap(&mut self.factor, &mut script.factor);
+        if let Some(history) = &mut script.history {
+            std::mem::swap(&mut self.history, history);
+        }
+
+        if let Some(compiled) = &mut self.compiled {
+            self.history.write_pos(compiled.numbers.get_mut());
+        }
+        if self.profiling {
+            self.call(|f| &f.profile_on);
+        }
+    }
+
+    pub fn info(&self) -> ScriptInfo {
+        self.info
+    }
+
+    /// The oversampling factor the script's `process()` runs at, or 1 if it doesn't define it.
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    pub fn controls(&self) -> &[Control] {
+        &self.controls
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Updates the `buffers` object after samples were loaded or removed, with the JSON from
+    /// [`SampleBank::index()`][crate::samples::SampleBank::index()]. Samples that don't fit are left
+    /// out.
+    pub fn set_samples(&mut self, samples: &[u8]) {
+        if samples.len() > TEXT_LEN {
+            return;
+        }
+
+        self.samples.clear();
+        self.samples.extend_from_slice(samples);
+        if let Some(compiled) = &mut self.compiled {
+            compiled.text.get_mut()[..samples.len()].copy_from_slice(samples);
+            compiled.numbers.get_mut()[ARG] = samples.len() as f64;
+            self.call(|f| &f.buffers);
+        }
+    }
+
+    /// Advances the history to the next sample and records its inputs, for scripts that don't
+    /// define `process()`. `process()` records its own inputs and outputs, at its own sample rate.
+    pub fn record_inputs(&mut self, inputs: &[f32]) {
+        self.history.record_inputs(inputs);
+        if let Some(compiled) = &mut self.compiled {
+            self.history.write_pos(compiled.numbers.get_mut());
+        }
+    }
+
+    /// Records the plugin's output for the current sample, for scripts that don't define
+    /// `process()`.
+    pub fn record_outputs(&mut self, outputs: &[f32]) {
+        self.history.record_outputs(outputs);
+    }
+
+    /// Silences the history, for when the plugin is reset.
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+        if let Some(compiled) = &mut self.compiled {
+            self.history.write_pos(compiled.numbers.get_mut());
+        }
+    }
+
+    /// Called once at the start of every block, before any per-sample functions.
+    pub fn block(&mut self, t: f64, info: &BlockInfo) {
+        if let Some(compiled) = &mut self.compiled {
+            let numbers = compiled.numbers.get_mut();
+            numbers[TIME] = t;
+            info.write(&mut numbers[BLOCK..BLOCK + BLOCK_LEN]);
+            self.call(|f| &f.block);
+        }
+    }
+
+    pub fn gain(&mut self, t: f64) -> Option<f32> {
+        if !self.info.gain {
+            return None;
+        }
+
+        self.compiled.as_mut()?.numbers.get_mut()[TIME] = t;
+        self.call(|f| &f.gain)
+            .filter(|gain| !gain.is_nan())
+            .map(|gain| gain as f32)
+    }
+
+    /// Processes a single frame of input samples into one or more output samples in `outputs`.
+    /// When the script declared a latency, `inputs` are the delayed inputs and `live` contains the
+    /// current input samples, which are what's recorded in the history. Otherwise `live` is empty.
+    /// Returns false if the script doesn't define `process()` or it failed.
+    pub fn process(
+        &mut self,
+        t: f64,
+        inputs: &[f32],
+        live: &[f32],
+        outputs: &mut Vec<f32>,
+    ) -> bool {
+        let compiled = match &mut self.compiled {
+            Some(compiled) if self.info.process => compiled,
+            _ => return false,
+        };
+
+        // The history has the live inputs, so `lookahead(ch, n)` can look ahead
+        self.history
+            .record_inputs(if live.is_empty() { inputs } else { live });
+        let numbers = compiled.numbers.get_mut();
+        numbers[TIME] = t;
+        self.history.write_pos(numbers);
+        let offsets = compiled.offsets;
+        let samples = compiled.samples.get_mut();
+        copy(&mut samples[offsets.inputs..offsets.outputs], inputs);
+
+        let count = self.call(|f| &f.process).map(|count| count as usize);
+        let samples = self.compiled.as_ref().unwrap().samples.get();
+        outputs.clear();
+        if let Some(count) = count {
+            outputs.extend_from_slice(
+                &samples[offsets.outputs..offsets.voice_outputs][..count.min(self.config.channels)],
+            );
+        }
+        self.history.record_outputs(outputs);
+
+        count.is_some()
+    }
+
+    /// Lets the script modify the spectra of all channels. Frames are only updated if the script
+    /// returns them with the right sizes.
+    pub fn spectral(&mut self, t: f64, frames: &mut [SpectralFrame]) {
+        let spectra = match &mut self.compiled {
+            Some(Compiled {
+                numbers,
+                spectra: Some(spectra),
+                ..
+            }) => {
+                numbers.get_mut()[TIME] = t;
+                spectra.get_mut()
+            }
+            _ => return,
+        };
+
+        let bins = spectra.len() / 2 / frames.len().max(1);
+        for (frame, spectrum) in frames.iter().zip(spectra.chunks_exact_mut(2 * bins)) {
+            copy(&mut spectrum[..bins], &frame.magnitude);
+            copy(&mut spectrum[bins..], &frame.phase);
+        }
+
+        if self.call(|f| &f.spectral).is_none() {
+            return;
+        }
+
+        let spectra = self.compiled.as_ref().unwrap().spectra.as_ref().unwrap();
+        for (frame, spectrum) in frames.iter_mut().zip(spectra.get().chunks_exact(2 * bins)) {
+            copy(&mut frame.magnitude, &spectrum[..bins]);
+            copy(&mut frame.phase, &spectrum[bins..]);
+        }
+    }
+
+    /// Renders a single sample for every active voice. The per-voice outputs are written to
+    /// `outputs` in the same order as `frames`, and the slots of the voices the script marked as
+    /// done to `done`. Returns false if the script doesn't define `voice()` or it failed.
+    pub fn voices(
+        &mut self,
+        t: f64,
+        frames: &[VoiceFrame],
+        outputs: &mut Vec<f32>,
+        done: &mut Vec<usize>,
+    ) -> bool {
+        let compiled = match &mut self.compiled {
+            Some(compiled) if self.info.voice => compiled,
+            _ => return false,
+        };
+
+        let numbers = compiled.numbers.get_mut();
+        numbers[TIME] = t;
+        numbers[ARG] = frames.len() as f64;
+        for (frame, row) in frames
+            .iter()
+            .zip(numbers[VOICES..MIDI].chunks_exact_mut(VoiceFrame::LEN))
+        {
+            frame.write(row);
+        }
+
+        outputs.clear();
+        done.clear();
+        if self.call(|f| &f.voices).is_none() {
+            return false;
+        }
+
+        let compiled = self.compiled.as_ref().unwrap();
+        let offsets = compiled.offsets;
+        let samples = compiled.samples.get();
+        outputs.extend_from_slice(&samples[offsets.voice_outputs..][..frames.len()]);
+        done.extend(
+            frames
+                .iter()
+                .zip(&samples[offsets.voice_done..][..frames.len()])
+                .filter(|(_, &done)| done != 0.0)
+                .map(|(frame, _)| frame.slot()),
+        );
+
+        true
+    }
+
+    /// Takes the changes the script made to its native DSP nodes since the last call. The specs of
+    /// new nodes are only included when `accepting` is set, otherwise the script keeps them for a
+    /// later block.
+    pub fn dsp(&mut self, accepting: bool) -> Option<DspUpdate> {
+        self.compiled.as_mut()?.numbers.get_mut()[ARG] = if accepting { 1.0 } else { 0.0 };
+        let len = self.call(|f| &f.dsp)? as usize;
+
+        let compiled = self.compiled.as_mut().unwrap();
+        let (numbers, nodes) = compiled.numbers.get_mut().split_at_mut(NODES);
+        let num_grains = (numbers[ARG] as usize).min(MAX_SCHEDULED_GRAINS);
+        let num_nodes = (numbers[NODE_COUNT] as usize).min(MAX_NODES);
+
+        Some(DspUpdate {
+            nodes: &mut nodes[..num_nodes * NODE_ROW],
+            grains: &numbers[GRAINS..][..num_grains * GRAIN_ROW],
+            specs: (len > 0).then(|| &compiled.text.get()[..len.min(TEXT_LEN)]),
+        })
+    }
+
+    /// Takes the MIDI events the script scheduled before `end`, sorted by time.
+    pub fn midi(&mut self, end: f64, events: &mut Vec<MidiOut>) {
+        events.clear();
+        let count = match self.compiled.as_mut() {
+            Some(compiled) => {
+                compiled.numbers.get_mut()[ARG] = end;
+                self.call(|f| &f.midi).unwrap_or(0.0) as usize
+            }
+            None => return,
+        };
+
+        let numbers = self.compiled.as_ref().unwrap().numbers.get();
+        events.extend(
+            numbers[MIDI..LOOPER]
+                .chunks_exact(MidiOut::LEN)
+                .take(count)
+                .filter_map(MidiOut::decode),
+        );
+    }
+
+    /// Takes the looper commands the script issued since the last call.
+    pub fn looper(&mut self, commands: &mut Vec<LooperCommand>) {
+        commands.clear();
+        let count = match self.call(|f| &f.looper) {
+            Some(count) => count as usize,
+            None => return,
+        };
+
+        let numbers = self.compiled.as_ref().unwrap().numbers.get();
+        commands.extend(
+            numbers[LOOPER..CONSOLE]
+                .chunks_exact(2)
+                .take(count)
+                .filter_map(|command| LooperCommand::decode(command[0], command[1])),
+        );
+    }
+
+    /// Takes the messages the script logged since the last call, and passes their level, text,
+    /// time and repeat count to `log`. Returns the number of messages the script dropped because
+    /// there were too many.
+    pub fn console(&mut self, mut log: impl FnMut(LogLevel, &str, f64, u32)) -> usize {
+        let count = match self.call(|f| &f.console) {
+            Some(count) => count as usize,
+            None => return 0,
+        };
+
+        let compiled = self.compiled.as_ref().unwrap();
+        let numbers = compiled.numbers.get();
+        let text = compiled.text.get();
+        let mut offset = 0;
+        for message in numbers[CONSOLE..GRAINS].chunks_exact(4).take(count) {
+            let len = (message[3] as usize).min(MAX_MESSAGE_LEN);
+            let bytes = text.get(offset..offset + len).unwrap_or_default();
+            offset += len;
+
+            if let Some(&level) = LogLevel::ALL.get(message[0] as usize) {
+                let text = std::str::from_utf8(bytes).unwrap_or_default();
+                log(level, text, message[1], message[2] as u32);
+            }
+        }
+
+        numbers[ARG] as usize
+    }
+
+    /// Sets the watch expressions that are evaluated by [`watch()`][Self::watch()]. Expressions
+    /// that don't fit are left out.
+    pub fn set_watches(&mut self, expressions: &[String]) {
+        let compiled = match &mut self.compiled {
+            Some(compiled) => compiled,
+            None => return,
+        };
+
+        let text = compiled.text.get_mut();
+        let mut len = 0;
+        for expression in expressions {
+            let separator = usize::from(len > 0);
+            let end = len + separator + expression.len();
+            if end > text.len() {
+                break;
+            }
+
+            text[len..len + separator].fill(b'\n');
+            text[len + separator..end].copy_from_slice(expression.as_bytes());
+            len = end;
+        }
+        compiled.numbers.get_mut()[ARG] = len as f64;
+
+        self.call(|f| &f.watch_set);
+    }
+
+    /// Reads the watched values from the script's globals, and returns them as JSON. Objects and
+    /// arrays other than arrays of numbers are only inspected again when `deep` is set. Returns
+    /// `None` if that failed.
+    pub fn watch(&mut self, deep: bool) -> Option<&[u8]> {
+        self.compiled.as_mut()?.numbers.get_mut()[ARG] = if deep { 1.0 } else { 0.0 };
+        self.text_result(|f| &f.watch)
+    }
+
+    /// Starts or stops profiling the script's functions.
+    pub fn set_profiling(&mut self, profiling: bool) {
+        if self.profiling != profiling {
+            self.profiling = profiling;
+            if profiling {
+                self.call(|f| &f.profile_on);
+            } else {
+                self.call(|f| &f.profile_off);
+            }
+        }
+    }
+
+    /// Takes the time in milliseconds the profiler attributed to every function since the last
+    /// call, as JSON. Returns `None` if that failed.
+    pub fn profile(&mut self) -> Option<&[u8]> {
+        self.text_result(|f| &f.profile_take)
+    }
+
+    /// Takes the time spent in the script since the last call.
+    pub fn take_elapsed(&mut self) -> Duration {
+        std::mem::take(&mut self.elapsed)
+    }
+
+    /// Calls one of the prelude's functions. Returns its result as a number, or `None` if the
+    /// script isn't compiled or the call threw. The time that took is added to the time spent in
+    /// the script.
+    fn call(&mut self, function: impl FnOnce(&Functions) -> &Function) -> Option<f64> {
+        let compiled = self.compiled.as_mut()?;
+        let start = Instant::now();
+        let result = compiled.runtime.call(function(&compiled.functions));
+        self.elapsed += start.elapsed();
+
+        result
+    }
+
+    /// Calls a function that writes text to the shared bytes and returns its length, or -1 if it
+    /// didn't fit.
+    fn text_result(&mut self, function: impl FnOnce(&Functions) -> &Function) -> Option<&[u8]> {
+        let len = self.call(function)?;
+        if !(0.0..=TEXT_LEN as f64).contains(&len) {
+            return None;
+        }
+
+        Some(&self.compiled.as_ref()?.text.get()[..len as usize])
+    }
+}
+
+/// Copies as many samples as fit.
+fn copy(to: &mut [f32], from: &[f32]) {
+    let len = to.len().min(from.len());
+    to[..len].copy_from_slice(&from[..len]);
+}
+
+/// Scripts can either be full programs that define functions like `gain(t)`, `process(t, inputs)`,
+/// `voice(t, note, velocity, state)` and `block(t, info)`, or a single expression which is
+/// shorthand for the body of `gain(t)`.
+fn compile(
+    code: &str,
+    config: &ScriptConfig,
+    samples: &[u8],
+    watchdog: Option<&Watchdog>,
+) -> Result<(Compiled, ScriptInfo), String> {
+    match instantiate(code, config, samples, watchdog) {
+        Ok((compiled, info)) if info.has_entry_point() => return Ok((compiled, info)),
+        // The code ran, so it's not an expression
+        Err(InstantiateError::Options(err)) => return Err(err),
+        _ => (),
+    }
+
+    instantiate(
+        &format!("function gain(t) {{ return {code}; }}"),
+        config,
+        samples,
+        watchdog,
+    )
+    .map_err(|(InstantiateError::Code(err) | InstantiateError::Options(err))| err)
+}
+
+/// Why [`instantiate()`] failed.
+enum InstantiateError {
+    /// The code, or the prelude, didn't run.
+    Code(String),
+    /// The code ran, but one of the options objects it defines is invalid.
+    Options(String),
+}
+
+impl From<String> for InstantiateError {
+    fn from(err: String) -> Self {
+        InstantiateError::Code(err)
+    }
+}
+
+/// Runs the prelude and the script in a fresh runtime, and shares the arrays with it. The script is
+/// initialized before its info is read, because the latency it can declare is limited by the length
+/// of the history in the layout. The history buffers are bound afterwards, by [`Script::compile()`].
+fn instantiate(
+    code: &str,
+    config: &ScriptConfig,
+    samples: &[u8],
+    watchdog: Option<&Watchdog>,
+) -> Result<(Compiled, ScriptInfo), InstantiateError> {
+    let mut runtime = Runtime::new();
+    if let Some(watchdog) = watchdog {
+        watchdog.attach(&mut runtime);
+    }
+    runtime.run(PRELUDE)?;
+
+    let offsets = SampleOffsets::new(config);
+    let mut numbers = runtime.share::<f64>(NUMBERS_LEN);
+    let shared_samples = runtime.share::<f32>(offsets.len);
+    let mut text = runtime.share::<u8>(TEXT_LEN);
+    let layout =
+        serde_json::to_string(&Layout::new(config, offsets)).map_err(|err| err.to_string())?;
+    runtime.call_json(
+        "__rjv_init",
+        &[
+            Arg::Numbers(&numbers),
+            Arg::Samples(&shared_samples),
+            Arg::Bytes(&text),
+            Arg::Json(&layout),
+        ],
+    )?;
+
+    // The samples are available to the script's top level code
+    text.get_mut()[..samples.len()].copy_from_slice(samples);
+    numbers.get_mut()[ARG] = samples.len() as f64;
+    runtime.call_json("__rjv_buffers", &[])?;
+
+    runtime.run(code)?;
+    let info = ScriptInfo::parse(&runtime.call_json("__rjv_info", &[])?)
+        .map_err(InstantiateError::Options)?;
+
+    let spectra = match info.spectral {
+        Some(options) => {
+            let bins = options.normalized().size / 2 + 1;
+            let spectra = runtime.share::<f32>(config.channels * 2 * bins);
+            runtime.call_json(
+                "__rjv_spectral_init",
+                &[Arg::Samples(&spectra), Arg::Number(bins as f64)],
+            )?;
+            Some(spectra)
+        }
+        None => None,
+    };
+
+    let compiled = Compiled {
+        functions: Functions::new(&mut runtime)?,
+        numbers,
+        samples: shared_samples,
+        text,
+        spectra,
+        offsets,
+        runtime,
+    };
+
+    Ok((compiled, info))
+}
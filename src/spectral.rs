@@ -0,0 +1,275 @@
+//! The short-time Fourier transform behind the script's `spectral(t, frames)` function. Input is
+//! collected per sample, and every `hop` samples the script gets the magnitude and phase spectra of
+//! the last `size` samples for every channel. The modified spectra are resynthesized with windowed
+//! overlap-add, which delays the output by `size` samples.
+
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use serde::Deserialize;
+use std::f32::consts::TAU;
+use std::sync::Arc;
+
+const MIN_SIZE: usize = 64;
+const MAX_SIZE: usize = 16384;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Window {
+    Hann,
+    Hamming,
+    Blackman,
+    Rectangular,
+}
+
+impl Window {
+    /// The periodic version of the window, as used for spectral analysis.
+    fn samples(self, size: usize) -> Vec<f32> {
+        (0..size)
+            .map(|i| {
+                let x = i as f32 / size as f32;
+                match self {
+                    Window::Hann => 0.5 - 0.5 * (TAU * x).cos(),
+                    Window::Hamming => 0.54 - 0.46 * (TAU * x).cos(),
+                    Window::Blackman => 0.42 - 0.5 * (TAU * x).cos() + 0.08 * (2.0 * TAU * x).cos(),
+                    Window::Rectangular => 1.0,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The options from the script's `spectralOptions` object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct SpectralOptions {
+    /// The FFT size, rounded up to a power of two.
+    pub size: usize,
+    /// The number of samples between frames.
+    pub hop: usize,
+    pub window: Window,
+}
+
+impl SpectralOptions {
+    /// Clamps the options to sizes [`Stft`] supports.
+    pub fn normalized(self) -> Self {
+        let size = self.size.clamp(MIN_SIZE, MAX_SIZE).next_power_of_two();
+
+        Self {
+            size,
+            hop: self.hop.clamp(1, size),
+            window: self.window,
+        }
+    }
+}
+
+/// A single channel's spectrum, as passed to and returned from the script. Both arrays have
+/// `size / 2 + 1` bins.
+#[derive(Debug, Clone, Default)]
+pub struct SpectralFrame {
+    pub magnitude: Vec<f32>,
+    pub phase: Vec<f32>,
+}
+
+pub struct Stft {
+    options: SpectralOptions,
+    window: Vec<f32>,
+    /// The synthesis window. This compensates for the FFT's gain and for the overlapping analysis
+    /// and synthesis windows, so an unmodified spectrum is reconstructed perfectly.
+    synthesis_window: Vec<f32>,
+    /// Ring buffers with the last `size` input samples, and with the output that's being summed,
+    /// per channel. Both are indexed by `pos`.
+    inputs: Vec<Vec<f32>>,
+    outputs: Vec<Vec<f32>>,
+    pos: usize,
+    /// The number of samples since the last frame.
+    since_frame: usize,
+    frames: Vec<SpectralFrame>,
+
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    time: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Stft {
+    pub fn new(options: SpectralOptions, num_channels: usize) -> Self {
+        let options = options.normalized();
+        let size = options.size;
+
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(size);
+        let inverse = planner.plan_fft_inverse(size);
+        let scratch_len = forward.get_scratch_len().max(inverse.get_scratch_len());
+
+        let window = options.window.samples(size);
+        let mut overlap = vec![0.0; options.hop];
+        for (i, w) in window.iter().enumerate() {
+            overlap[i % options.hop] += w * w;
+        }
+        let synthesis_window = window
+            .iter()
+            .enumerate()
+            .map(|(i, w)| w / overlap[i % options.hop].max(1e-3) / size as f32)
+            .collect();
+
+        Self {
+            options,
+            window,
+            synthesis_window,
+            inputs: vec![vec![0.0; size]; num_channels],
+            outputs: vec![vec![0.0; size]; num_channels],
+            pos: 0,
+            since_frame: 0,
+            frames: vec![
+                SpectralFrame {
+                    magnitude: vec![0.0; size / 2 + 1],
+                    phase: vec![0.0; size / 2 + 1],
+                };
+                num_channels
+            ],
+
+            time: forward.make_input_vec(),
+            spectrum: forward.make_output_vec(),
+            scratch: vec![Complex::default(); scratch_len],
+            forward,
+            inverse,
+        }
+    }
+
+    pub fn options(&self) -> SpectralOptions {
+        self.options
+    }
+
+    /// The number of samples the output is delayed by.
+    pub fn latency_samples(&self) -> u32 {
+        self.options.size as u32
+    }
+
+    /// Processes a single sample for every channel in place. `process` is called with the spectra
+    /// of all channels every `hop` samples.
+    pub fn process(&mut self, samples: &mut [f32], process: impl FnOnce(&mut [SpectralFrame])) {
+        let size = self.options.size;
+        for ((sample, input), output) in samples
+            .iter_mut()
+            .zip(&mut self.inputs)
+            .zip(&mut self.outputs)
+        {
+            input[self.pos] = *sample;
+            *sample = output[self.pos];
+            output[self.pos] = 0.0;
+        }
+
+        self.pos = (self.pos + 1) % size;
+        self.since_frame += 1;
+        if self.since_frame < self.options.hop {
+            return;
+        }
+        self.since_frame = 0;
+
+        // `pos` now points at the oldest input sample
+        for (input, frame) in self.inputs.iter().zip(&mut self.frames) {
+            for (i, (time, window)) in self.time.iter_mut().zip(&self.window).enumerate() {
+                *time = input[(self.pos + i) % size] * window;
+            }
+
+            let _ = self.forward.process_with_scratch(
+                &mut self.time,
+                &mut self.spectrum,
+                &mut self.scratch,
+            );
+            for ((bin, magnitude), phase) in self
+                .spectrum
+                .iter()
+                .zip(&mut frame.magnitude)
+                .zip(&mut frame.phase)
+            {
+                let (r, theta) = bin.to_polar();
+                *magnitude = r;
+                *phase = theta;
+            }
+        }
+
+        process(&mut self.frames);
+
+        for (output, frame) in self.outputs.iter_mut().zip(&self.frames) {
+            for ((bin, magnitude), phase) in self
+                .spectrum
+                .iter_mut()
+                .zip(&frame.magnitude)
+                .zip(&frame.phase)
+            {
+                *bin = Complex::from_polar(*magnitude, *phase);
+            }
+
+            // The DC and Nyquist bins can't have an imaginary part
+            if let Some(first) = self.spectrum.first_mut() {
+                first.im = 0.0;
+            }
+            if let Some(last) = self.spectrum.last_mut() {
+                last.im = 0.0;
+            }
+
+            let _ = self.inverse.process_with_scratch(
+                &mut self.spectrum,
+                &mut self.time,
+                &mut self.scratch,
+            );
+            for (i, (time, window)) in self.time.iter().zip(&self.synthesis_window).enumerate() {
+                output[(self.pos + i) % size] += time * window;
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for buffer in self.inputs.iter_mut().chain(&mut self.outputs) {
+            buffer.fill(0.0);
+        }
+        self.since_frame = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Passes noise through an STFT that doesn't touch the spectra, and checks that it comes out
+    /// unchanged after the reported latency. The first frames are skipped, since the frames that
+    /// would have covered the start of the input were never computed.
+    fn assert_identity(size: usize, hop: usize, window: Window) {
+        let mut stft = Stft::new(SpectralOptions { size, hop, window }, 2);
+        let latency = stft.latency_samples() as usize;
+        let input: Vec<[f32; 2]> = (0..size * 8)
+            .map(|i| {
+                let i = i as f32;
+                [
+                    (i * 0.37).sin() * 0.5 + (i * 0.011).cos() * 0.3,
+                    (i * 1.3).sin(),
+                ]
+            })
+            .collect();
+
+        for (n, frame) in input.iter().enumerate() {
+            let mut samples = *frame;
+            stft.process(&mut samples, |_| ());
+
+            if n >= latency + size {
+                for (channel, sample) in samples.iter().enumerate() {
+                    let expected = input[n - latency][channel];
+                    assert!(
+                        (sample - expected).abs() < 1e-4,
+                        "{window:?} {size}/{hop}, channel {channel}, sample {n}: expected \
+                         {expected}, got {sample}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unmodified_spectra_are_reconstructed_at_the_reported_latency() {
+        assert_identity(256, 64, Window::Hann);
+        assert_identity(512, 128, Window::Hamming);
+        assert_identity(256, 128, Window::Blackman);
+        assert_identity(128, 128, Window::Rectangular);
+    }
+}
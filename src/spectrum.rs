@@ -0,0 +1,262 @@
+//! The editor's spectrum analyzer. The editor takes the most recent samples from the scope's ring
+//! buffer every frame, so the audio thread doesn't do any extra work for it, and the FFT, smoothing
+//! and peak hold all happen on the GUI thread.
+
+use nih_plug::prelude::util;
+use nih_plug_egui::egui::{self, Align2, Color32, FontId, Sense, Stroke};
+use realfft::num_complex::Complex;
+use realfft::{RealFftPlanner, RealToComplex};
+use std::f32::consts::TAU;
+use std::sync::Arc;
+
+use crate::scope::Scope;
+
+/// The number of samples per FFT. At 48 kHz, the bins are about 12 Hz apart.
+const FFT_SIZE: usize = 4096;
+
+/// The analyzer's range. The frequency axis ends at the Nyquist frequency or at 20 kHz.
+const MIN_FREQ: f32 = 20.0;
+const MAX_FREQ: f32 = 20_000.0;
+const MIN_DB: f32 = -96.0;
+const MAX_DB: f32 = 6.0;
+
+/// The longest smoothing time the editor offers, in milliseconds.
+pub const MAX_SMOOTHING_MS: f32 = 2000.0;
+/// How long a peak stays put before it starts falling, and how fast it falls then.
+const PEAK_HOLD_SECONDS: f32 = 2.0;
+const PEAK_DECAY_DB_PER_SECOND: f32 = 24.0;
+
+/// The smoothed spectrum of one signal and its held peaks, in dB per bin.
+pub struct Spectrum {
+    smoothed: Vec<f32>,
+    peaks: Vec<f32>,
+    /// The time since every bin's peak was last raised, in seconds.
+    peak_ages: Vec<f32>,
+}
+
+impl Default for Spectrum {
+    fn default() -> Self {
+        Self {
+            smoothed: vec![MIN_DB; FFT_SIZE / 2 + 1],
+            peaks: vec![MIN_DB; FFT_SIZE / 2 + 1],
+            peak_ages: vec![0.0; FFT_SIZE / 2 + 1],
+        }
+    }
+}
+
+impl Spectrum {
+    fn update(&mut self, magnitudes: &[f32], smoothing: f32, dt: f32) {
+        for (((smoothed, peak), age), db) in self
+            .smoothed
+            .iter_mut()
+            .zip(&mut self.peaks)
+            .zip(&mut self.peak_ages)
+            .zip(magnitudes)
+        {
+            *smoothed = db + (*smoothed - db) * smoothing;
+
+            *age += dt;
+            if *smoothed >= *peak {
+                *peak = *smoothed;
+                *age = 0.0;
+            } else if *age > PEAK_HOLD_SECONDS {
+                *peak = (*peak - PEAK_DECAY_DB_PER_SECOND * dt).max(*smoothed);
+            }
+        }
+    }
+
+    fn reset_peaks(&mut self) {
+        self.peaks.copy_from_slice(&self.smoothed);
+        self.peak_ages.fill(0.0);
+    }
+}
+
+/// Computes the spectra of the input and output shown in the editor.
+pub struct SpectrumAnalyzer {
+    sample_rate: f32,
+    fft: Arc<dyn RealToComplex<f32>>,
+    window: Vec<f32>,
+    /// Scratch space for the samples read from the scope, the FFT and the magnitudes in dB.
+    input_samples: Vec<f32>,
+    output_samples: Vec<f32>,
+    fft_input: Vec<f32>,
+    fft_output: Vec<Complex<f32>>,
+    fft_scratch: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,
+
+    pub input: Spectrum,
+    pub output: Spectrum,
+}
+
+impl Default for SpectrumAnalyzer {
+    fn default() -> Self {
+        let fft = RealFftPlanner::new().plan_fft_forward(FFT_SIZE);
+        let fft_output = fft.make_output_vec();
+        let fft_scratch = fft.make_scratch_vec();
+
+        Self {
+            sample_rate: 1.0,
+            fft,
+            window: (0..FFT_SIZE)
+                .map(|i| 0.5 - 0.5 * (TAU * i as f32 / FFT_SIZE as f32).cos())
+                .collect(),
+            input_samples: Vec::with_capacity(FFT_SIZE),
+            output_samples: Vec::with_capacity(FFT_SIZE),
+            fft_input: vec![0.0; FFT_SIZE],
+            fft_output,
+            fft_scratch,
+            magnitudes: vec![MIN_DB; FFT_SIZE / 2 + 1],
+
+            input: Spectrum::default(),
+            output: Spectrum::default(),
+        }
+    }
+}
+
+impl SpectrumAnalyzer {
+    /// Analyzes the last `FFT_SIZE` samples in the scope. `smoothing_ms` is the time constant of
+    /// the smoothing and `dt` the time since the last update, both for smoothing and peak hold.
+    /// The input is only analyzed when `with_input` is set.
+    pub fn update(&mut self, scope: &Scope, smoothing_ms: f32, dt: f32, with_input: bool) {
+        self.sample_rate = scope.sample_rate();
+        scope.latest(FFT_SIZE, &mut self.input_samples, &mut self.output_samples);
+
+        let smoothing = if smoothing_ms > 0.0 {
+            (-dt * 1000.0 / smoothing_ms).exp()
+        } else {
+            0.0
+        };
+
+        self.analyze(false);
+        self.output.update(&self.magnitudes, smoothing, dt);
+        if with_input {
+            self.analyze(true);
+            self.input.update(&self.magnitudes, smoothing, dt);
+        }
+    }
+
+    /// Computes the magnitude spectrum of the input or output samples in `self.magnitudes`, scaled
+    /// so a full scale sine wave reads 0 dB.
+    fn analyze(&mut self, input: bool) {
+        let samples = if input {
+            &self.input_samples
+        } else {
+            &self.output_samples
+        };
+        for ((fft_input, sample), window) in
+            self.fft_input.iter_mut().zip(samples).zip(&self.window)
+        {
+            *fft_input = sample * window;
+        }
+
+        // The Hann window halves the amplitude, and the other half of the energy is in the
+        // negative frequencies
+        if self
+            .fft
+            .process_with_scratch(
+                &mut self.fft_input,
+                &mut self.fft_output,
+                &mut self.fft_scratch,
+            )
+            .is_err()
+        {
+            return;
+        }
+        let scale = 4.0 / FFT_SIZE as f32;
+        for (magnitude, bin) in self.magnitudes.iter_mut().zip(&self.fft_output) {
+            *magnitude = util::gain_to_db(bin.norm() * scale).max(MIN_DB);
+        }
+    }
+
+    pub fn reset_peaks(&mut self) {
+        self.input.reset_peaks();
+        self.output.reset_peaks();
+    }
+}
+
+/// Draws the output's spectrum on a logarithmic frequency axis, optionally with the input's
+/// spectrum behind it and the held peaks on top. Clicking the analyzer resets the peaks.
+pub fn spectrum(ui: &mut egui::Ui, analyzer: &mut SpectrumAnalyzer, input: bool, peak_hold: bool) {
+    let size = egui::vec2(ui.available_width(), 200.0);
+    let (rect, response) = ui.allocate_exact_size(size, Sense::click());
+    if response.clicked() {
+        analyzer.reset_peaks();
+    }
+
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, Color32::from_gray(245));
+
+    let nyquist = analyzer.sample_rate / 2.0;
+    let max_freq = MAX_FREQ.min(nyquist).max(MIN_FREQ * 2.0);
+    let x = |freq: f32| {
+        rect.left() + rect.width() * (freq / MIN_FREQ).ln() / (max_freq / MIN_FREQ).ln()
+    };
+    let freq = |x: f32| MIN_FREQ * (max_freq / MIN_FREQ).powf((x - rect.left()) / rect.width());
+    let y = |db: f32| {
+        rect.top() + rect.height() * (MAX_DB - db.clamp(MIN_DB, MAX_DB)) / (MAX_DB - MIN_DB)
+    };
+
+    let grid = Stroke::new(1.0, Color32::from_gray(220));
+    let text = Color32::from_gray(140);
+    for (grid_freq, label) in [(100.0, "100"), (1000.0, "1k"), (10_000.0, "10k")] {
+        if grid_freq < max_freq {
+            painter.vline(x(grid_freq), rect.y_range(), grid);
+            painter.text(
+                egui::pos2(x(grid_freq) + 2.0, rect.bottom() - 2.0),
+                Align2::LEFT_BOTTOM,
+                label,
+                FontId::proportional(10.0),
+                text,
+            );
+        }
+    }
+    for db in (-84..=0).step_by(12) {
+        painter.hline(rect.x_range(), y(db as f32), grid);
+        painter.text(
+            egui::pos2(rect.left() + 2.0, y(db as f32) - 1.0),
+            Align2::LEFT_BOTTOM,
+            format!("{db}"),
+            FontId::proportional(10.0),
+            text,
+        );
+    }
+
+    // Every pixel column shows the loudest bin in its frequency range, or interpolates between
+    // the two nearest bins at low frequencies where the bins are wider than a pixel
+    let bin_width = analyzer.sample_rate / FFT_SIZE as f32;
+    let line = |db: &[f32], color: Color32| {
+        let points = (0..rect.width().max(1.0) as usize)
+            .map(|column| {
+                let left = freq(rect.left() + column as f32) / bin_width;
+                let right = freq(rect.left() + column as f32 + 1.0) / bin_width;
+                let value = if right - left >= 1.0 {
+                    db[(left.ceil() as usize).min(db.len() - 1)
+                        ..(right.ceil() as usize).min(db.len())]
+                        .iter()
+                        .copied()
+                        .fold(MIN_DB, f32::max)
+                } else {
+                    let bin = (left as usize).min(db.len() - 2);
+                    let t = (left - bin as f32).clamp(0.0, 1.0);
+                    db[bin] + (db[bin + 1] - db[bin]) * t
+                };
+
+                egui::pos2(rect.left() + column as f32, y(value))
+            })
+            .collect();
+        painter.add(egui::Shape::line(points, Stroke::new(1.0, color)));
+    };
+
+    if input {
+        line(&analyzer.input.smoothed, Color32::from_gray(160));
+        if peak_hold {
+            line(&analyzer.input.peaks, Color32::from_gray(200));
+        }
+    }
+    line(&analyzer.output.smoothed, Color32::from_rgb(30, 90, 200));
+    if peak_hold {
+        line(&analyzer.output.peaks, Color32::from_rgb(230, 120, 40));
+    }
+
+    ui.ctx().request_repaint();
+}
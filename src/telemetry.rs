@@ -0,0 +1,263 @@
+//! The channel from the audio thread to the editor. Continuous values, like the meters, the CPU
+//! usage, the scope's samples and the script's status, are atomics that the editor reads whenever
+//! it draws. Discrete messages, like console output, watch values and profiles, go through a
+//! single producer, single consumer ring buffer of records, and are only sent while the editor is
+//! open. Writing to either never blocks or allocates on the Rust side, and records that don't fit
+//! in the ring buffer are dropped. The payloads themselves are mostly text the script put together,
+//! like console messages and watch values as JSON, and building those strings does allocate on the
+//! script's V8 heap.
+
+use nih_plug_egui::egui::{self, Color32};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+use crate::cpu::CpuReadings;
+use crate::meters::Meters;
+use crate::plot::LiveValues;
+use crate::scope::Scope;
+use crate::script::ScriptInfo;
+
+/// The ring buffer's size in bytes. This fits a few frames worth of large watch values.
+const RING_CAPACITY: usize = 1 << 20;
+/// Every record starts with its kind and the payload's length.
+const RECORD_HEADER_LEN: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    /// A console message, see `console::encode()`.
+    Console,
+    /// The watch expressions' generation, followed by their values as JSON.
+    Watch,
+    /// The time per function since the last profile, as JSON.
+    Profile,
+}
+
+impl RecordKind {
+    const ALL: [RecordKind; 3] = [RecordKind::Console, RecordKind::Watch, RecordKind::Profile];
+}
+
+/// The byte ring buffer the records are written to. Positions count the bytes written and read so
+/// far, and only the producer moves `written` and only the consumer moves `read`.
+struct Ring {
+    bytes: Vec<AtomicU8>,
+    written: AtomicUsize,
+    read: AtomicUsize,
+}
+
+impl Default for Ring {
+    fn default() -> Self {
+        Self {
+            bytes: (0..RING_CAPACITY).map(|_| AtomicU8::new(0)).collect(),
+            written: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Ring {
+    /// Writes a record whose payload is the concatenation of `parts`. Returns false if it doesn't
+    /// fit.
+    fn push(&self, kind: RecordKind, parts: &[&[u8]]) -> bool {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        let written = self.written.load(Ordering::Relaxed);
+        let free = RING_CAPACITY - (written - self.read.load(Ordering::Acquire));
+        if RECORD_HEADER_LEN + len > free || len > u32::MAX as usize {
+            return false;
+        }
+
+        let header = [kind as u8];
+        let len_bytes = (len as u32).to_le_bytes();
+        let bytes = header
+            .iter()
+            .chain(&len_bytes)
+            .chain(parts.iter().flat_map(|part| part.iter()));
+        let mut pos = written;
+        for byte in bytes {
+            self.bytes[pos % RING_CAPACITY].store(*byte, Ordering::Relaxed);
+            pos += 1;
+        }
+
+        self.written.store(pos, Ordering::Release);
+        true
+    }
+
+    /// Reads the next record's payload into `payload`, and returns its kind.
+    fn pop(&self, payload: &mut Vec<u8>) -> Option<RecordKind> {
+        let read = self.read.load(Ordering::Relaxed);
+        if read == self.written.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let byte = |i: usize| self.bytes[(read + i) % RING_CAPACITY].load(Ordering::Relaxed);
+        let kind = RecordKind::ALL.get(byte(0) as usize).copied();
+        let len = u32::from_le_bytes([byte(1), byte(2), byte(3), byte(4)]) as usize;
+        payload.clear();
+        payload.extend((0..len).map(|i| byte(RECORD_HEADER_LEN + i)));
+
+        self.read
+            .store(read + RECORD_HEADER_LEN + len, Ordering::Release);
+        kind
+    }
+}
+
+/// The script's status after it was last compiled.
+#[derive(Default)]
+pub struct ScriptStatus {
+    /// Incremented every time the script is compiled. Zero until the first compile.
+    compiles: AtomicU32,
+    compiled: AtomicBool,
+    gain: AtomicBool,
+    process: AtomicBool,
+    voice: AtomicBool,
+    block: AtomicBool,
+    spectral: AtomicBool,
+    latency: AtomicU32,
+}
+
+impl ScriptStatus {
+    pub fn publish(&self, info: &ScriptInfo, compiled: bool) {
+        self.compiled.store(compiled, Ordering::Relaxed);
+        self.gain.store(info.gain, Ordering::Relaxed);
+        self.process.store(info.process, Ordering::Relaxed);
+        self.voice.store(info.voice, Ordering::Relaxed);
+        self.block.store(info.block, Ordering::Relaxed);
+        self.spectral
+            .store(info.spectral.is_some(), Ordering::Relaxed);
+        self.latency.store(info.latency, Ordering::Relaxed);
+        self.compiles.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Everything the audio thread reports to the editor.
+#[derive(Default)]
+pub struct Telemetry {
+    pub status: ScriptStatus,
+    pub meters: Meters,
+    pub cpu: CpuReadings,
+    pub scope: Scope,
+    pub live: LiveValues,
+    ring: Ring,
+    /// The number of console messages that were dropped, because of the rate limit or because the
+    /// ring buffer was full.
+    dropped_messages: AtomicUsize,
+}
+
+impl Telemetry {
+    /// Writes a record for the editor. Returns false if it was dropped.
+    pub fn send(&self, kind: RecordKind, parts: &[&[u8]]) -> bool {
+        self.ring.push(kind, parts)
+    }
+
+    /// Reads the next record into `payload`. Only the editor reads records.
+    pub fn receive(&self, payload: &mut Vec<u8>) -> Option<RecordKind> {
+        self.ring.pop(payload)
+    }
+
+    pub fn drop_messages(&self, count: usize) {
+        self.dropped_messages.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn dropped_messages(&self) -> usize {
+        self.dropped_messages.load(Ordering::Relaxed)
+    }
+
+    pub fn clear_dropped_messages(&self) {
+        self.dropped_messages.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Draws a single line with the script's status and entry points.
+pub fn status(ui: &mut egui::Ui, status: &ScriptStatus) {
+    if status.compiles.load(Ordering::Acquire) == 0 {
+        ui.weak("Not compiled yet");
+        return;
+    }
+    if !status.compiled.load(Ordering::Relaxed) {
+        ui.colored_label(
+            Color32::from_rgb(200, 40, 40),
+            "The script doesn't compile, see the console for the error",
+        );
+        return;
+    }
+
+    let entry_points: Vec<_> = [
+        (&status.gain, "gain(t)"),
+        (&status.process, "process()"),
+        (&status.voice, "voice()"),
+        (&status.block, "block()"),
+        (&status.spectral, "spectral()"),
+    ]
+    .into_iter()
+    .filter(|(defined, _)| defined.load(Ordering::Relaxed))
+    .map(|(_, name)| name)
+    .collect();
+
+    let mut text = format!("Running {}", entry_points.join(", "));
+    let latency = status.latency.load(Ordering::Relaxed);
+    if latency > 0 {
+        text += &format!(" with {latency} samples of latency");
+    }
+    ui.colored_label(Color32::from_rgb(40, 140, 60), text);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fills the ring up to `pos` with a single record and reads it back, so the next record starts
+    /// there.
+    fn advance(ring: &Ring, pos: usize) {
+        let filler = vec![0; pos - RECORD_HEADER_LEN];
+        assert!(ring.push(RecordKind::Watch, &[&filler]));
+        assert_eq!(ring.pop(&mut Vec::new()), Some(RecordKind::Watch));
+    }
+
+    #[test]
+    fn records_wrap_past_the_end() {
+        let ring = Ring::default();
+        advance(&ring, RING_CAPACITY - 3);
+
+        let mut payload = Vec::new();
+        assert!(ring.push(RecordKind::Console, &[b"split ", b"in two"]));
+        assert_eq!(ring.pop(&mut payload), Some(RecordKind::Console));
+        assert_eq!(payload, b"split in two");
+        assert_eq!(ring.pop(&mut payload), None);
+    }
+
+    #[test]
+    fn a_full_ring_rejects_records_without_corrupting_the_next_ones() {
+        let ring = Ring::default();
+        advance(&ring, RING_CAPACITY / 2);
+        let filler = vec![7; RING_CAPACITY - 2 * RECORD_HEADER_LEN - 4];
+        assert!(ring.push(RecordKind::Watch, &[&filler]));
+
+        // There's room for a header and four bytes
+        assert!(!ring.push(RecordKind::Console, &[b"too long"]));
+        assert!(ring.push(RecordKind::Profile, &[b"{}", b"{}"]));
+        assert!(!ring.push(RecordKind::Console, &[b""]));
+
+        let mut payload = Vec::new();
+        assert_eq!(ring.pop(&mut payload), Some(RecordKind::Watch));
+        assert_eq!(payload, filler);
+        assert_eq!(ring.pop(&mut payload), Some(RecordKind::Profile));
+        assert_eq!(payload, b"{}{}");
+        assert_eq!(ring.pop(&mut payload), None);
+
+        // Reading made room again
+        assert!(ring.push(RecordKind::Console, &[b"fits"]));
+        assert_eq!(ring.pop(&mut payload), Some(RecordKind::Console));
+        assert_eq!(payload, b"fits");
+    }
+
+    #[test]
+    fn popping_an_empty_ring_leaves_the_payload_alone() {
+        let ring = Ring::default();
+        let mut payload = b"previous".to_vec();
+        assert_eq!(ring.pop(&mut payload), None);
+        assert_eq!(payload, b"previous");
+
+        assert!(ring.push(RecordKind::Console, &[]));
+        assert_eq!(ring.pop(&mut payload), Some(RecordKind::Console));
+        assert!(payload.is_empty());
+        assert_eq!(ring.pop(&mut payload), None);
+    }
+}
@@ -0,0 +1,27 @@
+use nih_plug::prelude::Transport;
+
+/// The parts of the host's transport the plugin uses, copied at the start of every block. The tests
+/// make these up themselves, because nih_plug's `Transport` can only be created by its wrappers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HostTransport {
+    pub playing: bool,
+    pub tempo: Option<f64>,
+    pub pos_samples: Option<i64>,
+    /// The position in quarter notes.
+    pub pos_beats: Option<f64>,
+    pub time_sig_numerator: Option<i32>,
+    pub time_sig_denominator: Option<i32>,
+}
+
+impl HostTransport {
+    pub fn new(transport: &Transport) -> Self {
+        Self {
+            playing: transport.playing,
+            tempo: transport.tempo,
+            pos_samples: transport.pos_samples(),
+            pos_beats: transport.pos_beats(),
+            time_sig_numerator: transport.time_sig_numerator,
+            time_sig_denominator: transport.time_sig_denominator,
+        }
+    }
+}
@@ -0,0 +1,500 @@
+use nih_plug::prelude::*;
+use std::cmp::Ordering;
+
+/// The maximum number of voices that can ever be active at the same time. The actual limit is
+/// controlled by the `voices` parameter.
+pub const MAX_VOICES: usize = 16;
+
+/// Which voice to take over when a note comes in while all voices are in use.
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoiceStealing {
+    #[id = "oldest"]
+    #[name = "Oldest"]
+    Oldest,
+    #[id = "quietest"]
+    #[name = "Quietest"]
+    Quietest,
+    #[id = "lowest"]
+    #[name = "Lowest"]
+    Lowest,
+    #[id = "highest"]
+    #[name = "Highest"]
+    Highest,
+    /// Ignore new notes until a voice frees up.
+    #[id = "none"]
+    #[name = "None"]
+    None,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    voice_id: i32,
+    channel: u8,
+    note: u8,
+    velocity: f32,
+
+    /// The number of samples since the note-on event.
+    age: u32,
+    /// The number of samples since the note-off event, if the voice is releasing.
+    released: Option<u32>,
+    /// Set for new voices so the script resets its per-voice state.
+    fresh: bool,
+    /// The absolute value of the voice's last output sample, used for stealing the quietest voice.
+    level: f32,
+    /// The normalized offset for the gain parameter, when the host modulates it for this voice.
+    gain_offset: Option<f32>,
+}
+
+/// A voice that has stopped playing. The host needs to be told about these through
+/// [`NoteEvent::VoiceTerminated`] for polyphonic modulation to work.
+#[derive(Debug, Clone, Copy)]
+pub struct TerminatedVoice {
+    pub voice_id: i32,
+    pub channel: u8,
+    pub note: u8,
+}
+
+impl TerminatedVoice {
+    pub fn event<S: SysExMessage>(self, timing: u32) -> NoteEvent<S> {
+        NoteEvent::VoiceTerminated {
+            timing,
+            voice_id: Some(self.voice_id),
+            channel: self.channel,
+            note: self.note,
+        }
+    }
+}
+
+/// A voice as it's passed to the script's `voice(t, note, velocity, state)` function.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VoiceFrame {
+    slot: usize,
+    fresh: bool,
+    /// Seconds since the note-on.
+    age: f32,
+    note: u8,
+    velocity: f32,
+    /// Seconds since the note-off, if the voice is releasing.
+    released: Option<f32>,
+}
+
+impl VoiceFrame {
+    /// The number of numbers [`write()`][Self::write()] writes.
+    pub const LEN: usize = 6;
+
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// Writes the frame for the script: its slot, whether it's new, its age, note, velocity, and
+    /// its release time, which is NaN while the note is held.
+    pub fn write(&self, row: &mut [f64]) {
+        row[0] = self.slot as f64;
+        row[1] = if self.fresh { 1.0 } else { 0.0 };
+        row[2] = self.age as f64;
+        row[3] = self.note as f64;
+        row[4] = self.velocity as f64;
+        row[5] = self.released.map_or(f64::NAN, |released| released as f64);
+    }
+}
+
+/// Keeps track of the active voices. The voices themselves are rendered by the script, this only
+/// decides which voices exist and for how long.
+#[derive(Default)]
+pub struct VoiceManager {
+    voices: [Option<Voice>; MAX_VOICES],
+    /// Set when the plugin was reset. The host still has to be told that the voices ended, which
+    /// can only happen in the next block.
+    reset: bool,
+}
+
+impl VoiceManager {
+    fn num_active(&self) -> usize {
+        self.voices.iter().filter(|v| v.is_some()).count()
+    }
+
+    /// Starts a new voice. If that requires stealing another voice, then that voice is returned.
+    pub fn note_on(
+        &mut self,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+        velocity: f32,
+        capacity: usize,
+        stealing: VoiceStealing,
+    ) -> Option<TerminatedVoice> {
+        let voice = Voice {
+            voice_id: voice_id.unwrap_or_else(|| fallback_voice_id(note, channel)),
+            channel,
+            note,
+            velocity,
+            age: 0,
+            released: None,
+            fresh: true,
+            level: 0.0,
+            gain_offset: None,
+        };
+
+        if self.num_active() < capacity.min(MAX_VOICES) {
+            if let Some(slot) = self.voices.iter_mut().find(|v| v.is_none()) {
+                *slot = Some(voice);
+                return None;
+            }
+        }
+
+        let victim = self.steal(stealing)?;
+        let stolen = self.voices[victim].replace(voice);
+
+        stolen.map(|v| v.terminated())
+    }
+
+    /// Terminates voices until no more than `capacity` are active, for when the voices parameter
+    /// was lowered while more voices were playing. The voices are picked the same way they're
+    /// stolen, or the oldest ones first when voices aren't stolen.
+    pub fn limit(
+        &mut self,
+        capacity: usize,
+        stealing: VoiceStealing,
+        mut terminated: impl FnMut(TerminatedVoice),
+    ) {
+        let stealing = match stealing {
+            VoiceStealing::None => VoiceStealing::Oldest,
+            stealing => stealing,
+        };
+        while self.num_active() > capacity.min(MAX_VOICES) {
+            match self
+                .steal(stealing)
+                .and_then(|victim| self.voices[victim].take())
+            {
+                Some(voice) => terminated(voice.terminated()),
+                None => break,
+            }
+        }
+    }
+
+    /// Ends all voices at the start of the next block, see
+    /// [`terminate_reset()`][Self::terminate_reset()].
+    pub fn reset(&mut self) {
+        self.reset = true;
+    }
+
+    /// Terminates all voices if the plugin was reset since the last block. This is called before
+    /// the block's note events are handled.
+    pub fn terminate_reset(&mut self, mut terminated: impl FnMut(TerminatedVoice)) {
+        if !std::mem::take(&mut self.reset) {
+            return;
+        }
+
+        for slot in self.voices.iter_mut() {
+            if let Some(voice) = slot.take() {
+                terminated(voice.terminated());
+            }
+        }
+    }
+
+    /// Puts the matching voices in their release phase.
+    pub fn note_off(&mut self, voice_id: Option<i32>, channel: u8, note: u8) {
+        for voice in self.voices.iter_mut().flatten() {
+            if voice.released.is_none() && voice.matches(voice_id, channel, note) {
+                voice.released = Some(0);
+            }
+        }
+    }
+
+    /// Immediately terminates the matching voices.
+    pub fn choke(
+        &mut self,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+        mut terminated: impl FnMut(TerminatedVoice),
+    ) {
+        for slot in self.voices.iter_mut() {
+            if let Some(voice) = slot {
+                if voice.matches(voice_id, channel, note) {
+                    terminated(voice.terminated());
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    pub fn modulate_gain(&mut self, voice_id: i32, normalized_offset: f32) {
+        for voice in self.voices.iter_mut().flatten() {
+            if voice.voice_id == voice_id {
+                voice.gain_offset = Some(normalized_offset);
+            }
+        }
+    }
+
+    /// Fills `frames` with the active voices and returns how many there are.
+    pub fn frames(&mut self, sample_rate: f32, frames: &mut [VoiceFrame; MAX_VOICES]) -> usize {
+        let mut num_frames = 0;
+        for (slot, voice) in self.voices.iter_mut().enumerate() {
+            if let Some(voice) = voice {
+                frames[num_frames] = VoiceFrame {
+                    slot,
+                    fresh: voice.fresh,
+                    age: voice.age as f32 / sample_rate,
+                    note: voice.note,
+                    velocity: voice.velocity,
+                    released: voice.released.map(|r| r as f32 / sample_rate),
+                };
+                voice.fresh = false;
+                num_frames += 1;
+            }
+        }
+
+        num_frames
+    }
+
+    /// Sums the script's per-voice outputs. `gain` maps a voice's polyphonic gain modulation
+    /// offset, if it has one, to a linear gain value.
+    pub fn mix(
+        &mut self,
+        frames: &[VoiceFrame],
+        outputs: &[f32],
+        gain: impl Fn(Option<f32>) -> f32,
+    ) -> f32 {
+        let mut sum = 0.0;
+        for (frame, output) in frames.iter().zip(outputs) {
+            if let Some(voice) = &mut self.voices[frame.slot] {
+                let sample = output * gain(voice.gain_offset);
+                voice.level = sample.abs();
+                sum += sample;
+            }
+        }
+
+        sum
+    }
+
+    /// Advances all voices by one sample. Voices that are done releasing, or that the script
+    /// marked as `done`, are terminated.
+    pub fn advance(
+        &mut self,
+        release_samples: u32,
+        done: &[usize],
+        mut terminated: impl FnMut(TerminatedVoice),
+    ) {
+        for (slot_idx, slot) in self.voices.iter_mut().enumerate() {
+            if let Some(voice) = slot {
+                voice.age += 1;
+                if let Some(released) = &mut voice.released {
+                    *released += 1;
+                }
+
+                let finished = matches!(voice.released, Some(r) if r >= release_samples);
+                if finished || done.contains(&slot_idx) {
+                    terminated(voice.terminated());
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    fn steal(&self, stealing: VoiceStealing) -> Option<usize> {
+        let active = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, v)| v.as_ref().map(|v| (idx, v)));
+
+        // Voices that are already releasing are always the first to go
+        let key = |v: &Voice| -> (bool, f32) {
+            let score = match stealing {
+                VoiceStealing::Oldest => -(v.age as f32),
+                VoiceStealing::Quietest => v.level,
+                VoiceStealing::Lowest => v.note as f32,
+                VoiceStealing::Highest => -(v.note as f32),
+                VoiceStealing::None => 0.0,
+            };
+
+            (v.released.is_none(), score)
+        };
+
+        match stealing {
+            VoiceStealing::None => None,
+            _ => active
+                .min_by(|(_, a), (_, b)| key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal))
+                .map(|(idx, _)| idx),
+        }
+    }
+}
+
+impl Voice {
+    fn matches(&self, voice_id: Option<i32>, channel: u8, note: u8) -> bool {
+        match voice_id {
+            Some(voice_id) => self.voice_id == voice_id,
+            None => self.channel == channel && self.note == note,
+        }
+    }
+
+    fn terminated(&self) -> TerminatedVoice {
+        TerminatedVoice {
+            voice_id: self.voice_id,
+            channel: self.channel,
+            note: self.note,
+        }
+    }
+}
+
+/// Hosts that don't support voice IDs still need a stable ID for every voice.
+fn fallback_voice_id(note: u8, channel: u8) -> i32 {
+    note as i32 | ((channel as i32) << 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays the notes as they're listed, on channel 0 and without voice IDs.
+    fn play(notes: &[u8], capacity: usize, stealing: VoiceStealing) -> VoiceManager {
+        let mut voices = VoiceManager::default();
+        for &note in notes {
+            assert!(voices
+                .note_on(None, 0, note, 1.0, capacity, stealing)
+                .is_none());
+            voices.advance(1000, &[], |_| panic!("no voice should end"));
+        }
+
+        voices
+    }
+
+    fn notes(voices: &VoiceManager) -> Vec<u8> {
+        let mut notes: Vec<u8> = voices.voices.iter().flatten().map(|v| v.note).collect();
+        notes.sort_unstable();
+        notes
+    }
+
+    #[test]
+    fn stealing_picks_the_expected_voice() {
+        for (stealing, victim) in [
+            (VoiceStealing::Oldest, 64),
+            (VoiceStealing::Lowest, 60),
+            (VoiceStealing::Highest, 67),
+        ] {
+            let mut voices = play(&[64, 60, 67], 3, stealing);
+            let stolen = voices.note_on(None, 0, 72, 1.0, 3, stealing);
+            assert_eq!(stolen.map(|v| v.note), Some(victim), "{stealing:?}");
+            assert_eq!(voices.num_active(), 3);
+        }
+
+        // The quietest voice is the one with the lowest last output
+        let mut voices = play(&[64, 60, 67], 3, VoiceStealing::Quietest);
+        let mut frames = [VoiceFrame::default(); MAX_VOICES];
+        let num_frames = voices.frames(1.0, &mut frames);
+        let outputs: Vec<f32> = frames[..num_frames]
+            .iter()
+            .map(|frame| match voices.voices[frame.slot].unwrap().note {
+                60 => 0.5,
+                64 => -0.1,
+                _ => 0.8,
+            })
+            .collect();
+        voices.mix(&frames[..num_frames], &outputs, |_| 1.0);
+        let stolen = voices.note_on(None, 0, 72, 1.0, 3, VoiceStealing::Quietest);
+        assert_eq!(stolen.map(|v| v.note), Some(64));
+
+        // Releasing voices go first, whatever the mode
+        let mut voices = play(&[64, 60, 67], 3, VoiceStealing::Lowest);
+        voices.note_off(None, 0, 67);
+        let stolen = voices.note_on(None, 0, 72, 1.0, 3, VoiceStealing::Lowest);
+        assert_eq!(stolen.map(|v| v.note), Some(67));
+
+        // Without stealing, new notes are ignored
+        let mut voices = play(&[64, 60, 67], 3, VoiceStealing::None);
+        assert!(voices
+            .note_on(None, 0, 72, 1.0, 3, VoiceStealing::None)
+            .is_none());
+        assert_eq!(notes(&voices), [60, 64, 67]);
+    }
+
+    #[test]
+    fn lowering_the_capacity_ends_the_excess_voices() {
+        let mut voices = play(&[64, 60, 67, 72], 4, VoiceStealing::Oldest);
+        let mut terminated = Vec::new();
+        voices.limit(2, VoiceStealing::Oldest, |v| terminated.push(v.note));
+        assert_eq!(terminated, [64, 60]);
+        assert_eq!(notes(&voices), [67, 72]);
+
+        // The oldest voices also go when voices aren't stolen
+        let mut voices = play(&[64, 60, 67, 72], 4, VoiceStealing::None);
+        let mut terminated = Vec::new();
+        voices.limit(3, VoiceStealing::None, |v| terminated.push(v.note));
+        assert_eq!(terminated, [64]);
+        voices.limit(3, VoiceStealing::None, |_| panic!("the voices fit"));
+    }
+
+    #[test]
+    fn released_voices_end_after_the_release_time() {
+        let mut voices = play(&[60, 64], 4, VoiceStealing::Oldest);
+        voices.note_off(None, 0, 60);
+        // A second note-off doesn't restart the release
+        voices.advance(3, &[], |_| panic!("still releasing"));
+        voices.note_off(None, 0, 60);
+        voices.advance(3, &[], |_| panic!("still releasing"));
+
+        let mut terminated = Vec::new();
+        voices.advance(3, &[], |v| terminated.push(v.note));
+        assert_eq!(terminated, [60]);
+        assert_eq!(notes(&voices), [64]);
+
+        // Voices the script marked as done end right away
+        let slot = voices.voices.iter().position(Option::is_some).unwrap();
+        let mut terminated = Vec::new();
+        voices.advance(3, &[slot], |v| terminated.push(v.note));
+        assert_eq!(terminated, [64]);
+    }
+
+    #[test]
+    fn choke_ends_the_matching_voices() {
+        let mut voices = VoiceManager::default();
+        voices.note_on(Some(7), 0, 60, 1.0, 4, VoiceStealing::Oldest);
+        voices.note_on(Some(8), 0, 60, 1.0, 4, VoiceStealing::Oldest);
+        voices.note_on(None, 1, 62, 1.0, 4, VoiceStealing::Oldest);
+
+        let mut terminated = Vec::new();
+        voices.choke(Some(8), 0, 60, |v| terminated.push(v.voice_id));
+        assert_eq!(terminated, [8]);
+
+        // Without a voice ID, the channel and note have to match
+        let mut terminated = Vec::new();
+        voices.choke(None, 0, 62, |v| terminated.push(v.voice_id));
+        assert!(terminated.is_empty());
+        voices.choke(None, 1, 62, |v| terminated.push(v.voice_id));
+        assert_eq!(terminated, [fallback_voice_id(62, 1)]);
+        assert_eq!(voices.num_active(), 1);
+    }
+
+    #[test]
+    fn voices_without_an_id_get_a_fallback_id() {
+        let mut voices = VoiceManager::default();
+        voices.note_on(None, 2, 60, 1.0, 4, VoiceStealing::Oldest);
+        voices.note_on(None, 3, 60, 1.0, 4, VoiceStealing::Oldest);
+
+        let mut terminated = Vec::new();
+        voices.choke(Some(fallback_voice_id(60, 3)), 0, 0, |v| {
+            terminated.push((v.voice_id, v.channel, v.note))
+        });
+        assert_eq!(terminated, [(fallback_voice_id(60, 3), 3, 60)]);
+        assert_ne!(fallback_voice_id(60, 2), fallback_voice_id(60, 3));
+        assert_ne!(fallback_voice_id(60, 2), fallback_voice_id(61, 2));
+    }
+
+    #[test]
+    fn a_reset_terminates_every_voice_in_the_next_block() {
+        let mut voices = play(&[60, 64, 67], 4, VoiceStealing::Oldest);
+        voices.note_off(None, 0, 64);
+
+        voices.reset();
+        assert_eq!(voices.num_active(), 3);
+        let mut terminated = Vec::new();
+        voices.terminate_reset(|v| terminated.push(v.note));
+        terminated.sort_unstable();
+        assert_eq!(terminated, [60, 64, 67]);
+        assert_eq!(voices.num_active(), 0);
+
+        // Only once
+        voices.terminate_reset(|_| panic!("already terminated"));
+    }
+}
@@ -0,0 +1,346 @@
+//! Watch expressions. The editor keeps a list of variables and their properties, and while the
+//! editor is open the audio thread reads them from the running script about once per frame, in
+//! between blocks, and sends their values back as telemetry records. Watches never call the
+//! script's functions or getters, so they can't change its state or keep it busy. The audio thread
+//! never waits for the editor: when it can't read the expressions, it tries again on the next frame.
+
+use nih_plug_egui::egui::{self, Color32, Sense, Stroke};
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::RwLock;
+
+use crate::script::ScriptEngine;
+use crate::telemetry::{RecordKind, Telemetry};
+
+/// How often the expressions are evaluated, in seconds.
+const WATCH_INTERVAL: f32 = 1.0 / 60.0;
+/// How often objects and arrays other than arrays of numbers are inspected again, in seconds.
+const INSPECT_INTERVAL: f32 = 0.25;
+
+/// A watched value, as inspected by the script. Arrays of numbers are sampled down to at most 1024
+/// values, and objects and other arrays are cut off after 32 entries, three levels deep, and 256
+/// values for all watches together.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum WatchValue {
+    Number {
+        value: f64,
+    },
+    /// An array or typed array of numbers. Values that aren't finite are replaced with zeroes and
+    /// counted in `invalid`.
+    Numbers {
+        values: Vec<f32>,
+        length: usize,
+        invalid: usize,
+    },
+    List {
+        items: Vec<WatchValue>,
+        length: usize,
+    },
+    Object {
+        entries: Vec<(String, WatchValue)>,
+        size: usize,
+    },
+    /// Everything else, formatted by the script.
+    Text {
+        text: String,
+    },
+    /// The expression threw.
+    Error {
+        message: String,
+    },
+}
+
+/// The watch expressions, shared between the editor and the audio thread.
+#[derive(Default)]
+pub struct Watches {
+    expressions: RwLock<Vec<String>>,
+    /// Incremented whenever the expressions change.
+    generation: AtomicU32,
+}
+
+impl Watches {
+    pub fn add(&self, expression: &str) {
+        self.expressions
+            .write()
+            .unwrap()
+            .push(expression.to_string());
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn remove(&self, index: usize) {
+        let mut expressions = self.expressions.write().unwrap();
+        if index < expressions.len() {
+            expressions.remove(index);
+            self.generation.fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+/// Evaluates the watch expressions on the audio thread.
+pub struct Watcher {
+    /// The generation of the expressions the script was last given. They're only passed to the
+    /// script again when they changed, or when the script was recompiled.
+    generation: Option<u32>,
+    /// Set when there are no expressions to evaluate.
+    empty: bool,
+    /// The time since the expressions were last evaluated, and since objects and arrays were last
+    /// inspected, in seconds.
+    elapsed: f32,
+    inspect_elapsed: f32,
+}
+
+impl Default for Watcher {
+    fn default() -> Self {
+        Self {
+            generation: None,
+            empty: true,
+            elapsed: WATCH_INTERVAL,
+            inspect_elapsed: INSPECT_INTERVAL,
+        }
+    }
+}
+
+impl Watcher {
+    /// Passes the expressions to the script again on the next update. Called when the script was
+    /// recompiled.
+    pub fn invalidate(&mut self) {
+        self.generation = None;
+    }
+
+    /// Called after every block while the editor is open, with the block's duration in seconds.
+    pub fn update(
+        &mut self,
+        watches: &Watches,
+        engine: &mut ScriptEngine,
+        telemetry: &Telemetry,
+        duration: f32,
+    ) {
+        self.elapsed += duration;
+        self.inspect_elapsed += duration;
+        if self.elapsed < WATCH_INTERVAL {
+            return;
+        }
+
+        let generation = watches.generation.load(Ordering::Acquire);
+        if self.generation != Some(generation) {
+            match watches.expressions.try_read() {
+                Ok(expressions) => {
+                    engine.set_watches(&expressions);
+                    self.empty = expressions.is_empty();
+                }
+                Err(_) => return,
+            }
+            self.generation = Some(generation);
+        }
+
+        self.elapsed = 0.0;
+        if self.empty {
+            return;
+        }
+
+        let deep = self.inspect_elapsed >= INSPECT_INTERVAL;
+        if deep {
+            self.inspect_elapsed = 0.0;
+        }
+        if let Some(json) = engine.watch(deep) {
+            telemetry.send(RecordKind::Watch, &[&generation.to_le_bytes(), json]);
+        }
+    }
+}
+
+/// The latest values the editor received, with the generation of the expressions they were
+/// evaluated for.
+#[derive(Default)]
+pub struct WatchValues {
+    generation: u32,
+    values: Vec<WatchValue>,
+}
+
+impl WatchValues {
+    /// Reads a watch record sent by `Watcher::update()`.
+    pub fn receive(&mut self, payload: &[u8]) {
+        if payload.len() < 4 {
+            return;
+        }
+
+        if let Ok(values) = serde_json::from_slice(&payload[4..]) {
+            self.generation = u32::from_le_bytes(payload[..4].try_into().unwrap());
+            self.values = values;
+        }
+    }
+}
+
+/// Draws the watch panel: a field to add expressions, and every expression with its value.
+pub fn watches(ui: &mut egui::Ui, watches: &Watches, values: &WatchValues, input: &mut String) {
+    ui.horizontal(|ui| {
+        let response = ui.add(
+            egui::TextEdit::singleline(input)
+                .hint_text("Variable, like state.level")
+                .desired_width(180.0),
+        );
+        let submitted = response.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+        if (ui.button("Watch").clicked() || submitted) && !input.trim().is_empty() {
+            watches.add(input.trim());
+            input.clear();
+        }
+    });
+
+    let expressions = watches.expressions.read().unwrap().clone();
+    let generation = watches.generation.load(Ordering::Acquire);
+
+    let mut removed = None;
+    for (i, expression) in expressions.iter().enumerate() {
+        let id = ui.id().with(("watch", i, expression));
+        let value = values.values.get(i).filter(|_| {
+            values.generation == generation && values.values.len() == expressions.len()
+        });
+
+        ui.horizontal(|ui| {
+            if ui.small_button("x").on_hover_text("Remove").clicked() {
+                removed = Some(i);
+            }
+            ui.monospace(expression);
+            match value {
+                Some(value) => inline(ui, value),
+                None => {
+                    ui.weak("...");
+                }
+            }
+        });
+        if let Some(value) = value {
+            ui.indent(id, |ui| children(ui, value, id));
+        }
+    }
+
+    if let Some(index) = removed {
+        watches.remove(index);
+    }
+
+    ui.ctx().request_repaint();
+}
+
+/// Draws the part of a value that goes on the same line as its name.
+fn inline(ui: &mut egui::Ui, value: &WatchValue) {
+    match value {
+        WatchValue::Number { value } => {
+            ui.monospace(number(*value));
+        }
+        WatchValue::Numbers {
+            values,
+            length,
+            invalid,
+        } => {
+            sparkline(ui, values);
+            let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+            let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let mut label = format!("[{length}]");
+            if !values.is_empty() {
+                label += &format!(" {} to {}", number(min as f64), number(max as f64));
+            }
+            ui.weak(label);
+            if *invalid > 0 {
+                ui.colored_label(
+                    Color32::from_rgb(200, 40, 40),
+                    format!("{invalid} not finite"),
+                );
+            }
+        }
+        WatchValue::List { length, .. } => {
+            ui.weak(format!("[{length}]"));
+        }
+        WatchValue::Object { size, .. } => {
+            ui.weak(format!("{{{size} keys}}"));
+        }
+        WatchValue::Text { text } => {
+            ui.monospace(text);
+        }
+        WatchValue::Error { message } => {
+            ui.colored_label(Color32::from_rgb(200, 40, 40), message);
+        }
+    }
+}
+
+/// Draws the entries of lists and objects as a tree.
+fn children(ui: &mut egui::Ui, value: &WatchValue, id: egui::Id) {
+    let (entries, total): (Vec<(String, &WatchValue)>, usize) = match value {
+        WatchValue::List { items, length } => (
+            items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| (i.to_string(), item))
+                .collect(),
+            *length,
+        ),
+        WatchValue::Object { entries, size } => (
+            entries
+                .iter()
+                .map(|(key, value)| (key.clone(), value))
+                .collect(),
+            *size,
+        ),
+        _ => return,
+    };
+
+    for (key, value) in &entries {
+        let id = id.with(key);
+        match value {
+            WatchValue::List { .. } | WatchValue::Object { .. } => {
+                egui::CollapsingHeader::new(egui::RichText::new(key).monospace())
+                    .id_source(id)
+                    .show(ui, |ui| {
+                        inline(ui, value);
+                        children(ui, value, id);
+                    });
+            }
+            _ => {
+                ui.horizontal(|ui| {
+                    ui.monospace(format!("{key}:"));
+                    inline(ui, value);
+                });
+            }
+        }
+    }
+    if total > entries.len() {
+        ui.weak(format!("... {} more", total - entries.len()));
+    }
+}
+
+fn sparkline(ui: &mut egui::Ui, values: &[f32]) {
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(120.0, 18.0), Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, Color32::from_gray(240));
+    if values.len() < 2 {
+        return;
+    }
+
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let range = if max > min { max - min } else { 1.0 };
+    let points = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            egui::pos2(
+                rect.left() + rect.width() * i as f32 / (values.len() - 1) as f32,
+                rect.bottom() - 1.0 - (rect.height() - 2.0) * (value - min) / range,
+            )
+        })
+        .collect();
+    painter.add(egui::Shape::line(
+        points,
+        Stroke::new(1.0, Color32::from_rgb(30, 90, 200)),
+    ));
+}
+
+/// Formats a number compactly. Integers are shown as they are, very small and very large numbers
+/// in scientific notation.
+fn number(value: f64) -> String {
+    if value == value.trunc() && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else if value.abs() < 1e-3 || value.abs() >= 1e6 {
+        format!("{value:.4e}")
+    } else {
+        format!("{value:.6}")
+    }
+}
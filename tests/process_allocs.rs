@@ -0,0 +1,378 @@
+//! Runs every script in `examples/` through the plugin's process function and checks that the audio
+//! thread doesn't allocate outside of the places it's explicitly allowed to. The allocation checks
+//! only exist in debug builds, and the test drives the plugin through hooks behind a feature, so
+//! this should be run with `cargo test --features test-hooks`.
+//!
+//! On top of the host playing and notes coming in, the scripts run with the editor open, with a
+//! sidechain, while the oversampling factor changes, while a sample is loaded, and while the code is
+//! edited. The work the editor and the background thread do themselves isn't checked.
+
+use assert_no_alloc::assert_no_alloc;
+use nih_plug::prelude::*;
+use rjv::{HostTransport, Rjv};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SAMPLE_RATE: f32 = 48000.0;
+const MAX_BLOCK_SIZE: usize = 64;
+/// Hosts don't always send blocks of the same size, so this cycles through these.
+const BLOCK_SIZES: [usize; 4] = [64, 1, 17, 33];
+const NUM_BLOCKS: usize = 4000;
+/// A note is played every this many blocks, for the scripts that define `voice()`.
+const NOTE_INTERVAL: usize = 250;
+/// The oversampling factor changes every this many blocks.
+const OVERSAMPLING_INTERVAL: usize = 300;
+const OVERSAMPLING_FACTORS: [usize; 4] = [2, 8, 1, 4];
+/// The block after which a sample is loaded.
+const LOAD_BLOCK: usize = 500;
+
+type Task = <Rjv as Plugin>::BackgroundTask;
+
+/// A process context that feeds the plugin a queue of note events, and holds on to the
+/// background tasks so they're run and dropped outside of the allocation checks.
+struct TestContext {
+    events: VecDeque<PluginNoteEvent<Rjv>>,
+    tasks: RefCell<Vec<Task>>,
+}
+
+impl TestContext {
+    fn new() -> Self {
+        Self {
+            events: VecDeque::with_capacity(16),
+            tasks: RefCell::new(Vec::with_capacity(16)),
+        }
+    }
+
+    fn run_tasks(&self, plugin: &mut Rjv) {
+        let executor = plugin.task_executor();
+        for task in self.tasks.borrow_mut().drain(..) {
+            executor(task);
+        }
+    }
+}
+
+impl InitContext<Rjv> for TestContext {
+    fn plugin_api(&self) -> PluginApi {
+        PluginApi::Standalone
+    }
+
+    fn execute(&self, task: Task) {
+        self.tasks.borrow_mut().push(task);
+    }
+
+    fn set_latency_samples(&self, _samples: u32) {}
+
+    fn set_current_voice_capacity(&self, _capacity: u32) {}
+}
+
+impl ProcessContext<Rjv> for TestContext {
+    fn plugin_api(&self) -> PluginApi {
+        PluginApi::Standalone
+    }
+
+    fn execute_background(&self, task: Task) {
+        self.tasks.borrow_mut().push(task);
+    }
+
+    fn execute_gui(&self, task: Task) {
+        self.tasks.borrow_mut().push(task);
+    }
+
+    fn transport(&self) -> &Transport {
+        unreachable!("process_block() gets the transport as an argument")
+    }
+
+    fn next_event(&mut self) -> Option<PluginNoteEvent<Rjv>> {
+        self.events.pop_front()
+    }
+
+    fn send_event(&mut self, _event: PluginNoteEvent<Rjv>) {}
+
+    fn set_latency_samples(&self, _samples: u32) {}
+
+    fn set_current_voice_capacity(&self, _capacity: u32) {}
+}
+
+/// What happens while a script runs.
+#[derive(Default)]
+struct Scenario<'a> {
+    /// Processes as if the editor was open, with a few watch expressions.
+    editor: bool,
+    /// Uses the stereo layout with a sidechain, which gets a signal of its own.
+    sidechain: bool,
+    /// Cycles through the oversampling factors.
+    oversampling: bool,
+    /// Loads this file as the sample `kick` partway through.
+    sample: Option<&'a Path>,
+    /// Replaces the script with each of these in turn, evenly spread over the run.
+    code_changes: &'a [String],
+}
+
+#[test]
+fn process_does_not_allocate() {
+    for code in examples() {
+        run_script(&code, &Scenario::default());
+    }
+}
+
+#[test]
+fn process_does_not_allocate_with_the_editor_open() {
+    let scenario = Scenario {
+        editor: true,
+        ..Scenario::default()
+    };
+    for code in examples() {
+        run_script(&code, &scenario);
+    }
+}
+
+#[test]
+fn process_does_not_allocate_with_a_sidechain() {
+    let scenario = Scenario {
+        sidechain: true,
+        ..Scenario::default()
+    };
+    for code in examples() {
+        run_script(&code, &scenario);
+    }
+}
+
+#[test]
+fn changing_the_oversampling_factor_does_not_allocate() {
+    let scenario = Scenario {
+        oversampling: true,
+        ..Scenario::default()
+    };
+    for code in examples() {
+        run_script(&code, &scenario);
+    }
+}
+
+#[test]
+fn loading_a_sample_does_not_allocate() {
+    let path = std::env::temp_dir().join(format!("rjv-process-allocs-{}.wav", std::process::id()));
+    write_kick(&path);
+    let code = fs::read_to_string(examples_dir().join("kick.js")).unwrap();
+    run_script(
+        &code,
+        &Scenario {
+            editor: true,
+            sample: Some(&path),
+            ..Scenario::default()
+        },
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn editing_the_code_does_not_allocate() {
+    let code_changes = examples();
+    run_script(
+        "Math.sin(t)",
+        &Scenario {
+            editor: true,
+            code_changes: &code_changes,
+            ..Scenario::default()
+        },
+    );
+}
+
+fn examples_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("examples")
+}
+
+/// The example scripts, sorted by name.
+fn examples() -> Vec<String> {
+    let mut paths: Vec<_> = fs::read_dir(examples_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "js"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    paths
+        .iter()
+        .map(|path| fs::read_to_string(path).unwrap())
+        .collect()
+}
+
+/// Writes a tenth of a second of a decaying sine as a 16 bit mono WAV file. Its sample rate differs
+/// from the session's, so it's resampled when it's loaded.
+fn write_kick(path: &Path) {
+    const KICK_SAMPLE_RATE: u32 = 44_100;
+    let samples: Vec<i16> = (0..KICK_SAMPLE_RATE / 10)
+        .map(|i| {
+            let t = i as f32 / KICK_SAMPLE_RATE as f32;
+            ((-t * 30.0).exp() * (TAU * 60.0 * t).sin() * i16::MAX as f32) as i16
+        })
+        .collect();
+
+    let data_len = samples.len() as u32 * 2;
+    let mut bytes = Vec::new();
+    bytes.extend(b"RIFF");
+    bytes.extend((36 + data_len).to_le_bytes());
+    bytes.extend(b"WAVEfmt ");
+    bytes.extend(16u32.to_le_bytes());
+    // PCM, one channel, the sample rate, the bytes per second, the bytes per frame and the bits per
+    // sample
+    bytes.extend(1u16.to_le_bytes());
+    bytes.extend(1u16.to_le_bytes());
+    bytes.extend(KICK_SAMPLE_RATE.to_le_bytes());
+    bytes.extend((KICK_SAMPLE_RATE * 2).to_le_bytes());
+    bytes.extend(2u16.to_le_bytes());
+    bytes.extend(16u16.to_le_bytes());
+    bytes.extend(b"data");
+    bytes.extend(data_len.to_le_bytes());
+    for sample in samples {
+        bytes.extend(sample.to_le_bytes());
+    }
+
+    fs::write(path, bytes).unwrap();
+}
+
+/// Points `buffer` at the first `num_samples` of every channel, and fills them with a sine at
+/// `freq`, starting at sample `time`.
+fn fill(
+    buffer: &mut Buffer,
+    channels: &mut [Vec<f32>],
+    num_samples: usize,
+    time: usize,
+    freq: f32,
+) {
+    unsafe {
+        buffer.set_slices(num_samples, |slices| {
+            slices.clear();
+            for channel in channels.iter_mut() {
+                slices.push(std::slice::from_raw_parts_mut(
+                    channel.as_mut_ptr(),
+                    num_samples,
+                ));
+            }
+        });
+    }
+    for channel in buffer.as_slice() {
+        for (i, sample) in channel.iter_mut().enumerate() {
+            let t = (time + i) as f32 / SAMPLE_RATE;
+            *sample = 0.5 * (TAU * freq * t).sin();
+        }
+    }
+}
+
+fn run_script(code: &str, scenario: &Scenario) {
+    let mut plugin = Rjv::with_code(code);
+    let mut context = TestContext::new();
+    let layout_name = if scenario.sidechain {
+        "Stereo with sidechain"
+    } else {
+        "Stereo"
+    };
+    let layout = Rjv::AUDIO_IO_LAYOUTS
+        .iter()
+        .find(|layout| layout.names.layout == Some(layout_name))
+        .unwrap();
+    let buffer_config = BufferConfig {
+        sample_rate: SAMPLE_RATE,
+        min_buffer_size: None,
+        max_buffer_size: MAX_BLOCK_SIZE as u32,
+        process_mode: ProcessMode::Realtime,
+    };
+    assert!(plugin.initialize(layout, &buffer_config, &mut context));
+    plugin.reset();
+
+    if scenario.editor {
+        plugin.simulate_editor(true);
+        for expression in [
+            "buffers",
+            "looper.status",
+            "missing.value",
+            "not an expression",
+        ] {
+            plugin.add_watch(expression);
+        }
+    }
+
+    // The buffers' slices point into these, the same way the wrappers' buffers point into the
+    // host's
+    let mut channels = vec![vec![0.0f32; MAX_BLOCK_SIZE]; 2];
+    let mut buffer = Buffer::default();
+    let mut sidechain_channels = vec![vec![0.0f32; MAX_BLOCK_SIZE]; 2];
+    let mut sidechain = Buffer::default();
+    let mut transport = HostTransport {
+        playing: true,
+        tempo: Some(120.0),
+        pos_samples: Some(0),
+        pos_beats: Some(0.0),
+        time_sig_numerator: Some(4),
+        time_sig_denominator: Some(4),
+    };
+    let code_change_interval = NUM_BLOCKS / (scenario.code_changes.len() + 1);
+
+    let mut time = 0;
+    for block in 0..NUM_BLOCKS {
+        let num_samples = BLOCK_SIZES[block % BLOCK_SIZES.len()];
+        fill(&mut buffer, &mut channels, num_samples, time, 220.0);
+        fill(
+            &mut sidechain,
+            &mut sidechain_channels,
+            num_samples,
+            time,
+            330.0,
+        );
+
+        if block % NOTE_INTERVAL == 0 {
+            context.events.push_back(NoteEvent::NoteOn {
+                timing: 0,
+                voice_id: None,
+                channel: 0,
+                note: 60 + (block / NOTE_INTERVAL % 12) as u8,
+                velocity: 0.8,
+            });
+        } else if block % NOTE_INTERVAL == NOTE_INTERVAL / 2 {
+            context.events.push_back(NoteEvent::NoteOff {
+                timing: 0,
+                voice_id: None,
+                channel: 0,
+                note: 60 + (block / NOTE_INTERVAL % 12) as u8,
+                velocity: 0.0,
+            });
+        }
+
+        // These are what the host, the editor and the background thread do in between blocks
+        if scenario.oversampling && block % OVERSAMPLING_INTERVAL == 0 {
+            let idx = block / OVERSAMPLING_INTERVAL % OVERSAMPLING_FACTORS.len();
+            plugin.set_oversampling(OVERSAMPLING_FACTORS[idx]);
+        }
+        if let Some(path) = scenario.sample.filter(|_| block == LOAD_BLOCK) {
+            plugin.task_executor()(Rjv::load_sample("kick", path.to_path_buf()));
+        }
+        if block > 0 && block % code_change_interval == 0 {
+            if let Some(code) = scenario.code_changes.get(block / code_change_interval - 1) {
+                plugin.set_code(code);
+            }
+        }
+        if scenario.editor && block == NUM_BLOCKS / 2 {
+            plugin.add_watch("buffers.kick");
+        }
+
+        let mut aux = AuxiliaryBuffers {
+            inputs: if scenario.sidechain {
+                std::slice::from_mut(&mut sidechain)
+            } else {
+                &mut []
+            },
+            outputs: &mut [],
+        };
+        assert_no_alloc(|| {
+            plugin.process_with_transport(&mut buffer, &mut aux, &transport, &mut context)
+        });
+
+        context.run_tasks(&mut plugin);
+        time += num_samples;
+        transport.pos_samples = Some(time as i64);
+        transport.pos_beats = Some(time as f64 / SAMPLE_RATE as f64 * 120.0 / 60.0);
+    }
+}